@@ -0,0 +1,1067 @@
+//! Loads a [`Config`] from the command line, the environment and a `bitcoin.conf` style config file.
+//!
+//! Each setting is taken from the first of these sources which has it:
+//!
+//! 1. The command line, as `-key=value` or `--key=value`. A flag alone (`-txindex`) means `-txindex=1`, and a `no`
+//!    prefix negates an option (`-notxindex`, `-noproxy`). `-regtest.port=value` only applies on regtest.
+//! 2. The environment, as `WARP_KEY=value` (e.g. `WARP_PRUNE=550`).
+//! 3. The section of the config file for the selected network (`[main]`, `[test]`, `[regtest]` or `[signet]`), or
+//!    lines like `regtest.port=value` anywhere in the file. The config file is `warp.conf` in the data directory,
+//!    unless `-conf` names another. Relative `-conf` paths are relative to the data directory.
+//! 4. The top of the config file, before any section. As in Core, `bind`, `port` and `warpport` set there only
+//!    apply to mainnet.
+//! 5. The defaults of the selected network.
+//!
+//! Options which can be given more than once, like `onlynet`, take all their values from the first source which
+//! has any. If another option is repeated, the last value on the command line wins, but the first in the config
+//! file does, matching Core. Unknown options are an error on the command line, but are ignored in the config file
+//! and the environment, so that a config file written for Core can be shared with Warp.
+use crate::{
+    default_data_dir, Config, Network, NetworkKind, ZmqEndpoint, ZmqTopic, MIN_PRUNE_TARGET_MB,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The config file read from the data directory when `-conf` isn't given
+const CONFIG_FILE: &str = "warp.conf";
+/// Bitcoin Core refuses user agents longer than this (`MAX_SUBVERSION_LENGTH`)
+const MAX_USER_AGENT_LEN: usize = 256;
+
+#[derive(Debug)]
+pub enum ArgsError {
+    /// The config file couldn't be read
+    Io(PathBuf, std::io::Error),
+    /// A line of the config file is neither a setting, a section header nor a comment
+    Syntax { line: usize, content: String },
+    /// The command line has an option we don't know
+    UnknownOption(String),
+    /// An option has a value we can't use
+    InvalidValue {
+        key: String,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgsError::Io(path, cause) => {
+                write!(
+                    f,
+                    "Could not read config file {}: {}",
+                    path.display(),
+                    cause
+                )
+            }
+            ArgsError::Syntax { line, content } => write!(
+                f,
+                "Could not parse line {} of the config file: {}",
+                line, content
+            ),
+            ArgsError::UnknownOption(option) => write!(f, "Unknown option {}", option),
+            ArgsError::InvalidValue { key, value, reason } => {
+                write!(f, "Invalid value for {} ({}): {}", key, value, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {}
+
+fn invalid(key: &str, value: &str, reason: impl fmt::Display) -> ArgsError {
+    ArgsError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    /// A boolean, which can be given without a value
+    Flag,
+    Value,
+    /// An option which can be given more than once
+    List,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Scope {
+    Global,
+    /// Only applies to mainnet when set at the top of the config file (Core's `NETWORK_ONLY`)
+    NetworkOnly,
+    /// Can't be set in the config file
+    NotInFile,
+}
+
+struct Opt {
+    name: &'static str,
+    kind: Kind,
+    scope: Scope,
+    /// The placeholder for the value in the help message
+    arg: &'static str,
+    help: &'static str,
+}
+
+const OPTIONS: &[Opt] = &[
+    Opt {
+        name: "help",
+        kind: Kind::Flag,
+        scope: Scope::NotInFile,
+        arg: "",
+        help: "Print this help message and exit",
+    },
+    Opt {
+        name: "conf",
+        kind: Kind::Value,
+        scope: Scope::NotInFile,
+        arg: "<file>",
+        help: "Read settings from <file>, relative to the data directory (default: warp.conf)",
+    },
+    Opt {
+        name: "datadir",
+        kind: Kind::Value,
+        scope: Scope::NotInFile,
+        arg: "<dir>",
+        help: "Keep data in <dir>, with testnet, regtest and signet in subdirectories (default: ~/.warp)",
+    },
+    Opt {
+        name: "daemon",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Run in the background as a service, without the interactive shell (default: 0)",
+    },
+    Opt {
+        name: "chain",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<chain>",
+        help: "Use the chain <chain>: main, test, regtest or signet (default: main)",
+    },
+    Opt {
+        name: "testnet",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Use testnet. Equivalent to -chain=test",
+    },
+    Opt {
+        name: "regtest",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Use regtest, where blocks can be mined instantly. Equivalent to -chain=regtest",
+    },
+    Opt {
+        name: "signet",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Use signet. Equivalent to -chain=signet",
+    },
+    Opt {
+        name: "signetchallenge",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<hex>",
+        help: "Use a custom signet whose blocks must satisfy the script <hex>",
+    },
+    Opt {
+        name: "bind",
+        kind: Kind::Value,
+        scope: Scope::NetworkOnly,
+        arg: "<ip>",
+        help: "Listen for inbound connections on <ip> (default: 127.0.0.1)",
+    },
+    Opt {
+        name: "port",
+        kind: Kind::Value,
+        scope: Scope::NetworkOnly,
+        arg: "<port>",
+        help: "Listen for Core nodes on <port> (default: 8333, testnet: 18333, regtest: 18444, signet: 38333)",
+    },
+    Opt {
+        name: "warpport",
+        kind: Kind::Value,
+        scope: Scope::NetworkOnly,
+        arg: "<port>",
+        help: "Listen for Warp nodes on <port> (default: the same as -port)",
+    },
+    Opt {
+        name: "server",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Accept JSON-RPC commands when running as a daemon (default: 1)",
+    },
+    Opt {
+        name: "rest",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Accept public REST requests on the RPC port, without authentication (default: 0)",
+    },
+    Opt {
+        name: "rpcbind",
+        kind: Kind::Value,
+        scope: Scope::NetworkOnly,
+        arg: "<ip>",
+        help: "Listen for JSON-RPC connections on <ip> (default: 127.0.0.1)",
+    },
+    Opt {
+        name: "rpcport",
+        kind: Kind::Value,
+        scope: Scope::NetworkOnly,
+        arg: "<port>",
+        help: "Listen for JSON-RPC connections on <port> (default: 8332, testnet: 18332, regtest: 18443, signet: 38332)",
+    },
+    Opt {
+        name: "rpcuser",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<user>",
+        help: "Username for JSON-RPC connections",
+    },
+    Opt {
+        name: "rpcpassword",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<pw>",
+        help: "Password for JSON-RPC connections. Without it, a random one is written to the cookie file",
+    },
+    Opt {
+        name: "rpccookiefile",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<file>",
+        help: "Location of the cookie file, relative to the network's data directory (default: .cookie)",
+    },
+    Opt {
+        name: "maxconnections",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<n>",
+        help: "Keep at most <n> connections to peers (default: 125)",
+    },
+    Opt {
+        name: "onlynet",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<net>",
+        help: "Only make outbound connections to <net>: ipv4, ipv6 or onion. Can be given more than once",
+    },
+    Opt {
+        name: "proxy",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<ip:port>",
+        help: "Make outbound connections through a SOCKS5 proxy",
+    },
+    Opt {
+        name: "onion",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<ip:port>",
+        help: "Reach onion peers through a different SOCKS5 proxy (default: -proxy)",
+    },
+    Opt {
+        name: "proxyrandomize",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Use fresh proxy credentials for every connection (default: 1)",
+    },
+    Opt {
+        name: "v2transport",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Support the encrypted v2 transport (BIP324) (default: 1)",
+    },
+    Opt {
+        name: "uacomment",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<comment>",
+        help: "Append <comment> to the user agent. Can be given more than once",
+    },
+    Opt {
+        name: "prune",
+        kind: Kind::Value,
+        scope: Scope::Global,
+        arg: "<n>",
+        help: "Keep at most <n> MiB of blocks on disk, or 0 to keep every block (default: 0, minimum: 550)",
+    },
+    Opt {
+        name: "txindex",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Maintain an index of every transaction in the chain (default: 0)",
+    },
+    Opt {
+        name: "scriptindex",
+        kind: Kind::Flag,
+        scope: Scope::Global,
+        arg: "",
+        help: "Maintain an index of the history of every output script (default: 0)",
+    },
+    Opt {
+        name: "zmqpubhashblock",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<address>",
+        help: "Publish the hash of each new block over ZMQ at <address>, tcp://<ip>:<port> or ipc://<path>. Can be given more than once",
+    },
+    Opt {
+        name: "zmqpubhashtx",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<address>",
+        help: "Publish the hash of each new transaction over ZMQ at <address>, tcp://<ip>:<port> or ipc://<path>. Can be given more than once",
+    },
+    Opt {
+        name: "zmqpubrawblock",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<address>",
+        help: "Publish each new block over ZMQ at <address>, tcp://<ip>:<port> or ipc://<path>. Can be given more than once",
+    },
+    Opt {
+        name: "zmqpubrawtx",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<address>",
+        help: "Publish each new transaction over ZMQ at <address>, tcp://<ip>:<port> or ipc://<path>. Can be given more than once",
+    },
+    Opt {
+        name: "zmqpubsequence",
+        kind: Kind::List,
+        scope: Scope::Global,
+        arg: "<address>",
+        help: "Publish block and mempool sequence events over ZMQ at <address>, tcp://<ip>:<port> or ipc://<path>. Can be given more than once",
+    },
+];
+
+fn find_option(name: &str) -> Option<&'static Opt> {
+    OPTIONS.iter().find(|option| option.name == name)
+}
+
+/// The help message listing every option
+pub fn help_message() -> String {
+    let mut message = String::from("Usage: warpd [options]\n\nOptions:\n");
+    for option in OPTIONS {
+        if option.arg.is_empty() {
+            message.push_str(&format!("  -{}\n", option.name));
+        } else {
+            message.push_str(&format!("  -{}={}\n", option.name, option.arg));
+        }
+        message.push_str(&format!("       {}\n\n", option.help));
+    }
+    message
+}
+
+/// A setting parsed from one source
+struct Setting {
+    /// The network the setting is limited to, from a `network.key` prefix
+    section: Option<String>,
+    option: &'static Opt,
+    /// `None` clears a list
+    value: Option<String>,
+}
+
+/// Splits `name` into an optional section and an option, and interprets a `no` prefix. Returns `None` for unknown
+/// options.
+fn interpret(name: &str, value: Option<&str>) -> Result<Option<Setting>, ArgsError> {
+    let (section, key) = match name.split_once('.') {
+        Some((section, key)) => (Some(section.to_string()), key),
+        None => (None, name),
+    };
+    let (option, negated) = match (find_option(key), key.strip_prefix("no")) {
+        (Some(option), _) => (option, false),
+        (None, Some(key)) => match find_option(key) {
+            Some(option) => (option, true),
+            None => return Ok(None),
+        },
+        (None, None) => return Ok(None),
+    };
+    let value = if negated {
+        // -nofoo=0 is a double negative, which Core reads as -foo=1
+        let negated = match value {
+            None | Some("") | Some("1") => true,
+            Some("0") => false,
+            Some(value) => return Err(invalid(name, value, "negated options take 0 or 1")),
+        };
+        match (negated, option.kind) {
+            (true, Kind::List) => None,
+            (true, _) => Some(String::from("0")),
+            (false, _) => Some(String::from("1")),
+        }
+    } else {
+        match (value, option.kind) {
+            (Some(value), _) => Some(value.to_string()),
+            (None, Kind::Flag) => Some(String::from("1")),
+            (None, _) => return Err(invalid(name, "", "a value is required")),
+        }
+    };
+    Ok(Some(Setting {
+        section,
+        option,
+        value,
+    }))
+}
+
+type Settings = HashMap<String, Vec<String>>;
+
+/// Records `value` for `key`. Options which aren't lists only ever have one value: the first one if `first_wins`,
+/// and otherwise the last.
+fn store(
+    settings: &mut Settings,
+    key: String,
+    option: &Opt,
+    value: Option<String>,
+    first_wins: bool,
+) {
+    match (option.kind, value) {
+        (Kind::List, Some(value)) => settings.entry(key).or_default().push(value),
+        (Kind::List, None) => {
+            settings.insert(key, Vec::new());
+        }
+        (_, Some(value)) if first_wins => {
+            settings.entry(key).or_insert_with(|| vec![value]);
+        }
+        (_, Some(value)) => {
+            settings.insert(key, vec![value]);
+        }
+        (_, None) => unreachable!("Only lists can be cleared"),
+    }
+}
+
+/// The settings from every source, before they're resolved for a network
+#[derive(Debug, Default, Clone)]
+pub struct Args {
+    command_line: Settings,
+    environment: Settings,
+    /// The settings at the top of the config file
+    config_file: Settings,
+    /// The settings in each section of the config file, by network name
+    sections: HashMap<String, Settings>,
+    /// The arguments after the last option on the command line
+    positional: Vec<String>,
+    /// Unknown options and sections from the config file and the environment
+    ignored: Vec<String>,
+}
+
+impl Args {
+    /// Reads the command line, then the environment, then the config file. A config file named by `-conf` must
+    /// exist, but the default one is optional.
+    pub fn load<A, E>(command_line: A, environment: E) -> Result<Args, ArgsError>
+    where
+        A: IntoIterator<Item = String>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let mut args = Args::parse_command_line(command_line)?;
+        args.read_environment(environment)?;
+        let data_dir = args.data_dir();
+        match args.config_file() {
+            Some(path) => args.read_config_file(data_dir.join(path))?,
+            None if data_dir.join(CONFIG_FILE).is_file() => {
+                args.read_config_file(data_dir.join(CONFIG_FILE))?
+            }
+            None => {}
+        }
+        Ok(args)
+    }
+
+    /// Parses options up to the first argument which isn't one. The rest are left in [`positional`](Args::positional).
+    pub fn parse_command_line<A: IntoIterator<Item = String>>(
+        command_line: A,
+    ) -> Result<Args, ArgsError> {
+        let mut args = Args::default();
+        let mut command_line = command_line.into_iter();
+        while let Some(arg) = command_line.next() {
+            let name = match arg.strip_prefix("--").or_else(|| arg.strip_prefix('-')) {
+                Some(name) if !name.is_empty() => name,
+                _ => {
+                    args.positional.push(arg);
+                    args.positional.extend(command_line);
+                    break;
+                }
+            };
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (name, None),
+            };
+            let name = match name {
+                "h" | "?" => "help",
+                name => name,
+            };
+            let setting = match interpret(name, value)? {
+                Some(setting) => setting,
+                None => return Err(ArgsError::UnknownOption(arg)),
+            };
+            let key = match setting.section {
+                Some(section) => {
+                    if section.parse::<Network>().is_err() {
+                        return Err(ArgsError::UnknownOption(arg));
+                    }
+                    format!("{}.{}", section, setting.option.name)
+                }
+                None => setting.option.name.to_string(),
+            };
+            store(
+                &mut args.command_line,
+                key,
+                setting.option,
+                setting.value,
+                false,
+            );
+        }
+        Ok(args)
+    }
+
+    /// Reads the variables named `WARP_<OPTION>`
+    pub fn read_environment<E: IntoIterator<Item = (String, String)>>(
+        &mut self,
+        environment: E,
+    ) -> Result<(), ArgsError> {
+        for (variable, value) in environment {
+            let name = match variable.strip_prefix("WARP_") {
+                Some(name) => name.to_ascii_lowercase(),
+                None => continue,
+            };
+            match interpret(&name, Some(&value))? {
+                Some(setting) if setting.section.is_none() => store(
+                    &mut self.environment,
+                    name,
+                    setting.option,
+                    setting.value,
+                    false,
+                ),
+                _ => self.ignored.push(variable),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_config_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), ArgsError> {
+        let path = path.as_ref();
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| ArgsError::Io(path.to_path_buf(), e))?;
+        self.read_config(&contents)
+    }
+
+    /// Reads the contents of a config file: `key=value` lines, `[network]` section headers and `#` comments
+    pub fn read_config(&mut self, contents: &str) -> Result<(), ArgsError> {
+        let mut current_section: Option<String> = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let section = section.trim().to_string();
+                if section.parse::<Network>().is_err() {
+                    self.ignored.push(format!("[{}]", section));
+                }
+                current_section = Some(section);
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => {
+                    return Err(ArgsError::Syntax {
+                        line: number + 1,
+                        content: line.to_string(),
+                    })
+                }
+            };
+            let setting = match interpret(name, Some(value))? {
+                Some(setting) => setting,
+                None => {
+                    self.ignored.push(name.to_string());
+                    continue;
+                }
+            };
+            if setting.option.scope == Scope::NotInFile {
+                return Err(invalid(name, value, "can't be set in a config file"));
+            }
+            let settings = match setting.section.or_else(|| current_section.clone()) {
+                None => &mut self.config_file,
+                Some(section) => {
+                    if section.parse::<Network>().is_err() {
+                        // Unknown section headers were already reported
+                        if current_section.as_ref() != Some(&section) {
+                            self.ignored.push(name.to_string());
+                        }
+                        continue;
+                    }
+                    self.sections.entry(section).or_default()
+                }
+            };
+            store(
+                settings,
+                setting.option.name.to_string(),
+                setting.option,
+                setting.value,
+                true,
+            );
+        }
+        Ok(())
+    }
+
+    /// The config file to read, from `-conf`
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.get(None, "conf").map(PathBuf::from)
+    }
+
+    /// The root of the data directory, from `-datadir`
+    pub fn data_dir(&self) -> PathBuf {
+        self.get(None, "datadir")
+            .map(PathBuf::from)
+            .unwrap_or_else(default_data_dir)
+    }
+
+    pub fn help_requested(&self) -> bool {
+        self.get(None, "help").is_some_and(|value| value != "0")
+    }
+
+    /// Whether to run as a service rather than with the interactive shell, from `-daemon`
+    pub fn daemon(&self) -> Result<bool, ArgsError> {
+        Ok(self.get_bool(None, "daemon")?.unwrap_or(false))
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// The unknown options and sections which were skipped, so that they can be reported
+    pub fn ignored(&self) -> &[String] {
+        &self.ignored
+    }
+
+    /// The values of `key` from the highest priority source which has it. `network` selects the config file section
+    /// and `network.key` options to consider.
+    fn values(&self, network: Option<Network>, key: &str) -> Option<&Vec<String>> {
+        let option = find_option(key).expect("Looked up an undeclared option");
+        if let Some(network) = network {
+            let sectioned = format!("{}.{}", network.name(), key);
+            if let Some(values) = self.command_line.get(&sectioned) {
+                return Some(values);
+            }
+        }
+        if let Some(values) = self.command_line.get(key) {
+            return Some(values);
+        }
+        if let Some(values) = self.environment.get(key) {
+            return Some(values);
+        }
+        if let Some(network) = network {
+            let section = self.sections.get(network.name());
+            if let Some(values) = section.and_then(|settings| settings.get(key)) {
+                return Some(values);
+            }
+        }
+        if option.scope == Scope::NetworkOnly && network != Some(Network::Mainnet) {
+            return None;
+        }
+        self.config_file.get(key)
+    }
+
+    fn get(&self, network: Option<Network>, key: &str) -> Option<&str> {
+        self.values(network, key)
+            .and_then(|values| values.last())
+            .map(String::as_str)
+    }
+
+    fn get_bool(&self, network: Option<Network>, key: &str) -> Result<Option<bool>, ArgsError> {
+        match self.get(network, key) {
+            None => Ok(None),
+            Some("") | Some("1") => Ok(Some(true)),
+            Some("0") => Ok(Some(false)),
+            Some(value) => Err(invalid(key, value, "expected 0 or 1")),
+        }
+    }
+
+    fn get_parsed<T>(&self, network: Option<Network>, key: &str) -> Result<Option<T>, ArgsError>
+    where
+        T: std::str::FromStr,
+        T::Err: fmt::Display,
+    {
+        self.get(network, key)
+            .map(|value| value.parse().map_err(|e| invalid(key, value, e)))
+            .transpose()
+    }
+
+    /// A proxy address, where `0` (as set by `-noproxy`) means no proxy
+    fn get_proxy(
+        &self,
+        network: Option<Network>,
+        key: &str,
+    ) -> Result<Option<Option<std::net::SocketAddr>>, ArgsError> {
+        match self.get(network, key) {
+            None => Ok(None),
+            Some("") | Some("0") => Ok(Some(None)),
+            Some(_) => self.get_parsed(network, key).map(|proxy| proxy.map(Some)),
+        }
+    }
+
+    /// The network selected by `-chain`, `-testnet`, `-regtest` or `-signet`. Selecting more than one is an error.
+    pub fn network(&self) -> Result<Network, ArgsError> {
+        let mut selected = Vec::new();
+        for (flag, network) in [
+            ("testnet", Network::Testnet),
+            ("regtest", Network::Regtest),
+            ("signet", Network::Signet),
+        ]
+        .iter()
+        {
+            if self.get_bool(None, flag)? == Some(true) {
+                selected.push(*network);
+            }
+        }
+        if let Some(chain) = self.get_parsed(None, "chain")? {
+            selected.push(chain);
+        }
+        match selected.as_slice() {
+            [] => Ok(Network::Mainnet),
+            [network] => Ok(*network),
+            _ => {
+                let names: Vec<_> = selected.iter().map(Network::name).collect();
+                Err(invalid(
+                    "chain",
+                    &names.join(", "),
+                    "use at most one of -chain, -testnet, -regtest and -signet",
+                ))
+            }
+        }
+    }
+
+    /// Builds the config for the selected network, applying every setting to it
+    pub fn to_config(&self) -> Result<Config, ArgsError> {
+        let network = self.network()?;
+        let net = Some(network);
+        let mut config = match network {
+            Network::Mainnet => Config::mainnet(),
+            Network::Testnet => Config::testnet(),
+            Network::Regtest => Config::regtest(),
+            Network::Signet => match self.get(net, "signetchallenge") {
+                Some(challenge) => Config::custom_signet(
+                    hex::decode(challenge).map_err(|e| invalid("signetchallenge", challenge, e))?,
+                ),
+                None => Config::signet(),
+            },
+        };
+        if let Some(dir) = self.get(None, "datadir") {
+            // Like Core, don't create a data directory in the wrong place because of a typo
+            if !Path::new(dir).is_dir() {
+                return Err(invalid("datadir", dir, "not an existing directory"));
+            }
+            config.set_data_dir(PathBuf::from(dir));
+        }
+        if let Some(prune) = self.get_parsed::<u64>(net, "prune")? {
+            if prune != 0 && prune < MIN_PRUNE_TARGET_MB {
+                return Err(invalid(
+                    "prune",
+                    &prune.to_string(),
+                    format!("must be 0 or at least {}", MIN_PRUNE_TARGET_MB),
+                ));
+            }
+            config.set_prune_target_mb(Some(prune).filter(|&mb| mb != 0));
+        }
+        if let Some(enabled) = self.get_bool(net, "txindex")? {
+            config.set_txindex(enabled);
+        }
+        if let Some(enabled) = self.get_bool(net, "scriptindex")? {
+            config.set_script_index(enabled);
+        }
+        if let Some(enabled) = self.get_bool(net, "v2transport")? {
+            config.set_v2_transport(enabled);
+        }
+        if let Some(proxy) = self.get_proxy(net, "proxy")? {
+            config.set_proxy(proxy);
+        }
+        if let Some(proxy) = self.get_proxy(net, "onion")? {
+            config.set_onion_proxy(proxy);
+        }
+        if let Some(enabled) = self.get_bool(net, "proxyrandomize")? {
+            config.set_proxy_randomize(enabled);
+        }
+        if let Some(values) = self.values(net, "onlynet") {
+            let networks = values
+                .iter()
+                .map(|value| {
+                    value
+                        .parse::<NetworkKind>()
+                        .map_err(|e| invalid("onlynet", value, e))
+                })
+                .collect::<Result<_, _>>()?;
+            config.set_only_net(networks);
+        }
+        if let Some(comments) = self.values(net, "uacomment") {
+            let safe = |c: char| c.is_ascii_alphanumeric() || " .,;-_?@".contains(c);
+            if let Some(comment) = comments.iter().find(|comment| !comment.chars().all(safe)) {
+                return Err(invalid("uacomment", comment, "contains unsafe characters"));
+            }
+            config.set_user_agent_comments(comments.clone());
+            if config.user_agent().len() > MAX_USER_AGENT_LEN {
+                return Err(invalid(
+                    "uacomment",
+                    &comments.join(", "),
+                    format!(
+                        "the user agent would be longer than {} characters",
+                        MAX_USER_AGENT_LEN
+                    ),
+                ));
+            }
+        }
+        if let Some(ip) = self.get_parsed(net, "bind")? {
+            config.set_ip_address(ip);
+        }
+        if let Some(port) = self.get_parsed::<u16>(net, "port")? {
+            config.set_core_port(port as usize);
+            config.set_warp_port(port as usize);
+        }
+        if let Some(port) = self.get_parsed::<u16>(net, "warpport")? {
+            config.set_warp_port(port as usize);
+        }
+        if let Some(max_peers) = self.get_parsed(net, "maxconnections")? {
+            config.set_max_peers(max_peers);
+        }
+        if let Some(enabled) = self.get_bool(net, "server")? {
+            config.set_rpc_server(enabled);
+        }
+        if let Some(enabled) = self.get_bool(net, "rest")? {
+            config.set_rest(enabled);
+        }
+        if let Some(ip) = self.get_parsed(net, "rpcbind")? {
+            config.set_rpc_bind(ip);
+        }
+        if let Some(port) = self.get_parsed::<u16>(net, "rpcport")? {
+            config.set_rpc_port(port as usize);
+        }
+        match (self.get(net, "rpcuser"), self.get(net, "rpcpassword")) {
+            (Some(user), Some(password)) => {
+                if user.contains(':') {
+                    return Err(invalid("rpcuser", user, "must not contain a colon"));
+                }
+                config.set_rpc_credentials(Some((user.to_string(), password.to_string())));
+            }
+            (None, None) => {}
+            (Some(user), None) => return Err(invalid("rpcuser", user, "needs -rpcpassword")),
+            (None, Some(_)) => return Err(invalid("rpcpassword", "", "needs -rpcuser")),
+        }
+        if let Some(path) = self.get(net, "rpccookiefile") {
+            config.set_rpc_cookie_file(PathBuf::from(path));
+        }
+        let mut notifications = Vec::new();
+        for topic in ZmqTopic::ALL.iter() {
+            let key = format!("zmqpub{}", topic.name());
+            for address in self.values(net, &key).into_iter().flatten() {
+                let endpoint = address
+                    .parse::<ZmqEndpoint>()
+                    .map_err(|e| invalid(&key, address, e))?;
+                notifications.push((*topic, endpoint));
+            }
+        }
+        config.set_zmq_notifications(notifications);
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_line(args: &[&str]) -> Result<Args, ArgsError> {
+        Args::parse_command_line(args.iter().map(|arg| arg.to_string()))
+    }
+
+    const CONFIG: &str = "
+        # Shared with Core
+        rpcallowip=127.0.0.1
+        txindex=1
+        port=9000
+        uacomment=top
+        [regtest]
+        prune=600 # trailing comment
+        onlynet=ipv4
+        onlynet=onion
+        [test]
+        prune=700
+        [nonsense]
+        txindex=0
+    ";
+
+    #[test]
+    fn sources_apply_in_order_of_precedence() {
+        let mut args = command_line(&["-regtest", "--noonlynet", "-prune=800"]).unwrap();
+        args.read_environment(vec![
+            (String::from("WARP_PRUNE"), String::from("900")),
+            (String::from("WARP_SCRIPTINDEX"), String::from("1")),
+            (String::from("WARP_UNKNOWN"), String::from("1")),
+            (String::from("HOME"), String::from("/root")),
+        ])
+        .unwrap();
+        args.read_config(CONFIG).unwrap();
+        let config = args.to_config().unwrap();
+        assert_eq!(config.network(), Network::Regtest);
+        // The command line beats the environment, which beats the config file
+        assert_eq!(config.prune_target_mb(), Some(800));
+        assert!(config.script_index());
+        // The top of the file applies to every network, except for network only options
+        assert!(config.txindex());
+        assert_eq!(config.core_port(), 18444);
+        assert!(config.user_agent().ends_with("(top)/"));
+        // -noonlynet clears the list from the config file
+        assert!(config.only_net().is_empty());
+        assert_eq!(args.ignored(), ["WARP_UNKNOWN", "rpcallowip", "[nonsense]"]);
+
+        let mut args = command_line(&["-chain=test"]).unwrap();
+        args.read_config(CONFIG).unwrap();
+        let config = args.to_config().unwrap();
+        assert_eq!(config.prune_target_mb(), Some(700));
+        assert_eq!(config.magic(), Config::testnet().magic());
+
+        let mut args = command_line(&[]).unwrap();
+        args.read_config(CONFIG).unwrap();
+        let config = args.to_config().unwrap();
+        assert_eq!(config.core_port(), 9000);
+        assert_eq!(config.warp_port(), 9000);
+        assert_eq!(config.prune_target_mb(), None);
+    }
+
+    #[test]
+    fn options_can_be_scoped_and_negated() {
+        let args = command_line(&[
+            "-regtest",
+            "-regtest.port=1234",
+            "-test.port=5678",
+            "-proxy=127.0.0.1:9050",
+            "-noproxy",
+            "-nov2transport",
+            "-onlynet=ipv4",
+            "-onlynet=tor",
+            "-bind=::1",
+            "-rpcuser=alice",
+            "-rpcpassword=secret",
+            "-regtest.rpcport=1235",
+            "-rest",
+            "-zmqpubhashblock=tcp://*:28332",
+            "-zmqpubsequence=tcp://127.0.0.1:28333",
+            "-zmqpubsequence=ipc:///tmp/warp.sock",
+            "getblockcount",
+            "-txindex",
+        ])
+        .unwrap();
+        let config = args.to_config().unwrap();
+        assert_eq!(config.core_port(), 1234);
+        assert_eq!(config.proxy(), None);
+        assert!(!config.v2_transport());
+        assert_eq!(config.only_net(), [NetworkKind::Ipv4, NetworkKind::Onion]);
+        assert_eq!(
+            config.ip_address(),
+            "::1".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert!(!config.txindex());
+        assert!(!args.daemon().unwrap());
+        assert_eq!(config.rpc_credentials(), Some(("alice", "secret")));
+        assert_eq!(config.rpc_port(), 1235);
+        assert!(config.rest());
+        assert_eq!(
+            config.zmq_notifications(),
+            [
+                (
+                    ZmqTopic::HashBlock,
+                    ZmqEndpoint::Tcp("0.0.0.0:28332".parse().unwrap())
+                ),
+                (
+                    ZmqTopic::Sequence,
+                    ZmqEndpoint::Tcp("127.0.0.1:28333".parse().unwrap())
+                ),
+                (
+                    ZmqTopic::Sequence,
+                    ZmqEndpoint::Ipc(PathBuf::from("/tmp/warp.sock"))
+                ),
+            ]
+        );
+        assert_eq!(args.positional(), ["getblockcount", "-txindex"]);
+
+        let mut args = command_line(&["-signet"]).unwrap();
+        args.read_config("[signet]\nsignetchallenge=51\n").unwrap();
+        let config = args.to_config().unwrap();
+        assert_eq!(config.chain_params().signet_challenge, Some(vec![0x51]));
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        let key_of = |error: ArgsError| match error {
+            ArgsError::InvalidValue { key, .. } => key,
+            error => panic!("Unexpected error {}", error),
+        };
+        let to_config = |args: &[&str]| command_line(args).unwrap().to_config().unwrap_err();
+        assert_eq!(key_of(to_config(&["-prune=100"])), "prune");
+        assert_eq!(key_of(to_config(&["-txindex=yes"])), "txindex");
+        assert_eq!(key_of(to_config(&["-onlynet=ipx"])), "onlynet");
+        assert_eq!(key_of(to_config(&["-uacomment=a/b"])), "uacomment");
+        assert_eq!(key_of(to_config(&["-port=70000"])), "port");
+        assert_eq!(key_of(to_config(&["-rpcuser=alice"])), "rpcuser");
+        assert_eq!(
+            key_of(to_config(&["-zmqpubrawtx=udp://127.0.0.1:1"])),
+            "zmqpubrawtx"
+        );
+        assert_eq!(
+            key_of(to_config(&["-datadir=/nonexistent/warp"])),
+            "datadir"
+        );
+        assert_eq!(key_of(to_config(&["-testnet", "-chain=regtest"])), "chain");
+        assert_eq!(key_of(command_line(&["-conf"]).unwrap_err()), "conf");
+        assert!(matches!(
+            command_line(&["-rpcallowip=10.0.0.0/8"]),
+            Err(ArgsError::UnknownOption(_))
+        ));
+
+        let mut args = Args::default();
+        assert!(matches!(
+            args.read_config("txindex=1\nregtest\n"),
+            Err(ArgsError::Syntax { line: 2, .. })
+        ));
+        assert_eq!(
+            key_of(args.read_config("conf=other.conf").unwrap_err()),
+            "conf"
+        );
+        let error = Args::load(vec![String::from("-conf=/nonexistent/warp.conf")], vec![]);
+        assert!(matches!(error, Err(ArgsError::Io(..))));
+    }
+
+    #[test]
+    fn reads_the_config_file_in_the_data_dir() {
+        let dir = std::env::temp_dir().join(format!("warp-args-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(CONFIG_FILE), "regtest=1\n").unwrap();
+        std::fs::write(dir.join("other.conf"), "testnet=1\n").unwrap();
+        let datadir = format!("-datadir={}", dir.display());
+
+        let config = Args::load(vec![datadir.clone()], vec![])
+            .unwrap()
+            .to_config()
+            .unwrap();
+        assert_eq!(config.network(), Network::Regtest);
+        assert_eq!(config.data_dir(), dir.join("regtest"));
+        std::fs::write(dir.join(CONFIG_FILE), "regtest=1\ndaemon=1\n").unwrap();
+        assert!(Args::load(vec![datadir.clone()], vec![])
+            .unwrap()
+            .daemon()
+            .unwrap());
+        let args = Args::load(vec![datadir, String::from("-conf=other.conf")], vec![]).unwrap();
+        assert_eq!(args.network().unwrap(), Network::Testnet);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
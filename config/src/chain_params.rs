@@ -0,0 +1,310 @@
+//! Consensus parameters for each network, following Core's `chainparams.cpp`.
+
+const MAGIC_MAINNET: u32 = 0xD9B4BEF9;
+const MAGIC_TESTNET: u32 = 0x0709110B;
+const MAGIC_REGTEST: u32 = 0xDAB5BFFA;
+
+/// The merkle root shared by every network's genesis block, which all have the same coinbase, in internal byte order
+const GENESIS_MERKLE_ROOT: [u8; 32] = [
+    0x3b, 0xa3, 0xed, 0xfd, 0x7a, 0x7b, 0x12, 0xb2, 0x7a, 0xc7, 0x2c, 0x3e, 0x67, 0x76, 0x8f, 0x61,
+    0x7f, 0xc8, 0x1b, 0xc3, 0x88, 0x8a, 0x51, 0x32, 0x3a, 0x9f, 0xb8, 0xaa, 0x4b, 0x1e, 0x5e, 0x4a,
+];
+
+// Genesis block hashes, in internal (little-endian) byte order
+const GENESIS_MAINNET: [u8; 32] = [
+    0x6f, 0xe2, 0x8c, 0x0a, 0xb6, 0xf1, 0xb3, 0x72, 0xc1, 0xa6, 0xa2, 0x46, 0xae, 0x63, 0xf7, 0x4f,
+    0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+const GENESIS_TESTNET: [u8; 32] = [
+    0x43, 0x49, 0x7f, 0xd7, 0xf8, 0x26, 0x95, 0x71, 0x08, 0xf4, 0xa3, 0x0f, 0xd9, 0xce, 0xc3, 0xae,
+    0xba, 0x79, 0x97, 0x20, 0x84, 0xe9, 0x0e, 0xad, 0x01, 0xea, 0x33, 0x09, 0x00, 0x00, 0x00, 0x00,
+];
+const GENESIS_REGTEST: [u8; 32] = [
+    0x06, 0x22, 0x6e, 0x46, 0x11, 0x1a, 0x0b, 0x59, 0xca, 0xaf, 0x12, 0x60, 0x43, 0xeb, 0x5b, 0xbf,
+    0x28, 0xc3, 0x4f, 0x3a, 0x5e, 0x33, 0x2a, 0x1f, 0xc7, 0xb2, 0xb7, 0x3c, 0xf1, 0x88, 0x91, 0x0f,
+];
+const GENESIS_SIGNET: [u8; 32] = [
+    0xf6, 0x1e, 0xee, 0x3b, 0x63, 0xa3, 0x80, 0xa4, 0x77, 0xa0, 0x63, 0xaf, 0x32, 0xb2, 0xbb, 0xc9,
+    0x7c, 0x9f, 0xf9, 0xf0, 0x1f, 0x2c, 0x42, 0x25, 0xe9, 0x73, 0x98, 0x81, 0x08, 0x00, 0x00, 0x00,
+];
+
+/// The 1-of-2 multisig which signs the blocks of the default signet
+const DEFAULT_SIGNET_CHALLENGE: [u8; 71] = [
+    0x51, 0x21, 0x03, 0xad, 0x5e, 0x0e, 0xda, 0xd1, 0x8c, 0xb1, 0xf0, 0xfc, 0x0d, 0x28, 0xa3, 0xd4,
+    0xf1, 0xf3, 0xe4, 0x45, 0x64, 0x03, 0x37, 0x48, 0x9a, 0xbb, 0x10, 0x40, 0x4f, 0x2d, 0x1e, 0x08,
+    0x6b, 0xe4, 0x30, 0x21, 0x03, 0x59, 0xef, 0x50, 0x21, 0x96, 0x4f, 0xe2, 0x2d, 0x6f, 0x8e, 0x05,
+    0xb2, 0x46, 0x3c, 0x95, 0x40, 0xce, 0x96, 0x88, 0x3f, 0xe3, 0xb2, 0x78, 0x76, 0x0f, 0x04, 0x8f,
+    0x51, 0x89, 0xf2, 0xe6, 0xc4, 0x52, 0xae,
+];
+
+// Difficulty retargets every two weeks, aiming for a block every ten minutes
+const POW_TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+const POW_TARGET_SPACING: u32 = 10 * 60;
+
+/// The fields of a network's genesis block header
+#[derive(Debug, Clone, PartialEq)]
+pub struct Genesis {
+    /// The block hash, in internal byte order
+    pub hash: [u8; 32],
+    pub version: u32,
+    /// The merkle root, in internal byte order
+    pub merkle_root: [u8; 32],
+    pub time: u32,
+    pub bits: u32,
+    pub nonce: u32,
+}
+
+impl Genesis {
+    fn new(hash: [u8; 32], time: u32, bits: u32, nonce: u32) -> Genesis {
+        Genesis {
+            hash,
+            version: 1,
+            merkle_root: GENESIS_MERKLE_ROOT,
+            time,
+            bits,
+            nonce,
+        }
+    }
+}
+
+/// The heights at which soft forks became mandatory. Core buries these deployments instead of checking the version
+/// bits signalled for them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Deployments {
+    /// Height in coinbase (BIP34)
+    pub bip34_height: u32,
+    /// `OP_CHECKLOCKTIMEVERIFY` (BIP65)
+    pub bip65_height: u32,
+    /// Strict DER signatures (BIP66)
+    pub bip66_height: u32,
+    /// Relative lock times: `OP_CHECKSEQUENCEVERIFY` and BIPs 68 and 112-113
+    pub csv_height: u32,
+    /// Segregated witness (BIPs 141, 143 and 147)
+    pub segwit_height: u32,
+}
+
+/// Everything that differs between networks at the consensus level
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainParams {
+    /// The message start bytes, read as a little endian integer
+    pub magic: u32,
+    pub genesis: Genesis,
+    pub deployments: Deployments,
+    /// The easiest allowed target, in compact form
+    pub pow_limit_bits: u32,
+    /// The time each difficulty period is meant to take, in seconds
+    pub pow_target_timespan: u32,
+    /// The time each block is meant to take, in seconds
+    pub pow_target_spacing: u32,
+    /// Whether a block more than twenty minutes after its parent may use the minimum difficulty (testnet's rule)
+    pub pow_allow_min_difficulty_blocks: bool,
+    /// Whether difficulty never changes (regtest)
+    pub pow_no_retargeting: bool,
+    /// The DNS seeds to find the first peers through
+    pub dns_seeds: Vec<&'static str>,
+    /// The script every signet block must satisfy, or `None` on other networks
+    pub signet_challenge: Option<Vec<u8>>,
+    /// The version byte of base58 pay to pubkey hash addresses
+    pub pubkey_address_prefix: u8,
+    /// The version byte of base58 pay to script hash addresses
+    pub script_address_prefix: u8,
+    /// The human readable part of segwit addresses (BIP173)
+    pub bech32_hrp: &'static str,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> ChainParams {
+        ChainParams {
+            magic: MAGIC_MAINNET,
+            genesis: Genesis::new(GENESIS_MAINNET, 1231006505, 0x1d00ffff, 2083236893),
+            deployments: Deployments {
+                bip34_height: 227931,
+                bip65_height: 388381,
+                bip66_height: 363725,
+                csv_height: 419328,
+                segwit_height: 481824,
+            },
+            pow_limit_bits: 0x1d00ffff,
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: false,
+            pow_no_retargeting: false,
+            dns_seeds: vec![
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+                "seed.mainnet.achownodes.xyz",
+            ],
+            signet_challenge: None,
+            pubkey_address_prefix: 0,
+            script_address_prefix: 5,
+            bech32_hrp: "bc",
+        }
+    }
+
+    /// Testnet3
+    pub fn testnet() -> ChainParams {
+        ChainParams {
+            magic: MAGIC_TESTNET,
+            genesis: Genesis::new(GENESIS_TESTNET, 1296688602, 0x1d00ffff, 414098458),
+            deployments: Deployments {
+                bip34_height: 21111,
+                bip65_height: 581885,
+                bip66_height: 330776,
+                csv_height: 770112,
+                segwit_height: 834624,
+            },
+            pow_limit_bits: 0x1d00ffff,
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: false,
+            dns_seeds: vec![
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+                "seed.testnet.achownodes.xyz",
+            ],
+            signet_challenge: None,
+            pubkey_address_prefix: 111,
+            script_address_prefix: 196,
+            bech32_hrp: "tb",
+        }
+    }
+
+    /// Regtest, where every soft fork is active from the start and blocks can be mined instantly
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            magic: MAGIC_REGTEST,
+            genesis: Genesis::new(GENESIS_REGTEST, 1296688602, 0x207fffff, 2),
+            deployments: Deployments {
+                bip34_height: 1,
+                bip65_height: 1,
+                bip66_height: 1,
+                csv_height: 1,
+                segwit_height: 0,
+            },
+            pow_limit_bits: 0x207fffff,
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: true,
+            dns_seeds: Vec::new(),
+            signet_challenge: None,
+            pubkey_address_prefix: 111,
+            script_address_prefix: 196,
+            bech32_hrp: "bcrt",
+        }
+    }
+
+    /// The default signet (BIP325)
+    pub fn signet() -> ChainParams {
+        let mut params = ChainParams::custom_signet(DEFAULT_SIGNET_CHALLENGE.to_vec());
+        params.dns_seeds = vec![
+            "seed.signet.bitcoin.sprovoost.nl",
+            "seed.signet.achownodes.xyz",
+        ];
+        params
+    }
+
+    /// A signet whose blocks must satisfy `challenge`. It shares the default signet's genesis block, but has its own
+    /// magic, so its nodes don't talk to the default signet's. Custom signets have no DNS seeds.
+    pub fn custom_signet(challenge: Vec<u8>) -> ChainParams {
+        ChainParams {
+            magic: signet_magic(&challenge),
+            genesis: Genesis::new(GENESIS_SIGNET, 1598918400, 0x1e0377ae, 52613770),
+            deployments: Deployments {
+                bip34_height: 1,
+                bip65_height: 1,
+                bip66_height: 1,
+                csv_height: 1,
+                segwit_height: 1,
+            },
+            pow_limit_bits: 0x1e0377ae,
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: false,
+            pow_no_retargeting: false,
+            dns_seeds: Vec::new(),
+            signet_challenge: Some(challenge),
+            pubkey_address_prefix: 111,
+            script_address_prefix: 196,
+            bech32_hrp: "tb",
+        }
+    }
+
+    /// The number of blocks between difficulty retargets
+    pub fn difficulty_adjustment_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+}
+
+/// A signet's magic is the first four bytes of the double SHA256 of its challenge, serialized with its length
+fn signet_magic(challenge: &[u8]) -> u32 {
+    let mut serialized = Vec::with_capacity(challenge.len() + 9);
+    match challenge.len() {
+        len if len < 0xfd => serialized.push(len as u8),
+        len if len <= 0xffff => {
+            serialized.push(0xfd);
+            serialized.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        len => {
+            serialized.push(0xfe);
+            serialized.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+    serialized.extend_from_slice(challenge);
+    let hash = warp_crypto::sha256d(&serialized);
+    u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genesis_headers_hash_to_genesis_hashes() {
+        for params in [
+            ChainParams::mainnet(),
+            ChainParams::testnet(),
+            ChainParams::regtest(),
+            ChainParams::signet(),
+        ]
+        .iter()
+        {
+            let genesis = &params.genesis;
+            let mut header = Vec::with_capacity(80);
+            header.extend_from_slice(&genesis.version.to_le_bytes());
+            header.extend_from_slice(&[0; 32]);
+            header.extend_from_slice(&genesis.merkle_root);
+            header.extend_from_slice(&genesis.time.to_le_bytes());
+            header.extend_from_slice(&genesis.bits.to_le_bytes());
+            header.extend_from_slice(&genesis.nonce.to_le_bytes());
+            assert_eq!(warp_crypto::sha256d(&header), genesis.hash);
+        }
+    }
+
+    #[test]
+    fn signet_magic_comes_from_the_challenge() {
+        // The default signet's message start is 0a03cf40
+        assert_eq!(
+            ChainParams::signet().magic.to_le_bytes(),
+            [0x0a, 0x03, 0xcf, 0x40]
+        );
+        let custom = ChainParams::custom_signet(vec![0x51]);
+        assert_ne!(custom.magic, ChainParams::signet().magic);
+        assert_eq!(custom.genesis, ChainParams::signet().genesis);
+        assert!(custom.dns_seeds.is_empty());
+        assert_eq!(
+            ChainParams::mainnet().difficulty_adjustment_interval(),
+            2016
+        );
+    }
+}
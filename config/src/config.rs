@@ -1,31 +1,96 @@
-const MAGIC_MAINNET: u32 = 0xD9B4BEF9;
-const MAGIC_TESTNET: u32 = 0x0709110B;
-const MAGIC_REGTEST: u32 = 0xDAB5BFFA;
+use crate::chain_params::ChainParams;
 
 const CORE_PORT_MAINNET: usize = 8333;
 const CORE_PORT_TESTNET: usize = 18333;
 const CORE_PORT_REGTEST: usize = 18444;
+const CORE_PORT_SIGNET: usize = 38333;
 
 const WARP_PORT_MAINNET: usize = 8333;
 const WARP_PORT_TESTNET: usize = 18333;
 const WARP_PORT_REGTEST: usize = 18444;
+const WARP_PORT_SIGNET: usize = 38333;
+
+const RPC_PORT_MAINNET: usize = 8332;
+const RPC_PORT_TESTNET: usize = 18332;
+const RPC_PORT_REGTEST: usize = 18443;
+const RPC_PORT_SIGNET: usize = 38332;
+
+/// The name of the file holding the RPC password when no `-rpcpassword` is set, as in Bitcoin Core
+pub const RPC_COOKIE_FILE: &str = ".cookie";
 
 // Max message size: 4 Mb (see https://github.com/bitcoin/bitcoin/blob/master/src/net.h)
 const MAX_SIZE_MAINNET: usize = 4 * 1000 * 1000;
 const MAX_SIZE_TESTNET: usize = 4 * 1000 * 1000;
 const MAX_SIZE_REGTEST: usize = 4 * 1000 * 1000;
+const MAX_SIZE_SIGNET: usize = 4 * 1000 * 1000;
+
+/// The node can serve the full block chain
+pub const NODE_NETWORK: u64 = 1;
+/// The node can serve at least the last 288 blocks (BIP159)
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+/// The node accepts encrypted v2 transport connections (BIP324)
+pub const NODE_P2P_V2: u64 = 1 << 11;
+
+/// Bitcoin Core refuses prune targets below this, which leaves room for the last 288 blocks plus their undo data
+pub const MIN_PRUNE_TARGET_MB: u64 = 550;
 
-const MAX_PEERS_MAINNET: usize = 10;
-const MAX_PEERS_TESTNET: usize = 10;
-const MAX_PEERS_REGTEST: usize = 10;
+/// Peers which don't answer a ping within this many seconds are disconnected (Core's `TIMEOUT_INTERVAL`)
+pub const DEFAULT_PING_TIMEOUT_SECS: u64 = 20 * 60;
+
+// Connection limits, matching Bitcoin Core's DEFAULT_MAX_PEER_CONNECTIONS. Some of the slots are reserved for
+// other Warp nodes, and the rest are left for Core nodes.
+const MAX_PEERS_MAINNET: usize = 125;
+const MAX_PEERS_TESTNET: usize = 125;
+const MAX_PEERS_REGTEST: usize = 125;
+const MAX_PEERS_SIGNET: usize = 125;
+
+const MAX_WARP_PEERS_MAINNET: usize = 8;
+const MAX_WARP_PEERS_TESTNET: usize = 8;
+const MAX_WARP_PEERS_REGTEST: usize = 8;
+const MAX_WARP_PEERS_SIGNET: usize = 8;
 
 #[derive(Debug, Clone)]
 pub struct Config {
     client_version: String,
     protocol_version: u32,
     services: u64,
+    /// The maximum size of the block and undo files to keep on disk, in MiB. `None` disables pruning.
+    prune_target_mb: Option<u64>,
+    /// Whether to maintain an index of every transaction in the chain (Core's `-txindex`)
+    txindex: bool,
+    /// Whether to maintain an index of the history of every output script
+    script_index: bool,
+    /// How long to wait for a pong before disconnecting a peer, in seconds
+    ping_timeout_secs: u64,
+    /// Whether to offer the encrypted v2 transport (BIP324), falling back to v1 for peers which don't support it
+    v2_transport: bool,
+    /// The SOCKS5 proxy to make outbound connections through (Core's `-proxy`)
+    proxy: Option<std::net::SocketAddr>,
+    /// The SOCKS5 proxy to reach onion peers through, if different from `proxy` (Core's `-onion`)
+    onion_proxy: Option<std::net::SocketAddr>,
+    /// Whether to use fresh proxy credentials for every connection, so that Tor puts each on its own circuit
+    proxy_randomize: bool,
+    /// The networks to make outbound connections to. Empty means all of them (Core's `-onlynet`).
+    only_net: Vec<NetworkKind>,
+    /// The address to listen for inbound connections on (Core's `-bind`)
     ip_address: std::net::IpAddr,
     user_agent: String,
+    /// Comments to append to the user agent, as in BIP14 (Core's `-uacomment`)
+    user_agent_comments: Vec<String>,
+    /// The root of the data directory. Networks other than mainnet keep their data in a subdirectory.
+    data_dir: std::path::PathBuf,
+    /// Whether to accept JSON-RPC requests (Core's `-server`)
+    rpc_server: bool,
+    /// Whether to serve the read-only REST interface on the RPC port (Core's `-rest`)
+    rest: bool,
+    /// The address to listen for RPC requests on (Core's `-rpcbind`)
+    rpc_bind: std::net::IpAddr,
+    /// The credentials RPC clients must present. Without them, a random password is written to the cookie file.
+    rpc_credentials: Option<(String, String)>,
+    /// Where to write the cookie file, relative to the network's data directory
+    rpc_cookie_file: std::path::PathBuf,
+    /// The sockets to publish each ZMQ notification topic on (Core's `-zmqpub<topic>`)
+    zmq_notifications: Vec<(ZmqTopic, ZmqEndpoint)>,
     network: Network,
     network_config: NetworkConfig,
 }
@@ -34,17 +99,123 @@ pub struct Config {
 pub struct NetworkConfig {
     core_port: usize,
     warp_port: usize,
-    magic: u32,
+    rpc_port: usize,
+    chain_params: ChainParams,
     max_msg_size: usize,
     max_peers: usize,
     max_warp_peers: usize,
 }
 
-#[derive(Debug, Clone)]
+/// The kinds of network a peer can be reached on, as named by Core's `-onlynet`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetworkKind {
+    Ipv4,
+    Ipv6,
+    Onion,
+}
+
+impl std::str::FromStr for NetworkKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<NetworkKind, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "ipv4" => Ok(NetworkKind::Ipv4),
+            "ipv6" => Ok(NetworkKind::Ipv6),
+            "onion" | "tor" => Ok(NetworkKind::Onion),
+            _ => Err(format!("Unknown network {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for NetworkKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NetworkKind::Ipv4 => write!(f, "ipv4"),
+            NetworkKind::Ipv6 => write!(f, "ipv6"),
+            NetworkKind::Onion => write!(f, "onion"),
+        }
+    }
+}
+
+/// A topic of ZMQ notifications, named as in Core
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ZmqTopic {
+    /// The hash of each new block
+    HashBlock,
+    /// The hash of each transaction entering the mempool or appearing in a connected or disconnected block
+    HashTx,
+    RawBlock,
+    RawTx,
+    /// Blocks connected and disconnected, and transactions added to and removed from the mempool
+    Sequence,
+}
+
+impl ZmqTopic {
+    pub const ALL: [ZmqTopic; 5] = [
+        ZmqTopic::HashBlock,
+        ZmqTopic::HashTx,
+        ZmqTopic::RawBlock,
+        ZmqTopic::RawTx,
+        ZmqTopic::Sequence,
+    ];
+
+    /// The topic's name, which is also the first frame of each message published on it
+    pub fn name(&self) -> &'static str {
+        match self {
+            ZmqTopic::HashBlock => "hashblock",
+            ZmqTopic::HashTx => "hashtx",
+            ZmqTopic::RawBlock => "rawblock",
+            ZmqTopic::RawTx => "rawtx",
+            ZmqTopic::Sequence => "sequence",
+        }
+    }
+}
+
+/// The address a ZMQ notification socket listens on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ZmqEndpoint {
+    /// `tcp://<ip>:<port>`, or `tcp://*:<port>` to listen on every interface
+    Tcp(std::net::SocketAddr),
+    /// `ipc://<path>`, a Unix domain socket
+    Ipc(std::path::PathBuf),
+}
+
+impl std::str::FromStr for ZmqEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ZmqEndpoint, String> {
+        if let Some(address) = s.strip_prefix("tcp://") {
+            let address = match address.strip_prefix("*:") {
+                Some(port) => format!("0.0.0.0:{}", port),
+                None => address.to_string(),
+            };
+            return address
+                .parse()
+                .map(ZmqEndpoint::Tcp)
+                .map_err(|_| String::from("expected tcp://<ip>:<port>"));
+        }
+        match s.strip_prefix("ipc://") {
+            Some(path) if !path.is_empty() => Ok(ZmqEndpoint::Ipc(std::path::PathBuf::from(path))),
+            _ => Err(String::from("expected tcp://<ip>:<port> or ipc://<path>")),
+        }
+    }
+}
+
+impl std::fmt::Display for ZmqEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ZmqEndpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+            ZmqEndpoint::Ipc(path) => write!(f, "ipc://{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Network {
     Mainnet,
     Testnet,
     Regtest,
+    Signet,
 }
 
 impl NetworkConfig {
@@ -52,30 +223,51 @@ impl NetworkConfig {
         NetworkConfig {
             core_port: CORE_PORT_MAINNET,
             warp_port: WARP_PORT_MAINNET,
-            magic: MAGIC_MAINNET,
+            rpc_port: RPC_PORT_MAINNET,
+            chain_params: ChainParams::mainnet(),
             max_msg_size: MAX_SIZE_MAINNET,
             max_peers: MAX_PEERS_MAINNET,
-            max_warp_peers: MAX_PEERS_MAINNET,
+            max_warp_peers: MAX_WARP_PEERS_MAINNET,
         }
     }
     pub fn testnet() -> NetworkConfig {
         NetworkConfig {
             core_port: CORE_PORT_TESTNET,
             warp_port: WARP_PORT_TESTNET,
-            magic: MAGIC_TESTNET,
+            rpc_port: RPC_PORT_TESTNET,
+            chain_params: ChainParams::testnet(),
             max_msg_size: MAX_SIZE_TESTNET,
             max_peers: MAX_PEERS_TESTNET,
-            max_warp_peers: MAX_PEERS_TESTNET,
+            max_warp_peers: MAX_WARP_PEERS_TESTNET,
         }
     }
     pub fn regtest() -> NetworkConfig {
         NetworkConfig {
             core_port: CORE_PORT_REGTEST,
             warp_port: WARP_PORT_REGTEST,
-            magic: MAGIC_REGTEST,
+            rpc_port: RPC_PORT_REGTEST,
+            chain_params: ChainParams::regtest(),
             max_msg_size: MAX_SIZE_REGTEST,
             max_peers: MAX_PEERS_REGTEST,
-            max_warp_peers: MAX_PEERS_REGTEST,
+            max_warp_peers: MAX_WARP_PEERS_REGTEST,
+        }
+    }
+    pub fn signet() -> NetworkConfig {
+        NetworkConfig::with_signet_params(ChainParams::signet())
+    }
+    /// A signet whose blocks must satisfy `challenge` instead of the default signet's
+    pub fn custom_signet(challenge: Vec<u8>) -> NetworkConfig {
+        NetworkConfig::with_signet_params(ChainParams::custom_signet(challenge))
+    }
+    fn with_signet_params(chain_params: ChainParams) -> NetworkConfig {
+        NetworkConfig {
+            core_port: CORE_PORT_SIGNET,
+            warp_port: WARP_PORT_SIGNET,
+            rpc_port: RPC_PORT_SIGNET,
+            chain_params,
+            max_msg_size: MAX_SIZE_SIGNET,
+            max_peers: MAX_PEERS_SIGNET,
+            max_warp_peers: MAX_WARP_PEERS_SIGNET,
         }
     }
 }
@@ -90,22 +282,130 @@ impl Network {
     pub fn regtest() -> Network {
         Network::Regtest
     }
+    pub fn signet() -> Network {
+        Network::Signet
+    }
+    /// The subdirectory of the data directory which holds the network's data, as named by Core
+    pub fn data_dir_name(&self) -> Option<&'static str> {
+        match self {
+            Network::Mainnet => None,
+            Network::Testnet => Some("testnet3"),
+            Network::Regtest => Some("regtest"),
+            Network::Signet => Some("signet"),
+        }
+    }
+    /// The network's name, as used by Core's `-chain` option and config file sections
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "main",
+            Network::Testnet => "test",
+            Network::Regtest => "regtest",
+            Network::Signet => "signet",
+        }
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        match s {
+            "main" => Ok(Network::Mainnet),
+            "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            "signet" => Ok(Network::Signet),
+            _ => Err(format!("Unknown chain {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The data directory used unless `-datadir` says otherwise: `~/.warp`, or `%APPDATA%\Warp` on Windows
+pub fn default_data_dir() -> std::path::PathBuf {
+    if cfg!(windows) {
+        if let Some(app_data) = std::env::var_os("APPDATA") {
+            return std::path::PathBuf::from(app_data).join("Warp");
+        }
+    }
+    match std::env::var_os("HOME") {
+        Some(home) => std::path::PathBuf::from(home).join(".warp"),
+        None => std::path::PathBuf::from(".warp"),
+    }
 }
 
 impl Config {
     pub fn mainnet() -> Config {
+        Config::new(Network::mainnet(), NetworkConfig::mainnet())
+    }
+    pub fn testnet() -> Config {
+        Config::new(Network::testnet(), NetworkConfig::testnet())
+    }
+    pub fn regtest() -> Config {
+        Config::new(Network::regtest(), NetworkConfig::regtest())
+    }
+    pub fn signet() -> Config {
+        Config::new(Network::signet(), NetworkConfig::signet())
+    }
+    /// A signet whose blocks must satisfy `challenge` instead of the default signet's
+    pub fn custom_signet(challenge: Vec<u8>) -> Config {
+        Config::new(Network::signet(), NetworkConfig::custom_signet(challenge))
+    }
+    fn new(network: Network, network_config: NetworkConfig) -> Config {
         Config {
             client_version: String::from(env!("CARGO_PKG_VERSION")),
-            protocol_version: 70015,
-            services: 0,
+            protocol_version: 70016,
+            services: NODE_NETWORK,
+            prune_target_mb: None,
+            txindex: false,
+            script_index: false,
+            ping_timeout_secs: DEFAULT_PING_TIMEOUT_SECS,
+            v2_transport: true,
+            proxy: None,
+            onion_proxy: None,
+            proxy_randomize: true,
+            only_net: Vec::new(),
             ip_address: "127.0.0.1".parse().unwrap(),
             user_agent: String::from("bitcoin-warp"),
-            network: Network::mainnet(),
-            network_config: NetworkConfig::mainnet(),
+            user_agent_comments: Vec::new(),
+            data_dir: default_data_dir(),
+            rpc_server: true,
+            rest: false,
+            rpc_bind: "127.0.0.1".parse().unwrap(),
+            rpc_credentials: None,
+            rpc_cookie_file: std::path::PathBuf::from(RPC_COOKIE_FILE),
+            zmq_notifications: Vec::new(),
+            network,
+            network_config,
+        }
+    }
+    pub fn network(&self) -> Network {
+        self.network
+    }
+    /// The directory holding this network's data
+    pub fn data_dir(&self) -> std::path::PathBuf {
+        match self.network.data_dir_name() {
+            Some(name) => self.data_dir.join(name),
+            None => self.data_dir.clone(),
         }
     }
+    /// Sets the root of the data directory, which is shared by every network
+    pub fn set_data_dir(&mut self, root: std::path::PathBuf) {
+        self.data_dir = root;
+    }
+    pub fn chain_params(&self) -> &ChainParams {
+        &self.network_config.chain_params
+    }
     pub fn magic(&self) -> u32 {
-        self.network_config.magic
+        self.network_config.chain_params.magic
+    }
+    /// The hash of the network's genesis block, in internal byte order
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        self.network_config.chain_params.genesis.hash
     }
     pub fn get_protocol_version(&self) -> u32 {
         self.protocol_version
@@ -113,12 +413,178 @@ impl Config {
     pub fn set_protocol_version(&mut self, version: u32) {
         self.protocol_version = version;
     }
+    /// The services we advertise. A pruned node can't serve old blocks, so it advertises
+    /// `NODE_NETWORK_LIMITED` instead of `NODE_NETWORK`.
     pub fn get_services(&self) -> u64 {
-        self.services
+        let services = if self.prune_target_mb.is_some() {
+            (self.services & !NODE_NETWORK) | NODE_NETWORK_LIMITED
+        } else {
+            self.services
+        };
+        if self.v2_transport {
+            services | NODE_P2P_V2
+        } else {
+            services
+        }
+    }
+    pub fn set_services(&mut self, services: u64) {
+        self.services = services;
+    }
+    pub fn ip_address(&self) -> std::net::IpAddr {
+        self.ip_address
+    }
+    pub fn set_ip_address(&mut self, ip_address: std::net::IpAddr) {
+        self.ip_address = ip_address;
+    }
+    pub fn prune_target_mb(&self) -> Option<u64> {
+        self.prune_target_mb
+    }
+    /// The prune target in bytes, as expected by the block store
+    pub fn prune_target_bytes(&self) -> Option<u64> {
+        self.prune_target_mb.map(|mb| mb * 1024 * 1024)
+    }
+    /// Enables pruning with a target of `target_mb` MiB, or disables it if `None`.
+    /// Targets below [`MIN_PRUNE_TARGET_MB`] are raised to the minimum.
+    pub fn set_prune_target_mb(&mut self, target_mb: Option<u64>) {
+        self.prune_target_mb = target_mb.map(|mb| mb.max(MIN_PRUNE_TARGET_MB));
+    }
+    pub fn txindex(&self) -> bool {
+        self.txindex
+    }
+    pub fn set_txindex(&mut self, enabled: bool) {
+        self.txindex = enabled;
+    }
+    pub fn script_index(&self) -> bool {
+        self.script_index
+    }
+    pub fn set_script_index(&mut self, enabled: bool) {
+        self.script_index = enabled;
+    }
+    /// The user agent we send in our `Version`, formatted as in BIP14 (e.g. `/bitcoin-warp:0.1.0/`, or
+    /// `/bitcoin-warp:0.1.0(comment)/` with comments)
+    pub fn user_agent(&self) -> String {
+        if self.user_agent_comments.is_empty() {
+            format!("/{}:{}/", self.user_agent, self.client_version)
+        } else {
+            format!(
+                "/{}:{}({})/",
+                self.user_agent,
+                self.client_version,
+                self.user_agent_comments.join("; ")
+            )
+        }
+    }
+    /// Sets the client name in the user agent. The version is always our own.
+    pub fn set_user_agent(&mut self, name: String) {
+        self.user_agent = name;
+    }
+    pub fn set_user_agent_comments(&mut self, comments: Vec<String>) {
+        self.user_agent_comments = comments;
+    }
+    pub fn ping_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.ping_timeout_secs)
+    }
+    pub fn set_ping_timeout_secs(&mut self, secs: u64) {
+        self.ping_timeout_secs = secs;
+    }
+    pub fn v2_transport(&self) -> bool {
+        self.v2_transport
+    }
+    pub fn set_v2_transport(&mut self, enabled: bool) {
+        self.v2_transport = enabled;
+    }
+    pub fn proxy(&self) -> Option<std::net::SocketAddr> {
+        self.proxy
+    }
+    pub fn set_proxy(&mut self, proxy: Option<std::net::SocketAddr>) {
+        self.proxy = proxy;
+    }
+    /// The proxy for onion peers, which defaults to the general proxy
+    pub fn onion_proxy(&self) -> Option<std::net::SocketAddr> {
+        self.onion_proxy.or(self.proxy)
+    }
+    pub fn set_onion_proxy(&mut self, proxy: Option<std::net::SocketAddr>) {
+        self.onion_proxy = proxy;
+    }
+    pub fn proxy_randomize(&self) -> bool {
+        self.proxy_randomize
+    }
+    pub fn set_proxy_randomize(&mut self, enabled: bool) {
+        self.proxy_randomize = enabled;
+    }
+    pub fn only_net(&self) -> &[NetworkKind] {
+        &self.only_net
+    }
+    pub fn set_only_net(&mut self, networks: Vec<NetworkKind>) {
+        self.only_net = networks;
     }
     pub fn get_max_msg_size(&self) -> usize {
         self.network_config.max_msg_size
     }
+    /// The port Core nodes connect to
+    pub fn core_port(&self) -> usize {
+        self.network_config.core_port
+    }
+    pub fn set_core_port(&mut self, port: usize) {
+        self.network_config.core_port = port;
+    }
+    /// The port Warp nodes connect to. Connections on it count against the Warp peer limit, unless it's shared
+    /// with the Core port.
+    pub fn warp_port(&self) -> usize {
+        self.network_config.warp_port
+    }
+    pub fn set_warp_port(&mut self, port: usize) {
+        self.network_config.warp_port = port;
+    }
+    /// The port to accept RPC requests on
+    pub fn rpc_port(&self) -> usize {
+        self.network_config.rpc_port
+    }
+    pub fn set_rpc_port(&mut self, port: usize) {
+        self.network_config.rpc_port = port;
+    }
+    pub fn rpc_server(&self) -> bool {
+        self.rpc_server
+    }
+    pub fn set_rpc_server(&mut self, enabled: bool) {
+        self.rpc_server = enabled;
+    }
+    pub fn rest(&self) -> bool {
+        self.rest
+    }
+    pub fn set_rest(&mut self, enabled: bool) {
+        self.rest = enabled;
+    }
+    pub fn rpc_bind(&self) -> std::net::IpAddr {
+        self.rpc_bind
+    }
+    pub fn set_rpc_bind(&mut self, ip_address: std::net::IpAddr) {
+        self.rpc_bind = ip_address;
+    }
+    /// The user name and password set with `-rpcuser` and `-rpcpassword`, if any
+    pub fn rpc_credentials(&self) -> Option<(&str, &str)> {
+        self.rpc_credentials
+            .as_ref()
+            .map(|(user, password)| (user.as_str(), password.as_str()))
+    }
+    pub fn set_rpc_credentials(&mut self, credentials: Option<(String, String)>) {
+        self.rpc_credentials = credentials;
+    }
+    /// The path of the cookie file, which is in the network's data directory unless set to an absolute path
+    pub fn rpc_cookie_file(&self) -> std::path::PathBuf {
+        self.data_dir().join(&self.rpc_cookie_file)
+    }
+    pub fn set_rpc_cookie_file(&mut self, path: std::path::PathBuf) {
+        self.rpc_cookie_file = path;
+    }
+    /// The ZMQ notification topics to publish, each with a socket to publish it on. A topic may be published on
+    /// several sockets, and a socket may carry several topics.
+    pub fn zmq_notifications(&self) -> &[(ZmqTopic, ZmqEndpoint)] {
+        &self.zmq_notifications
+    }
+    pub fn set_zmq_notifications(&mut self, notifications: Vec<(ZmqTopic, ZmqEndpoint)>) {
+        self.zmq_notifications = notifications;
+    }
     pub fn max_core_peers(&self) -> usize {
         self.network_config.max_peers - self.network_config.max_warp_peers
     }
@@ -128,4 +594,68 @@ impl Config {
     pub fn max_peers(&self) -> usize {
         self.network_config.max_peers
     }
+    /// Sets the total connection limit (Core's `-maxconnections`). The slots reserved for Warp nodes shrink to fit.
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.network_config.max_peers = max_peers;
+        self.network_config.max_warp_peers = self.network_config.max_warp_peers.min(max_peers);
+    }
+}
+
+#[test]
+fn pruned_nodes_advertise_network_limited() {
+    let mut config = Config::mainnet();
+    assert_eq!(config.get_services() & NODE_NETWORK, NODE_NETWORK);
+    config.set_prune_target_mb(Some(100));
+    assert_eq!(config.prune_target_mb(), Some(MIN_PRUNE_TARGET_MB));
+    assert_eq!(config.get_services() & NODE_NETWORK, 0);
+    assert_eq!(
+        config.get_services() & NODE_NETWORK_LIMITED,
+        NODE_NETWORK_LIMITED
+    );
+    config.set_prune_target_mb(None);
+    assert_eq!(config.get_services(), NODE_NETWORK | NODE_P2P_V2);
+    config.set_v2_transport(false);
+    assert_eq!(config.get_services(), NODE_NETWORK);
+}
+
+#[test]
+fn onion_proxy_defaults_to_proxy() {
+    let mut config = Config::mainnet();
+    assert_eq!(config.onion_proxy(), None);
+    config.set_proxy(Some("127.0.0.1:9050".parse().unwrap()));
+    assert_eq!(config.onion_proxy(), config.proxy());
+    config.set_onion_proxy(Some("127.0.0.1:9150".parse().unwrap()));
+    assert_eq!(
+        config.onion_proxy(),
+        Some("127.0.0.1:9150".parse().unwrap())
+    );
+    assert_eq!("Tor".parse(), Ok(NetworkKind::Onion));
+}
+
+#[test]
+fn presets_use_their_networks_parameters() {
+    let regtest = Config::regtest();
+    assert_eq!(regtest.network(), Network::Regtest);
+    assert_eq!(regtest.core_port(), 18444);
+    assert_eq!(regtest.rpc_port(), 18443);
+    assert!(regtest.chain_params().pow_no_retargeting);
+    assert!(regtest.chain_params().dns_seeds.is_empty());
+    assert_eq!(Config::testnet().magic(), 0x0709110B);
+    assert_ne!(
+        Config::testnet().genesis_hash(),
+        Config::mainnet().genesis_hash()
+    );
+    assert_eq!(regtest.data_dir(), default_data_dir().join("regtest"));
+    assert_eq!(Config::mainnet().data_dir(), default_data_dir());
+    assert_eq!(
+        regtest.rpc_cookie_file(),
+        default_data_dir().join("regtest").join(RPC_COOKIE_FILE)
+    );
+    let signet = Config::signet();
+    assert_eq!(signet.network(), Network::Signet);
+    assert_eq!(signet.core_port(), 38333);
+    assert_eq!(
+        Config::custom_signet(vec![0x51]).genesis_hash(),
+        signet.genesis_hash()
+    );
 }
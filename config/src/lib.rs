@@ -1,2 +1,9 @@
+mod args;
+mod chain_params;
 mod config;
-pub use self::config::{Config, Network, NetworkConfig};
+pub use self::args::{help_message, Args, ArgsError};
+pub use self::chain_params::{ChainParams, Deployments, Genesis};
+pub use self::config::{
+    default_data_dir, Config, Network, NetworkConfig, NetworkKind, ZmqEndpoint, ZmqTopic,
+    MIN_PRUNE_TARGET_MB, NODE_NETWORK, NODE_NETWORK_LIMITED, NODE_P2P_V2, RPC_COOKIE_FILE,
+};
@@ -0,0 +1,277 @@
+//! The cryptographic building blocks of the [BIP324](https://github.com/bitcoin/bips/blob/master/bip-0324.mediawiki)
+//! v2 P2P transport: an ElligatorSwift encoded key exchange, the ChaCha20-Poly1305 AEAD from RFC 8439, and the
+//! "forward secure" wrappers around ChaCha20 and the AEAD which rekey after every [`REKEY_INTERVAL`] messages.
+use crypto::chacha20::ChaCha20;
+use crypto::hkdf::{hkdf_expand, hkdf_extract};
+use crypto::mac::Mac;
+use crypto::poly1305::Poly1305;
+use crypto::sha2::Sha256;
+use crypto::symmetriccipher::SynchronousStreamCipher;
+use crypto::util::fixed_time_eq;
+use secp256k1::ellswift::{ElligatorSwift, ElligatorSwiftParty};
+use secp256k1::{Secp256k1, SecretKey};
+
+/// The number of messages encrypted with a key before it is replaced
+pub const REKEY_INTERVAL: u64 = 224;
+/// The length of a Poly1305 authentication tag
+pub const TAG_LEN: usize = 16;
+/// The length of an ElligatorSwift encoded public key
+pub const ELLSWIFT_LEN: usize = 64;
+
+/// Creates an ElligatorSwift encoded public key for `secret`, using `aux_rand` to pick one of its many encodings.
+/// Returns `None` if `secret` isn't a valid secret key.
+pub fn ellswift_create(secret: &[u8; 32], aux_rand: [u8; 32]) -> Option<[u8; ELLSWIFT_LEN]> {
+    let secret = SecretKey::from_slice(secret).ok()?;
+    Some(ElligatorSwift::from_seckey(&Secp256k1::new(), secret, Some(aux_rand)).to_array())
+}
+
+/// Computes the BIP324 shared secret between the initiator, who sent `ellswift_a`, and the responder, who sent
+/// `ellswift_b`. `secret` is our own secret key. Returns `None` if `secret` isn't a valid secret key.
+pub fn ellswift_ecdh(
+    ellswift_a: &[u8; ELLSWIFT_LEN],
+    ellswift_b: &[u8; ELLSWIFT_LEN],
+    secret: &[u8; 32],
+    initiator: bool,
+) -> Option<[u8; 32]> {
+    let secret = SecretKey::from_slice(secret).ok()?;
+    let party = if initiator {
+        ElligatorSwiftParty::A
+    } else {
+        ElligatorSwiftParty::B
+    };
+    let shared = ElligatorSwift::shared_secret(
+        ElligatorSwift::from_array(*ellswift_a),
+        ElligatorSwift::from_array(*ellswift_b),
+        secret,
+        party,
+        None,
+    );
+    Some(shared.to_secret_bytes())
+}
+
+/// HKDF-SHA256 (RFC 5869): extracts a pseudorandom key from `ikm` and `salt`
+pub fn hkdf_sha256_extract(salt: &[u8], ikm: &[u8]) -> [u8; 32] {
+    let mut prk = [0u8; 32];
+    hkdf_extract(Sha256::new(), salt, ikm, &mut prk);
+    prk
+}
+
+/// HKDF-SHA256 (RFC 5869): expands `prk` into 32 bytes of keying material for the purpose named by `info`
+pub fn hkdf_sha256_expand(prk: &[u8; 32], info: &[u8]) -> [u8; 32] {
+    let mut okm = [0u8; 32];
+    hkdf_expand(Sha256::new(), prk, info, &mut okm);
+    okm
+}
+
+/// Encrypts `plaintext` in place with the ChaCha20-Poly1305 AEAD of RFC 8439, returning the authentication tag.
+pub fn aead_encrypt(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    aad: &[u8],
+    plaintext: &mut [u8],
+) -> [u8; TAG_LEN] {
+    let mut cipher = ChaCha20::new(key, nonce);
+    let mut mac = poly1305_for(&mut cipher);
+    let input = plaintext.to_vec();
+    cipher.process(&input, plaintext);
+    authenticate(&mut mac, aad, plaintext)
+}
+
+/// Checks the tag and decrypts `ciphertext` in place with the ChaCha20-Poly1305 AEAD of RFC 8439. Returns `false`,
+/// leaving `ciphertext` untouched, if the tag doesn't match.
+pub fn aead_decrypt(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    aad: &[u8],
+    ciphertext: &mut [u8],
+    tag: &[u8],
+) -> bool {
+    let mut cipher = ChaCha20::new(key, nonce);
+    let mut mac = poly1305_for(&mut cipher);
+    if !fixed_time_eq(&authenticate(&mut mac, aad, ciphertext), tag) {
+        return false;
+    }
+    let input = ciphertext.to_vec();
+    cipher.process(&input, ciphertext);
+    true
+}
+
+/// Derives the one-time Poly1305 key from the first ChaCha20 block, leaving the cipher at block 1
+fn poly1305_for(cipher: &mut ChaCha20) -> Poly1305 {
+    let mut block = [0u8; 64];
+    cipher.process(&[0u8; 64], &mut block);
+    Poly1305::new(&block[..32])
+}
+
+fn authenticate(mac: &mut Poly1305, aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+    let padding = [0u8; 16];
+    mac.input(aad);
+    mac.input(&padding[..(16 - aad.len() % 16) % 16]);
+    mac.input(ciphertext);
+    mac.input(&padding[..(16 - ciphertext.len() % 16) % 16]);
+    mac.input(&(aad.len() as u64).to_le_bytes());
+    mac.input(&(ciphertext.len() as u64).to_le_bytes());
+    let mut tag = [0u8; TAG_LEN];
+    mac.raw_result(&mut tag);
+    tag
+}
+
+/// The 96-bit nonce for message `index` of epoch `epoch`: 32 bits of index followed by 64 bits of epoch
+fn nonce(index: u32, epoch: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&index.to_le_bytes());
+    nonce[4..].copy_from_slice(&epoch.to_le_bytes());
+    nonce
+}
+
+/// ChaCha20 used as a single keystream across many small messages (BIP324's `FSChaCha20`). After every
+/// [`REKEY_INTERVAL`] messages, the next 32 bytes of keystream become the new key, so that compromising the current
+/// key doesn't expose earlier messages.
+pub struct FsChaCha20 {
+    key: [u8; 32],
+    cipher: ChaCha20,
+    chunk_counter: u64,
+    rekey_counter: u64,
+}
+
+impl FsChaCha20 {
+    pub fn new(key: [u8; 32]) -> FsChaCha20 {
+        FsChaCha20 {
+            cipher: ChaCha20::new(&key, &nonce(0, 0)),
+            key,
+            chunk_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    /// Encrypts or decrypts one message in place
+    pub fn crypt(&mut self, chunk: &mut [u8]) {
+        let input = chunk.to_vec();
+        self.cipher.process(&input, chunk);
+        self.chunk_counter += 1;
+        if self.chunk_counter == REKEY_INTERVAL {
+            let mut new_key = [0u8; 32];
+            self.cipher.process(&[0u8; 32], &mut new_key);
+            self.key = new_key;
+            self.rekey_counter += 1;
+            self.cipher = ChaCha20::new(&self.key, &nonce(0, self.rekey_counter));
+            self.chunk_counter = 0;
+        }
+    }
+}
+
+/// The ChaCha20-Poly1305 AEAD with a nonce derived from a packet counter (BIP324's `FSChaCha20Poly1305`). After
+/// every [`REKEY_INTERVAL`] packets, the key is replaced with one derived from it.
+pub struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u64,
+}
+
+impl FsChaCha20Poly1305 {
+    pub fn new(key: [u8; 32]) -> FsChaCha20Poly1305 {
+        FsChaCha20Poly1305 {
+            key,
+            packet_counter: 0,
+        }
+    }
+
+    /// Encrypts the next packet in place, returning its tag
+    pub fn encrypt(&mut self, aad: &[u8], plaintext: &mut [u8]) -> [u8; TAG_LEN] {
+        let tag = aead_encrypt(&self.key, &self.next_nonce(), aad, plaintext);
+        self.advance();
+        tag
+    }
+
+    /// Decrypts the next packet in place. Returns `false` if it fails authentication, after which the stream can't
+    /// be recovered.
+    pub fn decrypt(&mut self, aad: &[u8], ciphertext: &mut [u8], tag: &[u8]) -> bool {
+        let valid = aead_decrypt(&self.key, &self.next_nonce(), aad, ciphertext, tag);
+        self.advance();
+        valid
+    }
+
+    fn next_nonce(&self) -> [u8; 12] {
+        nonce(
+            (self.packet_counter % REKEY_INTERVAL) as u32,
+            self.packet_counter / REKEY_INTERVAL,
+        )
+    }
+
+    fn advance(&mut self) {
+        self.packet_counter += 1;
+        if self.packet_counter.is_multiple_of(REKEY_INTERVAL) {
+            // The new key is the encryption of 32 zero bytes, with a nonce no packet uses
+            let epoch = self.packet_counter / REKEY_INTERVAL - 1;
+            let mut new_key = [0u8; 32];
+            aead_encrypt(&self.key, &nonce(u32::MAX, epoch), &[], &mut new_key);
+            self.key = new_key;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aead_matches_rfc8439() {
+        // The AEAD test vector from section 2.8.2 of RFC 8439
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = 0x80 + i as u8;
+        }
+        let nonce = [
+            0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+        ];
+        let aad = hex::decode("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+
+        let mut text = plaintext.clone();
+        let tag = aead_encrypt(&key, &nonce, &aad, &mut text);
+        assert_eq!(hex::encode(&text[..16]), "d31a8d34648e60db7b86afbc53ef7ec2");
+        assert_eq!(hex::encode(tag), "1ae10b594f09e26a7e902ecbd0600691");
+
+        let mut tampered = text.clone();
+        tampered[0] ^= 1;
+        assert!(!aead_decrypt(&key, &nonce, &aad, &mut tampered, &tag));
+        assert!(aead_decrypt(&key, &nonce, &aad, &mut text, &tag));
+        assert_eq!(text, plaintext);
+    }
+
+    #[test]
+    fn ciphers_stay_in_sync_across_rekeys() {
+        let mut sender = FsChaCha20::new([7; 32]);
+        let mut receiver = FsChaCha20::new([7; 32]);
+        let mut sealer = FsChaCha20Poly1305::new([9; 32]);
+        let mut opener = FsChaCha20Poly1305::new([9; 32]);
+        let mut first = None;
+        for i in 0..(REKEY_INTERVAL * 2 + 5) {
+            let mut chunk = (i as u32).to_le_bytes();
+            sender.crypt(&mut chunk[..3]);
+            if i == 0 {
+                first = Some(chunk);
+            } else if i == REKEY_INTERVAL {
+                // The same plaintext encrypts differently after a rekey
+                assert_ne!(Some(chunk), first);
+            }
+            receiver.crypt(&mut chunk[..3]);
+            assert_eq!(chunk, (i as u32).to_le_bytes());
+
+            let mut packet = vec![i as u8; 40];
+            let tag = sealer.encrypt(b"aad", &mut packet);
+            assert!(opener.decrypt(b"aad", &mut packet, &tag));
+            assert_eq!(packet, vec![i as u8; 40]);
+        }
+    }
+
+    #[test]
+    fn ellswift_parties_agree() {
+        let (secret_a, secret_b) = ([1u8; 32], [2u8; 32]);
+        let ellswift_a = ellswift_create(&secret_a, [3; 32]).unwrap();
+        let ellswift_b = ellswift_create(&secret_b, [4; 32]).unwrap();
+        let shared_a = ellswift_ecdh(&ellswift_a, &ellswift_b, &secret_a, true).unwrap();
+        let shared_b = ellswift_ecdh(&ellswift_a, &ellswift_b, &secret_b, false).unwrap();
+        assert_eq!(shared_a, shared_b);
+        assert!(ellswift_create(&[0; 32], [0; 32]).is_none());
+    }
+}
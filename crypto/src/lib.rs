@@ -2,6 +2,8 @@ extern crate crypto;
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 
+pub mod bip324;
+
 pub fn double_sha256(input: &Vec<u8>) -> [u8; 32] {
     let mut hasher = Sha256::new();
     let mut out = [0; 32];
@@ -13,6 +15,14 @@ pub fn double_sha256(input: &Vec<u8>) -> [u8; 32] {
     out
 }
 
+pub fn sha256(input: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    let mut out = [0; 32];
+    hasher.input(input);
+    hasher.result(&mut out);
+    out
+}
+
 pub fn sha256d(input: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
     let mut out = [0; 32];
@@ -24,6 +34,64 @@ pub fn sha256d(input: &[u8]) -> [u8; 32] {
     out
 }
 
+/// An incremental [sha256d] hasher for inputs that are too large to buffer in memory.
+///
+/// Implements `std::io::Write`, so anything `Serializable` can be written into it directly.
+pub struct Sha256dHasher {
+    inner: Sha256,
+}
+
+impl Sha256dHasher {
+    pub fn new() -> Sha256dHasher {
+        Sha256dHasher {
+            inner: Sha256::new(),
+        }
+    }
+    pub fn input(&mut self, data: &[u8]) {
+        self.inner.input(data)
+    }
+    pub fn finish(mut self) -> [u8; 32] {
+        let mut out = [0; 32];
+        self.inner.result(&mut out);
+        self.inner.reset();
+        self.inner.input(&out);
+        self.inner.result(&mut out);
+        out
+    }
+}
+
+impl Default for Sha256dHasher {
+    fn default() -> Self {
+        Sha256dHasher::new()
+    }
+}
+
+impl std::io::Write for Sha256dHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.input(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Expands a 33-byte compressed secp256k1 public key into its 65-byte uncompressed form.
+///
+/// Returns `None` if the input is not a valid point on the curve.
+pub fn decompress_pubkey(compressed: &[u8; 33]) -> Option<[u8; 65]> {
+    let key = secp256k1::PublicKey::from_slice(compressed).ok()?;
+    Some(key.serialize_uncompressed())
+}
+
+/// Compresses a 65-byte uncompressed secp256k1 public key into its 33-byte form.
+///
+/// Returns `None` if the input is not a valid point on the curve.
+pub fn compress_pubkey(uncompressed: &[u8; 65]) -> Option<[u8; 33]> {
+    let key = secp256k1::PublicKey::from_slice(uncompressed).ok()?;
+    Some(key.serialize())
+}
+
 pub fn merkleize(a: &[u8], b: &[u8]) -> [u8; 32] {
     let mut hasher = Sha256::new();
     let mut out = [0; 32];
@@ -37,7 +105,9 @@ pub fn merkleize(a: &[u8], b: &[u8]) -> [u8; 32] {
 }
 #[cfg(test)]
 mod tests {
-    use crate::{double_sha256, sha256d};
+    use crate::{
+        compress_pubkey, decompress_pubkey, double_sha256, sha256, sha256d, Sha256dHasher,
+    };
     #[test]
     fn test_double_sha256() {
         assert_eq!(
@@ -52,4 +122,35 @@ mod tests {
             "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
         )
     }
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            hex::encode(sha256(b"hello")),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        )
+    }
+    #[test]
+    fn test_sha256d_hasher() {
+        let mut hasher = Sha256dHasher::new();
+        hasher.input(b"hel");
+        hasher.input(b"lo");
+        assert_eq!(hasher.finish(), sha256d(b"hello"))
+    }
+    #[test]
+    fn test_decompress_pubkey() {
+        // The generator point
+        let mut compressed = [0u8; 33];
+        compressed.copy_from_slice(
+            &hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap(),
+        );
+        let uncompressed = decompress_pubkey(&compressed).unwrap();
+        assert_eq!(
+            hex::encode(&uncompressed[..]),
+            "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8"
+        );
+        assert_eq!(compress_pubkey(&uncompressed), Some(compressed));
+        compressed[0] = 0x05;
+        assert!(decompress_pubkey(&compressed).is_none());
+    }
 }
@@ -0,0 +1,56 @@
+use shared::{TxID, TxOutpoint};
+use std::fmt;
+
+/// An enumeration of the reasons a transaction can be rejected from the mempool.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MempoolError {
+    /// The transaction is already in the mempool.
+    AlreadyKnown,
+    /// The transaction breaks a consensus rule, and will never be valid.
+    Invalid(String),
+    /// The transaction is valid, but our relay policy doesn't accept it.
+    NonStandard(String),
+    /// The transaction spends outputs which are neither in the UTXO set nor the mempool.
+    MissingInputs(Vec<TxOutpoint>),
+    /// The transaction double-spends a mempool transaction which it isn't allowed to replace.
+    Conflict(TxID),
+    /// The transaction doesn't pay enough fee to be accepted (or to replace what it conflicts with).
+    InsufficientFee(String),
+    /// Accepting the transaction would exceed the limits on chains of unconfirmed transactions.
+    TooLongChain(String),
+    /// The mempool is full of transactions paying higher fees.
+    MempoolFull,
+    /// The transaction pays a higher fee rate than its submitter allowed.
+    MaxFeeExceeded(String),
+}
+
+impl std::error::Error for MempoolError {}
+
+impl fmt::Display for MempoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MempoolError::AlreadyKnown => write!(f, "transaction already in mempool"),
+            MempoolError::Invalid(cause) => write!(f, "invalid transaction: {}", cause),
+            MempoolError::NonStandard(cause) => write!(f, "non-standard transaction: {}", cause),
+            MempoolError::MissingInputs(missing) => {
+                write!(f, "missing {} input(s)", missing.len())
+            }
+            MempoolError::Conflict(txid) => {
+                // Txids are conventionally displayed in reverse byte order
+                let mut txid = *txid.inner();
+                txid.reverse();
+                write!(
+                    f,
+                    "conflicts with non-replaceable transaction {}",
+                    hex::encode(txid)
+                )
+            }
+            MempoolError::InsufficientFee(cause) => write!(f, "insufficient fee: {}", cause),
+            MempoolError::TooLongChain(cause) => {
+                write!(f, "too long chain of unconfirmed transactions: {}", cause)
+            }
+            MempoolError::MempoolFull => write!(f, "mempool full"),
+            MempoolError::MaxFeeExceeded(cause) => write!(f, "max fee exceeded: {}", cause),
+        }
+    }
+}
@@ -0,0 +1,546 @@
+//! Fee estimation from the confirmation times of our own mempool transactions, modelled on Bitcoin Core's
+//! `CBlockPolicyEstimator`.
+//!
+//! Each transaction is assigned to a bucket by fee rate when it enters the mempool. When it confirms, we record how
+//! many blocks it waited; if it leaves the mempool without confirming, we record a failure. These counts are kept
+//! as exponentially decaying averages over three horizons, so that recent blocks matter most. To estimate the fee
+//! rate needed to confirm within a target, we look for the cheapest range of buckets whose transactions confirmed
+//! within the target often enough.
+use crate::{FeeRate, Mempool, MempoolEntry};
+use shared::TxID;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use storage::StorageError;
+
+const FILE_VERSION: u32 = 1;
+/// The lowest and highest bucket boundaries, in satoshis per kvB
+const MIN_BUCKET_FEE_RATE: f64 = 1000.0;
+const MAX_BUCKET_FEE_RATE: f64 = 1e7;
+/// Each bucket covers fee rates 5% higher than the last
+const FEE_SPACING: f64 = 1.05;
+/// How many (decayed) transactions a range of buckets needs before we trust its success rate
+const SUFFICIENT_FEE_TXS: f64 = 0.1;
+const SUFFICIENT_TXS_SHORT: f64 = 0.5;
+
+/// Transactions at a fee rate must confirm within half the target this often
+const HALF_SUCCESS_PCT: f64 = 0.6;
+/// ... within the target this often
+const SUCCESS_PCT: f64 = 0.85;
+/// ... and within twice the target this often
+const DOUBLE_SUCCESS_PCT: f64 = 0.95;
+
+/// The short horizon tracks the last 12 blocks, the medium the last 48 and the long the last 1008
+const SHORT_PERIODS: usize = 12;
+const SHORT_SCALE: u32 = 1;
+const SHORT_DECAY: f64 = 0.962;
+const MEDIUM_PERIODS: usize = 24;
+const MEDIUM_SCALE: u32 = 2;
+const MEDIUM_DECAY: f64 = 0.9952;
+const LONG_PERIODS: usize = 42;
+const LONG_SCALE: u32 = 24;
+const LONG_DECAY: f64 = 0.99931;
+
+/// How cautious an estimate should be, as in Core's `estimatesmartfee`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EstimateMode {
+    /// Also requires the fee rate to have worked over the long horizon, so it is slower to drop when fees fall
+    Conservative,
+    /// Responds quickly to changes in fee rates, at some risk of underpaying
+    Economical,
+}
+
+/// A fee rate, along with the number of blocks it is expected to confirm within. This may be more than the
+/// requested target if we don't have enough data for the target itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeEstimate {
+    pub fee_rate: FeeRate,
+    pub blocks: u32,
+}
+
+/// Confirmation statistics over a single horizon
+#[derive(Debug, Clone)]
+struct ConfirmStats {
+    scale: u32,
+    decay: f64,
+    /// `confirmed[p][b]` counts the transactions in bucket `b` which confirmed within `(p + 1) * scale` blocks
+    confirmed: Vec<Vec<f64>>,
+    /// `failed[p][b]` counts the transactions in bucket `b` which waited at least `(p + 1) * scale` blocks, then
+    /// left the mempool without confirming
+    failed: Vec<Vec<f64>>,
+    /// The number of confirmed transactions in each bucket, and the sum of their fee rates
+    total: Vec<f64>,
+    fee_rate_sum: Vec<f64>,
+}
+
+impl ConfirmStats {
+    fn new(periods: usize, scale: u32, decay: f64, buckets: usize) -> ConfirmStats {
+        ConfirmStats {
+            scale,
+            decay,
+            confirmed: vec![vec![0.0; buckets]; periods],
+            failed: vec![vec![0.0; buckets]; periods],
+            total: vec![0.0; buckets],
+            fee_rate_sum: vec![0.0; buckets],
+        }
+    }
+
+    fn max_target(&self) -> u32 {
+        self.confirmed.len() as u32 * self.scale
+    }
+
+    fn decay(&mut self) {
+        let decay = self.decay;
+        for row in self.confirmed.iter_mut().chain(self.failed.iter_mut()) {
+            row.iter_mut().for_each(|count| *count *= decay);
+        }
+        self.total.iter_mut().for_each(|count| *count *= decay);
+        self.fee_rate_sum.iter_mut().for_each(|sum| *sum *= decay);
+    }
+
+    fn record_confirmation(&mut self, blocks: u32, bucket: usize, fee_rate: FeeRate) {
+        let periods = blocks.div_ceil(self.scale) as usize;
+        for row in self.confirmed.iter_mut().skip(periods.max(1) - 1) {
+            row[bucket] += 1.0;
+        }
+        self.total[bucket] += 1.0;
+        self.fee_rate_sum[bucket] += fee_rate.sat_per_kvb() as f64;
+    }
+
+    fn record_failure(&mut self, blocks: u32, bucket: usize) {
+        let periods = (blocks / self.scale) as usize;
+        for row in self.failed.iter_mut().take(periods) {
+            row[bucket] += 1.0;
+        }
+    }
+
+    /// The median fee rate of the cheapest range of buckets whose transactions confirmed within `target` blocks at
+    /// least `threshold` of the time. `pending[b]` counts the transactions in bucket `b` which are still waiting
+    /// after `target` blocks.
+    fn estimate(
+        &self,
+        target: u32,
+        threshold: f64,
+        sufficient: f64,
+        pending: &[f64],
+    ) -> Option<FeeRate> {
+        if target == 0 || target > self.max_target() {
+            return None;
+        }
+        let period = target.div_ceil(self.scale) as usize - 1;
+        let sufficient = sufficient / (1.0 - self.decay);
+        let (mut confirmed, mut total, mut failed, mut extra) = (0.0, 0.0, 0.0, 0.0);
+        let mut range_start = self.total.len() - 1;
+        let mut passing: Option<(usize, usize)> = None;
+        // Work down from the most expensive bucket, grouping buckets until each group has enough data
+        for bucket in (0..self.total.len()).rev() {
+            confirmed += self.confirmed[period][bucket];
+            total += self.total[bucket];
+            failed += self.failed[period][bucket];
+            extra += pending[bucket];
+            if total < sufficient {
+                continue;
+            }
+            if confirmed / (total + failed + extra) < threshold {
+                break;
+            }
+            passing = Some((bucket, range_start));
+            confirmed = 0.0;
+            total = 0.0;
+            failed = 0.0;
+            extra = 0.0;
+            range_start = bucket.saturating_sub(1);
+        }
+        let (low, high) = passing?;
+        // Find the bucket containing the median transaction in the passing range
+        let range_total: f64 = self.total[low..=high].iter().sum();
+        let mut seen = 0.0;
+        for bucket in low..=high {
+            seen += self.total[bucket];
+            if seen >= range_total / 2.0 && self.total[bucket] > 0.0 {
+                let average = self.fee_rate_sum[bucket] / self.total[bucket];
+                return Some(FeeRate::from_sat_per_kvb(average.round() as u64));
+            }
+        }
+        None
+    }
+
+    fn write<W: Write>(&self, target: &mut W) -> Result<(), StorageError> {
+        for row in self.confirmed.iter().chain(self.failed.iter()) {
+            write_values(target, row)?;
+        }
+        write_values(target, &self.total)?;
+        write_values(target, &self.fee_rate_sum)
+    }
+
+    fn read<R: Read>(&mut self, src: &mut R) -> Result<(), StorageError> {
+        for row in self.confirmed.iter_mut().chain(self.failed.iter_mut()) {
+            read_values(src, row)?;
+        }
+        read_values(src, &mut self.total)?;
+        read_values(src, &mut self.fee_rate_sum)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TrackedTx {
+    height: u32,
+    bucket: usize,
+}
+
+/// The number of blocks each tracked transaction has waited, sorted within each bucket
+struct Waits(Vec<Vec<u32>>);
+
+impl Waits {
+    /// Counts the transactions in each bucket which have already waited at least `target` blocks
+    fn pending(&self, target: u32) -> Vec<f64> {
+        self.0
+            .iter()
+            .map(|waits| (waits.len() - waits.partition_point(|wait| *wait < target)) as f64)
+            .collect()
+    }
+}
+
+/// Estimates the fee rate needed for a transaction to confirm within a number of blocks.
+#[derive(Debug, Clone)]
+pub struct FeeEstimator {
+    /// The upper bound of each bucket's fee rates. The last bucket is unbounded.
+    buckets: Vec<f64>,
+    short: ConfirmStats,
+    medium: ConfirmStats,
+    long: ConfirmStats,
+    /// The mempool transactions we are waiting to see confirmed
+    tracked: HashMap<TxID, TrackedTx>,
+    best_height: u32,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> FeeEstimator {
+        FeeEstimator::new()
+    }
+}
+
+impl FeeEstimator {
+    pub fn new() -> FeeEstimator {
+        let mut buckets = Vec::new();
+        let mut bound = MIN_BUCKET_FEE_RATE;
+        while bound <= MAX_BUCKET_FEE_RATE {
+            buckets.push(bound);
+            bound *= FEE_SPACING;
+        }
+        buckets.push(f64::INFINITY);
+        let count = buckets.len();
+        FeeEstimator {
+            buckets,
+            short: ConfirmStats::new(SHORT_PERIODS, SHORT_SCALE, SHORT_DECAY, count),
+            medium: ConfirmStats::new(MEDIUM_PERIODS, MEDIUM_SCALE, MEDIUM_DECAY, count),
+            long: ConfirmStats::new(LONG_PERIODS, LONG_SCALE, LONG_DECAY, count),
+            tracked: HashMap::new(),
+            best_height: 0,
+        }
+    }
+
+    /// The height of the last block processed
+    pub fn best_height(&self) -> u32 {
+        self.best_height
+    }
+
+    /// The highest confirmation target we can estimate for
+    pub fn max_target(&self) -> u32 {
+        self.long.max_target()
+    }
+
+    /// Starts tracking a transaction which just entered the mempool.
+    pub fn process_transaction(&mut self, entry: &MempoolEntry) {
+        let bucket = self.bucket_for(entry.fee_rate());
+        self.tracked.insert(
+            entry.txid().clone(),
+            TrackedTx {
+                height: entry.height(),
+                bucket,
+            },
+        );
+    }
+
+    /// Records the transactions confirmed by the block at `height`, and those which have left `mempool` without
+    /// confirming since the last block.
+    pub fn process_block(&mut self, height: u32, confirmed: &[MempoolEntry], mempool: &Mempool) {
+        // Blocks at or below our best height are reorgs or duplicates, which Core also ignores
+        if height <= self.best_height {
+            return;
+        }
+        self.best_height = height;
+        for stats in [&mut self.short, &mut self.medium, &mut self.long] {
+            stats.decay();
+        }
+        for entry in confirmed {
+            let tracked = match self.tracked.remove(entry.txid()) {
+                Some(tracked) => tracked,
+                None => continue,
+            };
+            let blocks = height.saturating_sub(tracked.height);
+            if blocks == 0 {
+                continue;
+            }
+            for stats in [&mut self.short, &mut self.medium, &mut self.long] {
+                stats.record_confirmation(blocks, tracked.bucket, entry.fee_rate());
+            }
+        }
+        let removed: Vec<TxID> = self
+            .tracked
+            .keys()
+            .filter(|txid| !mempool.contains(txid))
+            .cloned()
+            .collect();
+        for txid in removed {
+            let tracked = self.tracked.remove(&txid).expect("Just found");
+            let blocks = height.saturating_sub(tracked.height);
+            for stats in [&mut self.short, &mut self.medium, &mut self.long] {
+                stats.record_failure(blocks, tracked.bucket);
+            }
+        }
+    }
+
+    /// Estimates the fee rate needed to confirm within `target` blocks, like Core's `estimatesmartfee`. Returns
+    /// `None` if we haven't seen enough transactions confirm.
+    pub fn estimate_smart_fee(&self, target: u32, mode: EstimateMode) -> Option<FeeEstimate> {
+        // A transaction can't confirm before the next block, and it's hard to distinguish 1 from 2
+        let mut target = target.clamp(2, self.max_target());
+        // The fallback can try every target up to the maximum, so count the waiting transactions only once
+        let waits = self.waits();
+        loop {
+            if let Some(fee_rate) = self.estimate_for_target(target, mode, &waits) {
+                return Some(FeeEstimate {
+                    fee_rate,
+                    blocks: target,
+                });
+            }
+            // Fall back to the nearest target we have enough data for
+            if target >= self.max_target() {
+                return None;
+            }
+            target += 1;
+        }
+    }
+
+    fn estimate_for_target(
+        &self,
+        target: u32,
+        mode: EstimateMode,
+        waits: &Waits,
+    ) -> Option<FeeRate> {
+        let half = self.estimate_combined(target / 2, HALF_SUCCESS_PCT, waits);
+        let full = self.estimate_combined(target, SUCCESS_PCT, waits);
+        let double = self.estimate_combined(target * 2, DOUBLE_SUCCESS_PCT, waits);
+        let mut estimate = half.max(full).max(double);
+        if mode == EstimateMode::Conservative {
+            let pending = waits.pending(target * 2);
+            let long = self.long.estimate(
+                (target * 2).min(self.long.max_target()),
+                DOUBLE_SUCCESS_PCT,
+                SUFFICIENT_FEE_TXS,
+                &pending,
+            );
+            estimate = estimate.max(long);
+        }
+        // An estimate is only useful if we have one at the requested target itself
+        full?;
+        estimate
+    }
+
+    /// Estimates using the shortest horizon which covers `target`.
+    fn estimate_combined(&self, target: u32, threshold: f64, waits: &Waits) -> Option<FeeRate> {
+        let pending = waits.pending(target);
+        if target <= self.short.max_target() {
+            self.short
+                .estimate(target, threshold, SUFFICIENT_TXS_SHORT, &pending)
+        } else if target <= self.medium.max_target() {
+            self.medium
+                .estimate(target, threshold, SUFFICIENT_FEE_TXS, &pending)
+        } else {
+            self.long
+                .estimate(target, threshold, SUFFICIENT_FEE_TXS, &pending)
+        }
+    }
+
+    /// How long each tracked transaction has waited so far, by bucket
+    fn waits(&self) -> Waits {
+        let mut waits = vec![Vec::new(); self.buckets.len()];
+        for tracked in self.tracked.values() {
+            waits[tracked.bucket].push(self.best_height.saturating_sub(tracked.height));
+        }
+        for bucket in waits.iter_mut() {
+            bucket.sort_unstable();
+        }
+        Waits(waits)
+    }
+
+    fn bucket_for(&self, fee_rate: FeeRate) -> usize {
+        let rate = fee_rate.sat_per_kvb() as f64;
+        self.buckets
+            .iter()
+            .position(|bound| rate <= *bound)
+            .unwrap_or(self.buckets.len() - 1)
+    }
+
+    /// Saves the estimator's statistics. Transactions which are still unconfirmed are not saved.
+    pub fn write<W: Write>(&self, mut target: W) -> Result<(), StorageError> {
+        target.write_all(&FILE_VERSION.to_le_bytes())?;
+        target.write_all(&self.best_height.to_le_bytes())?;
+        target.write_all(&(self.buckets.len() as u32).to_le_bytes())?;
+        for stats in [&self.short, &self.medium, &self.long] {
+            stats.write(&mut target)?;
+        }
+        target.flush()?;
+        Ok(())
+    }
+
+    /// Loads statistics saved by [`FeeEstimator::write`].
+    pub fn read<R: Read>(mut src: R) -> Result<FeeEstimator, StorageError> {
+        let version = read_u32(&mut src)?;
+        if version != FILE_VERSION {
+            return Err(StorageError::Unsupported(format!(
+                "fee estimates version {}",
+                version
+            )));
+        }
+        let mut estimator = FeeEstimator::new();
+        estimator.best_height = read_u32(&mut src)?;
+        let buckets = read_u32(&mut src)? as usize;
+        if buckets != estimator.buckets.len() {
+            return Err(StorageError::Corruption(format!(
+                "fee estimates have {} buckets, expected {}",
+                buckets,
+                estimator.buckets.len()
+            )));
+        }
+        for stats in [
+            &mut estimator.short,
+            &mut estimator.medium,
+            &mut estimator.long,
+        ] {
+            stats.read(&mut src)?;
+        }
+        Ok(estimator)
+    }
+}
+
+fn write_values<W: Write>(target: &mut W, values: &[f64]) -> Result<(), StorageError> {
+    for value in values {
+        target.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_values<R: Read>(src: &mut R, values: &mut [f64]) -> Result<(), StorageError> {
+    let mut buf = [0u8; 8];
+    for value in values.iter_mut() {
+        src.read_exact(&mut buf)?;
+        *value = f64::from_le_bytes(buf);
+        if !value.is_finite() || *value < 0.0 {
+            return Err(StorageError::Corruption(String::from(
+                "invalid value in fee estimates",
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn read_u32<R: Read>(src: &mut R) -> Result<u32, StorageError> {
+    let mut buf = [0u8; 4];
+    src.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{u256, Block, BlockHash, Deserializable, Serializable, Transaction};
+    use shared::{TxInput, TxOutpoint, TxOutput};
+    use storage::{Chainstate, Coin, UtxoSet};
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn spend(n: u32, fee: i64) -> Transaction {
+        let outpoint = TxOutpoint::new(u256::from_bytes([1; 32]), n);
+        let mut script = vec![0, 20];
+        script.extend_from_slice(&[9; 20]);
+        let mut raw = Vec::new();
+        Transaction::new(
+            1,
+            vec![TxInput::new(outpoint, Vec::new(), 0xffffffff)],
+            vec![TxOutput::new(100_000 - fee, script)],
+        )
+        .serialize(&mut raw)
+        .unwrap();
+        let len = raw.len();
+        raw[len - 4..].copy_from_slice(&[0; 4]);
+        Transaction::deserialize(&raw[..]).unwrap()
+    }
+
+    #[test]
+    fn estimates_from_confirmed_transactions() {
+        let mut utxos = UtxoSet::new();
+        for n in 0..200 {
+            let coin = Coin::new(TxOutput::new(100_000, vec![0x51]), 1, false);
+            utxos.insert(TxOutpoint::new(u256::from_bytes([1; 32]), n), coin);
+        }
+        let mut mempool = Mempool::default();
+        let mut estimator = FeeEstimator::new();
+        assert_eq!(
+            estimator.estimate_smart_fee(2, EstimateMode::Economical),
+            None
+        );
+
+        let mut next = 0;
+        for height in 100..120 {
+            let chainstate = Chainstate::new(utxos.clone(), BlockHash::from([0; 32]), height);
+            let mut confirmed_next = Vec::new();
+            // Each block, three transactions paying 50 sat/vB confirm immediately while three paying 2 sat/vB
+            // never do
+            for _ in 0..3 {
+                for fee_rate in [50, 2] {
+                    let tx = spend(next, fee_rate * spend(next, 0).len() as i64);
+                    next += 1;
+                    mempool.accept(tx.clone(), &chainstate, NOW).unwrap();
+                    estimator.process_transaction(mempool.get(tx.txid()).unwrap());
+                    if fee_rate == 50 {
+                        confirmed_next.push(tx);
+                    }
+                }
+            }
+            let block = Block::new(Block::_test_block().header().clone(), confirmed_next);
+            let confirmed = mempool.remove_for_block(&block);
+            estimator.process_block(height + 1, &confirmed, &mempool);
+        }
+
+        let estimate = estimator
+            .estimate_smart_fee(2, EstimateMode::Economical)
+            .unwrap();
+        assert_eq!(estimate.fee_rate, FeeRate::from_sat_per_kvb(50_000));
+        assert_eq!(estimate.blocks, 2);
+        let conservative = estimator
+            .estimate_smart_fee(1, EstimateMode::Conservative)
+            .unwrap();
+        assert_eq!(conservative.fee_rate, FeeRate::from_sat_per_kvb(50_000));
+
+        // Estimates survive a restart
+        let mut saved = Vec::new();
+        estimator.write(&mut saved).unwrap();
+        let restored = FeeEstimator::read(&saved[..]).unwrap();
+        assert_eq!(restored.best_height(), 120);
+        assert_eq!(
+            restored.estimate_smart_fee(2, EstimateMode::Economical),
+            Some(estimate)
+        );
+        assert!(FeeEstimator::read(&saved[..saved.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn counts_pending_transactions_by_wait() {
+        let waits = Waits(vec![vec![0, 2, 2, 5], Vec::new(), vec![7]]);
+        assert_eq!(waits.pending(0), vec![4.0, 0.0, 1.0]);
+        assert_eq!(waits.pending(2), vec![3.0, 0.0, 1.0]);
+        assert_eq!(waits.pending(3), vec![1.0, 0.0, 1.0]);
+        assert_eq!(waits.pending(8), vec![0.0, 0.0, 0.0]);
+    }
+}
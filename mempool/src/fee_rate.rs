@@ -0,0 +1,34 @@
+/// A fee rate in satoshis per 1000 virtual bytes, the unit Bitcoin Core uses for its relay fees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    pub const fn from_sat_per_kvb(sat_per_kvb: u64) -> FeeRate {
+        FeeRate(sat_per_kvb)
+    }
+    /// The fee rate of a transaction paying `fee` satoshis for `size` bytes
+    pub fn from_fee(fee: i64, size: usize) -> FeeRate {
+        if size == 0 || fee <= 0 {
+            return FeeRate(0);
+        }
+        FeeRate((fee as u64).saturating_mul(1000) / size as u64)
+    }
+    pub fn sat_per_kvb(&self) -> u64 {
+        self.0
+    }
+    /// The fee a transaction of `size` bytes must pay to meet this rate
+    pub fn fee_for(&self, size: usize) -> i64 {
+        let fee = self.0.saturating_mul(size as u64) / 1000;
+        // Like Core, never round a non-zero rate down to a zero fee
+        if fee == 0 && self.0 > 0 && size > 0 {
+            return 1;
+        }
+        fee as i64
+    }
+}
+
+impl std::fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{:03} sat/vB", self.0 / 1000, self.0 % 1000)
+    }
+}
@@ -0,0 +1,21 @@
+//! The mempool: transactions which have been relayed to us but not yet confirmed.
+//!
+//! Transactions are checked against the UTXO set and Bitcoin Core's standardness policy before they are
+//! accepted. The mempool tracks chains of unconfirmed transactions so that it can enforce Core's package
+//! limits, evict the least valuable packages when it's full, and apply the BIP125 replace-by-fee rules.
+//!
+//! Transactions whose parents we haven't seen yet are held in an [`OrphanPool`] until the parents arrive. The
+//! [`FeeEstimator`] watches how long mempool transactions take to confirm in order to estimate fees.
+mod error;
+pub use error::MempoolError;
+mod fee_rate;
+pub use fee_rate::FeeRate;
+mod fee_estimator;
+pub use fee_estimator::{EstimateMode, FeeEstimate, FeeEstimator};
+mod mempool;
+pub use mempool::{
+    Mempool, MempoolEntry, MempoolEvent, MempoolLimits, RemovalReason, COINBASE_MATURITY, MAX_MONEY,
+};
+mod orphans;
+pub use orphans::{missing_parents, OrphanPool, DEFAULT_MAX_ORPHANS, ORPHAN_EXPIRE_TIME};
+pub mod policy;
@@ -0,0 +1,1036 @@
+use crate::policy::check_standard;
+use crate::{FeeRate, MempoolError};
+use shared::{Block, Transaction, TxID, TxOutpoint};
+use std::collections::{HashMap, HashSet};
+use storage::Chainstate;
+use tracing::debug;
+
+/// Coinbase outputs can't be spent until they are this many blocks deep
+pub const COINBASE_MATURITY: u32 = 100;
+/// The maximum number of satoshis that can ever exist
+pub const MAX_MONEY: i64 = 21_000_000 * 100_000_000;
+const SEQUENCE_FINAL: u32 = 0xffffffff;
+/// Inputs with a sequence number at or below this signal that their transaction may be replaced (BIP125)
+const MAX_BIP125_RBF_SEQUENCE: u32 = 0xfffffffd;
+/// Lock times below this are block heights, and those above are unix timestamps
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// The minimum fee rate raised by evictions halves every 12 hours (Core's `ROLLING_FEE_HALFLIFE`)
+const ROLLING_FEE_HALFLIFE: u64 = 12 * 60 * 60;
+
+/// Tunable limits for the mempool. The defaults match Bitcoin Core's.
+#[derive(Debug, Clone)]
+pub struct MempoolLimits {
+    /// The total serialized size of all transactions in the mempool, in bytes
+    pub max_size: usize,
+    /// How long a transaction may sit in the mempool before it is evicted, in seconds
+    pub expiry: u64,
+    /// Transactions paying less than this are never accepted
+    pub min_relay_fee_rate: FeeRate,
+    /// A replacement must pay for its own relay at this rate, on top of the fees of what it replaces
+    pub incremental_relay_fee_rate: FeeRate,
+    /// The maximum number of in-mempool ancestors of a transaction, including itself
+    pub max_ancestors: usize,
+    /// The maximum combined size of a transaction and its in-mempool ancestors
+    pub max_ancestor_size: usize,
+    /// The maximum number of in-mempool descendants of a transaction, including itself
+    pub max_descendants: usize,
+    /// The maximum combined size of a transaction and its in-mempool descendants
+    pub max_descendant_size: usize,
+    /// The maximum number of transactions a single replacement may evict
+    pub max_replacements: usize,
+}
+
+impl Default for MempoolLimits {
+    fn default() -> MempoolLimits {
+        MempoolLimits {
+            max_size: 300_000_000,
+            expiry: 336 * 60 * 60,
+            min_relay_fee_rate: FeeRate::from_sat_per_kvb(1000),
+            incremental_relay_fee_rate: FeeRate::from_sat_per_kvb(1000),
+            max_ancestors: 25,
+            max_ancestor_size: 101_000,
+            max_descendants: 25,
+            max_descendant_size: 101_000,
+            max_replacements: 100,
+        }
+    }
+}
+
+/// Why a transaction left the mempool (Core's `MemPoolRemovalReason`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    /// It was confirmed by a block
+    Block,
+    /// It conflicted with a block, or descended from a transaction which did
+    Conflict,
+    /// It was replaced under BIP125, or descended from a transaction which was
+    Replaced,
+    /// It sat in the mempool for longer than the expiry time
+    Expiry,
+    /// It was evicted to keep the mempool within its size limit
+    SizeLimit,
+}
+
+/// A change to the contents of the mempool, as recorded once [`Mempool::record_events`] has been called. Each
+/// event carries the mempool sequence number it was assigned, so that a subscriber can tell whether it missed any.
+#[derive(Debug, Clone)]
+pub enum MempoolEvent {
+    Added {
+        tx: Transaction,
+        sequence: u64,
+    },
+    Removed {
+        txid: TxID,
+        reason: RemovalReason,
+        sequence: u64,
+    },
+}
+
+/// A transaction in the mempool, along with the statistics of its in-mempool descendants.
+#[derive(Debug, Clone)]
+pub struct MempoolEntry {
+    tx: Transaction,
+    fee: i64,
+    size: usize,
+    time: u64,
+    height: u32,
+    parents: HashSet<TxID>,
+    children: HashSet<TxID>,
+    /// These include the entry itself
+    descendant_count: usize,
+    descendant_size: usize,
+    descendant_fee: i64,
+}
+
+impl MempoolEntry {
+    pub fn tx(&self) -> &Transaction {
+        &self.tx
+    }
+    pub fn txid(&self) -> &TxID {
+        self.tx.txid()
+    }
+    pub fn fee(&self) -> i64 {
+        self.fee
+    }
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn fee_rate(&self) -> FeeRate {
+        FeeRate::from_fee(self.fee, self.size)
+    }
+    /// The unix time at which the transaction entered the mempool
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+    /// The chain height when the transaction entered the mempool
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn descendant_count(&self) -> usize {
+        self.descendant_count
+    }
+    pub fn descendant_size(&self) -> usize {
+        self.descendant_size
+    }
+    pub fn descendant_fee(&self) -> i64 {
+        self.descendant_fee
+    }
+    /// Entries are evicted in order of the higher of their own fee rate and the fee rate of their descendant
+    /// package, so that a low fee parent is kept if a child is paying for it.
+    fn descendant_score(&self) -> FeeRate {
+        self.fee_rate()
+            .max(FeeRate::from_fee(self.descendant_fee, self.descendant_size))
+    }
+}
+
+/// Unconfirmed transactions which we'd be willing to include in a block, and to relay.
+#[derive(Debug)]
+pub struct Mempool {
+    limits: MempoolLimits,
+    entries: HashMap<TxID, MempoolEntry>,
+    /// The txid of each mempool transaction, by wtxid, for peers which ask for transactions by wtxid
+    by_wtxid: HashMap<TxID, TxID>,
+    /// The mempool transaction spending each outpoint
+    spends: HashMap<TxOutpoint, TxID>,
+    total_size: usize,
+    /// Raised when transactions are evicted for space, so that we don't accept something we just evicted
+    rolling_min_fee_rate: FeeRate,
+    last_rolling_update: u64,
+    /// Counts every addition and removal, like Core's `mempool_sequence`
+    sequence: u64,
+    /// The changes since the last call to `take_events`, if they're being recorded
+    events: Option<Vec<MempoolEvent>>,
+}
+
+impl Default for Mempool {
+    fn default() -> Mempool {
+        Mempool::new(MempoolLimits::default())
+    }
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Mempool {
+        Mempool {
+            limits,
+            entries: HashMap::new(),
+            by_wtxid: HashMap::new(),
+            spends: HashMap::new(),
+            total_size: 0,
+            rolling_min_fee_rate: FeeRate::default(),
+            last_rolling_update: 0,
+            // Core starts counting at one, so that zero can mean "no sequence"
+            sequence: 1,
+            events: None,
+        }
+    }
+
+    /// The sequence number the next addition or removal will be assigned
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Starts recording every addition and removal, to be collected with [`Mempool::take_events`]
+    pub fn record_events(&mut self) {
+        self.events.get_or_insert_with(Vec::new);
+    }
+
+    /// The events recorded since the last call, in the order they happened
+    pub fn take_events(&mut self) -> Vec<MempoolEvent> {
+        self.events.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    fn record(&mut self, event: impl FnOnce(u64) -> MempoolEvent) {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        if let Some(events) = self.events.as_mut() {
+            events.push(event(sequence));
+        }
+    }
+
+    pub fn limits(&self) -> &MempoolLimits {
+        &self.limits
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    /// The combined size of every transaction in the mempool, in bytes
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+    pub fn contains(&self, txid: &TxID) -> bool {
+        self.entries.contains_key(txid)
+    }
+    pub fn get(&self, txid: &TxID) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+    /// The mempool transaction with the witness txid `wtxid`, if any
+    pub fn get_by_wtxid(&self, wtxid: &TxID) -> Option<&MempoolEntry> {
+        self.entries.get(self.by_wtxid.get(wtxid)?)
+    }
+    pub fn contains_wtxid(&self, wtxid: &TxID) -> bool {
+        self.by_wtxid.contains_key(wtxid)
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.entries.values()
+    }
+    /// The mempool transaction which spends `outpoint`, if any
+    pub fn spender(&self, outpoint: &TxOutpoint) -> Option<&TxID> {
+        self.spends.get(outpoint)
+    }
+
+    /// The lowest fee rate a new transaction must pay at unix time `now`. This is the minimum relay fee rate,
+    /// unless the mempool has recently been full.
+    pub fn min_fee_rate(&self, now: u64) -> FeeRate {
+        let elapsed = now.saturating_sub(self.last_rolling_update);
+        let decayed = self.rolling_min_fee_rate.sat_per_kvb() as f64
+            / 2f64.powf(elapsed as f64 / ROLLING_FEE_HALFLIFE as f64);
+        // Like Core, drop the rolling minimum entirely once it has decayed to a negligible rate
+        if decayed < self.limits.incremental_relay_fee_rate.sat_per_kvb() as f64 / 2.0 {
+            return self.limits.min_relay_fee_rate;
+        }
+        self.limits
+            .min_relay_fee_rate
+            .max(FeeRate::from_sat_per_kvb(decayed as u64))
+    }
+
+    /// Validates a transaction against the UTXO set and our relay policy, and adds it to the mempool.
+    ///
+    /// If the transaction replaces conflicting mempool transactions under BIP125, their txids (and those of
+    /// their descendants) are returned. Relative lock-times (BIP68) and scripts are not checked.
+    pub fn accept(
+        &mut self,
+        tx: Transaction,
+        chainstate: &Chainstate,
+        now: u64,
+    ) -> Result<Vec<TxID>, MempoolError> {
+        self.accept_with_max_fee_rate(tx, chainstate, now, None)
+    }
+
+    /// Like [`Mempool::accept`], but also rejects the transaction if it pays more than `max_fee_rate`, which
+    /// guards against a mistake in a locally submitted transaction burning its inputs as fees.
+    pub fn accept_with_max_fee_rate(
+        &mut self,
+        tx: Transaction,
+        chainstate: &Chainstate,
+        now: u64,
+        max_fee_rate: Option<FeeRate>,
+    ) -> Result<Vec<TxID>, MempoolError> {
+        let txid = tx.txid().clone();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if tx.is_coinbase() {
+            return Err(MempoolError::Invalid(String::from("coinbase")));
+        }
+        if tx.inputs().is_empty() || tx.outputs().is_empty() {
+            return Err(MempoolError::Invalid(String::from("no inputs or outputs")));
+        }
+        check_standard(&tx)?;
+        let next_height = chainstate.height() + 1;
+        if !is_final(&tx, next_height, now) {
+            return Err(MempoolError::NonStandard(String::from("non-final")));
+        }
+
+        let mut seen = HashSet::new();
+        let mut conflicts = HashSet::new();
+        let mut parents = HashSet::new();
+        let mut missing = Vec::new();
+        let mut input_value = 0;
+        for input in tx.inputs() {
+            let outpoint = input.previous_outpoint();
+            if !seen.insert(outpoint) {
+                return Err(MempoolError::Invalid(String::from("duplicate inputs")));
+            }
+            if let Some(spender) = self.spends.get(outpoint) {
+                conflicts.insert(spender.clone());
+            }
+            let parent_txid = TxID::from(*outpoint.hash().to_le_bytes());
+            if let Some(parent) = self.entries.get(&parent_txid) {
+                match parent.tx.outputs().get(outpoint.index() as usize) {
+                    Some(output) => input_value += output.value(),
+                    None => {
+                        return Err(MempoolError::Invalid(String::from(
+                            "spends a nonexistent output",
+                        )))
+                    }
+                }
+                parents.insert(parent_txid);
+            } else if let Some(coin) = chainstate.utxos().get(outpoint) {
+                if coin.is_coinbase() && next_height - coin.height() < COINBASE_MATURITY {
+                    return Err(MempoolError::Invalid(String::from(
+                        "premature spend of coinbase",
+                    )));
+                }
+                input_value += coin.value();
+            } else {
+                missing.push(outpoint.clone());
+            }
+        }
+        if !missing.is_empty() {
+            return Err(MempoolError::MissingInputs(missing));
+        }
+
+        let mut output_value = 0;
+        for output in tx.outputs() {
+            if output.value() < 0 || output.value() > MAX_MONEY {
+                return Err(MempoolError::Invalid(String::from(
+                    "output value out of range",
+                )));
+            }
+            output_value += output.value();
+        }
+        if output_value > MAX_MONEY {
+            return Err(MempoolError::Invalid(String::from(
+                "total output value out of range",
+            )));
+        }
+        if input_value < output_value {
+            return Err(MempoolError::Invalid(String::from(
+                "inputs are worth less than outputs",
+            )));
+        }
+        let fee = input_value - output_value;
+        let size = tx.vsize();
+        if let Some(max_fee_rate) = max_fee_rate {
+            if fee > max_fee_rate.fee_for(size) {
+                return Err(MempoolError::MaxFeeExceeded(format!(
+                    "{} is above the maximum of {}",
+                    FeeRate::from_fee(fee, size),
+                    max_fee_rate
+                )));
+            }
+        }
+        let min_fee_rate = self.min_fee_rate(now);
+        if fee < min_fee_rate.fee_for(size) {
+            return Err(MempoolError::InsufficientFee(format!(
+                "{} is below the minimum of {}",
+                FeeRate::from_fee(fee, size),
+                min_fee_rate
+            )));
+        }
+
+        let ancestors = self.ancestors(&parents);
+        self.check_package_limits(&ancestors, size)?;
+        let replaced = if conflicts.is_empty() {
+            Vec::new()
+        } else {
+            self.check_replacement(fee, size, &conflicts, &parents, &ancestors)?
+        };
+        for conflict in conflicts.iter() {
+            self.remove_with_descendants(conflict, RemovalReason::Replaced);
+        }
+
+        for ancestor in ancestors.iter() {
+            let ancestor = self
+                .entries
+                .get_mut(ancestor)
+                .expect("Ancestors are in the mempool");
+            ancestor.descendant_count += 1;
+            ancestor.descendant_size += size;
+            ancestor.descendant_fee += fee;
+        }
+        for parent in parents.iter() {
+            let parent = self
+                .entries
+                .get_mut(parent)
+                .expect("Parents are in the mempool");
+            parent.children.insert(txid.clone());
+        }
+        for input in tx.inputs() {
+            self.spends
+                .insert(input.previous_outpoint().clone(), txid.clone());
+        }
+        self.total_size += size;
+        self.record(|sequence| MempoolEvent::Added {
+            tx: tx.clone(),
+            sequence,
+        });
+        self.by_wtxid.insert(tx.wtxid().clone(), txid.clone());
+        self.entries.insert(
+            txid.clone(),
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                time: now,
+                height: chainstate.height(),
+                parents,
+                children: HashSet::new(),
+                descendant_count: 1,
+                descendant_size: size,
+                descendant_fee: fee,
+            },
+        );
+        debug!(
+            "Accepted transaction {:?} paying {}, replacing {}",
+            txid,
+            FeeRate::from_fee(fee, size),
+            replaced.len()
+        );
+
+        self.trim_to_size(now);
+        if !self.entries.contains_key(&txid) {
+            return Err(MempoolError::MempoolFull);
+        }
+        Ok(replaced)
+    }
+
+    /// Removes the transactions confirmed by a newly connected block, along with any which conflict with it.
+    /// Returns the confirmed entries.
+    pub fn remove_for_block(&mut self, block: &Block) -> Vec<MempoolEntry> {
+        let mut confirmed = Vec::new();
+        for tx in block.transactions() {
+            if let Some(entry) = self.remove_entry(tx.txid(), RemovalReason::Block) {
+                confirmed.push(entry);
+            }
+            for input in tx.inputs() {
+                if let Some(conflict) = self.spends.get(input.previous_outpoint()).cloned() {
+                    debug!("Removing {:?}, which conflicts with the block", conflict);
+                    self.remove_with_descendants(&conflict, RemovalReason::Conflict);
+                }
+            }
+        }
+        confirmed
+    }
+
+    /// Removes a transaction and all of its descendants, returning the removed entries.
+    pub fn remove(&mut self, txid: &TxID, reason: RemovalReason) -> Vec<MempoolEntry> {
+        self.remove_with_descendants(txid, reason)
+    }
+
+    /// Evicts transactions which entered the mempool more than the expiry time before `now`, along with their
+    /// descendants. Returns the txids of the evicted transactions.
+    pub fn expire(&mut self, now: u64) -> Vec<TxID> {
+        let cutoff = now.saturating_sub(self.limits.expiry);
+        let expired: Vec<TxID> = self
+            .entries
+            .values()
+            .filter(|entry| entry.time < cutoff)
+            .map(|entry| entry.txid().clone())
+            .collect();
+        let mut removed = Vec::new();
+        for txid in expired {
+            removed.extend(
+                self.remove_with_descendants(&txid, RemovalReason::Expiry)
+                    .into_iter()
+                    .map(|entry| entry.txid().clone()),
+            );
+        }
+        removed
+    }
+
+    /// Evicts the transactions with the lowest descendant fee rates until the mempool fits within its size limit,
+    /// raising the minimum fee rate to match. Returns the txids of the evicted transactions.
+    pub fn trim_to_size(&mut self, now: u64) -> Vec<TxID> {
+        let mut removed = Vec::new();
+        while self.total_size > self.limits.max_size {
+            let (txid, score) = match self
+                .entries
+                .values()
+                .map(|entry| (entry.txid(), entry.descendant_score()))
+                .min_by_key(|(_, score)| *score)
+            {
+                Some((txid, score)) => (txid.clone(), score),
+                None => break,
+            };
+            let min_fee_rate = FeeRate::from_sat_per_kvb(
+                score.sat_per_kvb() + self.limits.incremental_relay_fee_rate.sat_per_kvb(),
+            );
+            if min_fee_rate > self.min_fee_rate(now) {
+                self.rolling_min_fee_rate = min_fee_rate;
+                self.last_rolling_update = now;
+            }
+            removed.extend(
+                self.remove_with_descendants(&txid, RemovalReason::SizeLimit)
+                    .into_iter()
+                    .map(|entry| entry.txid().clone()),
+            );
+        }
+        if !removed.is_empty() {
+            debug!(
+                "Evicted {} transactions from the full mempool. Minimum fee rate is now {}",
+                removed.len(),
+                self.min_fee_rate(now)
+            );
+        }
+        removed
+    }
+
+    fn check_package_limits(
+        &self,
+        ancestors: &HashSet<TxID>,
+        size: usize,
+    ) -> Result<(), MempoolError> {
+        if ancestors.len() + 1 > self.limits.max_ancestors {
+            return Err(MempoolError::TooLongChain(format!(
+                "too many ancestors (limit {})",
+                self.limits.max_ancestors
+            )));
+        }
+        let ancestor_size: usize = ancestors.iter().map(|txid| self.entries[txid].size).sum();
+        if ancestor_size + size > self.limits.max_ancestor_size {
+            return Err(MempoolError::TooLongChain(format!(
+                "ancestors exceed {} bytes",
+                self.limits.max_ancestor_size
+            )));
+        }
+        for ancestor in ancestors.iter().map(|txid| &self.entries[txid]) {
+            if ancestor.descendant_count + 1 > self.limits.max_descendants {
+                return Err(MempoolError::TooLongChain(format!(
+                    "too many descendants of an ancestor (limit {})",
+                    self.limits.max_descendants
+                )));
+            }
+            if ancestor.descendant_size + size > self.limits.max_descendant_size {
+                return Err(MempoolError::TooLongChain(format!(
+                    "descendants of an ancestor exceed {} bytes",
+                    self.limits.max_descendant_size
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the BIP125 replacement rules, returning the txids of every transaction which would be replaced.
+    fn check_replacement(
+        &self,
+        fee: i64,
+        size: usize,
+        conflicts: &HashSet<TxID>,
+        parents: &HashSet<TxID>,
+        ancestors: &HashSet<TxID>,
+    ) -> Result<Vec<TxID>, MempoolError> {
+        // Rule 1: the originals must signal replaceability, either directly or through an unconfirmed ancestor
+        for conflict in conflicts.iter() {
+            if !self.signals_replaceability(conflict) {
+                return Err(MempoolError::Conflict(conflict.clone()));
+            }
+        }
+        let mut replaced = HashSet::new();
+        for conflict in conflicts.iter() {
+            replaced.extend(self.descendants(conflict));
+        }
+        if replaced.len() > self.limits.max_replacements {
+            return Err(MempoolError::NonStandard(format!(
+                "too many potential replacements ({})",
+                replaced.len()
+            )));
+        }
+        if !ancestors.is_disjoint(&replaced) {
+            return Err(MempoolError::Invalid(String::from(
+                "spends a transaction it replaces",
+            )));
+        }
+        // Rule 2: the replacement may only spend unconfirmed outputs which the originals also spent
+        let original_parents: HashSet<&TxID> = conflicts
+            .iter()
+            .flat_map(|conflict| self.entries[conflict].parents.iter())
+            .collect();
+        if parents
+            .iter()
+            .any(|parent| !original_parents.contains(parent))
+        {
+            return Err(MempoolError::NonStandard(String::from(
+                "replacement adds unconfirmed inputs",
+            )));
+        }
+        // The replacement must pay a higher fee rate than each transaction it directly replaces
+        let fee_rate = FeeRate::from_fee(fee, size);
+        for conflict in conflicts.iter() {
+            if fee_rate <= self.entries[conflict].fee_rate() {
+                return Err(MempoolError::InsufficientFee(format!(
+                    "replacement fee rate {} is not above {}",
+                    fee_rate,
+                    self.entries[conflict].fee_rate()
+                )));
+            }
+        }
+        // Rules 3 and 4: it must pay for everything it replaces, plus its own relay
+        let replaced_fees: i64 = replaced.iter().map(|txid| self.entries[txid].fee).sum();
+        if fee < replaced_fees {
+            return Err(MempoolError::InsufficientFee(format!(
+                "replacement pays {} but replaces {} in fees",
+                fee, replaced_fees
+            )));
+        }
+        let relay_fee = self.limits.incremental_relay_fee_rate.fee_for(size);
+        if fee - replaced_fees < relay_fee {
+            return Err(MempoolError::InsufficientFee(format!(
+                "replacement must pay at least {} more than it replaces",
+                relay_fee
+            )));
+        }
+        Ok(replaced.into_iter().collect())
+    }
+
+    fn signals_replaceability(&self, txid: &TxID) -> bool {
+        let mut candidates = vec![txid.clone()];
+        candidates.extend(self.ancestors(&self.entries[txid].parents));
+        candidates.iter().any(|txid| {
+            self.entries[txid]
+                .tx
+                .inputs()
+                .iter()
+                .any(|input| input.sequence() <= MAX_BIP125_RBF_SEQUENCE)
+        })
+    }
+
+    /// The in-mempool ancestors of a transaction with the given parents
+    fn ancestors(&self, parents: &HashSet<TxID>) -> HashSet<TxID> {
+        let mut ancestors = HashSet::new();
+        let mut queue: Vec<&TxID> = parents.iter().collect();
+        while let Some(txid) = queue.pop() {
+            if ancestors.insert(txid.clone()) {
+                queue.extend(self.entries[txid].parents.iter());
+            }
+        }
+        ancestors
+    }
+
+    /// A transaction and all of its in-mempool descendants
+    fn descendants(&self, txid: &TxID) -> HashSet<TxID> {
+        let mut descendants = HashSet::new();
+        let mut queue = vec![txid];
+        while let Some(txid) = queue.pop() {
+            if descendants.insert(txid.clone()) {
+                queue.extend(self.entries[txid].children.iter());
+            }
+        }
+        descendants
+    }
+
+    fn remove_with_descendants(&mut self, txid: &TxID, reason: RemovalReason) -> Vec<MempoolEntry> {
+        if !self.entries.contains_key(txid) {
+            return Vec::new();
+        }
+        // Remove children before their parents, so each removal can update the remaining ancestors. A
+        // descendant always has more ancestors than the transactions it descends from.
+        let mut descendants: Vec<(usize, TxID)> = self
+            .descendants(txid)
+            .into_iter()
+            .map(|txid| (self.ancestors(&self.entries[&txid].parents).len(), txid))
+            .collect();
+        descendants.sort_by_key(|(ancestors, _)| std::cmp::Reverse(*ancestors));
+        descendants
+            .into_iter()
+            .filter_map(|(_, txid)| self.remove_entry(&txid, reason))
+            .collect()
+    }
+
+    /// Removes a single transaction, leaving any children in place
+    fn remove_entry(&mut self, txid: &TxID, reason: RemovalReason) -> Option<MempoolEntry> {
+        let ancestors = self.ancestors(&self.entries.get(txid)?.parents);
+        let entry = self.entries.remove(txid)?;
+        self.by_wtxid.remove(entry.tx.wtxid());
+        for ancestor in ancestors.iter() {
+            let ancestor = self
+                .entries
+                .get_mut(ancestor)
+                .expect("Ancestors are in the mempool");
+            ancestor.descendant_count -= 1;
+            ancestor.descendant_size -= entry.size;
+            ancestor.descendant_fee -= entry.fee;
+        }
+        for parent in entry.parents.iter() {
+            if let Some(parent) = self.entries.get_mut(parent) {
+                parent.children.remove(txid);
+            }
+        }
+        for child in entry.children.iter() {
+            if let Some(child) = self.entries.get_mut(child) {
+                child.parents.remove(txid);
+            }
+        }
+        for input in entry.tx.inputs() {
+            self.spends.remove(input.previous_outpoint());
+        }
+        self.total_size -= entry.size;
+        self.record(|sequence| MempoolEvent::Removed {
+            txid: txid.clone(),
+            reason,
+            sequence,
+        });
+        Some(entry)
+    }
+}
+
+/// Whether `tx` could be included in the block at `height`. Like Core's `IsFinalTx`, but time locks are compared
+/// against `now` rather than the median time past.
+fn is_final(tx: &Transaction, height: u32, now: u64) -> bool {
+    let locktime = tx.locktime();
+    if locktime == 0 {
+        return true;
+    }
+    let unlocked = if locktime < LOCKTIME_THRESHOLD {
+        locktime < height
+    } else {
+        (locktime as u64) < now
+    };
+    unlocked
+        || tx
+            .inputs()
+            .iter()
+            .all(|input| input.sequence() == SEQUENCE_FINAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{u256, Deserializable, Serializable, TxInput, TxOutput};
+    use storage::{Coin, UtxoSet};
+
+    const NOW: u64 = 1_600_000_000;
+    const RBF: u32 = MAX_BIP125_RBF_SEQUENCE;
+
+    fn funding(n: u8) -> TxOutpoint {
+        TxOutpoint::new(u256::from_bytes([n; 32]), 0)
+    }
+
+    /// A chain at height 200 with 100,000 satoshi coins at `funding(1)` to `funding(4)`, and an immature
+    /// coinbase output at `funding(9)`.
+    fn chainstate() -> Chainstate {
+        let mut utxos = UtxoSet::new();
+        for n in 1..=4 {
+            utxos.insert(
+                funding(n),
+                Coin::new(TxOutput::new(100_000, script()), 1, false),
+            );
+        }
+        utxos.insert(
+            funding(9),
+            Coin::new(TxOutput::new(100_000, script()), 150, true),
+        );
+        Chainstate::new(utxos, shared::BlockHash::from([0; 32]), 200)
+    }
+
+    fn script() -> Vec<u8> {
+        let mut script = vec![0, 20];
+        script.extend_from_slice(&[9; 20]);
+        script
+    }
+
+    /// Builds a transaction with a lock time of zero and its txid filled in
+    fn tx(inputs: &[(TxOutpoint, u32)], outputs: &[i64]) -> Transaction {
+        let inputs = inputs
+            .iter()
+            .map(|(outpoint, sequence)| TxInput::new(outpoint.clone(), Vec::new(), *sequence))
+            .collect();
+        let outputs = outputs
+            .iter()
+            .map(|value| TxOutput::new(*value, script()))
+            .collect();
+        let mut raw = Vec::new();
+        Transaction::new(1, inputs, outputs)
+            .serialize(&mut raw)
+            .unwrap();
+        let len = raw.len();
+        raw[len - 4..].copy_from_slice(&[0; 4]);
+        Transaction::deserialize(&raw[..]).unwrap()
+    }
+
+    fn outpoint(tx: &Transaction, index: u32) -> TxOutpoint {
+        TxOutpoint::new(u256::from_bytes(*tx.txid().inner()), index)
+    }
+
+    #[test]
+    fn accepts_chains_of_transactions() {
+        let chainstate = chainstate();
+        let mut mempool = Mempool::default();
+        let parent = tx(&[(funding(1), SEQUENCE_FINAL)], &[90_000]);
+        let child = tx(&[(outpoint(&parent, 0), SEQUENCE_FINAL)], &[80_000]);
+        mempool.accept(parent.clone(), &chainstate, NOW).unwrap();
+        mempool.accept(child.clone(), &chainstate, NOW).unwrap();
+
+        let entry = mempool.get(parent.txid()).unwrap();
+        assert_eq!(entry.fee(), 10_000);
+        assert_eq!(entry.descendant_count(), 2);
+        assert_eq!(entry.descendant_fee(), 20_000);
+        assert_eq!(mempool.spender(&outpoint(&parent, 0)), Some(child.txid()));
+        assert_eq!(mempool.total_size(), parent.len() + child.len());
+
+        assert_eq!(
+            mempool.accept(child, &chainstate, NOW),
+            Err(MempoolError::AlreadyKnown)
+        );
+        let orphan = tx(&[(funding(7), SEQUENCE_FINAL)], &[90_000]);
+        assert_eq!(
+            mempool.accept(orphan, &chainstate, NOW),
+            Err(MempoolError::MissingInputs(vec![funding(7)]))
+        );
+        let immature = tx(&[(funding(9), SEQUENCE_FINAL)], &[90_000]);
+        assert!(matches!(
+            mempool.accept(immature, &chainstate, NOW),
+            Err(MempoolError::Invalid(_))
+        ));
+        let free = tx(&[(funding(2), SEQUENCE_FINAL)], &[100_000]);
+        assert!(matches!(
+            mempool.accept(free, &chainstate, NOW),
+            Err(MempoolError::InsufficientFee(_))
+        ));
+        let dust = tx(&[(funding(2), SEQUENCE_FINAL)], &[90_000, 100]);
+        assert!(matches!(
+            mempool.accept(dust, &chainstate, NOW),
+            Err(MempoolError::NonStandard(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_fees_above_the_maximum() {
+        let chainstate = chainstate();
+        let mut mempool = Mempool::default();
+        let generous = tx(&[(funding(1), SEQUENCE_FINAL)], &[50_000]);
+        let max_fee_rate = FeeRate::from_fee(40_000, generous.len());
+        assert!(matches!(
+            mempool.accept_with_max_fee_rate(
+                generous.clone(),
+                &chainstate,
+                NOW,
+                Some(max_fee_rate)
+            ),
+            Err(MempoolError::MaxFeeExceeded(_))
+        ));
+        assert!(mempool.get(generous.txid()).is_none());
+
+        let max_fee_rate = FeeRate::from_fee(60_000, generous.len());
+        mempool
+            .accept_with_max_fee_rate(generous.clone(), &chainstate, NOW, Some(max_fee_rate))
+            .unwrap();
+        assert_eq!(mempool.get(generous.txid()).unwrap().fee(), 50_000);
+    }
+
+    #[test]
+    fn enforces_package_limits() {
+        let chainstate = chainstate();
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_ancestors: 2,
+            ..MempoolLimits::default()
+        });
+        let parent = tx(&[(funding(1), SEQUENCE_FINAL)], &[90_000]);
+        let child = tx(&[(outpoint(&parent, 0), SEQUENCE_FINAL)], &[80_000]);
+        let grandchild = tx(&[(outpoint(&child, 0), SEQUENCE_FINAL)], &[70_000]);
+        mempool.accept(parent, &chainstate, NOW).unwrap();
+        mempool.accept(child, &chainstate, NOW).unwrap();
+        assert!(matches!(
+            mempool.accept(grandchild, &chainstate, NOW),
+            Err(MempoolError::TooLongChain(_))
+        ));
+    }
+
+    #[test]
+    fn replaces_signaling_transactions() {
+        let chainstate = chainstate();
+        let mut mempool = Mempool::default();
+        let final_original = tx(&[(funding(1), SEQUENCE_FINAL)], &[99_000]);
+        mempool
+            .accept(final_original.clone(), &chainstate, NOW)
+            .unwrap();
+        let replacement = tx(&[(funding(1), SEQUENCE_FINAL)], &[90_000]);
+        assert_eq!(
+            mempool.accept(replacement, &chainstate, NOW),
+            Err(MempoolError::Conflict(final_original.txid().clone()))
+        );
+
+        let original = tx(&[(funding(2), RBF)], &[99_000]);
+        let child = tx(&[(outpoint(&original, 0), SEQUENCE_FINAL)], &[98_000]);
+        mempool.accept(original.clone(), &chainstate, NOW).unwrap();
+        mempool.accept(child.clone(), &chainstate, NOW).unwrap();
+        // Must pay for both the original and its child, plus its own relay
+        let cheap = tx(&[(funding(2), RBF)], &[98_500]);
+        assert!(matches!(
+            mempool.accept(cheap, &chainstate, NOW),
+            Err(MempoolError::InsufficientFee(_))
+        ));
+        let replacement = tx(&[(funding(2), RBF)], &[95_000]);
+        let mut replaced = mempool
+            .accept(replacement.clone(), &chainstate, NOW)
+            .unwrap();
+        replaced.sort_by_key(|txid| *txid.inner());
+        let mut expected = vec![original.txid().clone(), child.txid().clone()];
+        expected.sort_by_key(|txid| *txid.inner());
+        assert_eq!(replaced, expected);
+        assert!(!mempool.contains(original.txid()) && !mempool.contains(child.txid()));
+        assert_eq!(mempool.spender(&funding(2)), Some(replacement.txid()));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn removes_confirmed_and_conflicting_transactions() {
+        let chainstate = chainstate();
+        let mut mempool = Mempool::default();
+        let parent = tx(&[(funding(1), SEQUENCE_FINAL)], &[90_000]);
+        let child = tx(&[(outpoint(&parent, 0), SEQUENCE_FINAL)], &[80_000]);
+        let conflicted = tx(&[(funding(2), SEQUENCE_FINAL)], &[90_000]);
+        let conflicted_child = tx(&[(outpoint(&conflicted, 0), SEQUENCE_FINAL)], &[80_000]);
+        for tx in [&parent, &child, &conflicted, &conflicted_child] {
+            mempool.accept((*tx).clone(), &chainstate, NOW).unwrap();
+        }
+
+        let double_spend = tx(&[(funding(2), SEQUENCE_FINAL)], &[95_000]);
+        let block = Block::new(
+            Block::_test_block().header().clone(),
+            vec![parent.clone(), double_spend],
+        );
+        let confirmed = mempool.remove_for_block(&block);
+        assert_eq!(confirmed.len(), 1);
+        assert_eq!(confirmed[0].txid(), parent.txid());
+        assert_eq!(mempool.len(), 1);
+        let entry = mempool.get(child.txid()).unwrap();
+        assert_eq!(entry.descendant_count(), 1);
+        assert!(entry.parents.is_empty());
+        assert_eq!(mempool.total_size(), child.len());
+        assert!(mempool.contains_wtxid(child.wtxid()));
+        assert!(!mempool.contains_wtxid(parent.wtxid()));
+        assert_eq!(mempool.by_wtxid.len(), 1);
+    }
+
+    #[test]
+    fn records_events_with_sequence_numbers() {
+        let chainstate = chainstate();
+        let mut mempool = Mempool::default();
+        let unrecorded = tx(&[(funding(3), SEQUENCE_FINAL)], &[90_000]);
+        mempool.accept(unrecorded, &chainstate, NOW).unwrap();
+        assert!(mempool.take_events().is_empty());
+        assert_eq!(mempool.sequence(), 2);
+
+        mempool.record_events();
+        let parent = tx(&[(funding(1), SEQUENCE_FINAL)], &[90_000]);
+        let child = tx(&[(outpoint(&parent, 0), SEQUENCE_FINAL)], &[80_000]);
+        let conflicted = tx(&[(funding(2), SEQUENCE_FINAL)], &[90_000]);
+        for tx in [&parent, &child, &conflicted] {
+            mempool.accept((*tx).clone(), &chainstate, NOW).unwrap();
+        }
+        let double_spend = tx(&[(funding(2), SEQUENCE_FINAL)], &[95_000]);
+        let block = Block::new(
+            Block::_test_block().header().clone(),
+            vec![parent.clone(), double_spend],
+        );
+        mempool.remove_for_block(&block);
+
+        let events: Vec<(TxID, Option<RemovalReason>, u64)> = mempool
+            .take_events()
+            .into_iter()
+            .map(|event| match event {
+                MempoolEvent::Added { tx, sequence } => (tx.txid().clone(), None, sequence),
+                MempoolEvent::Removed {
+                    txid,
+                    reason,
+                    sequence,
+                } => (txid, Some(reason), sequence),
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (parent.txid().clone(), None, 2),
+                (child.txid().clone(), None, 3),
+                (conflicted.txid().clone(), None, 4),
+                (parent.txid().clone(), Some(RemovalReason::Block), 5),
+                (conflicted.txid().clone(), Some(RemovalReason::Conflict), 6),
+            ]
+        );
+        assert!(mempool.take_events().is_empty());
+        assert_eq!(mempool.sequence(), 7);
+    }
+
+    #[test]
+    fn evicts_cheapest_packages_when_full() {
+        let chainstate = chainstate();
+        let size = tx(&[(funding(1), SEQUENCE_FINAL)], &[90_000]).len();
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_size: size * 2,
+            ..MempoolLimits::default()
+        });
+        let cheap = tx(&[(funding(1), SEQUENCE_FINAL)], &[99_000]);
+        let middle = tx(&[(funding(2), SEQUENCE_FINAL)], &[95_000]);
+        let expensive = tx(&[(funding(3), SEQUENCE_FINAL)], &[90_000]);
+        for tx in [&cheap, &middle, &expensive] {
+            mempool.accept((*tx).clone(), &chainstate, NOW).unwrap();
+        }
+        assert!(!mempool.contains(cheap.txid()));
+        assert_eq!(mempool.len(), 2);
+        // We shouldn't accept anything paying less than what we just evicted
+        let min_fee_rate = mempool.min_fee_rate(NOW);
+        assert!(min_fee_rate > FeeRate::from_fee(1000, size));
+        assert_eq!(
+            mempool.accept(cheap, &chainstate, NOW),
+            Err(MempoolError::InsufficientFee(format!(
+                "{} is below the minimum of {}",
+                FeeRate::from_fee(1000, size),
+                min_fee_rate
+            )))
+        );
+        // The minimum decays back to the relay fee
+        assert_eq!(
+            mempool.min_fee_rate(NOW + 30 * ROLLING_FEE_HALFLIFE),
+            mempool.limits().min_relay_fee_rate
+        );
+
+        let expired = mempool.expire(NOW + mempool.limits().expiry + 1);
+        assert_eq!(expired.len(), 2);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.total_size(), 0);
+    }
+}
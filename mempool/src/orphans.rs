@@ -0,0 +1,325 @@
+use crate::policy::MAX_STANDARD_TX_SIZE;
+use rand::Rng;
+use shared::{u256, Block, Transaction, TxID, TxOutpoint};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use tracing::debug;
+
+/// The default number of orphans to keep (Core's `DEFAULT_MAX_ORPHAN_TRANSACTIONS`)
+pub const DEFAULT_MAX_ORPHANS: usize = 100;
+/// Orphans whose parents haven't arrived within this many seconds are dropped
+pub const ORPHAN_EXPIRE_TIME: u64 = 20 * 60;
+
+#[derive(Debug)]
+struct Orphan {
+    tx: Transaction,
+    /// The peer which relayed the orphan, and should be able to give us its parents
+    from: SocketAddr,
+    expires: u64,
+}
+
+/// Transactions which spend outputs we haven't seen yet, held until their parents arrive.
+///
+/// Orphans are indexed by the outpoints they spend, so that when a parent is accepted we can find the
+/// orphans which might now be valid.
+#[derive(Debug)]
+pub struct OrphanPool {
+    max_orphans: usize,
+    orphans: HashMap<TxID, Orphan>,
+    by_outpoint: HashMap<TxOutpoint, HashSet<TxID>>,
+}
+
+impl Default for OrphanPool {
+    fn default() -> OrphanPool {
+        OrphanPool::new(DEFAULT_MAX_ORPHANS)
+    }
+}
+
+impl OrphanPool {
+    pub fn new(max_orphans: usize) -> OrphanPool {
+        OrphanPool {
+            max_orphans,
+            orphans: HashMap::new(),
+            by_outpoint: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+    pub fn contains(&self, txid: &TxID) -> bool {
+        self.orphans.contains_key(txid)
+    }
+    /// The peer which relayed an orphan
+    pub fn relayed_by(&self, txid: &TxID) -> Option<SocketAddr> {
+        self.orphans.get(txid).map(|orphan| orphan.from)
+    }
+
+    /// Stores an orphan relayed by `from`. Returns `false` if it was already stored, or is too large to be
+    /// worth keeping. If the pool is full, a random orphan is evicted to make room, so that an attacker can't
+    /// predict which honest orphans its own will push out.
+    pub fn add(&mut self, tx: Transaction, from: SocketAddr, now: u64) -> bool {
+        self.add_expiring(tx, from, now + ORPHAN_EXPIRE_TIME, now)
+    }
+
+    /// Like [`OrphanPool::add`], but the orphan expires at `expires`. An orphan which was taken out to be
+    /// reconsidered, and still has missing parents, is put back with its original expiry this way, so that a
+    /// chain of orphans can't stay in the pool indefinitely.
+    pub fn add_expiring(
+        &mut self,
+        tx: Transaction,
+        from: SocketAddr,
+        expires: u64,
+        now: u64,
+    ) -> bool {
+        let txid = tx.txid().clone();
+        // An attacker could otherwise fill our memory with large orphans which will never be valid
+        if self.orphans.contains_key(&txid)
+            || tx.vsize() > MAX_STANDARD_TX_SIZE
+            || self.max_orphans == 0
+        {
+            return false;
+        }
+        for input in tx.inputs() {
+            self.by_outpoint
+                .entry(input.previous_outpoint().clone())
+                .or_default()
+                .insert(txid.clone());
+        }
+        self.orphans
+            .insert(txid.clone(), Orphan { tx, from, expires });
+        self.expire(now);
+        if !self.orphans.contains_key(&txid) {
+            return false;
+        }
+        // The new orphan is never the one evicted, since we're about to ask for its parents
+        while self.orphans.len() > self.max_orphans {
+            let victim = self
+                .orphans
+                .keys()
+                .filter(|known| **known != txid)
+                .nth(rand::thread_rng().gen_range(0..self.orphans.len() - 1))
+                .cloned()
+                .expect("The pool holds other orphans");
+            self.remove(&victim);
+        }
+        true
+    }
+
+    /// When an orphan expires
+    pub fn expires(&self, txid: &TxID) -> Option<u64> {
+        self.orphans.get(txid).map(|orphan| orphan.expires)
+    }
+
+    pub fn remove(&mut self, txid: &TxID) -> Option<Transaction> {
+        let orphan = self.orphans.remove(txid)?;
+        for input in orphan.tx.inputs() {
+            let outpoint = input.previous_outpoint();
+            if let Some(spenders) = self.by_outpoint.get_mut(outpoint) {
+                spenders.remove(txid);
+                if spenders.is_empty() {
+                    self.by_outpoint.remove(outpoint);
+                }
+            }
+        }
+        Some(orphan.tx)
+    }
+
+    /// Drops every orphan relayed by a peer, typically because it disconnected. Returns the number dropped.
+    pub fn remove_for_peer(&mut self, peer: &SocketAddr) -> usize {
+        let txids: Vec<TxID> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.from == *peer)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        for txid in txids.iter() {
+            self.remove(txid);
+        }
+        if !txids.is_empty() {
+            debug!("Dropped {} orphans from peer {}", txids.len(), peer);
+        }
+        txids.len()
+    }
+
+    /// Drops orphans whose parents haven't arrived in time. Returns the number dropped.
+    pub fn expire(&mut self, now: u64) -> usize {
+        let expired: Vec<TxID> = self
+            .orphans
+            .iter()
+            .filter(|(_, orphan)| orphan.expires <= now)
+            .map(|(txid, _)| txid.clone())
+            .collect();
+        for txid in expired.iter() {
+            self.remove(txid);
+        }
+        expired.len()
+    }
+
+    /// Drops orphans which were confirmed by a block, or which conflict with it.
+    pub fn remove_for_block(&mut self, block: &Block) -> usize {
+        let mut removed = 0;
+        for tx in block.transactions() {
+            if self.remove(tx.txid()).is_some() {
+                removed += 1;
+            }
+            for input in tx.inputs() {
+                let conflicts: Vec<TxID> = self
+                    .by_outpoint
+                    .get(input.previous_outpoint())
+                    .map(|spenders| spenders.iter().cloned().collect())
+                    .unwrap_or_default();
+                for txid in conflicts.iter() {
+                    if self.remove(txid).is_some() {
+                        removed += 1;
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    /// The orphans which spend an output of `parent`, and should be reconsidered now that it has arrived.
+    pub fn children_of(&self, parent: &Transaction) -> Vec<TxID> {
+        let hash = u256::from_bytes(*parent.txid().inner());
+        let mut children = HashSet::new();
+        for index in 0..parent.outputs().len() {
+            if let Some(spenders) = self
+                .by_outpoint
+                .get(&TxOutpoint::new(hash.clone(), index as u32))
+            {
+                children.extend(spenders.iter().cloned());
+            }
+        }
+        children.into_iter().collect()
+    }
+}
+
+/// The distinct txids of the transactions which created `outpoints`, for requesting an orphan's missing parents.
+pub fn missing_parents(outpoints: &[TxOutpoint]) -> Vec<TxID> {
+    let mut seen = HashSet::new();
+    outpoints
+        .iter()
+        .map(|outpoint| TxID::from(*outpoint.hash().to_le_bytes()))
+        .filter(|txid| seen.insert(txid.clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{Deserializable, Serializable, TxInput, TxOutput};
+
+    const NOW: u64 = 1_600_000_000;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn tx(inputs: &[TxOutpoint], value: i64) -> Transaction {
+        let inputs = inputs
+            .iter()
+            .map(|outpoint| TxInput::new(outpoint.clone(), Vec::new(), 0xffffffff))
+            .collect();
+        let mut raw = Vec::new();
+        Transaction::new(1, inputs, vec![TxOutput::new(value, vec![0x51])])
+            .serialize(&mut raw)
+            .unwrap();
+        Transaction::deserialize(&raw[..]).unwrap()
+    }
+
+    fn outpoint(tx: &Transaction, index: u32) -> TxOutpoint {
+        TxOutpoint::new(u256::from_bytes(*tx.txid().inner()), index)
+    }
+
+    #[test]
+    fn finds_orphans_when_parents_arrive() {
+        let mut orphans = OrphanPool::default();
+        let parent = tx(&[TxOutpoint::new(u256::from_bytes([1; 32]), 0)], 1000);
+        let child = tx(&[outpoint(&parent, 0)], 900);
+        assert!(orphans.add(child.clone(), peer(1), NOW));
+        assert!(!orphans.add(child.clone(), peer(2), NOW));
+        assert_eq!(orphans.relayed_by(child.txid()), Some(peer(1)));
+        assert_eq!(orphans.children_of(&parent), vec![child.txid().clone()]);
+        assert_eq!(
+            missing_parents(&[outpoint(&parent, 0), outpoint(&parent, 1)]),
+            vec![parent.txid().clone()]
+        );
+
+        assert_eq!(orphans.remove(child.txid()).unwrap().txid(), child.txid());
+        assert!(orphans.children_of(&parent).is_empty());
+        assert!(orphans.is_empty());
+    }
+
+    #[test]
+    fn evicts_random_orphans_when_full() {
+        let first = tx(&[TxOutpoint::new(u256::from_bytes([1; 32]), 0)], 1000);
+        let second = tx(&[TxOutpoint::new(u256::from_bytes([2; 32]), 0)], 1000);
+        let third = tx(&[TxOutpoint::new(u256::from_bytes([3; 32]), 0)], 1000);
+        let mut evicted = HashSet::new();
+        for _ in 0..100 {
+            let mut orphans = OrphanPool::new(2);
+            orphans.add(first.clone(), peer(1), NOW);
+            orphans.add(second.clone(), peer(2), NOW + 1);
+            orphans.add(third.clone(), peer(1), NOW + 2);
+            assert_eq!(orphans.len(), 2);
+            let victim = [&first, &second, &third]
+                .iter()
+                .find(|tx| !orphans.contains(tx.txid()))
+                .map(|tx| tx.txid().clone())
+                .unwrap();
+            // The index stays consistent with what's left
+            assert!(orphans
+                .by_outpoint
+                .values()
+                .all(|txids| !txids.contains(&victim)));
+            evicted.insert(victim);
+        }
+        // Any orphan may be evicted, not just the oldest, but never the one being added
+        assert_eq!(evicted.len(), 2);
+        assert!(!evicted.contains(third.txid()));
+
+        assert!(!OrphanPool::new(0).add(first, peer(1), NOW));
+    }
+
+    #[test]
+    fn keeps_the_original_expiry_when_put_back() {
+        let mut orphans = OrphanPool::default();
+        let orphan = tx(&[TxOutpoint::new(u256::from_bytes([1; 32]), 0)], 1000);
+        orphans.add(orphan.clone(), peer(1), NOW);
+        let expires = orphans.expires(orphan.txid()).unwrap();
+        assert_eq!(expires, NOW + ORPHAN_EXPIRE_TIME);
+
+        let orphan = orphans.remove(orphan.txid()).unwrap();
+        assert!(orphans.add_expiring(orphan.clone(), peer(1), expires, NOW + 60));
+        assert_eq!(orphans.expires(orphan.txid()), Some(expires));
+        assert_eq!(orphans.expire(expires), 1);
+        // An orphan which has already expired isn't kept
+        assert!(!orphans.add_expiring(orphan, peer(1), expires, expires));
+        assert!(orphans.is_empty());
+    }
+
+    #[test]
+    fn evicts_by_age_peer_and_block() {
+        let mut orphans = OrphanPool::new(2);
+        let first = tx(&[TxOutpoint::new(u256::from_bytes([1; 32]), 0)], 1000);
+        let second = tx(&[TxOutpoint::new(u256::from_bytes([2; 32]), 0)], 1000);
+        let third = tx(&[TxOutpoint::new(u256::from_bytes([3; 32]), 0)], 1000);
+        orphans.add(second.clone(), peer(2), NOW + 1);
+        orphans.add(third.clone(), peer(1), NOW + 2);
+
+        assert_eq!(orphans.remove_for_peer(&peer(1)), 1);
+        assert!(orphans.contains(second.txid()));
+        assert_eq!(orphans.expire(NOW + 1 + ORPHAN_EXPIRE_TIME), 1);
+        assert!(orphans.is_empty());
+
+        orphans.add(first.clone(), peer(1), NOW);
+        let conflict = tx(&[TxOutpoint::new(u256::from_bytes([1; 32]), 0)], 500);
+        let block = Block::new(Block::_test_block().header().clone(), vec![conflict]);
+        assert_eq!(orphans.remove_for_block(&block), 1);
+        assert!(orphans.is_empty());
+    }
+}
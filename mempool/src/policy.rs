@@ -0,0 +1,227 @@
+//! Standardness rules. These aren't consensus rules, but like Bitcoin Core we refuse to relay transactions which
+//! break them, since they're either wasteful or reserved for future soft forks.
+use crate::{FeeRate, MempoolError};
+use shared::{Transaction, TxOutput};
+
+/// The largest transaction we will relay, in virtual bytes (Core's `MAX_STANDARD_TX_WEIGHT` / 4)
+pub const MAX_STANDARD_TX_SIZE: usize = 100_000;
+/// The largest scriptSig we will relay, which is enough for a 15-of-15 P2SH multisig
+pub const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+/// The largest `OP_RETURN` output script we will relay
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+/// Outputs worth less than it would cost to spend them at this rate are dust (Core's `DUST_RELAY_TX_FEE`)
+pub const DUST_RELAY_FEE_RATE: FeeRate = FeeRate::from_sat_per_kvb(3000);
+
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_RETURN: u8 = 0x6a;
+const OP_DUP: u8 = 0x76;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_HASH160: u8 = 0xa9;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKMULTISIG: u8 = 0xae;
+
+/// The standard output script templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    Multisig,
+    NullData,
+    WitnessV0KeyHash,
+    WitnessV0ScriptHash,
+    Taproot,
+    /// A witness program of a version reserved for future upgrades
+    WitnessUnknown,
+}
+
+/// Identifies which standard template `script` matches, if any.
+pub fn classify(script: &[u8]) -> Option<ScriptType> {
+    match script {
+        [OP_DUP, OP_HASH160, 20, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => {
+            Some(ScriptType::PubKeyHash)
+        }
+        [OP_HASH160, 20, hash @ .., OP_EQUAL] if hash.len() == 20 => Some(ScriptType::ScriptHash),
+        [33, key @ .., OP_CHECKSIG] if key.len() == 33 => Some(ScriptType::PubKey),
+        [65, key @ .., OP_CHECKSIG] if key.len() == 65 => Some(ScriptType::PubKey),
+        [OP_RETURN, rest @ ..] if is_push_only(rest) && script.len() <= MAX_OP_RETURN_RELAY => {
+            Some(ScriptType::NullData)
+        }
+        [OP_0, 20, program @ ..] if program.len() == 20 => Some(ScriptType::WitnessV0KeyHash),
+        [OP_0, 32, program @ ..] if program.len() == 32 => Some(ScriptType::WitnessV0ScriptHash),
+        [OP_1, 32, program @ ..] if program.len() == 32 => Some(ScriptType::Taproot),
+        [version, len, program @ ..]
+            if (OP_1..=OP_16).contains(version)
+                && (2..=40).contains(len)
+                && program.len() == *len as usize =>
+        {
+            Some(ScriptType::WitnessUnknown)
+        }
+        _ if is_standard_multisig(script) => Some(ScriptType::Multisig),
+        _ => None,
+    }
+}
+
+/// Bare multisig is only standard for up to 3 keys
+fn is_standard_multisig(script: &[u8]) -> bool {
+    let (required, keys, total) = match script {
+        [required, keys @ .., total, OP_CHECKMULTISIG]
+            if (OP_1..=OP_16).contains(required) && (OP_1..=OP_16).contains(total) =>
+        {
+            (required - OP_1 + 1, keys, total - OP_1 + 1)
+        }
+        _ => return false,
+    };
+    let mut count = 0;
+    let mut rest = keys;
+    while let [len, tail @ ..] = rest {
+        if (*len != 33 && *len != 65) || tail.len() < *len as usize {
+            return false;
+        }
+        rest = &tail[*len as usize..];
+        count += 1;
+    }
+    count == total && required <= total && total <= 3
+}
+
+/// Whether `script` consists only of data pushes, as required of scriptSigs.
+pub fn is_push_only(script: &[u8]) -> bool {
+    let mut rest = script;
+    while let [opcode, tail @ ..] = rest {
+        let (len, tail) = match *opcode {
+            op if op < OP_PUSHDATA1 => (op as usize, tail),
+            OP_PUSHDATA1 if !tail.is_empty() => (tail[0] as usize, &tail[1..]),
+            OP_PUSHDATA2 if tail.len() >= 2 => {
+                (u16::from_le_bytes([tail[0], tail[1]]) as usize, &tail[2..])
+            }
+            OP_PUSHDATA4 if tail.len() >= 4 => (
+                u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) as usize,
+                &tail[4..],
+            ),
+            // OP_1NEGATE, OP_RESERVED and OP_1 to OP_16 push without a payload
+            op if op > OP_PUSHDATA4 && op <= OP_16 => (0, tail),
+            _ => return false,
+        };
+        if tail.len() < len {
+            return false;
+        }
+        rest = &tail[len..];
+    }
+    true
+}
+
+/// Whether an output is worth less than the fee needed to spend it at [`DUST_RELAY_FEE_RATE`].
+pub fn is_dust(output: &TxOutput) -> bool {
+    let script = output.pk_script();
+    if script.first() == Some(&OP_RETURN) {
+        return false;
+    }
+    // The size of an input spending this output: outpoint, sequence and a typical scriptSig (or its witness
+    // equivalent, which is discounted)
+    let is_witness = matches!(
+        classify(script),
+        Some(ScriptType::WitnessV0KeyHash)
+            | Some(ScriptType::WitnessV0ScriptHash)
+            | Some(ScriptType::Taproot)
+            | Some(ScriptType::WitnessUnknown)
+    );
+    let spend_size = if is_witness {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    output.value() < DUST_RELAY_FEE_RATE.fee_for(output.len() + spend_size)
+}
+
+/// Checks the rules Bitcoin Core applies in `IsStandardTx`.
+pub fn check_standard(tx: &Transaction) -> Result<(), MempoolError> {
+    if tx.version() < 1 || tx.version() > 2 {
+        return Err(MempoolError::NonStandard(format!(
+            "version {}",
+            tx.version()
+        )));
+    }
+    if tx.vsize() > MAX_STANDARD_TX_SIZE {
+        return Err(MempoolError::NonStandard(format!(
+            "{} vbytes is too large",
+            tx.vsize()
+        )));
+    }
+    for input in tx.inputs() {
+        if input.signature_script().len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            return Err(MempoolError::NonStandard(String::from(
+                "scriptsig too large",
+            )));
+        }
+        if !is_push_only(input.signature_script()) {
+            return Err(MempoolError::NonStandard(String::from(
+                "scriptsig not push-only",
+            )));
+        }
+    }
+    let mut null_data_outputs = 0;
+    for output in tx.outputs() {
+        match classify(output.pk_script()) {
+            None => return Err(MempoolError::NonStandard(String::from("scriptpubkey"))),
+            Some(ScriptType::NullData) => null_data_outputs += 1,
+            Some(_) if is_dust(output) => {
+                return Err(MempoolError::NonStandard(String::from("dust")))
+            }
+            Some(_) => {}
+        }
+    }
+    if null_data_outputs > 1 {
+        return Err(MempoolError::NonStandard(String::from("multi-op-return")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p2pkh() -> Vec<u8> {
+        let mut script = vec![OP_DUP, OP_HASH160, 20];
+        script.extend_from_slice(&[7; 20]);
+        script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
+        script
+    }
+
+    #[test]
+    fn classifies_standard_scripts() {
+        assert_eq!(classify(&p2pkh()), Some(ScriptType::PubKeyHash));
+        let mut p2wsh = vec![OP_0, 32];
+        p2wsh.extend_from_slice(&[1; 32]);
+        assert_eq!(classify(&p2wsh), Some(ScriptType::WitnessV0ScriptHash));
+        let mut multisig = vec![OP_1 + 1];
+        for _ in 0..3 {
+            multisig.push(33);
+            multisig.extend_from_slice(&[2; 33]);
+        }
+        multisig.extend_from_slice(&[OP_1 + 2, OP_CHECKMULTISIG]);
+        assert_eq!(classify(&multisig), Some(ScriptType::Multisig));
+        assert_eq!(
+            classify(&[OP_RETURN, 3, 1, 2, 3]),
+            Some(ScriptType::NullData)
+        );
+        assert_eq!(classify(&[OP_RETURN, 3, 1, 2]), None);
+        assert_eq!(classify(&[OP_1]), None);
+        // Too short to hold any keys, which used to panic while slicing them out
+        assert_eq!(classify(&[OP_1, OP_CHECKMULTISIG]), None);
+        assert_eq!(classify(&[OP_CHECKMULTISIG]), None);
+    }
+
+    #[test]
+    fn dust_threshold_matches_core() {
+        // Core's dust threshold for P2PKH at the default dust relay fee is 546 satoshis
+        assert!(is_dust(&TxOutput::new(545, p2pkh())));
+        assert!(!is_dust(&TxOutput::new(546, p2pkh())));
+        assert!(!is_dust(&TxOutput::new(0, vec![OP_RETURN])));
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
@@ -6,24 +7,41 @@ use std::{
 
 use futures::Stream;
 
+use crate::PeerAddress;
+
+/// The addresses the crawler may connect to, tried in the order they were added
 pub struct AddressBook {
     candidate_book: CandidateBook,
 }
 struct CandidateBook {
-    candidates: Vec<SocketAddr>,
+    /// May include onion addresses, which are only reachable through a proxy
+    candidates: VecDeque<PeerAddress>,
 }
 
 impl std::iter::Iterator for CandidateBook {
-    type Item = SocketAddr;
+    type Item = PeerAddress;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.candidates.pop()
+        self.candidates.pop_front()
     }
 }
 impl AddressBook {
-    pub fn next_candidate(&mut self) -> Option<SocketAddr> {
+    pub fn new(candidates: Vec<PeerAddress>) -> AddressBook {
+        AddressBook {
+            candidate_book: CandidateBook {
+                candidates: candidates.into(),
+            },
+        }
+    }
+
+    pub fn next_candidate(&mut self) -> Option<PeerAddress> {
         self.candidate_book.next()
     }
+
+    /// Adds candidates to be tried after those already in the book
+    pub fn add_candidates<I: IntoIterator<Item = PeerAddress>>(&mut self, candidates: I) {
+        self.candidate_book.candidates.extend(candidates);
+    }
 }
 
 // impl Stream for CandidateBook {
@@ -0,0 +1,272 @@
+//! Banned and discouraged addresses.
+//!
+//! Bans are set by the operator, cover whole subnets, and persist across restarts. Discouragement is the automatic
+//! response to a peer reaching the misbehavior threshold: it only covers a single address, and is forgotten on
+//! restart, so that an attacker can't get honest nodes discouraged permanently (for example by sharing their IP).
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// The file in the data directory which holds the ban list
+pub const BAN_LIST_FILE: &str = "banlist.dat";
+/// How long a ban or discouragement lasts if no time is given, in seconds (Core's `DEFAULT_MISBEHAVING_BANTIME`)
+pub const DEFAULT_BAN_TIME: u64 = 24 * 60 * 60;
+/// The longest ban that can be set, in seconds. Keeps expiry times far from overflowing.
+pub const MAX_BAN_TIME: u64 = 100 * 365 * 24 * 60 * 60;
+
+const BAN_LIST_VERSION: u32 = 1;
+
+/// A range of IP addresses sharing a prefix, like `192.168.0.0/16`. A single address is a subnet of full length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// Creates a subnet, masking off any bits of `address` beyond the prefix. Returns `None` if the prefix is
+    /// longer than the address.
+    pub fn new(address: IpAddr, prefix_len: u8) -> Option<Subnet> {
+        let network = match address {
+            IpAddr::V4(ip) if prefix_len <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) if prefix_len <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+            _ => return None,
+        };
+        Some(Subnet {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// The subnet containing only `address`
+    pub fn single(address: IpAddr) -> Subnet {
+        let prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        Subnet::new(address, prefix_len).expect("Full length prefixes are valid")
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        // IPv4 peers may show up as IPv4-mapped IPv6 addresses
+        let address = match (self.network, address) {
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => IpAddr::V4(ip),
+                None => return false,
+            },
+            _ => *address,
+        };
+        Subnet::new(address, self.prefix_len) == Some(*self)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    /// Parses `address/prefix_len`, or a bare address
+    fn from_str(s: &str) -> Result<Subnet, String> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address =
+            IpAddr::from_str(address).map_err(|e| format!("Invalid address {}: {}", address, e))?;
+        match prefix_len {
+            None => Ok(Subnet::single(address)),
+            Some(prefix_len) => prefix_len
+                .parse()
+                .ok()
+                .and_then(|prefix_len| Subnet::new(address, prefix_len))
+                .ok_or_else(|| format!("Invalid prefix length {}", prefix_len)),
+        }
+    }
+}
+
+/// The subnets we refuse to connect to, or accept connections from, with the unix time each ban expires.
+#[derive(Debug, Default)]
+pub struct BanList {
+    banned: HashMap<Subnet, u64>,
+    discouraged: HashMap<IpAddr, u64>,
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    /// Bans a subnet until `until`. An existing ban is only ever extended.
+    pub fn ban(&mut self, subnet: Subnet, until: u64) {
+        let expiry = self.banned.entry(subnet).or_insert(until);
+        *expiry = (*expiry).max(until);
+    }
+
+    /// Lifts a ban, returning `false` if the subnet wasn't banned.
+    pub fn unban(&mut self, subnet: &Subnet) -> bool {
+        self.banned.remove(subnet).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.banned.clear();
+    }
+
+    /// Discourages a misbehaving peer's address for [`DEFAULT_BAN_TIME`].
+    pub fn discourage(&mut self, address: IpAddr, now: u64) {
+        self.discouraged.insert(address, now + DEFAULT_BAN_TIME);
+    }
+
+    /// Whether `address` is covered by a ban which hasn't expired
+    pub fn is_banned(&self, address: &IpAddr, now: u64) -> bool {
+        self.banned
+            .iter()
+            .any(|(subnet, until)| *until > now && subnet.contains(address))
+    }
+
+    pub fn is_discouraged(&self, address: &IpAddr, now: u64) -> bool {
+        self.discouraged
+            .get(address)
+            .is_some_and(|until| *until > now)
+    }
+
+    /// Whether we should refuse to connect to, or accept a connection from, `address`
+    pub fn should_refuse(&self, address: &IpAddr, now: u64) -> bool {
+        self.is_banned(address, now) || self.is_discouraged(address, now)
+    }
+
+    /// The current bans, sorted by subnet
+    pub fn banned(&self) -> Vec<(Subnet, u64)> {
+        let mut banned: Vec<(Subnet, u64)> = self
+            .banned
+            .iter()
+            .map(|(subnet, until)| (*subnet, *until))
+            .collect();
+        banned.sort();
+        banned
+    }
+
+    /// Forgets bans and discouragements which have expired. Returns `true` if any bans were removed.
+    pub fn sweep(&mut self, now: u64) -> bool {
+        let before = self.banned.len();
+        self.banned.retain(|_, until| *until > now);
+        self.discouraged.retain(|_, until| *until > now);
+        self.banned.len() != before
+    }
+
+    /// Writes the bans (but not discouragements) in a versioned binary format.
+    pub fn write<W: Write>(&self, mut target: W) -> io::Result<()> {
+        target.write_u32::<LittleEndian>(BAN_LIST_VERSION)?;
+        target.write_u32::<LittleEndian>(self.banned.len() as u32)?;
+        for (subnet, until) in self.banned() {
+            match subnet.network {
+                IpAddr::V4(ip) => {
+                    target.write_u8(4)?;
+                    target.write_all(&ip.octets())?;
+                }
+                IpAddr::V6(ip) => {
+                    target.write_u8(6)?;
+                    target.write_all(&ip.octets())?;
+                }
+            }
+            target.write_u8(subnet.prefix_len)?;
+            target.write_u64::<LittleEndian>(until)?;
+        }
+        target.flush()
+    }
+
+    /// Reads a ban list written by [`BanList::write`].
+    pub fn read<R: Read>(mut source: R) -> io::Result<BanList> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let version = source.read_u32::<LittleEndian>()?;
+        if version != BAN_LIST_VERSION {
+            return Err(invalid(format!("Unsupported ban list version {}", version)));
+        }
+        let count = source.read_u32::<LittleEndian>()?;
+        let mut list = BanList::new();
+        for _ in 0..count {
+            let address = match source.read_u8()? {
+                4 => {
+                    let mut octets = [0u8; 4];
+                    source.read_exact(&mut octets)?;
+                    IpAddr::from(octets)
+                }
+                6 => {
+                    let mut octets = [0u8; 16];
+                    source.read_exact(&mut octets)?;
+                    IpAddr::from(octets)
+                }
+                family => return Err(invalid(format!("Unknown address family {}", family))),
+            };
+            let prefix_len = source.read_u8()?;
+            let until = source.read_u64::<LittleEndian>()?;
+            let subnet = Subnet::new(address, prefix_len)
+                .ok_or_else(|| invalid(format!("Invalid subnet {}/{}", address, prefix_len)))?;
+            list.ban(subnet, until);
+        }
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_600_000_000;
+
+    #[test]
+    fn parses_and_matches_subnets() {
+        let subnet: Subnet = "192.168.7.9/16".parse().unwrap();
+        assert_eq!(subnet.to_string(), "192.168.0.0/16");
+        assert!(subnet.contains(&"192.168.255.1".parse().unwrap()));
+        assert!(subnet.contains(&"::ffff:192.168.1.1".parse().unwrap()));
+        assert!(!subnet.contains(&"192.169.0.1".parse().unwrap()));
+        let single: Subnet = "2001:db8::1".parse().unwrap();
+        assert_eq!(single.prefix_len(), 128);
+        assert!(!single.contains(&"2001:db8::2".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+        assert!("0.0.0.0/0"
+            .parse::<Subnet>()
+            .unwrap()
+            .contains(&"8.8.8.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn bans_expire_and_persist() {
+        let mut bans = BanList::new();
+        let address: IpAddr = "10.1.2.3".parse().unwrap();
+        bans.ban("10.1.0.0/16".parse().unwrap(), NOW + 10);
+        bans.ban("2001:db8::/32".parse().unwrap(), NOW + 20);
+        bans.discourage("10.9.9.9".parse().unwrap(), NOW);
+        assert!(bans.is_banned(&address, NOW));
+        assert!(bans.should_refuse(&"10.9.9.9".parse().unwrap(), NOW));
+
+        let mut saved = Vec::new();
+        bans.write(&mut saved).unwrap();
+        let mut restored = BanList::read(&saved[..]).unwrap();
+        assert_eq!(restored.banned(), bans.banned());
+        // Discouragement doesn't survive a restart
+        assert!(!restored.should_refuse(&"10.9.9.9".parse().unwrap(), NOW));
+
+        assert!(restored.sweep(NOW + 10));
+        assert!(!restored.is_banned(&address, NOW + 10));
+        assert!(restored.unban(&"2001:db8::/32".parse().unwrap()));
+        assert!(restored.banned().is_empty());
+    }
+}
@@ -17,6 +17,10 @@ use shared::Serializable;
 use shared::Transaction;
 use shared::{BlockHeader, CompactInt, Deserializable, DeserializationError, InventoryData};
 use tracing::{self, debug, trace};
+
+/// The parse error returned when a message's payload doesn't match its checksum
+pub(crate) const BAD_CHECKSUM: &str = "Message payload does not match its checksum";
+
 /// A [Codec](https://tokio-rs.github.io/tokio/doc/tokio_util/codec/index.html) converting a raw TcpStream into a Sink + Stream of Bitcoin Wire Protocol [`Message`s](crate::Message).
 ///
 /// This struct handles the serialization and sending of [`Message`s](crate::Message). Callers simply construct a [Framed](https://tokio-rs.github.io/tokio/doc/tokio_util/codec/struct.Framed.html)
@@ -62,7 +66,7 @@ impl tokio_util::codec::Encoder<Message> for Codec {
         [0u8; 4].serialize(&mut target)?;
 
         // Serialize the message body. Some messages need non-default serialization, so use a custom Codec method
-        self.serialize_body(&item, &mut target)?;
+        Codec::serialize_body(&item, &mut target)?;
 
         // Fill in the checksum
         let checksum = warp_crypto::sha256d(&dst[start_payload..(start_payload + payload_size)]);
@@ -104,6 +108,10 @@ impl tokio_util::codec::Decoder for Codec {
                 }
 
                 let mut reader = src.split_to(header.get_payload_size());
+                if warp_crypto::sha256d(&reader[..])[0..4] != header.get_checksum()[..] {
+                    self.set_decoder_state(DecoderState::Header);
+                    return Err(DeserializationError::Parse(String::from(BAD_CHECKSUM)));
+                }
 
                 let contents = self.deserialize(&mut reader)?;
                 Ok(Some(contents))
@@ -143,7 +151,7 @@ impl Codec {
         self.state = state;
     }
     /// Returns the size of message (excluding the header) after serialization
-    fn get_serialized_size(msg: &Message) -> usize {
+    pub(crate) fn get_serialized_size(msg: &Message) -> usize {
         match msg {
             Message::Addr(ref addrs) => CompactInt::size(addrs.len()) + addrs.len() * (4 + 8 + 18),
             Message::BlockTxn(block_txn) => block_txn.serialized_size(),
@@ -195,11 +203,12 @@ impl Codec {
             Message::Tx(transaction) => transaction.len(),
             Message::Verack => 0,
             Message::Version(version) => version.serialized_size(),
+            Message::WtxidRelay => 0,
+            Message::Unknown { payload, .. } => payload.len(),
         }
     }
 
-    fn serialize_body<W: std::io::Write>(
-        &self,
+    pub(crate) fn serialize_body<W: std::io::Write>(
         item: &Message,
         target: &mut W,
     ) -> Result<(), std::io::Error> {
@@ -213,97 +222,103 @@ impl Codec {
                     target.write_u8(0)?;
                 }
             }
+            // The command goes in the header, so only the payload is written
+            Message::Unknown { payload, .. } => payload.serialize(target)?,
             _ => item.serialize(target)?,
         }
         Ok(())
     }
 
-    fn deserialize(&mut self, mut src: &mut BytesMut) -> Result<Message, DeserializationError> {
-        match self.state {
+    fn deserialize(&mut self, src: &mut BytesMut) -> Result<Message, DeserializationError> {
+        let command = match self.state {
             DecoderState::Header => {
                 unreachable!(
                     "Should never try to decode message body while in 'Header' decoder state"
                 );
             }
-            DecoderState::Body { ref header } => {
-                let msg = match header.get_command() {
-                    crate::Command::Addr => {
-                        Message::Addr(Vec::<EncapsulatedAddr>::deserialize(&mut src)?)
-                    }
-                    crate::Command::Version => Message::Version(Version::deserialize(&mut src)?),
-                    crate::Command::Verack => Message::Verack,
-                    crate::Command::GetBlocks => {
-                        Message::GetBlocks(GetBlocks::deserialize(&mut src)?)
-                    }
-                    crate::Command::GetData => {
-                        Message::GetData(<Vec<InventoryData>>::deserialize(&mut src)?)
-                    }
-                    crate::Command::Block => Message::Block(shared::Block::deserialize(&mut src)?),
-                    crate::Command::GetHeaders => {
-                        Message::GetHeaders(GetHeaders::deserialize(&mut src)?)
-                    }
-                    crate::Command::Headers => {
-                        // Custom deserialization necessary to account for extra
-                        // Transaction count field. Note that transaction count is always zero in a headers message.
-                        let count = CompactInt::deserialize(&mut src)?;
-                        let mut result = Vec::with_capacity(count.value() as usize);
-                        for _ in 0..result.len() {
-                            result.push(BlockHeader::deserialize(&mut src)?);
-                            let _ = u8::deserialize(&mut src)?;
-                        }
-                        Message::Headers(result)
-                    }
-                    crate::Command::Inv => {
-                        Message::Inv(<Vec<InventoryData>>::deserialize(&mut src)?)
-                    }
-                    crate::Command::MemPool => Message::MemPool,
-                    crate::Command::MerkleBlock => {
-                        Message::MerkleBlock(MerkleBlock::deserialize(&mut src)?)
-                    }
-                    crate::Command::CmpctBlock => {
-                        Message::CompactBlock(CompactBlock::deserialize(&mut src)?)
-                    }
-                    crate::Command::GetBlockTxn => {
-                        Message::GetBlockTxn(GetBlockTxn::deserialize(&mut src)?)
-                    }
-                    crate::Command::BlockTxn => Message::BlockTxn(BlockTxn::deserialize(&mut src)?),
-                    crate::Command::SendCmpct => {
-                        Message::SendCompact(SendCompact::deserialize(&mut src)?)
-                    }
-                    crate::Command::NotFound => {
-                        Message::NotFound(<Vec<InventoryData>>::deserialize(&mut src)?)
-                    }
-                    crate::Command::Tx => Message::Tx(Transaction::deserialize(&mut src)?),
-                    crate::Command::Alert => {
-                        // TODO: Verify that no additional cleanup is required.
-                        self.set_decoder_state(DecoderState::Header);
-                        return Err(DeserializationError::Parse(format!(
-                            "Received Alert message! Alert is insecure and deprecated"
-                        )));
-                    }
-                    crate::Command::FeeFilter => Message::FeeFilter(u64::deserialize(&mut src)?),
-                    crate::Command::FilterAdd => {
-                        Message::FilterAdd(<Vec<Vec<u8>>>::deserialize(&mut src)?)
-                    }
-                    crate::Command::FilterClear => Message::FilterClear,
-                    crate::Command::FilterLoad => {
-                        Message::FilterLoad(FilterLoad::deserialize(&mut src)?)
-                    }
-                    crate::Command::GetAddr => Message::GetAddr,
-                    crate::Command::Ping => Message::Ping(Nonce::deserialize(&mut src)?),
-                    crate::Command::Pong => Message::Pong(Nonce::deserialize(&mut src)?),
-                    crate::Command::Reject => Message::Reject(Reject::deserialize(&mut src)?),
-                    crate::Command::SendHeaders => Message::SendHeaders,
-                };
+            DecoderState::Body { ref header } => header.get_command(),
+        };
+        self.set_decoder_state(DecoderState::Header);
+        Codec::deserialize_payload(command, src)
+    }
 
-                trace!("Received {:?}", msg);
-                if src.remaining() != 0 {
-                    debug!("Had leftover bytes after decoding message. Weird.",);
+    /// Decodes the payload of a `command` message, shared with the v2 transport which frames messages differently
+    pub(crate) fn deserialize_payload(
+        command: crate::Command,
+        mut src: &mut BytesMut,
+    ) -> Result<Message, DeserializationError> {
+        let msg = match command {
+            crate::Command::Addr => Message::Addr(Vec::<EncapsulatedAddr>::deserialize(&mut src)?),
+            crate::Command::Version => Message::Version(Version::deserialize(&mut src)?),
+            crate::Command::Verack => Message::Verack,
+            crate::Command::GetBlocks => Message::GetBlocks(GetBlocks::deserialize(&mut src)?),
+            crate::Command::GetData => {
+                Message::GetData(<Vec<InventoryData>>::deserialize(&mut src)?)
+            }
+            crate::Command::Block => Message::Block(shared::Block::deserialize(&mut src)?),
+            crate::Command::GetHeaders => Message::GetHeaders(GetHeaders::deserialize(&mut src)?),
+            crate::Command::Headers => {
+                // Custom deserialization necessary to account for extra
+                // Transaction count field. Note that transaction count is always zero in a headers message.
+                let count = CompactInt::deserialize(&mut src)?;
+                let mut result = Vec::new();
+                for _ in 0..count.value() {
+                    result.push(BlockHeader::deserialize(&mut src)?);
+                    let _ = u8::deserialize(&mut src)?;
+                }
+                Message::Headers(result)
+            }
+            crate::Command::Inv => Message::Inv(<Vec<InventoryData>>::deserialize(&mut src)?),
+            crate::Command::MemPool => Message::MemPool,
+            crate::Command::MerkleBlock => {
+                Message::MerkleBlock(MerkleBlock::deserialize(&mut src)?)
+            }
+            crate::Command::CmpctBlock => {
+                Message::CompactBlock(CompactBlock::deserialize(&mut src)?)
+            }
+            crate::Command::GetBlockTxn => {
+                Message::GetBlockTxn(GetBlockTxn::deserialize(&mut src)?)
+            }
+            crate::Command::BlockTxn => Message::BlockTxn(BlockTxn::deserialize(&mut src)?),
+            crate::Command::SendCmpct => Message::SendCompact(SendCompact::deserialize(&mut src)?),
+            crate::Command::NotFound => {
+                Message::NotFound(<Vec<InventoryData>>::deserialize(&mut src)?)
+            }
+            crate::Command::Tx => Message::Tx(Transaction::deserialize(&mut src)?),
+            crate::Command::Alert => {
+                return Err(DeserializationError::Parse(format!(
+                    "Received Alert message! Alert is insecure and deprecated"
+                )));
+            }
+            crate::Command::FeeFilter => Message::FeeFilter(u64::deserialize(&mut src)?),
+            crate::Command::FilterAdd => Message::FilterAdd(<Vec<Vec<u8>>>::deserialize(&mut src)?),
+            crate::Command::FilterClear => Message::FilterClear,
+            crate::Command::FilterLoad => Message::FilterLoad(FilterLoad::deserialize(&mut src)?),
+            crate::Command::GetAddr => Message::GetAddr,
+            crate::Command::Ping => Message::Ping(Nonce::deserialize(&mut src)?),
+            crate::Command::Pong => Message::Pong(Nonce::deserialize(&mut src)?),
+            crate::Command::Reject => Message::Reject(Reject::deserialize(&mut src)?),
+            crate::Command::SendHeaders => Message::SendHeaders,
+            crate::Command::WtxidRelay => Message::WtxidRelay,
+            crate::Command::Unknown(bytes) => {
+                debug!("Received unknown command {}", command);
+                Message::Unknown {
+                    command: bytes,
+                    payload: src.split().freeze(),
                 }
-                self.set_decoder_state(DecoderState::Header);
-                Ok(msg)
             }
+        };
+
+        // A payload we didn't use up was misparsed, so whatever we decoded can't be trusted
+        if src.remaining() != 0 {
+            return Err(DeserializationError::Parse(format!(
+                "{} bytes left over after decoding {}",
+                src.remaining(),
+                command
+            )));
         }
+        trace!("Received {:?}", msg);
+        Ok(msg)
     }
 }
 
@@ -351,6 +366,112 @@ mod message_roundtrip_tests {
             _ => panic!("Wrong message! Expected Verack received {:?}", actual),
         }
     }
+    #[test]
+    fn skips_unknown_commands() {
+        let mut out = BytesMut::with_capacity(1000);
+        let mut codec = Codec::new(config::Config::mainnet().magic());
+        let unknown = Message::Unknown {
+            command: *b"sendaddrv2\0\0",
+            payload: bytes::Bytes::from_static(&[1, 2, 3]),
+        };
+        codec.encode(unknown, &mut out).unwrap();
+        codec.encode(Message::Verack, &mut out).unwrap();
+
+        match codec.decode(&mut out) {
+            Ok(Some(Message::Unknown { command, payload })) => {
+                assert_eq!(crate::Command::Unknown(command).to_string(), "sendaddrv2");
+                assert_eq!(&payload[..], &[1, 2, 3]);
+            }
+            other => panic!("Expected an unknown message, got {:?}", other),
+        }
+        // The rest of the stream is unaffected
+        assert!(matches!(codec.decode(&mut out), Ok(Some(Message::Verack))));
+    }
+    #[test]
+    fn wtxidrelay_roundtrip() {
+        let actual = Codec::roundtrip(Message::WtxidRelay);
+        match actual {
+            Message::WtxidRelay => {}
+            _ => panic!("Wrong message! Expected WtxidRelay received {:?}", actual),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut codec = Codec::new(config::Config::mainnet().magic());
+        let mut out = BytesMut::with_capacity(100);
+        codec.encode(Message::Ping(7), &mut out).unwrap();
+        // Corrupt the nonce
+        let last = out.len() - 1;
+        out[last] ^= 1;
+        match codec.decode(&mut out) {
+            Err(shared::DeserializationError::Parse(reason)) => {
+                assert_eq!(reason, super::BAD_CHECKSUM)
+            }
+            other => panic!("Expected a checksum error, got {:?}", other),
+        }
+
+        // The codec recovers for the next message
+        codec.encode(Message::Verack, &mut out).unwrap();
+        assert!(matches!(codec.decode(&mut out), Ok(Some(Message::Verack))));
+    }
+
+    /// Decodes a `command` message carrying `payload`
+    fn decode_raw(command: &str, payload: &[u8]) -> Result<Message, shared::DeserializationError> {
+        let mut name = [0u8; 12];
+        name[..command.len()].copy_from_slice(command.as_bytes());
+        let mut out = BytesMut::with_capacity(100);
+        let mut codec = Codec::new(config::Config::mainnet().magic());
+        let raw = Message::Unknown {
+            command: name,
+            payload: bytes::Bytes::copy_from_slice(payload),
+        };
+        codec.encode(raw, &mut out).unwrap();
+        codec.decode(&mut out).map(Option::unwrap)
+    }
+
+    #[test]
+    fn rejects_leftover_payload() {
+        assert!(matches!(decode_raw("ping", &[7; 8]), Ok(Message::Ping(_))));
+        assert!(decode_raw("ping", &[7; 9]).is_err());
+        assert!(decode_raw("verack", &[0]).is_err());
+    }
+
+    #[test]
+    fn headers_roundtrip() {
+        let header = shared::Block::_test_block().header().clone();
+        match Codec::roundtrip(Message::Headers(vec![header.clone(), header.clone()])) {
+            Message::Headers(headers) => {
+                assert_eq!(headers.len(), 2);
+                assert_eq!(headers[1].hash(), header.hash());
+            }
+            other => panic!("Wrong message! Expected Headers received {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decodes_witness_transactions() {
+        // The native P2WPKH example of BIP143
+        let raw = hex::decode(
+            "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000049\
+            4830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b19\
+            4ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279\
+            655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a914\
+            8280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21\
+            b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb\
+            1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee012102\
+            5476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000",
+        )
+        .unwrap();
+        match decode_raw("tx", &raw) {
+            Ok(Message::Tx(tx)) => {
+                assert_eq!((tx.inputs().len(), tx.outputs().len()), (2, 2));
+                assert!(tx.has_witness());
+                assert_eq!(tx.len(), raw.len());
+            }
+            other => panic!("Expected a transaction, got {:?}", other),
+        }
+    }
 
     #[test]
     fn version_roundtrip() {
@@ -359,6 +480,7 @@ mod message_roundtrip_tests {
             0,
             SocketAddr::from(([192, 168, 0, 0], 8333)),
             0,
+            0x5eed,
             &config::Config::mainnet(),
         );
         let expected = v1.clone();
@@ -427,10 +549,9 @@ mod message_size_tests {
 
     impl Message {
         fn to_bytes(&self) -> Result<BytesMut, std::io::Error> {
-            let codec = BitcoinCodec::new(0);
-            let out = BytesMut::with_capacity(BitcoinCodec::get_serialized_size(&self));
+            let out = BytesMut::with_capacity(BitcoinCodec::get_serialized_size(self));
             let mut out = out.writer();
-            let _ = codec.serialize_body(&self, &mut out)?;
+            BitcoinCodec::serialize_body(self, &mut out)?;
             Ok(out.into_inner())
         }
         fn serialized_size(&self) -> usize {
@@ -1,7 +1,8 @@
 use bytes::Buf;
 use shared::{Deserializable, DeserializationError, Serializable};
+use std::fmt;
 
-/// A shorthand way of referring to a type of [Message](crate::Message). A `Command` is a single byte, while a [Message](crate::Message) is about 90 bytes.
+/// A shorthand way of referring to a type of [Message](crate::Message). A `Command` is at most 13 bytes, while a [Message](crate::Message) is about 90 bytes.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Command {
     Version,
@@ -31,6 +32,9 @@ pub enum Command {
     Pong,
     Reject,
     SendHeaders,
+    WtxidRelay,
+    /// A command we don't recognize, perhaps from a newer version of the protocol
+    Unknown([u8; 12]),
 }
 impl Command {
     pub fn bytes(&self) -> &[u8; 12] {
@@ -62,8 +66,66 @@ impl Command {
             Command::Pong => b"pong\0\0\0\0\0\0\0\0",
             Command::Reject => b"reject\0\0\0\0\0\0",
             Command::SendHeaders => b"sendheaders\0",
+            Command::WtxidRelay => b"wtxidrelay\0\0",
+            Command::Unknown(command) => command,
         }
     }
+
+    /// The one byte id which stands in for this command in the v2 transport (BIP324), if it has one
+    pub fn short_id(&self) -> Option<u8> {
+        SHORT_IDS
+            .iter()
+            .position(|name| *name == self.bytes())
+            .map(|index| index as u8 + 1)
+    }
+
+    /// The command sent as one byte `id` in the v2 transport (BIP324). Ids assigned to commands we don't implement
+    /// map to [`Command::Unknown`], while unassigned ids map to `None`.
+    pub fn from_short_id(id: u8) -> Option<Command> {
+        let name = SHORT_IDS.get((id as usize).checked_sub(1)?)?;
+        Command::deserialize(&name[..]).ok()
+    }
+}
+
+/// The commands with one byte ids in the v2 transport, in the order BIP324 assigns them starting from 1
+const SHORT_IDS: [&[u8; 12]; 28] = [
+    b"addr\0\0\0\0\0\0\0\0",
+    b"block\0\0\0\0\0\0\0",
+    b"blocktxn\0\0\0\0",
+    b"cmpctblock\0\0",
+    b"feefilter\0\0\0",
+    b"filteradd\0\0\0",
+    b"filterclear\0",
+    b"filterload\0\0",
+    b"getblocks\0\0\0",
+    b"getblocktxn\0",
+    b"getdata\0\0\0\0\0",
+    b"getheaders\0\0",
+    b"headers\0\0\0\0\0",
+    b"inv\0\0\0\0\0\0\0\0\0",
+    b"mempool\0\0\0\0\0",
+    b"merkleblock\0",
+    b"notfound\0\0\0\0",
+    b"ping\0\0\0\0\0\0\0\0",
+    b"pong\0\0\0\0\0\0\0\0",
+    b"sendcmpct\0\0\0",
+    b"tx\0\0\0\0\0\0\0\0\0\0",
+    b"getcfilters\0",
+    b"cfilter\0\0\0\0\0",
+    b"getcfheaders",
+    b"cfheaders\0\0\0",
+    b"getcfcheckpt",
+    b"cfcheckpt\0\0\0",
+    b"addrv2\0\0\0\0\0\0",
+];
+
+impl fmt::Display for Command {
+    /// Writes the command as it appears on the wire, without the trailing nulls
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.bytes();
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        write!(f, "{}", String::from_utf8_lossy(&bytes[..end]))
+    }
 }
 
 impl Serializable for Command {
@@ -112,7 +174,12 @@ impl Deserializable for Command {
             b"pong\0\0\0\0\0\0\0\0" => Command::Pong,
             b"reject\0\0\0\0\0\0" => Command::Reject,
             b"sendheaders\0" => Command::SendHeaders,
-            _ => return Err(DeserializationError::parse(&buf, "Command")),
+            b"wtxidrelay\0\0" => Command::WtxidRelay,
+            _ => {
+                let mut command = [0u8; 12];
+                command.copy_from_slice(&buf[..12]);
+                Command::Unknown(command)
+            }
         };
         Ok(command)
     }
@@ -20,3 +20,15 @@ pub const EWMA_DECAY_RATE: Duration = Duration::from_secs(5 * 60);
 /// Since adding peers is a high-latency operation, we expect lots of them to be added at once
 /// when backpressure on the network gets too hight. This constant keeps the crawler from going too far overboard
 pub const MAX_PENDING_HANDSHAKES: usize = 20;
+
+/// How long the crawler waits for a connection to a candidate (Core's `-timeout` default)
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Peers speaking an older protocol version are disconnected during the handshake (Core's `MIN_PEER_PROTO_VERSION`)
+pub const MIN_PEER_PROTO_VERSION: u32 = 31800;
+
+/// How long a peer has to complete the version handshake (Core's `-peertimeout` default)
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The first protocol version which supports announcing transactions by wtxid (BIP339)
+pub const WTXID_RELAY_VERSION: u32 = 70016;
@@ -1,26 +1,43 @@
-use std::{future, net::SocketAddr, pin::Pin};
+//! Finding peers to connect to.
+//!
+//! Whenever it is asked for another peer, the crawler connects to the next candidate from its [`AddressBook`], with
+//! at most [`MAX_PENDING_HANDSHAKES`] connection attempts in progress at once. When the address book runs dry, it
+//! is refilled from the network's DNS seeds, at most once per crawl interval.
+//!
+//! Connected peers are sent back as [`Change::Insert`], without having done the version handshake, since only the
+//! receiver knows the height of its chain. A failed attempt is sent back as an error, and the next candidate is
+//! tried in its place.
+use std::{net::SocketAddr, pin::Pin, time::Duration};
 
 use config::Config;
 use futures::{
-    channel::{mpsc, oneshot},
-    stream::{FuturesOrdered, FuturesUnordered},
-    Future, FutureExt, StreamExt,
+    channel::mpsc,
+    stream::{FuturesUnordered, StreamExt},
+    Future, SinkExt,
 };
-use tokio::task::JoinHandle;
+use tokio::{task::JoinHandle, time::Instant};
+use tower::discover::Change;
+use tracing::{debug, info};
 
 use crate::{
-    address_book::AddressBook, constants::MAX_PENDING_HANDSHAKES, peer_set::PeerChange, Peer,
-    PeerError,
+    address_book::AddressBook,
+    constants::{CONNECT_TIMEOUT, MAX_PENDING_HANDSHAKES},
+    Peer, PeerAddress, PeerError,
 };
 
+/// A newly connected peer, or the error from a failed connection attempt
+pub type PeerChange = Result<Change<SocketAddr, Peer>, PeerError>;
+
+type Connection = Pin<Box<dyn Future<Output = (PeerAddress, Result<Peer, PeerError>)> + Send>>;
+
 pub fn start_crawler(
     needs_peers_rx: mpsc::Receiver<()>,
     discovered_peers_tx: mpsc::Sender<PeerChange>,
-    crawl_interval: std::time::Duration,
+    crawl_interval: Duration,
     address_book: AddressBook,
     config: Config,
 ) -> JoinHandle<Result<(), Box<dyn std::error::Error + Send>>> {
-    tokio::spawn(run(
+    tokio::spawn(crawl(
         needs_peers_rx,
         discovered_peers_tx,
         crawl_interval,
@@ -28,43 +45,123 @@ pub fn start_crawler(
         config,
     ))
 }
-async fn run(
+
+/// Connects to a new peer for every demand received on `needs_peers_rx`, until either channel is closed
+pub async fn crawl(
     mut needs_peers_rx: mpsc::Receiver<()>,
-    discovered_peers_tx: mpsc::Sender<PeerChange>,
-    crawl_interval: std::time::Duration,
+    mut discovered_peers_tx: mpsc::Sender<PeerChange>,
+    crawl_interval: Duration,
     mut address_book: AddressBook,
     config: Config,
 ) -> Result<(), Box<dyn std::error::Error + Send>> {
     let mut timer = tokio::time::interval(crawl_interval);
-    let mut in_progress_connections: FuturesUnordered<
-        Pin<Box<dyn futures::Future<Output = Result<Peer, PeerError>> + Send>>,
-    > = FuturesUnordered::new();
-    // Push a future which never resolves to prevent the FuturesUnordered stream from terminating
-    // in_progress_connections.push(future::pending().boxed());
+    let mut pending: FuturesUnordered<Connection> = FuturesUnordered::new();
+    // Peers we were asked for, but haven't started connecting to yet
+    let mut wanted = 0;
+    let mut last_seed_query: Option<Instant> = None;
     loop {
         tokio::select! {
-            _ = timer.tick() => {
-                try_add_peer(&mut in_progress_connections, &mut address_book, config.clone())
-            }
-            val = needs_peers_rx.next() => {
-                todo!()
+            demand = needs_peers_rx.next() => match demand {
+                Some(()) => wanted += 1,
+                None => return Ok(()),
+            },
+            Some((address, result)) = pending.next(), if !pending.is_empty() => {
+                let change = match result {
+                    Ok(peer) => Ok(Change::Insert(peer.get_ip_address(), peer)),
+                    Err(e) => {
+                        debug!("Crawler: could not connect to {}: {}", address, e);
+                        wanted += 1;
+                        Err(e)
+                    }
+                };
+                if discovered_peers_tx.send(change).await.is_err() {
+                    return Ok(());
+                }
             }
+            // Retries the DNS seeds if they had nothing for us last time
+            _ = timer.tick() => {}
+        }
+        while wanted > 0 && pending.len() < MAX_PENDING_HANDSHAKES {
+            let candidate = match address_book.next_candidate() {
+                Some(candidate) => candidate,
+                None if last_seed_query
+                    .is_none_or(|queried| queried.elapsed() >= crawl_interval) =>
+                {
+                    last_seed_query = Some(Instant::now());
+                    address_book.add_candidates(query_dns_seeds(&config).await);
+                    continue;
+                }
+                None => break,
+            };
+            wanted -= 1;
+            pending.push(connect(candidate, config.clone()));
         }
-        return Ok(());
     }
 }
 
-fn try_add_peer(
-    pending: &mut FuturesUnordered<
-        Pin<Box<dyn futures::Future<Output = Result<Peer, PeerError>> + Send>>,
-    >,
-    address_book: &mut AddressBook,
-    config: Config,
-) {
-    if pending.len() > MAX_PENDING_HANDSHAKES + 1 {
-        return;
+fn connect(address: PeerAddress, config: Config) -> Connection {
+    Box::pin(async move {
+        let result = match tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            Peer::at_address(address.clone(), 0, config),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(elapsed) => Err(PeerError::from(elapsed)),
+        };
+        (address, result)
+    })
+}
+
+/// Looks up peers from the network's DNS seeds, on its default port
+async fn query_dns_seeds(config: &Config) -> Vec<PeerAddress> {
+    let mut found = Vec::new();
+    for seed in config.chain_params().dns_seeds.iter() {
+        match tokio::net::lookup_host((*seed, config.core_port() as u16)).await {
+            Ok(addrs) => found.extend(addrs.map(PeerAddress::Ip)),
+            Err(e) => debug!("Crawler: could not query DNS seed {}: {}", seed, e),
+        }
     }
-    if let Some(candidate_addr) = address_book.next_candidate() {
-        pending.push(Box::pin(Peer::at_address(candidate_addr, config)))
+    info!("Crawler: found {} addresses from DNS seeds", found.len());
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connects_to_candidates_on_demand() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap();
+        // Nothing listens on a port which was just given up
+        let closed = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let mut config = Config::mainnet();
+        config.set_v2_transport(false);
+        let book = AddressBook::new(vec![PeerAddress::Ip(closed), PeerAddress::Ip(listening)]);
+        let (mut needs_peers_tx, needs_peers_rx) = mpsc::channel(1);
+        let (discovered_peers_tx, mut discovered_peers_rx) = mpsc::channel(1);
+        let crawler = start_crawler(
+            needs_peers_rx,
+            discovered_peers_tx,
+            Duration::from_secs(60),
+            book,
+            config,
+        );
+
+        needs_peers_tx.send(()).await.unwrap();
+        assert!(discovered_peers_rx.next().await.unwrap().is_err());
+        // The failed attempt's demand goes to the next candidate
+        match discovered_peers_rx.next().await.unwrap() {
+            Ok(Change::Insert(addr, _)) => assert_eq!(addr, listening),
+            _ => panic!("expected a new peer"),
+        }
+        drop(needs_peers_tx);
+        assert!(crawler.await.unwrap().is_ok());
     }
 }
@@ -0,0 +1,213 @@
+//! Inbound connection eviction, following Bitcoin Core's `SelectNodeToEvict`.
+//!
+//! When every inbound slot is taken, a new connection is only accepted if an existing inbound peer can be evicted
+//! to make room for it. Peers are protected if they're hard for an attacker to imitate: peers from many different
+//! network groups, with low latency, which recently gave us transactions or blocks we didn't have, or which have
+//! been connected for a long time. An attacker would need to beat honest peers on every one of those measures to
+//! take over all of our slots. Of the peers left unprotected, we evict the newest peer from the network group
+//! with the most connections, since that's where an attacker with a few IP ranges would be concentrated.
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Peers protected because they're in distinct network groups
+const PROTECTED_BY_NETGROUP: usize = 4;
+/// Peers protected for having the lowest ping times
+const PROTECTED_BY_PING: usize = 8;
+/// Peers protected for most recently relaying a new transaction to us
+const PROTECTED_BY_TX_RELAY: usize = 4;
+/// Peers protected for most recently relaying a new block to us
+const PROTECTED_BY_BLOCK_RELAY: usize = 4;
+
+/// The network group of an address: the /16 of an IPv4 address, or the /32 of an IPv6 address. Addresses in the
+/// same group are likely to be controlled by the same operator.
+pub fn netgroup(address: &IpAddr) -> Vec<u8> {
+    let address = match address {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => *address,
+        },
+        _ => *address,
+    };
+    match address {
+        IpAddr::V4(ip) => {
+            if ip.is_loopback() || ip.is_private() {
+                // Local peers all share one group
+                vec![0]
+            } else {
+                vec![4, ip.octets()[0], ip.octets()[1]]
+            }
+        }
+        IpAddr::V6(ip) => {
+            let octets = ip.octets();
+            vec![6, octets[0], octets[1], octets[2], octets[3]]
+        }
+    }
+}
+
+/// What we know about an inbound peer when deciding whether to evict it
+#[derive(Debug, Clone)]
+pub struct EvictionCandidate {
+    pub peer: SocketAddr,
+    pub connected: Instant,
+    /// The lowest ping time we've measured, if any
+    pub min_ping: Option<Duration>,
+    /// The last time the peer sent us a block we didn't already have
+    pub last_block_time: Option<Instant>,
+    /// The last time the peer sent us a transaction we accepted to the mempool
+    pub last_tx_time: Option<Instant>,
+    /// Whether the peer asked for transaction relay in its `Version`
+    pub relay_txs: bool,
+    /// Peers which misbehaved, but not enough to be disconnected, are evicted first
+    pub prefer_evict: bool,
+}
+
+impl EvictionCandidate {
+    pub fn new(peer: SocketAddr, connected: Instant) -> EvictionCandidate {
+        EvictionCandidate {
+            peer,
+            connected,
+            min_ping: None,
+            last_block_time: None,
+            last_tx_time: None,
+            relay_txs: true,
+            prefer_evict: false,
+        }
+    }
+}
+
+/// Chooses which inbound peer to evict to make room for a new connection, or `None` if every peer is protected.
+///
+/// `netgroup_key` should be a secret chosen at random on startup. Peers protected for netgroup diversity are
+/// picked in the order of their keyed netgroup hashes, so an attacker can't tell which groups we favor.
+pub fn select_peer_to_evict(
+    mut candidates: Vec<EvictionCandidate>,
+    netgroup_key: u64,
+) -> Option<SocketAddr> {
+    let keyed_netgroup = |candidate: &EvictionCandidate| {
+        let mut hasher = DefaultHasher::new();
+        netgroup_key.hash(&mut hasher);
+        netgroup(&candidate.peer.ip()).hash(&mut hasher);
+        hasher.finish()
+    };
+
+    protect_by(&mut candidates, PROTECTED_BY_NETGROUP, |a, b| {
+        keyed_netgroup(a).cmp(&keyed_netgroup(b))
+    });
+    protect_by(&mut candidates, PROTECTED_BY_PING, |a, b| {
+        let ping = |c: &EvictionCandidate| c.min_ping.unwrap_or(Duration::MAX);
+        ping(a).cmp(&ping(b))
+    });
+    protect_by(&mut candidates, PROTECTED_BY_TX_RELAY, |a, b| {
+        b.last_tx_time.cmp(&a.last_tx_time)
+    });
+    // Peers which don't relay transactions can only compete on blocks
+    protect_by(&mut candidates, PROTECTED_BY_BLOCK_RELAY, |a, b| {
+        (!b.relay_txs, b.last_block_time).cmp(&(!a.relay_txs, a.last_block_time))
+    });
+    let longest_connected = candidates.len() / 2;
+    protect_by(&mut candidates, longest_connected, |a, b| {
+        a.connected.cmp(&b.connected)
+    });
+
+    if candidates.iter().any(|candidate| candidate.prefer_evict) {
+        candidates.retain(|candidate| candidate.prefer_evict);
+    }
+
+    // Evict the newest peer from the largest network group. If groups are tied, pick the one whose newest
+    // member connected most recently.
+    let mut groups: HashMap<Vec<u8>, Vec<EvictionCandidate>> = HashMap::new();
+    for candidate in candidates {
+        groups
+            .entry(netgroup(&candidate.peer.ip()))
+            .or_default()
+            .push(candidate);
+    }
+    groups
+        .into_values()
+        .filter_map(|members| {
+            let newest = members.iter().max_by_key(|c| c.connected)?.clone();
+            Some((members.len(), newest))
+        })
+        .max_by_key(|(size, newest)| (*size, newest.connected))
+        .map(|(_, newest)| newest.peer)
+}
+
+/// Removes the `count` best candidates according to `order`, which sorts the best first
+fn protect_by<F>(candidates: &mut Vec<EvictionCandidate>, count: usize, order: F)
+where
+    F: Fn(&EvictionCandidate, &EvictionCandidate) -> std::cmp::Ordering,
+{
+    candidates.sort_by(|a, b| order(a, b));
+    let count = count.min(candidates.len());
+    candidates.drain(..count);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(ip: [u8; 4], connected: Instant) -> EvictionCandidate {
+        EvictionCandidate::new(SocketAddr::from((ip, 8333)), connected)
+    }
+
+    #[test]
+    fn groups_addresses_by_prefix() {
+        let group = |ip: &str| netgroup(&ip.parse().unwrap());
+        assert_eq!(group("1.2.3.4"), group("1.2.200.1"));
+        assert_ne!(group("1.2.3.4"), group("1.3.3.4"));
+        assert_eq!(group("::ffff:1.2.3.4"), group("1.2.9.9"));
+        assert_eq!(group("2001:db8:1::1"), group("2001:db8:2::1"));
+        assert_eq!(group("127.0.0.1"), group("192.168.1.1"));
+    }
+
+    #[test]
+    fn evicts_newest_peer_from_largest_netgroup() {
+        let start = Instant::now();
+        let mut candidates = Vec::new();
+        // 30 honest peers in distinct groups, most of which are fast, useful or long lived, and 20 attacker peers
+        // sharing one group
+        for i in 0..30u8 {
+            let mut honest = candidate([11 + i, 1, 1, 1], start);
+            match i {
+                0..=7 => honest.min_ping = Some(Duration::from_millis(10)),
+                8..=11 => honest.last_tx_time = Some(start + Duration::from_secs(50)),
+                12..=15 => honest.last_block_time = Some(start + Duration::from_secs(50)),
+                20..=29 => honest.connected = start + Duration::from_secs(1000),
+                _ => {}
+            }
+            candidates.push(honest);
+        }
+        for i in 0..20u8 {
+            candidates.push(candidate(
+                [99, 99, 0, i],
+                start + Duration::from_secs(100 + i as u64),
+            ));
+        }
+        let evicted = select_peer_to_evict(candidates.clone(), 7).unwrap();
+        assert_eq!(evicted, SocketAddr::from(([99, 99, 0, 19], 8333)));
+
+        // Recently relaying a transaction protects a peer
+        candidates[49].last_tx_time = Some(start + Duration::from_secs(500));
+        let evicted = select_peer_to_evict(candidates.clone(), 7).unwrap();
+        assert_eq!(evicted, SocketAddr::from(([99, 99, 0, 18], 8333)));
+
+        // Misbehaving peers go first, even though they're in small groups
+        for candidate in candidates[20..30].iter_mut() {
+            candidate.prefer_evict = true;
+        }
+        let evicted = select_peer_to_evict(candidates.clone(), 7).unwrap();
+        assert!(candidates[20..30].iter().any(|c| c.peer == evicted));
+    }
+
+    #[test]
+    fn protects_everyone_when_there_are_few_peers() {
+        let start = Instant::now();
+        let candidates = (0..4u8)
+            .map(|i| candidate([20 + i, 0, 0, 1], start))
+            .collect();
+        assert_eq!(select_peer_to_evict(candidates, 0), None);
+    }
+}
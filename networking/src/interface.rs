@@ -11,6 +11,8 @@ pub enum NetworkRequest {
     // NextBlock,
     /// Requests all transactions with provided hashes
     TransactionsByHash(HashSet<TxID>),
+    /// Requests all transactions with provided wtxids, from peers which announce transactions by wtxid (BIP339)
+    TransactionsByWtxid(HashSet<TxID>),
     /// Requests headers starting with the first header in the vec. If max_responses is not provided, the Service will attempt to return every header up to the current tip.
     ///
     /// last_known_headers should be ordered from newest to oldest (i.e. from now toward Genesis block) if it contains more than one item
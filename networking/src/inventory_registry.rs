@@ -0,0 +1,234 @@
+//! Tracks which peers have advertised which inventory, so that requests for blocks and transactions can be sent to
+//! a peer which is known to have them.
+//!
+//! Following [Zcash Zebra](https://github.com/ZcashFoundation/zebra/blob/main/zebra-network/src/peer_set/inventory_registry.rs),
+//! advertisements are kept for between one and two rotation intervals. Every interval, the current set of
+//! advertisements becomes the previous set, and the previous set is forgotten. This bounds the registry's size
+//! without tracking a timestamp for every entry.
+//!
+//! The registry is fed by whoever handles inbound `Inv` messages, which is the daemon's peer tasks.
+use shared::{u256, BlockHash, InventoryData, InventoryType, TxID};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How often the registry forgets old advertisements
+pub const INVENTORY_ROTATION_INTERVAL: Duration = Duration::from_secs(53);
+
+/// A block or transaction which peers announce, and can be asked for
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum InventoryHash {
+    Error,
+    Tx(TxID),
+    /// A transaction announced by its wtxid, to or by a peer which negotiated wtxid relay (BIP339)
+    Wtx(TxID),
+    Block(BlockHash),
+    FilteredBlock(),
+}
+impl From<TxID> for InventoryHash {
+    fn from(tx: TxID) -> InventoryHash {
+        InventoryHash::Tx(tx)
+    }
+}
+
+impl From<BlockHash> for InventoryHash {
+    fn from(hash: BlockHash) -> InventoryHash {
+        InventoryHash::Block(hash)
+    }
+}
+
+impl InventoryHash {
+    /// The inventory entry to put in an `Inv` or `GetData` message for this item, if it can be requested
+    pub fn inventory_data(&self) -> Option<InventoryData> {
+        let (inventory_type, hash) = match self {
+            InventoryHash::Tx(txid) => (InventoryType::Tx, txid.inner()),
+            InventoryHash::Wtx(wtxid) => (InventoryType::WitnessTx, wtxid.inner()),
+            InventoryHash::Block(hash) => (InventoryType::Block, hash.inner()),
+            InventoryHash::Error | InventoryHash::FilteredBlock() => return None,
+        };
+        Some(InventoryData::from(inventory_type, u256::from_bytes(*hash)))
+    }
+}
+
+impl From<&InventoryData> for InventoryHash {
+    fn from(inv: &InventoryData) -> InventoryHash {
+        let hash = *inv.hash.to_le_bytes();
+        match inv.inventory_type {
+            InventoryType::Tx => InventoryHash::Tx(TxID::from(hash)),
+            InventoryType::WitnessTx => InventoryHash::Wtx(TxID::from(hash)),
+            // Every other kind of inventory refers to a block, which the peer can serve in any format
+            _ => InventoryHash::Block(BlockHash::from(hash)),
+        }
+    }
+}
+
+/// A map from each [`InventoryHash`] to the peers which advertised it.
+#[derive(Debug)]
+pub struct InventoryRegistry {
+    current: HashMap<InventoryHash, HashSet<SocketAddr>>,
+    prev: HashMap<InventoryHash, HashSet<SocketAddr>>,
+    next_rotation: Instant,
+}
+
+impl Default for InventoryRegistry {
+    fn default() -> InventoryRegistry {
+        InventoryRegistry::new()
+    }
+}
+
+impl InventoryRegistry {
+    pub fn new() -> InventoryRegistry {
+        InventoryRegistry {
+            current: HashMap::new(),
+            prev: HashMap::new(),
+            next_rotation: Instant::now() + INVENTORY_ROTATION_INTERVAL,
+        }
+    }
+
+    /// Records that `peer` advertised `hash`.
+    pub fn register(&mut self, hash: InventoryHash, peer: SocketAddr) {
+        self.current.entry(hash).or_default().insert(peer);
+    }
+
+    /// Records every item in an `Inv` message from `peer`.
+    pub fn register_inv(&mut self, peer: SocketAddr, inventory: &[InventoryData]) {
+        for inv in inventory {
+            self.register(InventoryHash::from(inv), peer);
+        }
+    }
+
+    /// Records that `peer` answered a request for these items with `notfound`, so that they're asked for elsewhere.
+    pub fn register_missing(&mut self, peer: &SocketAddr, inventory: &[InventoryData]) {
+        for inv in inventory {
+            let hash = InventoryHash::from(inv);
+            for map in [&mut self.current, &mut self.prev].iter_mut() {
+                if let Some(peers) = map.get_mut(&hash) {
+                    peers.remove(peer);
+                    if peers.is_empty() {
+                        map.remove(&hash);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The peers which recently advertised `hash`
+    pub fn peers(&self, hash: &InventoryHash) -> impl Iterator<Item = &SocketAddr> {
+        let current = self.current.get(hash);
+        let prev = self
+            .prev
+            .get(hash)
+            .into_iter()
+            .flatten()
+            .filter(move |peer| !current.is_some_and(|current| current.contains(*peer)));
+        current.into_iter().flatten().chain(prev)
+    }
+
+    /// The peer which advertised the most of `hashes`, out of those `usable` accepts. `None` if no usable peer is
+    /// known to have any of them, in which case the caller should pick a peer some other way.
+    pub fn best_peer<F>(&self, hashes: &[InventoryHash], usable: F) -> Option<SocketAddr>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        let mut advertised: HashMap<SocketAddr, usize> = HashMap::new();
+        for hash in hashes {
+            for peer in self.peers(hash).filter(|peer| usable(peer)) {
+                *advertised.entry(*peer).or_default() += 1;
+            }
+        }
+        advertised
+            .into_iter()
+            .max_by_key(|(peer, count)| (*count, *peer))
+            .map(|(peer, _)| peer)
+    }
+
+    /// Forgets everything advertised by a peer, typically because it disconnected.
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        for map in [&mut self.current, &mut self.prev].iter_mut() {
+            map.retain(|_, peers| {
+                peers.remove(peer);
+                !peers.is_empty()
+            });
+        }
+    }
+
+    /// Rotates the registry if the rotation interval has elapsed.
+    pub fn expire(&mut self, now: Instant) {
+        if now >= self.next_rotation {
+            self.prev = std::mem::take(&mut self.current);
+            self.next_rotation = now + INVENTORY_ROTATION_INTERVAL;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn remembers_advertisements_for_two_rotations() {
+        let mut registry = InventoryRegistry::new();
+        let tx = InventoryHash::Tx(TxID::from([1; 32]));
+        let now = Instant::now();
+
+        registry.register(tx.clone(), peer(1));
+        registry.register_inv(
+            peer(2),
+            &[InventoryData::from(
+                InventoryType::Tx,
+                u256::from_bytes([1; 32]),
+            )],
+        );
+        let mut peers: Vec<_> = registry.peers(&tx).cloned().collect();
+        peers.sort();
+        assert_eq!(peers, vec![peer(1), peer(2)]);
+
+        // After one rotation the advertisements are still known, and re-advertising doesn't duplicate them
+        registry.expire(now + INVENTORY_ROTATION_INTERVAL * 2);
+        registry.register(tx.clone(), peer(1));
+        assert_eq!(registry.peers(&tx).count(), 2);
+
+        registry.remove_peer(&peer(2));
+        assert_eq!(
+            registry.peers(&tx).cloned().collect::<Vec<_>>(),
+            vec![peer(1)]
+        );
+
+        registry.expire(now + INVENTORY_ROTATION_INTERVAL * 4);
+        registry.expire(now + INVENTORY_ROTATION_INTERVAL * 6);
+        assert_eq!(registry.peers(&tx).count(), 0);
+    }
+
+    #[test]
+    fn prefers_the_peer_with_the_most_inventory() {
+        let mut registry = InventoryRegistry::new();
+        let blocks: Vec<InventoryHash> = (1..=3)
+            .map(|i| InventoryHash::Block(BlockHash::from([i; 32])))
+            .collect();
+        registry.register(blocks[0].clone(), peer(1));
+        registry.register(blocks[0].clone(), peer(2));
+        registry.register(blocks[1].clone(), peer(2));
+
+        assert_eq!(registry.best_peer(&blocks, |_| true), Some(peer(2)));
+        assert_eq!(
+            registry.best_peer(&blocks, |peer| peer.port() != 2),
+            Some(peer(1))
+        );
+        assert_eq!(registry.best_peer(&blocks[2..], |_| true), None);
+
+        // A peer which doesn't have an item after all stops being asked for it
+        registry.register_missing(
+            &peer(2),
+            &[InventoryData::from(
+                InventoryType::Block,
+                u256::from_bytes([1; 32]),
+            )],
+        );
+        assert_eq!(registry.best_peer(&blocks[..1], |_| true), Some(peer(1)));
+        assert_eq!(registry.best_peer(&blocks[1..], |_| true), Some(peer(2)));
+    }
+}
@@ -15,7 +15,7 @@ mod command;
 pub use command::Command;
 
 mod message;
-pub use message::Message;
+pub use message::{GetBlocks, GetHeaders, Message};
 
 mod types;
 
@@ -34,9 +34,35 @@ mod interface;
 pub use interface::{NetworkRequest, NetworkResponse};
 
 mod address_book;
+pub use address_book::AddressBook;
+mod ban_list;
+pub use ban_list::{BanList, Subnet, BAN_LIST_FILE, DEFAULT_BAN_TIME, MAX_BAN_TIME};
 mod constants;
 mod crawler;
-mod peer_set;
+pub use crawler::{crawl, start_crawler, PeerChange};
+mod eviction;
+pub use eviction::{netgroup, select_peer_to_evict, EvictionCandidate};
+mod inventory_registry;
+pub use inventory_registry::{InventoryHash, InventoryRegistry, INVENTORY_ROTATION_INTERVAL};
+pub use tower::discover::Change;
+
+mod misbehavior;
+pub use misbehavior::{Misbehavior, PeerScores, DISCOURAGEMENT_THRESHOLD};
+
+mod ping;
+pub use ping::{PingTracker, PING_INTERVAL};
+
+mod v2_transport;
+pub use v2_transport::{Transport, V2Codec};
+
+mod proxy;
+pub use proxy::{Connector, PeerAddress, ProxyCredentials};
+
+mod relay;
+pub use relay::{
+    KnownInventory, Relay, INBOUND_INVENTORY_BROADCAST_INTERVAL, INVENTORY_BROADCAST_MAX,
+    MAX_KNOWN_INVENTORY, OUTBOUND_INVENTORY_BROADCAST_INTERVAL,
+};
 
 // mod messages;
 // pub use messages::Addr;
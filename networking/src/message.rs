@@ -1,5 +1,6 @@
 use crate::types::{Nonce, PrefilledTransaction, ProtocolVersion, Services};
 use crate::Command;
+use bytes::Bytes;
 use serde_derive::Serializable;
 use shared::BlockHeader;
 use shared::EncapsulatedAddr;
@@ -74,6 +75,14 @@ pub enum Message {
     Tx(Transaction),
     Verack,
     Version(Version),
+    /// Sent between `Version` and `Verack` to ask for transactions to be announced by wtxid (BIP339)
+    WtxidRelay,
+    /// A message with a command we don't recognize. Its payload is kept as is, so that it can be handled by whoever
+    /// is interested in it, or simply ignored.
+    Unknown {
+        command: [u8; 12],
+        payload: Bytes,
+    },
 }
 
 impl Message {
@@ -82,6 +91,7 @@ impl Message {
         peer_services: u64,
         warpd_ip: SocketAddr,
         best_block: u32,
+        nonce: Nonce,
         config: &config::Config,
     ) -> Message {
         Message::Version(Version::new(
@@ -89,6 +99,7 @@ impl Message {
             peer_services,
             warpd_ip,
             best_block,
+            nonce,
             config,
         ))
     }
@@ -112,7 +123,7 @@ impl Message {
             Message::Inv { .. } => Command::Inv,
             Message::MemPool {} => Command::MemPool,
             Message::MerkleBlock { .. } => Command::MerkleBlock,
-            Message::NotFound { .. } => Command::MemPool,
+            Message::NotFound { .. } => Command::NotFound,
             Message::Ping { .. } => Command::Ping,
             Message::Pong { .. } => Command::Pong,
             Message::Reject { .. } => Command::Reject,
@@ -121,6 +132,8 @@ impl Message {
             Message::Tx { .. } => Command::Tx,
             Message::Verack {} => Command::Verack,
             Message::Version { .. } => Command::Version,
+            Message::WtxidRelay => Command::WtxidRelay,
+            Message::Unknown { command, .. } => Command::Unknown(*command),
         }
     }
 }
@@ -34,6 +34,16 @@ impl GetBlocks {
         message
     }
 
+    /// The hashes of the sender's block locator, most recent first
+    pub fn locator(&self) -> &[BlockHash] {
+        &self.block_header_hashes
+    }
+
+    /// The hash of the last block wanted, or all zeroes for as many as fit in one reply
+    pub fn stop_hash(&self) -> &BlockHash {
+        &self.stop_hash
+    }
+
     // pub fn new(payload: Payload::GetBlocksPayload,config: &Config) -> GetBlocks {
     //     let mut message = GetBlocks {
     //         protocol_version: config.get_protocol_version(),
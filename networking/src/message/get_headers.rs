@@ -32,6 +32,16 @@ impl GetHeaders {
         }
         message
     }
+
+    /// The hashes of the sender's block locator, most recent first
+    pub fn locator(&self) -> &[BlockHash] {
+        &self.block_header_hashes
+    }
+
+    /// The hash of the last block wanted, or all zeroes for as many as fit in one reply
+    pub fn stop_hash(&self) -> &BlockHash {
+        &self.stop_hash
+    }
 }
 impl super::Payload for GetHeaders {
     fn serialized_size(&self) -> usize {
@@ -26,6 +26,7 @@ impl Version {
         peer_services: u64,
         daemon_ip: SocketAddr,
         best_block: u32,
+        nonce: Nonce,
         config: &Config,
     ) -> Version {
         Version {
@@ -36,8 +37,8 @@ impl Version {
             receiver: peer_ip,
             transmitter_services: config.get_services(),
             transmitter_ip: daemon_ip,
-            nonce: 0 as u64,
-            user_agent: String::new(),
+            nonce,
+            user_agent: config.user_agent(),
             best_block: best_block,
             relay: true,
         }
@@ -105,6 +106,7 @@ fn serial_size() {
         2371,
         ([192, 168, 0, 2], 8333).into(),
         0x2329381,
+        0x1234,
         &config::Config::mainnet(),
     );
     let serial = msg.to_bytes().expect("Serializing into vec shouldn't fail");
@@ -39,6 +39,10 @@ impl MessageHeader {
         self.command.clone()
     }
 
+    pub fn get_checksum(&self) -> [u8; 4] {
+        self.checksum
+    }
+
     pub fn get_payload_size(&self) -> usize {
         self.payload_size as usize
     }
@@ -0,0 +1,130 @@
+//! Misbehavior scoring. Like Bitcoin Core, each protocol violation is worth a number of points, and a peer whose
+//! score reaches [`DISCOURAGEMENT_THRESHOLD`] is disconnected and discouraged from reconnecting.
+use crate::PeerError;
+use shared::DeserializationError;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use tracing::{debug, info};
+
+/// The score at which a peer is disconnected and discouraged
+pub const DISCOURAGEMENT_THRESHOLD: u32 = 100;
+
+/// The ways a peer can violate the protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Misbehavior {
+    /// Sent a message whose payload doesn't match the checksum in its header
+    BadChecksum,
+    /// Sent a header which doesn't meet its own proof of work target
+    InvalidHeaderPow,
+    /// Sent blocks, transactions or headers we didn't ask for, in excess of what an honest peer would
+    UnrequestedData,
+    /// Sent a block which failed validation
+    InvalidBlock,
+    /// Any other behavior which is never expected of an honest peer
+    Malicious(String),
+}
+
+impl Misbehavior {
+    /// The number of points the violation is worth
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehavior::BadChecksum => 10,
+            Misbehavior::InvalidHeaderPow => 100,
+            Misbehavior::UnrequestedData => 20,
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::Malicious(_) => 100,
+        }
+    }
+
+    /// The misbehavior which caused a connection error, if the error was the peer's fault
+    pub fn from_error(error: &PeerError) -> Option<Misbehavior> {
+        match error {
+            PeerError::Deserialzation(DeserializationError::Parse(reason))
+                if reason == crate::codec::BAD_CHECKSUM =>
+            {
+                Some(Misbehavior::BadChecksum)
+            }
+            PeerError::Malicious(reason) => Some(Misbehavior::Malicious(reason.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Misbehavior::BadChecksum => write!(f, "bad message checksum"),
+            Misbehavior::InvalidHeaderPow => write!(f, "header with invalid proof of work"),
+            Misbehavior::UnrequestedData => write!(f, "unrequested data"),
+            Misbehavior::InvalidBlock => write!(f, "invalid block"),
+            Misbehavior::Malicious(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// The misbehavior score of each connected peer
+#[derive(Debug, Default)]
+pub struct PeerScores {
+    scores: HashMap<SocketAddr, u32>,
+}
+
+impl PeerScores {
+    pub fn new() -> PeerScores {
+        PeerScores::default()
+    }
+
+    pub fn score(&self, peer: &SocketAddr) -> u32 {
+        self.scores.get(peer).copied().unwrap_or(0)
+    }
+
+    /// Adds the points for a violation to a peer's score. Returns `true` if the peer has now reached the
+    /// discouragement threshold, and should be disconnected.
+    pub fn misbehaving(&mut self, peer: SocketAddr, misbehavior: &Misbehavior) -> bool {
+        let score = self.scores.entry(peer).or_default();
+        let previous = *score;
+        *score = score.saturating_add(misbehavior.score());
+        debug!(
+            "Peer {} misbehaving ({}): score {} -> {}",
+            peer, misbehavior, previous, *score
+        );
+        if previous < DISCOURAGEMENT_THRESHOLD && *score >= DISCOURAGEMENT_THRESHOLD {
+            info!("Discouraging peer {} for {}", peer, misbehavior);
+            return true;
+        }
+        false
+    }
+
+    /// Forgets a peer's score once it disconnects
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.scores.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discourages_peers_at_the_threshold() {
+        let peer = SocketAddr::from(([127, 0, 0, 1], 8333));
+        let mut scores = PeerScores::new();
+        for _ in 0..4 {
+            assert!(!scores.misbehaving(peer, &Misbehavior::UnrequestedData));
+        }
+        assert!(scores.misbehaving(peer, &Misbehavior::UnrequestedData));
+        // The caller is only told once
+        assert!(!scores.misbehaving(peer, &Misbehavior::BadChecksum));
+        assert_eq!(scores.score(&peer), 110);
+
+        scores.remove_peer(&peer);
+        assert_eq!(scores.score(&peer), 0);
+        assert_eq!(
+            Misbehavior::from_error(&PeerError::Deserialzation(DeserializationError::Parse(
+                String::from(crate::codec::BAD_CHECKSUM)
+            ))),
+            Some(Misbehavior::BadChecksum)
+        );
+        assert_eq!(Misbehavior::from_error(&PeerError::ConnectionClosed), None);
+    }
+}
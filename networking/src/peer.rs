@@ -1,20 +1,34 @@
-use crate::{command::Command, BitcoinCodec, Message, NetworkRequest, NetworkResponse};
-use config::Config;
+use crate::{
+    command::Command,
+    constants,
+    message::Version,
+    v2_transport::{self, Handshake},
+    BitcoinCodec, Connector, EvictionCandidate, Message, PeerAddress, PingTracker, Transport,
+};
+use bytes::BytesMut;
+use config::{Config, NODE_P2P_V2};
 use futures::{prelude::*, FutureExt};
 use shared::DeserializationError;
-use std::time::Duration;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use std::{fmt, sync::Arc};
-use std::{net::SocketAddr, pin::Pin};
-use tower::Service;
-// use tower::Service;
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+};
 // use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::time::timeout;
-use tokio_util::codec::Framed;
-use tracing::{info, span::Span, trace, trace_span};
+use tokio_util::codec::{Decoder, Framed, FramedParts};
+use tracing::{debug, info, span::Span, trace, trace_span};
 
 type Result<T> = std::result::Result<T, PeerError>;
 
+/// The nonces of our outbound connections which are still handshaking. An inbound `Version` carrying one of them
+/// means we've connected to ourselves.
+static HANDSHAKE_NONCES: Mutex<BTreeSet<u64>> = Mutex::new(BTreeSet::new());
+
 /// An enumeration of errors that a Peer connection can encounter, including malicious behavior.
 #[derive(Debug)]
 pub enum PeerError {
@@ -26,6 +40,14 @@ pub enum PeerError {
     Unexpected(String),
     ConnectionClosed,
     MessageRejected(String),
+    /// The peer's address is banned or discouraged
+    Banned(IpAddr),
+    /// Every connection slot is taken, and no peer could be evicted to make room
+    NoConnectionSlots,
+    /// The peer's `Version` carried one of our own nonces
+    SelfConnection,
+    /// The peer speaks a protocol version older than [`MIN_PEER_PROTO_VERSION`](constants::MIN_PEER_PROTO_VERSION)
+    ObsoleteVersion(u32),
 }
 impl From<DeserializationError> for PeerError {
     fn from(kind: DeserializationError) -> PeerError {
@@ -54,68 +76,175 @@ impl fmt::Display for PeerError {
             PeerError::ConnectionClosed => Ok(()),
             PeerError::Unexpected(cause) => cause.fmt(f),
             PeerError::MessageRejected(cause) => cause.fmt(f),
+            PeerError::Banned(address) => write!(f, "{} is banned", address),
+            PeerError::NoConnectionSlots => write!(f, "no free connection slots"),
+            PeerError::SelfConnection => write!(f, "connected to ourselves"),
+            PeerError::ObsoleteVersion(version) => {
+                write!(f, "obsolete protocol version {}", version)
+            }
         }
     }
 }
 
-/// A [`tower::Service`](https://docs.rs/tower/0.4.1/tower/trait.Service.html) representing an individual peer.
-///
-/// Relies on a backing `NodeDataStore` [`Service`](https://docs.rs/tower/0.4.1/tower/trait.Service.html) to obtain block and transaction information.
-/// If the `NodeDataStore` polls unready, this information will be propagated to the `ConnectionManager` struct, which may drop the the corresponding peer
-/// to relieve backpressure.
+/// A connection to a single peer, over which [`Message`]s are sent and received once the version handshake is done.
+/// Whoever owns it decides what to ask the peer for and how to answer it.
 #[derive(Debug)]
 pub struct Peer {
     peer_id: usize,
+    address: PeerAddress,
+    /// Identifies the peer. For onion peers, this is a placeholder derived from the onion address.
     ip_address: SocketAddr,
     nonce: u64,
     daemon_address: SocketAddr,
     daemon_protocol_version: u32,
+    /// The protocol version from the peer's `Version`, or 0 before the handshake
+    remote_protocol_version: u32,
+    /// The services the peer advertised in its `Version`
     services: u64,
-    connection: Framed<TcpStream, BitcoinCodec>,
+    /// The height of the peer's best block when it connected
+    start_height: u32,
+    /// The user agent from the peer's `Version`
+    user_agent: String,
+    connection: Framed<TcpStream, Transport>,
+    /// Messages which arrived during the handshake, to be returned once it's complete
+    queued: VecDeque<Message>,
     config: Config,
     span: Span,
+    inbound: bool,
+    /// Whether the peer is another Warp node, which counts against the Warp connection limit
+    warp: bool,
+    connected: Instant,
+    ping: PingTracker,
+    last_block_time: Option<Instant>,
+    last_tx_time: Option<Instant>,
+    relay_txs: bool,
+    /// Whether the peer asked for transactions to be announced by wtxid (BIP339)
+    wtxid_relay: bool,
+    /// Whether the last message failed to decode, so anything already buffered must be decoded by hand
+    decode_failed: bool,
 }
 
 impl Peer {
+    /// Connects to `address`, directly or through a proxy depending on `config`. `services` are those the address is
+    /// known to offer, or 0 if we don't know. Like Core, we only open with the v2 handshake if they include
+    /// `NODE_P2P_V2`, since a v1 peer hangs up on it and costs us a reconnection.
     pub fn at_address(
-        address: SocketAddr,
+        address: impl Into<PeerAddress>,
+        services: u64,
         config: Config,
     ) -> Pin<Box<dyn Future<Output = Result<Peer>> + Send>> {
+        let address = address.into();
         async move {
-            info!("Peer: Opening connection to {:?}...", address.ip());
-            let connection = timeout(Duration::from_secs(5), TcpStream::connect(address)).await??;
-            let codec = BitcoinCodec::new(config.magic());
+            info!("Peer: Opening connection to {}...", address);
+            let connector = Connector::from_config(&config);
+            let mut connection = connector.connect(&address).await?;
+            let mut transport = Transport::V1(BitcoinCodec::new(config.magic()));
+            let mut received = BytesMut::new();
+            if config.v2_transport() && services & NODE_P2P_V2 != 0 {
+                let handshake = v2_transport::initiate(
+                    &mut connection,
+                    config.magic(),
+                    config.get_max_msg_size(),
+                );
+                let result = match timeout(constants::HANDSHAKE_TIMEOUT, handshake).await {
+                    Ok(result) => result.map_err(PeerError::from),
+                    Err(elapsed) => Err(PeerError::from(elapsed)),
+                };
+                match result {
+                    Ok((codec, leftover)) => {
+                        transport = Transport::V2(Box::new(codec));
+                        received = leftover;
+                    }
+                    Err(e) => {
+                        // The peer doesn't speak v2, and has probably hung up on us
+                        debug!(
+                            "Peer: v2 handshake with {} failed ({}), retrying with v1",
+                            address, e
+                        );
+                        connection = connector.connect(&address).await?;
+                    }
+                }
+            }
             info!("Peer: Connected");
             Ok(Peer {
                 peer_id: 0,
-                ip_address: address,
-                nonce: 0,
+                ip_address: address.socket_addr(),
+                address,
+                nonce: rand::random(),
                 daemon_address: connection
                     .local_addr()
                     .expect("Connection should have a local address"),
                 daemon_protocol_version: config.get_protocol_version(),
+                remote_protocol_version: 0,
                 services: 0,
-                connection: Framed::new(connection, codec),
+                start_height: 0,
+                user_agent: String::new(),
+                connection: framed(connection, transport, received),
+                queued: VecDeque::new(),
                 config,
                 span: trace_span!("peer"),
+                inbound: false,
+                warp: false,
+                connected: Instant::now(),
+                ping: PingTracker::new(),
+                last_block_time: None,
+                last_tx_time: None,
+                relay_txs: true,
+                wtxid_relay: false,
+                decode_failed: false,
             })
         }
         .boxed()
     }
-    pub async fn from_connection(id: usize, connection: TcpStream, config: Config) -> Peer {
-        let codec = BitcoinCodec::new(config.magic());
+    /// Wraps an inbound connection. If v2 is enabled, this waits for the peer's first bytes to tell whether it's
+    /// opening a v2 handshake or sending a v1 `Version`, and completes the v2 handshake if needed.
+    pub async fn from_connection(
+        id: usize,
+        mut connection: TcpStream,
+        config: Config,
+    ) -> Result<Peer> {
         info!("Receiving from {:?}", connection.peer_addr());
-        Peer {
+        let ip_address = connection.peer_addr()?;
+        let (transport, received) = if config.v2_transport() {
+            let handshake =
+                v2_transport::respond(&mut connection, config.magic(), config.get_max_msg_size());
+            match timeout(constants::HANDSHAKE_TIMEOUT, handshake).await?? {
+                Handshake::V2(codec, leftover) => (Transport::V2(codec), leftover),
+                Handshake::V1(received) => {
+                    (Transport::V1(BitcoinCodec::new(config.magic())), received)
+                }
+            }
+        } else {
+            (
+                Transport::V1(BitcoinCodec::new(config.magic())),
+                BytesMut::new(),
+            )
+        };
+        Ok(Peer {
             peer_id: id,
             services: 0,
-            ip_address: connection.peer_addr().unwrap(),
-            nonce: 0,
+            start_height: 0,
+            user_agent: String::new(),
+            address: PeerAddress::Ip(ip_address),
+            ip_address,
+            nonce: rand::random(),
             daemon_address: connection.local_addr().unwrap(),
             daemon_protocol_version: config.get_protocol_version(),
-            connection: Framed::new(connection, codec),
+            remote_protocol_version: 0,
+            connection: framed(connection, transport, received),
+            queued: VecDeque::new(),
             config,
             span: trace_span!("peer", id = id),
-        }
+            inbound: true,
+            warp: false,
+            connected: Instant::now(),
+            ping: PingTracker::new(),
+            last_block_time: None,
+            last_tx_time: None,
+            relay_txs: true,
+            wtxid_relay: false,
+            decode_failed: false,
+        })
     }
     pub async fn send(&mut self, msg: Message) -> Result<()> {
         trace!("Peer {}: Sending {:?}", self.peer_id, &msg);
@@ -126,20 +255,170 @@ impl Peer {
     pub fn get_ip_address(&self) -> SocketAddr {
         self.ip_address
     }
+    /// The address we know the peer by, which may be an onion address
+    pub fn get_address(&self) -> &PeerAddress {
+        &self.address
+    }
     pub fn get_daemon_address(&self) -> SocketAddr {
         self.daemon_address
     }
 
+    /// The height of the peer's best block when it connected
     pub fn get_best_block(&self) -> u32 {
-        0
+        self.start_height
     }
-    pub async fn receive(&mut self, _timeout_duration: Option<Duration>) -> Result<Message> {
-        let result = match self.connection.next().await {
-            None => Err(PeerError::ConnectionClosed),
-            Some(contents) => Ok(contents?),
-        };
-        trace!("Peer {}: Received {:?}", self.peer_id, &result);
-        result
+    /// The protocol version both sides speak: the lower of ours and the peer's
+    pub fn protocol_version(&self) -> u32 {
+        self.daemon_protocol_version
+            .min(self.remote_protocol_version)
+    }
+    /// The services the peer advertised in its `Version`
+    pub fn services(&self) -> u64 {
+        self.services
+    }
+
+    /// The user agent the peer sent in its `Version`, like `/Satoshi:27.0.0/`
+    pub fn user_agent(&self) -> &str {
+        &self.user_agent
+    }
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+    pub fn is_warp(&self) -> bool {
+        self.warp
+    }
+    pub fn set_warp(&mut self, warp: bool) {
+        self.warp = warp;
+    }
+    pub fn min_ping(&self) -> Option<Duration> {
+        self.ping.min_rtt()
+    }
+    /// The round trip times measured by pinging the peer
+    pub fn pings(&self) -> &PingTracker {
+        &self.ping
+    }
+    /// Records that the peer sent us a block we didn't already have
+    pub fn record_block_relay(&mut self, now: Instant) {
+        self.last_block_time = Some(now);
+    }
+    /// Records that the peer sent us a transaction we accepted to the mempool
+    pub fn record_tx_relay(&mut self, now: Instant) {
+        self.last_tx_time = Some(now);
+    }
+    /// Whether the peer wants transactions relayed to it
+    pub fn relay_txs(&self) -> bool {
+        self.relay_txs
+    }
+    pub fn set_relay_txs(&mut self, relay_txs: bool) {
+        self.relay_txs = relay_txs;
+    }
+    /// Whether transactions are announced to and requested from the peer by wtxid, rather than txid
+    pub fn wtxid_relay(&self) -> bool {
+        self.wtxid_relay
+    }
+    /// Whether the connection uses the encrypted v2 transport (BIP324)
+    pub fn is_v2(&self) -> bool {
+        matches!(self.connection.codec(), Transport::V2(_))
+    }
+    /// The v2 session id, which both ends of an encrypted connection agree on
+    pub fn session_id(&self) -> Option<&[u8; 32]> {
+        match self.connection.codec() {
+            Transport::V2(codec) => Some(codec.session_id()),
+            Transport::V1(_) => None,
+        }
+    }
+
+    /// What the eviction logic needs to know about this peer
+    pub fn eviction_candidate(&self) -> EvictionCandidate {
+        EvictionCandidate {
+            peer: self.ip_address,
+            connected: self.connected,
+            min_ping: self.ping.min_rtt(),
+            last_block_time: self.last_block_time,
+            last_tx_time: self.last_tx_time,
+            relay_txs: self.relay_txs,
+            prefer_evict: false,
+        }
+    }
+    /// Waits for the next message from the peer, giving up after `timeout_duration` if one is given.
+    ///
+    /// Pings and pongs are handled here rather than returned: pings are answered, and while we wait, the peer is
+    /// pinged whenever a ping is due. If the peer stops answering pings, this returns a timeout error.
+    pub async fn receive(&mut self, timeout_duration: Option<Duration>) -> Result<Message> {
+        if let Some(msg) = self.queued.pop_front() {
+            return Ok(msg);
+        }
+        let msg = match timeout_duration {
+            Some(duration) => timeout(duration, self.next_message()).await?,
+            None => self.next_message().await,
+        }?;
+        if let Message::WtxidRelay = msg {
+            return Err(PeerError::Message(String::from(
+                "wtxidrelay received after verack",
+            )));
+        }
+        Ok(msg)
+    }
+    /// The next message from the connection.
+    ///
+    /// A decoding error ends a `Framed` stream, and it won't decode what's already buffered until more data arrives.
+    /// After one we clear the error and decode the buffer ourselves, so that the caller can choose to carry on.
+    async fn next_frame(&mut self) -> Option<std::result::Result<Message, DeserializationError>> {
+        if self.decode_failed {
+            let mut buffered = self.connection.read_buffer_mut().split();
+            let decoded = self
+                .connection
+                .codec_mut()
+                .decode(&mut buffered)
+                .transpose();
+            self.connection.read_buffer_mut().unsplit(buffered);
+            if decoded.is_some() {
+                return decoded;
+            }
+            self.decode_failed = false;
+        }
+        let contents = self.connection.next().await;
+        if let Some(Err(_)) = contents {
+            // Returns immediately, resetting the stream
+            self.connection.next().await;
+            self.decode_failed = true;
+        }
+        contents
+    }
+    async fn next_message(&mut self) -> Result<Message> {
+        loop {
+            let ping_timeout = self.config.ping_timeout();
+            let deadline = self.ping.next_deadline(ping_timeout);
+            let ping_due = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                contents = self.next_frame() => {
+                    let result = match contents {
+                        None => Err(PeerError::ConnectionClosed),
+                        Some(contents) => Ok(contents?),
+                    };
+                    trace!("Peer {}: Received {:?}", self.peer_id, &result);
+                    match result? {
+                        Message::Ping(nonce) => self.send(Message::Pong(nonce)).await?,
+                        Message::Pong(nonce) => {
+                            if let Some(rtt) = self.ping.on_pong(nonce, Instant::now()) {
+                                trace!("Peer {}: Ping took {:?}", self.peer_id, rtt);
+                            }
+                        }
+                        msg => return Ok(msg),
+                    }
+                }
+                _ = ping_due => {
+                    if let Some(ping) = self.ping.poll(Instant::now(), ping_timeout)? {
+                        self.send(ping).await?;
+                    }
+                }
+            }
+        }
     }
     pub async fn receive_expected(
         &mut self,
@@ -159,23 +438,90 @@ impl Peer {
         Ok(msg)
     }
 
+    /// Performs the handshake on an outbound connection: we send our `Version` first.
     pub async fn perform_handshake(&mut self, best_block: Option<u32>) -> Result<()> {
-        self.send(self.create_version_msg(best_block)).await?;
-        self.receive_expected(Command::Version, Some(Duration::from_secs(60)))
-            .await?;
-        self.receive_expected(Command::Verack, Some(Duration::from_secs(60)))
-            .await?;
-        self.send(Message::Verack {}).await?;
-        info!("Peer {}: HandShake complete", self.peer_id);
-        Ok(())
+        HANDSHAKE_NONCES.lock().unwrap().insert(self.nonce);
+        let result = timeout(constants::HANDSHAKE_TIMEOUT, self.handshake(best_block)).await;
+        HANDSHAKE_NONCES.lock().unwrap().remove(&self.nonce);
+        result?
     }
+    /// Performs the handshake on an inbound connection: we answer the peer's `Version` with our own.
     pub async fn accept_handshake(&mut self, best_block: Option<u32>) -> Result<()> {
-        self.receive_expected(Command::Version, Some(Duration::from_secs(60)))
-            .await?;
-        self.send(self.create_version_msg(best_block)).await?;
-        self.send(Message::Verack {}).await?;
-        self.receive_expected(Command::Verack, None).await?;
-        info!("Peer {}: HandShake complete", self.peer_id);
+        timeout(constants::HANDSHAKE_TIMEOUT, self.handshake(best_block)).await?
+    }
+    /// Exchanges `Version` and `Verack` messages. Anything else the peer sends once we have its `Version` (like
+    /// `SendHeaders` or `FeeFilter`) is queued for [`Peer::receive`], and anything it sends before is ignored.
+    async fn handshake(&mut self, best_block: Option<u32>) -> Result<()> {
+        if !self.inbound {
+            self.send(self.create_version_msg(best_block)).await?;
+        }
+        let mut got_version = false;
+        let mut got_verack = false;
+        while !(got_version && got_verack) {
+            match self.next_message().await? {
+                Message::Version(version) if got_version => {
+                    debug!(
+                        "Peer {}: Ignoring redundant version {}",
+                        self.peer_id,
+                        version.protocol_version()
+                    );
+                }
+                Message::Version(version) => {
+                    self.accept_version(&version)?;
+                    if self.inbound {
+                        self.send(self.create_version_msg(best_block)).await?;
+                    }
+                    if self.protocol_version() >= constants::WTXID_RELAY_VERSION {
+                        self.send(Message::WtxidRelay).await?;
+                    }
+                    self.send(Message::Verack).await?;
+                    got_version = true;
+                }
+                Message::Verack if got_version => got_verack = true,
+                // Only meaningful from peers new enough to know about it
+                Message::WtxidRelay if got_version => {
+                    self.wtxid_relay = self.protocol_version() >= constants::WTXID_RELAY_VERSION;
+                }
+                msg if got_version => self.queued.push_back(msg),
+                msg => debug!(
+                    "Peer {}: Ignoring {:?} before version handshake",
+                    self.peer_id,
+                    msg.command()
+                ),
+            }
+        }
+        info!(
+            "Peer {}: HandShake complete, protocol version {}",
+            self.peer_id,
+            self.protocol_version()
+        );
+        self.ping.start(Instant::now());
+        Ok(())
+    }
+    /// Checks the peer's `Version`, and records what it tells us about the peer
+    fn accept_version(&mut self, version: &Version) -> Result<()> {
+        let nonce = version.nonce();
+        if nonce == self.nonce
+            || (self.inbound && HANDSHAKE_NONCES.lock().unwrap().contains(&nonce))
+        {
+            return Err(PeerError::SelfConnection);
+        }
+        if version.protocol_version() < constants::MIN_PEER_PROTO_VERSION {
+            return Err(PeerError::ObsoleteVersion(version.protocol_version()));
+        }
+        self.remote_protocol_version = version.protocol_version();
+        self.services = version.services();
+        self.start_height = version.best_block();
+        self.user_agent = version.user_agent().to_string();
+        self.relay_txs = version.relay();
+        trace!(
+            "Peer {}: version {}, services {:#x}, user agent {}, height {}",
+            self.peer_id,
+            version.protocol_version(),
+            version.services(),
+            version.user_agent(),
+            version.best_block()
+        );
         Ok(())
     }
     pub fn create_version_msg(&self, best_block: Option<u32>) -> Message {
@@ -184,23 +530,248 @@ impl Peer {
             self.services,
             self.daemon_address,
             best_block.unwrap_or(0),
+            self.nonce,
             &self.config,
         )
     }
 }
 
-impl Service<NetworkRequest> for Peer {
-    type Response = NetworkResponse;
+/// Frames `connection` with `transport`, starting with any bytes already read from it during the v2 handshake
+fn framed(
+    connection: TcpStream,
+    transport: Transport,
+    received: BytesMut,
+) -> Framed<TcpStream, Transport> {
+    let mut parts = FramedParts::new::<Message>(connection, transport);
+    parts.read_buf = received;
+    Framed::from_parts(parts)
+}
 
-    type Error = PeerError;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Misbehavior;
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Encoder;
 
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send + 'static>>;
+    /// A peer connected to a raw socket, which speaks v1 for the tests to script
+    async fn connected_pair() -> (Peer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut config = Config::mainnet();
+        config.set_v2_transport(false);
+        let (peer, remote) = tokio::join!(Peer::at_address(address, 0, config), async {
+            listener.accept().await.unwrap().0
+        });
+        (peer.unwrap(), remote)
+    }
 
-    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<Result<()>> {
-        todo!()
+    /// An outbound and an inbound peer connected to each other
+    async fn connected_peers(outbound: Config, inbound: Config) -> (Peer, Peer) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (outbound, inbound) =
+            tokio::join!(Peer::at_address(address, NODE_P2P_V2, outbound), async {
+                let (connection, _) = listener.accept().await.unwrap();
+                Peer::from_connection(1, connection, inbound).await
+            });
+        (outbound.unwrap(), inbound.unwrap())
     }
 
-    fn call(&mut self, req: NetworkRequest) -> Self::Future {
-        todo!()
+    #[tokio::test]
+    async fn handshake_tolerates_message_order() {
+        let (mut peer, remote) = connected_pair().await;
+        let config = Config::mainnet();
+        let mut remote = Framed::new(remote, BitcoinCodec::new(config.magic()));
+        let version = Message::version(
+            peer.get_daemon_address(),
+            0,
+            peer.get_ip_address(),
+            654_321,
+            42,
+            &config,
+        );
+        let remote_side = async {
+            // Sent before the version, so it's ignored
+            remote.send(Message::SendHeaders).await.unwrap();
+            remote.send(version).await.unwrap();
+            remote.send(Message::FeeFilter(1000)).await.unwrap();
+            remote.send(Message::WtxidRelay).await.unwrap();
+            remote.send(Message::Verack).await.unwrap();
+        };
+        let (result, _) = tokio::join!(peer.perform_handshake(None), remote_side);
+        result.unwrap();
+
+        assert_eq!(peer.get_best_block(), 654_321);
+        assert_eq!(peer.services(), config.get_services());
+        assert_eq!(peer.protocol_version(), config.get_protocol_version());
+        assert!(peer.wtxid_relay());
+        assert!(matches!(
+            peer.receive(Some(Duration::from_secs(1))).await,
+            Ok(Message::FeeFilter(1000))
+        ));
+        // Nothing else arrives, so the timeout applies
+        assert!(matches!(
+            peer.receive(Some(Duration::from_millis(50))).await,
+            Err(PeerError::Timeout(_))
+        ));
+
+        // Our version carries a random nonce and a BIP14 user agent
+        let ours = match remote.next().await {
+            Some(Ok(Message::Version(version))) => version,
+            other => panic!("Expected a version, got {:?}", other),
+        };
+        assert_ne!(ours.nonce(), 0);
+        assert_eq!(ours.user_agent(), &config.user_agent());
+        // We ask for wtxid relay before acknowledging the peer's version
+        assert!(matches!(remote.next().await, Some(Ok(Message::WtxidRelay))));
+        assert!(matches!(remote.next().await, Some(Ok(Message::Verack))));
+    }
+
+    #[tokio::test]
+    async fn keeps_receiving_after_a_bad_checksum() {
+        let (mut peer, remote) = connected_pair().await;
+        let mut remote = Framed::new(remote, BitcoinCodec::new(Config::mainnet().magic()));
+        let mut corrupted = BytesMut::new();
+        remote
+            .codec_mut()
+            .encode(Message::FeeFilter(1000), &mut corrupted)
+            .unwrap();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        // Both arrive together, so the second is already buffered when the first fails
+        remote.write_buffer_mut().extend_from_slice(&corrupted);
+        remote.send(Message::FeeFilter(2000)).await.unwrap();
+        match peer.receive(Some(Duration::from_secs(1))).await {
+            Err(e) => assert_eq!(Misbehavior::from_error(&e), Some(Misbehavior::BadChecksum)),
+            other => panic!("Expected a bad checksum, got {:?}", other),
+        }
+        assert!(matches!(
+            peer.receive(Some(Duration::from_secs(1))).await,
+            Ok(Message::FeeFilter(2000))
+        ));
+        remote.send(Message::FeeFilter(3000)).await.unwrap();
+        assert!(matches!(
+            peer.receive(Some(Duration::from_secs(1))).await,
+            Ok(Message::FeeFilter(3000))
+        ));
+    }
+
+    #[tokio::test]
+    async fn detects_self_connection() {
+        let (mut outbound, mut inbound) =
+            connected_peers(Config::mainnet(), Config::mainnet()).await;
+        let (_, result) = tokio::join!(
+            outbound.perform_handshake(None),
+            // The inbound side hangs up once it notices
+            async move { inbound.accept_handshake(None).await }
+        );
+        assert!(matches!(result, Err(PeerError::SelfConnection)));
+    }
+
+    #[tokio::test]
+    async fn rejects_obsolete_versions() {
+        let (mut peer, remote) = connected_pair().await;
+        let mut config = Config::mainnet();
+        config.set_protocol_version(constants::MIN_PEER_PROTO_VERSION - 1);
+        let mut remote = Framed::new(remote, BitcoinCodec::new(config.magic()));
+        let version = Message::version(
+            peer.get_daemon_address(),
+            0,
+            peer.get_ip_address(),
+            0,
+            42,
+            &config,
+        );
+        let (result, _) = tokio::join!(peer.perform_handshake(None), remote.send(version));
+        assert!(matches!(result, Err(PeerError::ObsoleteVersion(_))));
+    }
+
+    // Both ends of these connections live in one process, so a full handshake would look like a self-connection.
+    // Messages are exchanged directly instead.
+    #[tokio::test]
+    async fn encrypts_connections_between_v2_peers() {
+        let (mut outbound, mut inbound) =
+            connected_peers(Config::mainnet(), Config::mainnet()).await;
+        assert!(outbound.is_v2() && inbound.is_v2());
+        assert!(outbound.session_id().is_some());
+        assert_eq!(outbound.session_id(), inbound.session_id());
+        outbound.send(Message::FeeFilter(1000)).await.unwrap();
+        assert!(matches!(
+            inbound.receive(Some(Duration::from_secs(1))).await,
+            Ok(Message::FeeFilter(1000))
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_tries_v2_with_peers_advertising_it() {
+        // The inbound side would accept v2, but we don't know that the address supports it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (outbound, accepted) =
+            tokio::join!(Peer::at_address(address, 0, Config::mainnet()), async {
+                listener.accept().await.unwrap().0
+            });
+        let (mut outbound, remote) = (outbound.unwrap(), accepted);
+        assert!(!outbound.is_v2());
+        // The connection opens with a v1 version rather than a v2 key, so no reconnection was needed
+        let version = outbound.create_version_msg(None);
+        let (sent, inbound) = tokio::join!(outbound.send(version), async {
+            let mut inbound = Peer::from_connection(1, remote, Config::mainnet()).await?;
+            inbound
+                .receive(Some(Duration::from_secs(1)))
+                .await
+                .map(|msg| (inbound, msg))
+        });
+        sent.unwrap();
+        let (inbound, msg) = inbound.unwrap();
+        assert!(!inbound.is_v2());
+        assert!(matches!(msg, Message::Version(_)));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_v1() {
+        let mut v1_only = Config::mainnet();
+        v1_only.set_v2_transport(false);
+        // A v1 peer accepting our connection hangs up on the v2 handshake, so we reconnect with v1
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (outbound, inbound) = tokio::join!(
+            Peer::at_address(address, NODE_P2P_V2, Config::mainnet()),
+            async {
+                let (first, _) = listener.accept().await.unwrap();
+                let mut first = Peer::from_connection(1, first, v1_only.clone())
+                    .await
+                    .unwrap();
+                // The v2 key doesn't parse as a v1 message header
+                assert!(first.receive(None).await.is_err());
+                drop(first);
+                let (connection, _) = listener.accept().await.unwrap();
+                Peer::from_connection(2, connection, v1_only.clone()).await
+            }
+        );
+        let (mut outbound, mut inbound) = (outbound.unwrap(), inbound.unwrap());
+        assert!(!outbound.is_v2() && !inbound.is_v2());
+        assert_eq!(outbound.session_id(), None);
+        outbound.send(Message::Verack).await.unwrap();
+        assert!(matches!(
+            inbound.receive(Some(Duration::from_secs(1))).await,
+            Ok(Message::Verack)
+        ));
+
+        // A v2 peer accepting a v1 connection notices the v1 version and carries on in v1
+        let (mut outbound, remote) = connected_pair().await;
+        let version = outbound.create_version_msg(None);
+        let (sent, inbound) = tokio::join!(outbound.send(version), async {
+            let mut inbound = Peer::from_connection(3, remote, Config::mainnet()).await?;
+            inbound
+                .receive(Some(Duration::from_secs(1)))
+                .await
+                .map(|msg| (inbound, msg))
+        });
+        sent.unwrap();
+        let (inbound, msg) = inbound.unwrap();
+        assert!(!inbound.is_v2());
+        assert!(matches!(msg, Message::Version(_)));
     }
 }
@@ -0,0 +1,175 @@
+//! Keepalive pings and latency measurement.
+//!
+//! Once a handshake completes, we ping the peer every [`PING_INTERVAL`] with a random nonce and match the `Pong`
+//! which echoes it. A peer which doesn't answer within the ping timeout is assumed dead. The round trip times give
+//! the peer's minimum ping, by which eviction protects the fastest peers, and a decaying average of its latency.
+use crate::{constants, Message, PeerError};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How often to ping each peer (Core's `PING_INTERVAL`)
+pub const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Tracks the pings sent to a single peer, and the round trip times measured from its pongs
+#[derive(Debug)]
+pub struct PingTracker {
+    /// When to send the next ping. `None` until the handshake completes.
+    next_ping: Option<Instant>,
+    /// The nonce of the ping awaiting a pong, and when it was sent
+    pending: Option<(u64, Instant)>,
+    min_rtt: Option<Duration>,
+    total_rtt: Duration,
+    samples: u32,
+    /// A peak-sensitive moving average of the round trip time, like tower's `PeakEwma`
+    ewma_rtt: Duration,
+    last_sample: Option<Instant>,
+}
+
+impl Default for PingTracker {
+    fn default() -> PingTracker {
+        PingTracker::new()
+    }
+}
+
+impl PingTracker {
+    pub fn new() -> PingTracker {
+        PingTracker {
+            next_ping: None,
+            pending: None,
+            min_rtt: None,
+            total_rtt: Duration::from_secs(0),
+            samples: 0,
+            ewma_rtt: constants::DEFAULT_EWMA_RTT,
+            last_sample: None,
+        }
+    }
+
+    /// Starts pinging. The first ping is due immediately.
+    pub fn start(&mut self, now: Instant) {
+        self.next_ping = Some(now);
+    }
+
+    /// The lowest round trip time measured
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+    /// The mean of the round trip times measured
+    pub fn average_rtt(&self) -> Option<Duration> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some(self.total_rtt / self.samples)
+    }
+    /// The moving average of the round trip time, which jumps up immediately when the peer slows down, and decays
+    /// back over [`EWMA_DECAY_RATE`](constants::EWMA_DECAY_RATE) when it recovers
+    pub fn ewma_rtt(&self) -> Duration {
+        self.ewma_rtt
+    }
+
+    /// The next time [`PingTracker::poll`] has something to do
+    pub fn next_deadline(&self, timeout: Duration) -> Option<Instant> {
+        match self.pending {
+            Some((_, sent)) => Some(sent + timeout),
+            None => self.next_ping,
+        }
+    }
+
+    /// Returns a ping to send if one is due, or an error if the outstanding ping has gone unanswered for
+    /// longer than `timeout`.
+    pub fn poll(&mut self, now: Instant, timeout: Duration) -> Result<Option<Message>, PeerError> {
+        match (self.pending, self.next_ping) {
+            (Some((_, sent)), _) if now.duration_since(sent) >= timeout => Err(PeerError::Timeout(
+                format!("No pong received within {} seconds", timeout.as_secs()),
+            )),
+            (None, Some(next)) if next <= now => {
+                let nonce = rand::random();
+                self.pending = Some((nonce, now));
+                Ok(Some(Message::Ping(nonce)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Matches a pong against the outstanding ping, returning the round trip time if it matches.
+    pub fn on_pong(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        match self.pending {
+            Some((expected, sent)) if expected == nonce => {
+                self.pending = None;
+                self.next_ping = Some(now + PING_INTERVAL);
+                let rtt = now.duration_since(sent);
+                self.record(rtt, now);
+                Some(rtt)
+            }
+            _ => {
+                debug!("Ignoring unsolicited pong with nonce {}", nonce);
+                None
+            }
+        }
+    }
+
+    fn record(&mut self, rtt: Duration, now: Instant) {
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+        self.total_rtt += rtt;
+        self.samples += 1;
+        self.ewma_rtt = if rtt > self.ewma_rtt {
+            rtt
+        } else {
+            let elapsed = self
+                .last_sample
+                .map_or(Duration::from_secs(0), |last| now.duration_since(last));
+            let decay = (-elapsed.as_secs_f64() / constants::EWMA_DECAY_RATE.as_secs_f64()).exp();
+            self.ewma_rtt.mul_f64(decay) + rtt.mul_f64(1.0 - decay)
+        };
+        self.last_sample = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+    fn ping_nonce(msg: Option<Message>) -> u64 {
+        match msg {
+            Some(Message::Ping(nonce)) => nonce,
+            other => panic!("Expected a ping, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn measures_round_trips() {
+        let start = Instant::now();
+        let mut pings = PingTracker::new();
+        assert!(pings.poll(start, TIMEOUT).unwrap().is_none());
+        pings.start(start);
+
+        let nonce = ping_nonce(pings.poll(start, TIMEOUT).unwrap());
+        // Only one ping is outstanding at a time
+        assert!(pings.poll(start, TIMEOUT).unwrap().is_none());
+        assert_eq!(pings.on_pong(nonce.wrapping_add(1), start), None);
+        let rtt = pings.on_pong(nonce, start + Duration::from_millis(300));
+        assert_eq!(rtt, Some(Duration::from_millis(300)));
+        assert_eq!(pings.min_rtt(), rtt);
+        // The load estimate starts pessimistic, and decays towards the measured round trip
+        assert!(pings.ewma_rtt() <= constants::DEFAULT_EWMA_RTT);
+
+        let later = start + PING_INTERVAL + Duration::from_millis(300);
+        assert_eq!(pings.next_deadline(TIMEOUT), Some(later));
+        let nonce = ping_nonce(pings.poll(later, TIMEOUT).unwrap());
+        pings.on_pong(nonce, later + Duration::from_millis(100));
+        assert_eq!(pings.min_rtt(), Some(Duration::from_millis(100)));
+        assert_eq!(pings.average_rtt(), Some(Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn times_out_without_pong() {
+        let start = Instant::now();
+        let mut pings = PingTracker::new();
+        pings.start(start);
+        pings.poll(start, TIMEOUT).unwrap();
+        assert_eq!(pings.next_deadline(TIMEOUT), Some(start + TIMEOUT));
+        assert!(pings.poll(start + TIMEOUT / 2, TIMEOUT).unwrap().is_none());
+        assert!(pings.poll(start + TIMEOUT, TIMEOUT).is_err());
+    }
+}
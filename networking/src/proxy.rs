@@ -0,0 +1,544 @@
+//! Outbound connections, made directly or through a SOCKS5 proxy (RFC 1928) such as Tor.
+//!
+//! A [`Connector`] decides how to reach a [`PeerAddress`]: IP peers are dialed directly unless a proxy is configured,
+//! and onion peers always go through the onion proxy. When proxy randomization is on, every connection authenticates
+//! with fresh credentials (RFC 1929), which Tor takes as a request to put it on its own circuit.
+use config::{Config, NetworkKind};
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use tracing::debug;
+
+/// How long to wait for a TCP connection to a peer or proxy
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the proxy has to answer each step of the SOCKS5 negotiation. Building a Tor circuit can take a while.
+pub const SOCKS5_RECV_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The length of a v3 onion address, without the `.onion` suffix
+const ONION_V3_LEN: usize = 56;
+/// The prefix Core uses for addresses which don't map to an IP (`fd6b:88c0:8724::/48`)
+const INTERNAL_PREFIX: [u8; 6] = [0xfd, 0x6b, 0x88, 0xc0, 0x87, 0x24];
+
+const SOCKS_VERSION: u8 = 0x05;
+const SOCKS_NO_AUTH: u8 = 0x00;
+const SOCKS_USER_PASS: u8 = 0x02;
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const SOCKS_USER_PASS_VERSION: u8 = 0x01;
+const SOCKS_CONNECT: u8 = 0x01;
+const SOCKS_IPV4: u8 = 0x01;
+const SOCKS_DOMAIN: u8 = 0x03;
+const SOCKS_IPV6: u8 = 0x04;
+
+/// The address of a peer we can connect to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum PeerAddress {
+    Ip(SocketAddr),
+    /// A Tor v3 onion service, e.g. `<56 base32 characters>.onion`
+    Onion {
+        host: String,
+        port: u16,
+    },
+}
+
+impl PeerAddress {
+    /// The network the peer is on
+    pub fn network(&self) -> NetworkKind {
+        match self {
+            PeerAddress::Ip(SocketAddr::V4(_)) => NetworkKind::Ipv4,
+            PeerAddress::Ip(SocketAddr::V6(addr)) => match addr.ip().to_ipv4_mapped() {
+                Some(_) => NetworkKind::Ipv4,
+                None => NetworkKind::Ipv6,
+            },
+            PeerAddress::Onion { .. } => NetworkKind::Onion,
+        }
+    }
+
+    /// The socket address which identifies the peer. Onion peers have no IP, so like Core, we make one up in an
+    /// internal range from a hash of the onion name.
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self {
+            PeerAddress::Ip(addr) => *addr,
+            PeerAddress::Onion { host, port } => {
+                let hash = warp_crypto::sha256(host.as_bytes());
+                let mut ip = [0u8; 16];
+                ip[..INTERNAL_PREFIX.len()].copy_from_slice(&INTERNAL_PREFIX);
+                ip[INTERNAL_PREFIX.len()..].copy_from_slice(&hash[..16 - INTERNAL_PREFIX.len()]);
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), *port)
+            }
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            PeerAddress::Ip(addr) => addr.port(),
+            PeerAddress::Onion { port, .. } => *port,
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddress {
+    fn from(addr: SocketAddr) -> PeerAddress {
+        PeerAddress::Ip(addr)
+    }
+}
+
+impl FromStr for PeerAddress {
+    type Err = String;
+
+    /// Parses `ip:port`, `[ipv6]:port` or `name.onion:port`
+    fn from_str(s: &str) -> Result<PeerAddress, String> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(PeerAddress::Ip(addr));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("{} has no port", s))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("{} is not a valid port", port))?;
+        let host = host.to_ascii_lowercase();
+        let name = host
+            .strip_suffix(".onion")
+            .ok_or_else(|| format!("{} is neither an IP address nor an onion address", host))?;
+        if name.len() != ONION_V3_LEN
+            || !name
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b))
+        {
+            return Err(format!("{} is not a v3 onion address", host));
+        }
+        Ok(PeerAddress::Onion { host, port })
+    }
+}
+
+impl fmt::Display for PeerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerAddress::Ip(addr) => addr.fmt(f),
+            PeerAddress::Onion { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// SOCKS5 username and password. Tor ignores their contents, but isolates streams which use different ones.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl ProxyCredentials {
+    /// Fresh credentials, for a connection which shouldn't share a Tor circuit with any other
+    pub fn random() -> ProxyCredentials {
+        ProxyCredentials {
+            username: format!("{:016x}", rand::random::<u64>()),
+            password: format!("{:016x}", rand::random::<u64>()),
+        }
+    }
+}
+
+/// Makes outbound connections according to the proxy and `onlynet` settings
+#[derive(Clone, Debug, Default)]
+pub struct Connector {
+    proxy: Option<SocketAddr>,
+    onion_proxy: Option<SocketAddr>,
+    randomize_credentials: bool,
+    only_net: Vec<NetworkKind>,
+}
+
+impl Connector {
+    /// A connector which dials every peer directly
+    pub fn direct() -> Connector {
+        Connector::default()
+    }
+
+    pub fn from_config(config: &Config) -> Connector {
+        Connector {
+            proxy: config.proxy(),
+            onion_proxy: config.onion_proxy(),
+            randomize_credentials: config.proxy_randomize(),
+            only_net: config.only_net().to_vec(),
+        }
+    }
+
+    /// Whether we're allowed to, and able to, connect to `address`
+    pub fn is_reachable(&self, address: &PeerAddress) -> bool {
+        let network = address.network();
+        if !self.only_net.is_empty() && !self.only_net.contains(&network) {
+            return false;
+        }
+        network != NetworkKind::Onion || self.onion_proxy.is_some()
+    }
+
+    /// Opens a connection to `address`, through the appropriate proxy if there is one
+    pub async fn connect(&self, address: &PeerAddress) -> io::Result<TcpStream> {
+        if !self.is_reachable(address) {
+            return Err(io::Error::other(format!("{} is not reachable", address)));
+        }
+        let proxy = match address {
+            PeerAddress::Ip(addr) => match self.proxy {
+                Some(proxy) => proxy,
+                None => return timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await?,
+            },
+            PeerAddress::Onion { .. } => self
+                .onion_proxy
+                .expect("Onion peers are unreachable without a proxy"),
+        };
+        let credentials = if self.randomize_credentials {
+            Some(ProxyCredentials::random())
+        } else {
+            None
+        };
+        debug!("Connecting to {} through proxy {}", address, proxy);
+        socks5_connect(proxy, address, credentials.as_ref()).await
+    }
+}
+
+/// Connects to `target` through the SOCKS5 proxy at `proxy`, authenticating with `credentials` if given
+pub async fn socks5_connect(
+    proxy: SocketAddr,
+    target: &PeerAddress,
+    credentials: Option<&ProxyCredentials>,
+) -> io::Result<TcpStream> {
+    let mut stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(proxy)).await??;
+
+    // Offer username/password authentication only if we have credentials
+    let greeting: &[u8] = match credentials {
+        Some(_) => &[SOCKS_VERSION, 2, SOCKS_NO_AUTH, SOCKS_USER_PASS],
+        None => &[SOCKS_VERSION, 1, SOCKS_NO_AUTH],
+    };
+    stream.write_all(greeting).await?;
+    let mut choice = [0u8; 2];
+    read_exact(&mut stream, &mut choice).await?;
+    if choice[0] != SOCKS_VERSION {
+        return Err(socks_error("Proxy doesn't speak SOCKS5"));
+    }
+    match (choice[1], credentials) {
+        (SOCKS_NO_AUTH, _) => {}
+        (SOCKS_USER_PASS, Some(credentials)) => authenticate(&mut stream, credentials).await?,
+        (SOCKS_NO_ACCEPTABLE_METHODS, _) => {
+            return Err(socks_error(
+                "Proxy requires an unsupported authentication method",
+            ))
+        }
+        _ => {
+            return Err(socks_error(
+                "Proxy chose an authentication method we didn't offer",
+            ))
+        }
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    match target {
+        PeerAddress::Ip(SocketAddr::V4(addr)) => {
+            request.push(SOCKS_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        PeerAddress::Ip(SocketAddr::V6(addr)) => {
+            request.push(SOCKS_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        PeerAddress::Onion { host, .. } => {
+            request.push(SOCKS_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    read_exact(&mut stream, &mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(socks_error("Malformed reply from proxy"));
+    }
+    if reply[1] != 0 {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!(
+                "Proxy could not connect to {}: {}",
+                target,
+                reply_error(reply[1])
+            ),
+        ));
+    }
+    // Skip the address the proxy bound, which we have no use for
+    let address_len = match reply[3] {
+        SOCKS_IPV4 => 4,
+        SOCKS_IPV6 => 16,
+        SOCKS_DOMAIN => {
+            let mut len = [0u8; 1];
+            read_exact(&mut stream, &mut len).await?;
+            len[0] as usize
+        }
+        _ => return Err(socks_error("Malformed reply from proxy")),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    read_exact(&mut stream, &mut bound).await?;
+    Ok(stream)
+}
+
+/// Username/password authentication (RFC 1929)
+async fn authenticate(stream: &mut TcpStream, credentials: &ProxyCredentials) -> io::Result<()> {
+    let (username, password) = (
+        credentials.username.as_bytes(),
+        credentials.password.as_bytes(),
+    );
+    if username.len() > 255 || password.len() > 255 {
+        return Err(socks_error("Proxy credentials are too long"));
+    }
+    let mut request = vec![SOCKS_USER_PASS_VERSION, username.len() as u8];
+    request.extend_from_slice(username);
+    request.push(password.len() as u8);
+    request.extend_from_slice(password);
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 2];
+    read_exact(stream, &mut reply).await?;
+    if reply[0] != SOCKS_USER_PASS_VERSION || reply[1] != 0 {
+        return Err(socks_error("Proxy rejected our credentials"));
+    }
+    Ok(())
+}
+
+async fn read_exact(stream: &mut TcpStream, buf: &mut [u8]) -> io::Result<()> {
+    timeout(SOCKS5_RECV_TIMEOUT, stream.read_exact(buf)).await??;
+    Ok(())
+}
+
+fn socks_error(cause: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, cause.to_string())
+}
+
+/// Describes a SOCKS5 reply code, including the extended codes Tor uses for onion services
+fn reply_error(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "connection not allowed",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        0xf0 => "onion service descriptor can not be found",
+        0xf1 => "onion service descriptor is invalid",
+        0xf2 => "onion service introduction failed",
+        0xf3 => "onion service rendezvous failed",
+        0xf4 => "onion service missing client authorization",
+        0xf5 => "onion service wrong client authorization",
+        0xf6 => "onion service invalid address",
+        0xf7 => "onion service introduction timed out",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const ONION: &str = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+
+    /// A SOCKS5 proxy which handles one connection, requiring credentials if `require_auth` is set. Returns the
+    /// proxy's address, and a handle resolving to the credentials and connect request it received.
+    async fn socks5_stub(
+        require_auth: bool,
+    ) -> (
+        SocketAddr,
+        tokio::task::JoinHandle<(Option<ProxyCredentials>, Vec<u8>)>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut header = [0u8; 2];
+            stream.read_exact(&mut header).await.unwrap();
+            let mut methods = vec![0u8; header[1] as usize];
+            stream.read_exact(&mut methods).await.unwrap();
+
+            let mut credentials = None;
+            if require_auth {
+                if !methods.contains(&SOCKS_USER_PASS) {
+                    stream
+                        .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS])
+                        .await
+                        .unwrap();
+                    return (None, Vec::new());
+                }
+                stream
+                    .write_all(&[SOCKS_VERSION, SOCKS_USER_PASS])
+                    .await
+                    .unwrap();
+                let mut version = [0u8; 1];
+                stream.read_exact(&mut version).await.unwrap();
+                let mut fields = Vec::new();
+                for _ in 0..2 {
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len).await.unwrap();
+                    let mut field = vec![0u8; len[0] as usize];
+                    stream.read_exact(&mut field).await.unwrap();
+                    fields.push(String::from_utf8(field).unwrap());
+                }
+                credentials = Some(ProxyCredentials {
+                    password: fields.pop().unwrap(),
+                    username: fields.pop().unwrap(),
+                });
+                stream
+                    .write_all(&[SOCKS_USER_PASS_VERSION, 0])
+                    .await
+                    .unwrap();
+            } else {
+                stream
+                    .write_all(&[SOCKS_VERSION, SOCKS_NO_AUTH])
+                    .await
+                    .unwrap();
+            }
+
+            let mut request = vec![0u8; 4];
+            stream.read_exact(&mut request).await.unwrap();
+            let address_len = match request[3] {
+                SOCKS_IPV4 => 4,
+                SOCKS_IPV6 => 16,
+                _ => {
+                    let mut len = [0u8; 1];
+                    stream.read_exact(&mut len).await.unwrap();
+                    request.push(len[0]);
+                    len[0] as usize
+                }
+            };
+            let mut rest = vec![0u8; address_len + 2];
+            stream.read_exact(&mut rest).await.unwrap();
+            request.extend_from_slice(&rest);
+            // Succeed, bound to 0.0.0.0:0, then echo a byte through the tunnel
+            stream
+                .write_all(&[SOCKS_VERSION, 0, 0, SOCKS_IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).await.unwrap();
+            stream.write_all(&byte).await.unwrap();
+            (credentials, request)
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn parses_peer_addresses() {
+        let ip: PeerAddress = "127.0.0.1:8333".parse().unwrap();
+        assert_eq!(ip.network(), NetworkKind::Ipv4);
+        assert_eq!(ip.socket_addr(), "127.0.0.1:8333".parse().unwrap());
+        let ipv6: PeerAddress = "[2001:db8::1]:8333".parse().unwrap();
+        assert_eq!(ipv6.network(), NetworkKind::Ipv6);
+
+        let onion: PeerAddress = format!("{}:8333", ONION.to_uppercase()).parse().unwrap();
+        assert_eq!(onion.network(), NetworkKind::Onion);
+        assert_eq!(onion.to_string(), format!("{}:8333", ONION));
+        // Onion peers get a stable placeholder address in Core's internal range
+        let placeholder = onion.socket_addr();
+        assert_eq!(placeholder, onion.socket_addr());
+        assert_eq!(placeholder.port(), 8333);
+        match placeholder.ip() {
+            IpAddr::V6(ip) => assert_eq!(&ip.octets()[..6], &INTERNAL_PREFIX),
+            IpAddr::V4(_) => panic!("Expected an IPv6 placeholder"),
+        }
+
+        assert!("example.com:8333".parse::<PeerAddress>().is_err());
+        assert!("abc.onion:8333".parse::<PeerAddress>().is_err());
+        assert!(ONION.parse::<PeerAddress>().is_err());
+    }
+
+    #[test]
+    fn onlynet_limits_reachable_peers() {
+        let onion: PeerAddress = format!("{}:8333", ONION).parse().unwrap();
+        let ip: PeerAddress = "127.0.0.1:8333".parse().unwrap();
+        let mut config = Config::mainnet();
+        assert!(Connector::from_config(&config).is_reachable(&ip));
+        assert!(!Connector::from_config(&config).is_reachable(&onion));
+
+        config.set_onion_proxy(Some("127.0.0.1:9050".parse().unwrap()));
+        config.set_only_net(vec![NetworkKind::Onion]);
+        let connector = Connector::from_config(&config);
+        assert!(connector.is_reachable(&onion));
+        assert!(!connector.is_reachable(&ip));
+    }
+
+    #[tokio::test]
+    async fn connects_to_onions_with_isolated_credentials() {
+        let mut credentials = Vec::new();
+        for _ in 0..2 {
+            let (proxy, stub) = socks5_stub(true).await;
+            let mut config = Config::mainnet();
+            config.set_proxy(Some(proxy));
+            let onion: PeerAddress = format!("{}:8333", ONION).parse().unwrap();
+            let mut stream = Connector::from_config(&config)
+                .connect(&onion)
+                .await
+                .unwrap();
+            stream.write_all(&[42]).await.unwrap();
+            let mut echo = [0u8; 1];
+            stream.read_exact(&mut echo).await.unwrap();
+            assert_eq!(echo, [42]);
+
+            let (used, request) = stub.await.unwrap();
+            // The onion name is sent for the proxy to resolve
+            let mut expected = vec![SOCKS_VERSION, SOCKS_CONNECT, 0, SOCKS_DOMAIN, 62];
+            expected.extend_from_slice(ONION.as_bytes());
+            expected.extend_from_slice(&8333u16.to_be_bytes());
+            assert_eq!(request, expected);
+            credentials.push(used.unwrap());
+        }
+        assert_ne!(credentials[0], credentials[1]);
+    }
+
+    #[tokio::test]
+    async fn proxies_ip_connections_without_credentials() {
+        let (proxy, stub) = socks5_stub(false).await;
+        let mut config = Config::mainnet();
+        config.set_proxy(Some(proxy));
+        config.set_proxy_randomize(false);
+        let target: PeerAddress = "10.0.0.1:8333".parse().unwrap();
+        let mut stream = Connector::from_config(&config)
+            .connect(&target)
+            .await
+            .unwrap();
+        stream.write_all(&[7]).await.unwrap();
+        let mut echo = [0u8; 1];
+        stream.read_exact(&mut echo).await.unwrap();
+
+        let (used, request) = stub.await.unwrap();
+        assert_eq!(used, None);
+        assert_eq!(
+            request,
+            vec![
+                SOCKS_VERSION,
+                SOCKS_CONNECT,
+                0,
+                SOCKS_IPV4,
+                10,
+                0,
+                0,
+                1,
+                0x20,
+                0x8d
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_proxy_failures() {
+        let (proxy, _stub) = socks5_stub(true).await;
+        let target: PeerAddress = "10.0.0.1:8333".parse().unwrap();
+        // The stub insists on credentials, and we have none to offer
+        let result = socks5_connect(proxy, &target, None).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,467 @@
+//! Transaction and block announcement.
+//!
+//! Like Bitcoin Core, we don't announce transactions the moment we accept them. Instead, each peer's announcements
+//! are queued and flushed in a single `Inv` on a timer whose intervals are exponentially distributed (i.e. the
+//! sends form a Poisson process). This batches announcements, and makes it much harder for an observer connected to
+//! many nodes to work out where a transaction originated. Inbound peers share a single timer, so that an attacker
+//! can't learn more by making extra connections to us.
+//!
+//! Blocks are time sensitive, so they are announced on the next call to [`Relay::poll`] regardless of the timers.
+//!
+//! Peers which negotiated wtxid relay (BIP339) have transactions announced by wtxid instead of txid.
+use crate::{InventoryHash, Message};
+use rand::Rng;
+use shared::{BlockHash, InventoryData, TxID};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The average time between transaction announcements to inbound peers
+pub const INBOUND_INVENTORY_BROADCAST_INTERVAL: Duration = Duration::from_secs(5);
+/// The average time between transaction announcements to outbound peers. Outbound peers are chosen by us, so
+/// they're less likely to be spies, and we can afford to relay to them faster.
+pub const OUTBOUND_INVENTORY_BROADCAST_INTERVAL: Duration = Duration::from_secs(2);
+/// The most transactions announced to a peer in a single `Inv`. Any others wait for the next one.
+pub const INVENTORY_BROADCAST_MAX: usize = 1000;
+/// The number of announcements remembered for each peer (the size of Core's `m_tx_inventory_known_filter`)
+pub const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// The inventory a peer is known to have, because it announced it to us, sent it to us, or we announced it to them.
+///
+/// Only the most recent [`MAX_KNOWN_INVENTORY`] items are remembered, so a peer can't exhaust our memory.
+#[derive(Debug)]
+pub struct KnownInventory {
+    capacity: usize,
+    items: HashSet<InventoryHash>,
+    order: VecDeque<InventoryHash>,
+}
+
+impl KnownInventory {
+    pub fn new(capacity: usize) -> KnownInventory {
+        KnownInventory {
+            capacity,
+            items: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    pub fn contains(&self, item: &InventoryHash) -> bool {
+        self.items.contains(item)
+    }
+
+    /// Remembers an item, forgetting the oldest if we're at capacity. Returns `false` if it was already known.
+    pub fn insert(&mut self, item: InventoryHash) -> bool {
+        if !self.items.insert(item.clone()) {
+            return false;
+        }
+        self.order.push_back(item);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.items.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// What we know about a single peer for the purposes of relay
+#[derive(Debug)]
+struct PeerRelay {
+    inbound: bool,
+    /// The `relay` flag from the peer's `Version`. If false, the peer doesn't want transaction announcements.
+    relay_txs: bool,
+    /// The minimum fee rate (in satoshis per 1000 bytes) of transactions the peer wants announced, from `FeeFilter`
+    fee_filter: u64,
+    /// Whether the peer wants transactions announced by wtxid
+    wtxid_relay: bool,
+    known: KnownInventory,
+    pending_txs: HashSet<TxID>,
+    pending_blocks: Vec<BlockHash>,
+    /// When transactions are next announced to this peer. Unused for inbound peers, which share a timer.
+    next_inv_send: Instant,
+}
+
+/// Decides which inventory to announce to which peers, and when.
+///
+/// The relay doesn't do any IO itself. The caller registers peers as their handshakes complete, feeds it what
+/// peers tell us and what we want to announce, and periodically calls [`Relay::poll`] to collect the `Inv`
+/// messages which are due.
+#[derive(Debug)]
+pub struct Relay {
+    peers: HashMap<SocketAddr, PeerRelay>,
+    next_inbound_inv_send: Option<Instant>,
+}
+
+impl Default for Relay {
+    fn default() -> Relay {
+        Relay::new()
+    }
+}
+
+impl Relay {
+    pub fn new() -> Relay {
+        Relay {
+            peers: HashMap::new(),
+            next_inbound_inv_send: None,
+        }
+    }
+
+    /// Starts relaying to a peer whose handshake has completed. `relay_txs` is the `relay` flag from its `Version`.
+    pub fn add_peer(&mut self, peer: SocketAddr, inbound: bool, relay_txs: bool, now: Instant) {
+        let interval = if inbound {
+            INBOUND_INVENTORY_BROADCAST_INTERVAL
+        } else {
+            OUTBOUND_INVENTORY_BROADCAST_INTERVAL
+        };
+        self.peers.insert(
+            peer,
+            PeerRelay {
+                inbound,
+                relay_txs,
+                fee_filter: 0,
+                wtxid_relay: false,
+                known: KnownInventory::new(MAX_KNOWN_INVENTORY),
+                pending_txs: HashSet::new(),
+                pending_blocks: Vec::new(),
+                next_inv_send: poisson_next_send(now, interval),
+            },
+        );
+    }
+
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+
+    pub fn num_peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Records the fee rate from a peer's `FeeFilter` message. Transactions paying less won't be announced to it.
+    pub fn set_fee_filter(&mut self, peer: &SocketAddr, fee_rate: u64) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.fee_filter = fee_rate;
+        }
+    }
+
+    /// Records whether a peer negotiated wtxid relay during its handshake
+    pub fn set_wtxid_relay(&mut self, peer: &SocketAddr, wtxid_relay: bool) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.wtxid_relay = wtxid_relay;
+        }
+    }
+
+    /// Records that a peer has an item, because it announced or sent it to us. We won't announce it back.
+    pub fn mark_known(&mut self, peer: &SocketAddr, item: InventoryHash) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            if let InventoryHash::Tx(txid) = &item {
+                state.pending_txs.remove(txid);
+            }
+            state.known.insert(item);
+        }
+    }
+
+    /// Records that a peer has a transaction, by whichever of its hashes we'd announce it to the peer with.
+    pub fn mark_transaction_known(&mut self, peer: &SocketAddr, txid: &TxID, wtxid: &TxID) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.pending_txs.remove(txid);
+            if state.wtxid_relay {
+                state.known.insert(InventoryHash::Wtx(wtxid.clone()));
+            } else {
+                state.known.insert(InventoryHash::Tx(txid.clone()));
+            }
+        }
+    }
+
+    /// Whether a peer is known to have an item
+    pub fn is_known(&self, peer: &SocketAddr, item: &InventoryHash) -> bool {
+        self.peers
+            .get(peer)
+            .map(|state| state.known.contains(item))
+            .unwrap_or(false)
+    }
+
+    /// Queues transactions to be announced to every peer which wants them and doesn't already have them.
+    pub fn advertise_transactions<I: IntoIterator<Item = TxID>>(&mut self, txids: I) {
+        for txid in txids {
+            let item = InventoryHash::Tx(txid.clone());
+            for state in self.peers.values_mut() {
+                if state.relay_txs && !state.known.contains(&item) {
+                    state.pending_txs.insert(txid.clone());
+                }
+            }
+        }
+    }
+
+    /// Queues blocks to be announced to every peer which doesn't already have them.
+    pub fn advertise_blocks<I: IntoIterator<Item = BlockHash>>(&mut self, hashes: I) {
+        for hash in hashes {
+            let item = InventoryHash::Block(hash.clone());
+            for state in self.peers.values_mut() {
+                if !state.known.contains(&item) && !state.pending_blocks.contains(&hash) {
+                    state.pending_blocks.push(hash.clone());
+                }
+            }
+        }
+    }
+
+    /// The earliest time at which [`Relay::poll`] might have transactions to announce
+    pub fn next_send(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter(|state| !state.pending_txs.is_empty())
+            .map(|state| match (state.inbound, self.next_inbound_inv_send) {
+                (true, Some(shared_timer)) => shared_timer,
+                (true, None) => state.next_inv_send,
+                (false, _) => state.next_inv_send,
+            })
+            .min()
+    }
+
+    /// Collects the `Inv` messages which are due to be sent.
+    ///
+    /// `lookup` finds the fee rate (in satoshis per 1000 bytes) and wtxid of a mempool transaction. Transactions
+    /// which have left the mempool since they were queued are dropped, as are those below a peer's fee filter. The
+    /// highest paying transactions are announced first.
+    pub fn poll<F>(&mut self, now: Instant, lookup: F) -> Vec<(SocketAddr, Message)>
+    where
+        F: Fn(&TxID) -> Option<(u64, TxID)>,
+    {
+        let inbound_due = match self.next_inbound_inv_send {
+            Some(next) => next <= now,
+            None => true,
+        };
+        if inbound_due {
+            self.next_inbound_inv_send =
+                Some(poisson_next_send(now, INBOUND_INVENTORY_BROADCAST_INTERVAL));
+        }
+
+        let mut messages = Vec::new();
+        for (peer, state) in self.peers.iter_mut() {
+            let mut inventory: Vec<InventoryData> = Vec::new();
+            for hash in state.pending_blocks.drain(..) {
+                let item = InventoryHash::Block(hash);
+                inventory.extend(item.inventory_data());
+                state.known.insert(item);
+            }
+
+            let txs_due = if state.inbound {
+                inbound_due
+            } else if state.next_inv_send <= now {
+                state.next_inv_send = poisson_next_send(now, OUTBOUND_INVENTORY_BROADCAST_INTERVAL);
+                true
+            } else {
+                false
+            };
+            if txs_due && !state.pending_txs.is_empty() {
+                let fee_filter = state.fee_filter;
+                let mut candidates: Vec<(u64, TxID, TxID)> = state
+                    .pending_txs
+                    .iter()
+                    .filter_map(|txid| {
+                        lookup(txid).map(|(rate, wtxid)| (rate, txid.clone(), wtxid))
+                    })
+                    .collect();
+                // Keep the order independent of the hash set's, so the result doesn't leak anything about it
+                candidates.sort_by_key(|(rate, txid, _)| (Reverse(*rate), *txid.inner()));
+                let mut remaining = HashSet::new();
+                let mut announced = 0;
+                for (rate, txid, wtxid) in candidates {
+                    if rate < fee_filter {
+                        continue;
+                    }
+                    if announced == INVENTORY_BROADCAST_MAX {
+                        remaining.insert(txid);
+                        continue;
+                    }
+                    let item = if state.wtxid_relay {
+                        InventoryHash::Wtx(wtxid)
+                    } else {
+                        InventoryHash::Tx(txid)
+                    };
+                    if state.known.insert(item.clone()) {
+                        inventory.extend(item.inventory_data());
+                        announced += 1;
+                    }
+                }
+                state.pending_txs = remaining;
+            }
+
+            if !inventory.is_empty() {
+                messages.push((*peer, Message::Inv(inventory)));
+            }
+        }
+        messages
+    }
+}
+
+/// Picks the next send time for a Poisson process with the given average interval
+fn poisson_next_send(now: Instant, average_interval: Duration) -> Instant {
+    let uniform: f64 = rand::thread_rng().gen();
+    // -ln(1 - U) is exponentially distributed with mean 1. Using 1 - U avoids taking the log of zero.
+    now + average_interval.mul_f64(-(1.0 - uniform).ln())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{u256, InventoryType};
+
+    // A Poisson timer is almost certain to have fired after this long
+    const LATER: Duration = Duration::from_secs(60 * 60);
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn txid(byte: u8) -> TxID {
+        TxID::from([byte; 32])
+    }
+
+    fn announced(messages: &[(SocketAddr, Message)], to: SocketAddr) -> Vec<u256> {
+        messages
+            .iter()
+            .filter(|(addr, _)| *addr == to)
+            .flat_map(|(_, msg)| match msg {
+                Message::Inv(inventory) => inventory
+                    .iter()
+                    .map(|inv| inv.hash.clone())
+                    .collect::<Vec<_>>(),
+                other => panic!("Unexpected message {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn announces_transactions_peers_want() {
+        let now = Instant::now();
+        let mut relay = Relay::new();
+        relay.add_peer(peer(1), false, true, now);
+        relay.add_peer(peer(2), true, true, now);
+        relay.add_peer(peer(3), true, false, now);
+        relay.add_peer(peer(4), false, true, now);
+        relay.set_fee_filter(&peer(4), 2000);
+        // Peer 1 told us about the first transaction, so it shouldn't hear about it from us
+        relay.mark_known(&peer(1), InventoryHash::Tx(txid(1)));
+
+        relay.advertise_transactions(vec![txid(1), txid(2)]);
+        let fee_rate = |txid: &TxID| {
+            let rate = if txid.inner()[0] == 1 { 1000 } else { 5000 };
+            Some((rate, txid.clone()))
+        };
+        let messages = relay.poll(now + LATER, fee_rate);
+
+        let expected = |bytes: &[u8]| -> Vec<u256> {
+            bytes.iter().map(|b| u256::from_bytes([*b; 32])).collect()
+        };
+        assert_eq!(announced(&messages, peer(1)), expected(&[2]));
+        // Higher fee rates are announced first
+        assert_eq!(announced(&messages, peer(2)), expected(&[2, 1]));
+        // Peer 3 asked not to receive transactions
+        assert!(announced(&messages, peer(3)).is_empty());
+        // The first transaction is below peer 4's fee filter
+        assert_eq!(announced(&messages, peer(4)), expected(&[2]));
+        assert!(relay.is_known(&peer(2), &InventoryHash::Tx(txid(1))));
+
+        // Nothing is announced twice
+        relay.advertise_transactions(vec![txid(1), txid(2)]);
+        assert!(relay.poll(now + LATER * 2, fee_rate).is_empty());
+        assert_eq!(relay.next_send(), None);
+    }
+
+    #[test]
+    fn trickles_transactions_but_not_blocks() {
+        let now = Instant::now();
+        let mut relay = Relay::new();
+        relay.add_peer(peer(1), false, false, now);
+        relay.add_peer(peer(2), false, true, now);
+
+        relay.advertise_transactions(vec![txid(1)]);
+        relay.advertise_blocks(vec![BlockHash::from([9; 32])]);
+        let messages = relay.poll(now, |txid| Some((1000, txid.clone())));
+        // Blocks go out immediately, even to peers which don't want transactions
+        assert_eq!(
+            announced(&messages, peer(1)),
+            vec![u256::from_bytes([9; 32])]
+        );
+        assert_eq!(
+            announced(&messages, peer(2)),
+            vec![u256::from_bytes([9; 32])]
+        );
+        assert!(relay.next_send().unwrap() > now);
+
+        // Transactions which left the mempool before their announcement are dropped
+        relay.advertise_transactions(vec![txid(2)]);
+        let messages = relay.poll(now + LATER, |txid| {
+            if txid.inner()[0] == 1 {
+                Some((1000, txid.clone()))
+            } else {
+                None
+            }
+        });
+        assert_eq!(
+            announced(&messages, peer(2)),
+            vec![u256::from_bytes([1; 32])]
+        );
+
+        relay.remove_peer(&peer(2));
+        assert_eq!(relay.num_peers(), 1);
+    }
+
+    #[test]
+    fn announces_by_wtxid_to_peers_which_opt_in() {
+        let now = Instant::now();
+        let mut relay = Relay::new();
+        relay.add_peer(peer(1), false, true, now);
+        relay.add_peer(peer(2), false, true, now);
+        relay.add_peer(peer(3), false, true, now);
+        relay.set_wtxid_relay(&peer(2), true);
+        relay.set_wtxid_relay(&peer(3), true);
+        // Peer 3 sent us the transaction, so already has it
+        relay.mark_transaction_known(&peer(3), &txid(1), &txid(2));
+
+        relay.advertise_transactions(vec![txid(1)]);
+        let messages = relay.poll(now + LATER, |_| Some((1000, txid(2))));
+        let inventory = |to: SocketAddr| -> Vec<(u8, u256)> {
+            messages
+                .iter()
+                .filter(|(addr, _)| *addr == to)
+                .flat_map(|(_, msg)| match msg {
+                    Message::Inv(inventory) => inventory
+                        .iter()
+                        .map(|inv| (inv.inventory_type as u8, inv.hash.clone()))
+                        .collect::<Vec<_>>(),
+                    other => panic!("Unexpected message {:?}", other),
+                })
+                .collect()
+        };
+        assert_eq!(
+            inventory(peer(1)),
+            vec![(InventoryType::Tx as u8, u256::from_bytes([1; 32]))]
+        );
+        assert_eq!(
+            inventory(peer(2)),
+            vec![(InventoryType::WitnessTx as u8, u256::from_bytes([2; 32]))]
+        );
+        assert!(inventory(peer(3)).is_empty());
+        assert!(relay.is_known(&peer(2), &InventoryHash::Wtx(txid(2))));
+    }
+
+    #[test]
+    fn forgets_oldest_known_inventory() {
+        let mut known = KnownInventory::new(2);
+        assert!(known.insert(InventoryHash::Tx(txid(1))));
+        assert!(!known.insert(InventoryHash::Tx(txid(1))));
+        known.insert(InventoryHash::Tx(txid(2)));
+        known.insert(InventoryHash::Tx(txid(3)));
+        assert_eq!(known.len(), 2);
+        assert!(!known.contains(&InventoryHash::Tx(txid(1))));
+        assert!(known.contains(&InventoryHash::Tx(txid(3))));
+    }
+}
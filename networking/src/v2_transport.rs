@@ -0,0 +1,734 @@
+//! The encrypted v2 transport (BIP324).
+//!
+//! A v2 connection starts with each side sending an ElligatorSwift encoded public key followed by up to
+//! [`MAX_GARBAGE_LEN`] bytes of random garbage, so that the stream is indistinguishable from random bytes. Both sides
+//! derive the session keys from the shared secret, then send a garbage terminator and a version packet which
+//! authenticates the garbage. From then on, every message travels in a packet made of an encrypted 3 byte length,
+//! and the header byte and contents sealed with ChaCha20-Poly1305. Packets with the [`IGNORE`] bit set are decoys,
+//! which carry no message. Commonly used commands are sent as a single byte id instead of their 12 byte name.
+//!
+//! Once the handshake completes, [`V2Codec`] frames messages, and [`Transport`] lets a peer use either it or the
+//! plaintext [`BitcoinCodec`].
+use crate::{command::Command, BitcoinCodec, Message};
+use bytes::{Buf, BufMut, BytesMut};
+use shared::{Deserializable, DeserializationError, Serializable};
+use std::{fmt, io};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace};
+use warp_crypto::bip324::{self, FsChaCha20, FsChaCha20Poly1305, ELLSWIFT_LEN, TAG_LEN};
+
+/// The most garbage either side may send before its garbage terminator
+pub const MAX_GARBAGE_LEN: usize = 4095;
+/// The header bit which marks a decoy packet, to be discarded by the receiver
+pub const IGNORE: u8 = 0x80;
+
+const GARBAGE_TERMINATOR_LEN: usize = 16;
+const LENGTH_LEN: usize = 3;
+const HEADER_LEN: usize = 1;
+/// The length of a command sent by name rather than by short id
+const COMMAND_LEN: usize = 12;
+
+/// The BIP324 parameters of a connection, derived from the ECDH shared secret
+struct SessionKeys {
+    session_id: [u8; 32],
+    send_length: [u8; 32],
+    send_packet: [u8; 32],
+    receive_length: [u8; 32],
+    receive_packet: [u8; 32],
+    send_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+    receive_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+}
+
+impl SessionKeys {
+    fn derive(shared_secret: &[u8; 32], magic: u32, initiator: bool) -> SessionKeys {
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&magic.to_le_bytes());
+        let prk = bip324::hkdf_sha256_extract(&salt, shared_secret);
+        let expand = |info: &[u8]| bip324::hkdf_sha256_expand(&prk, info);
+
+        let terminators = expand(b"garbage_terminators");
+        let mut initiator_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+        let mut responder_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+        initiator_terminator.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_LEN]);
+        responder_terminator.copy_from_slice(&terminators[GARBAGE_TERMINATOR_LEN..]);
+
+        let (initiator_length, initiator_packet) = (expand(b"initiator_L"), expand(b"initiator_P"));
+        let (responder_length, responder_packet) = (expand(b"responder_L"), expand(b"responder_P"));
+        let session_id = expand(b"session_id");
+        if initiator {
+            SessionKeys {
+                session_id,
+                send_length: initiator_length,
+                send_packet: initiator_packet,
+                receive_length: responder_length,
+                receive_packet: responder_packet,
+                send_terminator: initiator_terminator,
+                receive_terminator: responder_terminator,
+            }
+        } else {
+            SessionKeys {
+                session_id,
+                send_length: responder_length,
+                send_packet: responder_packet,
+                receive_length: initiator_length,
+                receive_packet: initiator_packet,
+                send_terminator: responder_terminator,
+                receive_terminator: initiator_terminator,
+            }
+        }
+    }
+}
+
+/// A [Codec](https://tokio-rs.github.io/tokio/doc/tokio_util/codec/index.html) which encrypts and frames
+/// [`Message`s](crate::Message) as BIP324 packets. It's created by the v2 handshake ([`initiate`] or [`respond`]).
+pub struct V2Codec {
+    session_id: [u8; 32],
+    send_length: FsChaCha20,
+    send_packet: FsChaCha20Poly1305,
+    receive_length: FsChaCha20,
+    receive_packet: FsChaCha20Poly1305,
+    /// The decrypted length of the packet being received, once its length has been read
+    pending_len: Option<usize>,
+    /// Packets with longer contents are rejected
+    max_contents_len: usize,
+}
+
+impl fmt::Debug for V2Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("V2Codec")
+            .field("session_id", &self.session_id)
+            .field("pending_len", &self.pending_len)
+            .finish()
+    }
+}
+
+impl V2Codec {
+    fn new(keys: &SessionKeys, max_msg_size: usize) -> V2Codec {
+        V2Codec {
+            session_id: keys.session_id,
+            send_length: FsChaCha20::new(keys.send_length),
+            send_packet: FsChaCha20Poly1305::new(keys.send_packet),
+            receive_length: FsChaCha20::new(keys.receive_length),
+            receive_packet: FsChaCha20Poly1305::new(keys.receive_packet),
+            pending_len: None,
+            max_contents_len: HEADER_LEN + COMMAND_LEN + max_msg_size,
+        }
+    }
+
+    /// Identifies the connection. Both sides compute the same id, so it can be compared out of band to rule out a
+    /// man in the middle.
+    pub fn session_id(&self) -> &[u8; 32] {
+        &self.session_id
+    }
+
+    /// Encrypts a packet carrying `contents` onto `dst`. `aad` must be the garbage we sent for the first packet, and
+    /// empty afterwards.
+    fn encrypt_packet(&mut self, header: u8, contents: &[u8], aad: &[u8], dst: &mut BytesMut) {
+        let mut length = (contents.len() as u32).to_le_bytes();
+        self.send_length.crypt(&mut length[..LENGTH_LEN]);
+
+        let mut plaintext = Vec::with_capacity(HEADER_LEN + contents.len());
+        plaintext.push(header);
+        plaintext.extend_from_slice(contents);
+        let tag = self.send_packet.encrypt(aad, &mut plaintext);
+
+        dst.reserve(LENGTH_LEN + plaintext.len() + TAG_LEN);
+        dst.put_slice(&length[..LENGTH_LEN]);
+        dst.put_slice(&plaintext);
+        dst.put_slice(&tag);
+    }
+
+    /// Encrypts a decoy packet, which the peer discards
+    pub fn encode_decoy(&mut self, len: usize, dst: &mut BytesMut) {
+        self.encrypt_packet(IGNORE, &vec![0u8; len], &[], dst);
+    }
+
+    /// Decrypts the next packet from `src`, returning its header byte and contents, or `None` if it hasn't fully
+    /// arrived yet. `aad` must be the garbage the peer sent for the first packet, and empty afterwards.
+    fn decrypt_packet(
+        &mut self,
+        src: &mut BytesMut,
+        aad: &[u8],
+    ) -> Result<Option<(u8, BytesMut)>, DeserializationError> {
+        let contents_len = match self.pending_len {
+            Some(len) => len,
+            None => {
+                if src.len() < LENGTH_LEN {
+                    return Ok(None);
+                }
+                let mut length = [0u8; 4];
+                length[..LENGTH_LEN].copy_from_slice(&src[..LENGTH_LEN]);
+                self.receive_length.crypt(&mut length[..LENGTH_LEN]);
+                let len = u32::from_le_bytes(length) as usize;
+                if len > self.max_contents_len {
+                    return Err(DeserializationError::Parse(format!(
+                        "v2 packet of {} bytes exceeds the maximum of {}",
+                        len, self.max_contents_len
+                    )));
+                }
+                src.advance(LENGTH_LEN);
+                self.pending_len = Some(len);
+                len
+            }
+        };
+        let packet_len = HEADER_LEN + contents_len + TAG_LEN;
+        if src.len() < packet_len {
+            src.reserve(packet_len - src.len());
+            return Ok(None);
+        }
+        self.pending_len = None;
+        let mut packet = src.split_to(packet_len);
+        let tag = packet.split_off(HEADER_LEN + contents_len);
+        if !self.receive_packet.decrypt(aad, &mut packet, &tag) {
+            return Err(DeserializationError::Parse(String::from(
+                "v2 packet failed authentication",
+            )));
+        }
+        let header = packet[0];
+        packet.advance(HEADER_LEN);
+        Ok(Some((header, packet)))
+    }
+
+    /// Decodes the message carried by a packet's contents: a short id, or a zero followed by the 12 byte command,
+    /// then the payload. Returns `None` for short ids we don't know.
+    fn decode_contents(mut contents: BytesMut) -> Result<Option<Message>, DeserializationError> {
+        if contents.is_empty() {
+            return Err(DeserializationError::Parse(String::from(
+                "v2 packet has no message type",
+            )));
+        }
+        let id = contents.get_u8();
+        let command = if id == 0 {
+            Command::deserialize(&mut contents)?
+        } else {
+            match Command::from_short_id(id) {
+                Some(command) => command,
+                None => {
+                    debug!("Ignoring v2 message with unknown short id {}", id);
+                    return Ok(None);
+                }
+            }
+        };
+        BitcoinCodec::deserialize_payload(command, &mut contents).map(Some)
+    }
+}
+
+impl tokio_util::codec::Encoder<Message> for V2Codec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let command = item.command();
+        let mut contents =
+            Vec::with_capacity(HEADER_LEN + COMMAND_LEN + BitcoinCodec::get_serialized_size(&item));
+        match command.short_id() {
+            Some(id) => contents.push(id),
+            None => {
+                contents.push(0);
+                command.serialize(&mut contents)?;
+            }
+        }
+        BitcoinCodec::serialize_body(&item, &mut contents)?;
+        self.encrypt_packet(0, &contents, &[], dst);
+        Ok(())
+    }
+}
+
+impl tokio_util::codec::Decoder for V2Codec {
+    type Error = DeserializationError;
+
+    type Item = Message;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some((header, contents)) = self.decrypt_packet(src, &[])? {
+            if header & IGNORE != 0 {
+                trace!("Ignoring v2 decoy packet");
+                continue;
+            }
+            if let Some(msg) = V2Codec::decode_contents(contents)? {
+                trace!("Received {:?}", msg);
+                return Ok(Some(msg));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// The codec a peer connection uses: the plaintext v1 protocol, or the encrypted v2 transport
+#[derive(Debug)]
+pub enum Transport {
+    V1(BitcoinCodec),
+    V2(Box<V2Codec>),
+}
+
+impl tokio_util::codec::Encoder<Message> for Transport {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match self {
+            Transport::V1(codec) => codec.encode(item, dst),
+            Transport::V2(codec) => codec.encode(item, dst),
+        }
+    }
+}
+
+impl tokio_util::codec::Decoder for Transport {
+    type Error = DeserializationError;
+
+    type Item = Message;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            Transport::V1(codec) => codec.decode(src),
+            Transport::V2(codec) => codec.decode(src),
+        }
+    }
+}
+
+/// The outcome of the handshake on an inbound connection
+pub enum Handshake {
+    /// The peer speaks v2. Holds the codec, and any bytes received after the handshake.
+    V2(Box<V2Codec>, BytesMut),
+    /// The peer opened with a v1 `Version`. Holds the bytes received so far, for the v1 codec to decode.
+    V1(BytesMut),
+}
+
+/// Performs the v2 handshake on an outbound connection. Returns the codec and any bytes received after the handshake.
+///
+/// A peer which doesn't support v2 will fail to parse our key as a v1 message header and hang up, which shows up
+/// here as an error. The caller is expected to reconnect with v1.
+pub async fn initiate<S>(
+    stream: &mut S,
+    magic: u32,
+    max_msg_size: usize,
+) -> io::Result<(V2Codec, BytesMut)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (secret, our_key, garbage) = ephemeral_key()?;
+    send_key(stream, &our_key, &garbage).await?;
+
+    let mut received = BytesMut::with_capacity(ELLSWIFT_LEN);
+    read_at_least(stream, &mut received, ELLSWIFT_LEN).await?;
+    let their_key = split_key(&mut received);
+    let shared_secret = bip324::ellswift_ecdh(&our_key, &their_key, &secret, true)
+        .expect("Secret key was checked when the public key was created");
+    let keys = SessionKeys::derive(&shared_secret, magic, true);
+    complete(stream, keys, max_msg_size, &garbage, received).await
+}
+
+/// Performs the handshake on an inbound connection, detecting whether the peer speaks v2 or v1.
+pub async fn respond<S>(stream: &mut S, magic: u32, max_msg_size: usize) -> io::Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // A v1 peer opens with the header of its `Version`, which no v2 key may start with
+    let mut v1_prefix = magic.to_le_bytes().to_vec();
+    v1_prefix.extend_from_slice(Command::Version.bytes());
+    let mut received = BytesMut::with_capacity(ELLSWIFT_LEN);
+    read_at_least(stream, &mut received, v1_prefix.len()).await?;
+    if received[..v1_prefix.len()] == v1_prefix[..] {
+        debug!("Peer opened with a v1 version, falling back to v1");
+        return Ok(Handshake::V1(received));
+    }
+
+    read_at_least(stream, &mut received, ELLSWIFT_LEN).await?;
+    let their_key = split_key(&mut received);
+    let (secret, our_key, garbage) = ephemeral_key()?;
+    send_key(stream, &our_key, &garbage).await?;
+    let shared_secret = bip324::ellswift_ecdh(&their_key, &our_key, &secret, false)
+        .expect("Secret key was checked when the public key was created");
+    let keys = SessionKeys::derive(&shared_secret, magic, false);
+    let (codec, leftover) = complete(stream, keys, max_msg_size, &garbage, received).await?;
+    Ok(Handshake::V2(Box::new(codec), leftover))
+}
+
+/// A fresh secret key, its ElligatorSwift encoding, and a random amount of garbage to send after it
+fn ephemeral_key() -> io::Result<([u8; 32], [u8; ELLSWIFT_LEN], Vec<u8>)> {
+    let secret: [u8; 32] = rand::random();
+    let key = bip324::ellswift_create(&secret, rand::random())
+        .ok_or_else(|| io::Error::other("Generated an invalid secret key"))?;
+    let garbage_len = rand::random::<usize>() % (MAX_GARBAGE_LEN + 1);
+    let garbage = (0..garbage_len).map(|_| rand::random()).collect();
+    Ok((secret, key, garbage))
+}
+
+async fn send_key<S>(stream: &mut S, key: &[u8; ELLSWIFT_LEN], garbage: &[u8]) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut out = Vec::with_capacity(ELLSWIFT_LEN + garbage.len());
+    out.extend_from_slice(key);
+    out.extend_from_slice(garbage);
+    stream.write_all(&out).await
+}
+
+fn split_key(received: &mut BytesMut) -> [u8; ELLSWIFT_LEN] {
+    let mut key = [0u8; ELLSWIFT_LEN];
+    key.copy_from_slice(&received.split_to(ELLSWIFT_LEN));
+    key
+}
+
+/// Reads from `stream` until `buf` holds at least `len` bytes
+async fn read_at_least<S>(stream: &mut S, buf: &mut BytesMut, len: usize) -> io::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    while buf.len() < len {
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed during v2 handshake",
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// The second half of the handshake, once the keys are known: sends our garbage terminator and version packet, then
+/// finds the peer's garbage terminator and authenticates its garbage with the first packet which follows.
+async fn complete<S>(
+    stream: &mut S,
+    keys: SessionKeys,
+    max_msg_size: usize,
+    our_garbage: &[u8],
+    mut received: BytesMut,
+) -> io::Result<(V2Codec, BytesMut)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut codec = V2Codec::new(&keys, max_msg_size);
+    let mut out = BytesMut::new();
+    out.put_slice(&keys.send_terminator);
+    // The version packet's contents are reserved for future extensions, and empty for now
+    codec.encrypt_packet(0, &[], our_garbage, &mut out);
+    stream.write_all(&out).await?;
+
+    let garbage_len = loop {
+        if let Some(position) = received
+            .windows(GARBAGE_TERMINATOR_LEN)
+            .position(|window| window == keys.receive_terminator)
+        {
+            if position > MAX_GARBAGE_LEN {
+                return Err(invalid_data("Too much garbage from v2 peer"));
+            }
+            break position;
+        }
+        if received.len() >= MAX_GARBAGE_LEN + GARBAGE_TERMINATOR_LEN {
+            return Err(invalid_data("No garbage terminator from v2 peer"));
+        }
+        let wanted = received.len() + 1;
+        read_at_least(stream, &mut received, wanted).await?;
+    };
+    let mut aad = received.split_to(garbage_len);
+    received.advance(GARBAGE_TERMINATOR_LEN);
+
+    // Decoys may precede the version packet, and only the first packet authenticates the garbage
+    loop {
+        match codec.decrypt_packet(&mut received, &aad) {
+            Ok(Some((header, _))) => {
+                aad.clear();
+                if header & IGNORE == 0 {
+                    break;
+                }
+            }
+            Ok(None) => {
+                let wanted = received.len() + 1;
+                read_at_least(stream, &mut received, wanted).await?
+            }
+            Err(e) => return Err(invalid_data(&e.to_string())),
+        }
+    }
+    Ok((codec, received))
+}
+
+fn invalid_data(cause: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, cause.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio_util::codec::{Decoder, Encoder, Framed, FramedParts};
+    use warp_crypto::bip324::REKEY_INTERVAL;
+
+    fn codec_pair() -> (V2Codec, V2Codec) {
+        let secret = [7u8; 32];
+        let initiator = SessionKeys::derive(&secret, 0xd9b4bef9, true);
+        let responder = SessionKeys::derive(&secret, 0xd9b4bef9, false);
+        assert_eq!(initiator.send_terminator, responder.receive_terminator);
+        assert_eq!(initiator.receive_terminator, responder.send_terminator);
+        (
+            V2Codec::new(&initiator, 1000),
+            V2Codec::new(&responder, 1000),
+        )
+    }
+
+    /// Derives the session from `secret` and the two ElligatorSwift keys, then encrypts `skip` empty decoys and a
+    /// packet carrying `contents`, returning the session keys and the final packet
+    fn encode_vector(
+        secret: &str,
+        ours: &str,
+        theirs: &str,
+        initiator: bool,
+        skip: usize,
+        contents: &str,
+        ignore: bool,
+    ) -> (SessionKeys, [u8; 32], BytesMut) {
+        let mut secret_bytes = [0u8; 32];
+        secret_bytes.copy_from_slice(&hex::decode(secret).unwrap());
+        let (mut our_key, mut their_key) = ([0u8; ELLSWIFT_LEN], [0u8; ELLSWIFT_LEN]);
+        our_key.copy_from_slice(&hex::decode(ours).unwrap());
+        their_key.copy_from_slice(&hex::decode(theirs).unwrap());
+        let shared_secret = if initiator {
+            bip324::ellswift_ecdh(&our_key, &their_key, &secret_bytes, true)
+        } else {
+            bip324::ellswift_ecdh(&their_key, &our_key, &secret_bytes, false)
+        }
+        .unwrap();
+
+        let keys =
+            SessionKeys::derive(&shared_secret, config::Config::mainnet().magic(), initiator);
+        let mut codec = V2Codec::new(&keys, 1000);
+        let mut wire = BytesMut::new();
+        for _ in 0..skip {
+            codec.encrypt_packet(IGNORE, &[], &[], &mut wire);
+        }
+        wire.clear();
+        let header = if ignore { IGNORE } else { 0 };
+        codec.encrypt_packet(header, &hex::decode(contents).unwrap(), &[], &mut wire);
+        (keys, shared_secret, wire)
+    }
+
+    const VECTOR_SECRET: &str = "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7";
+    const VECTOR_OURS: &str = "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa186f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b";
+    const VECTOR_THEIRS: &str = "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafaffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5";
+
+    #[test]
+    fn matches_bip324_packet_encoding_vector() {
+        // The first vector of BIP324's packet_encoding_test_vectors.csv
+        let (keys, shared_secret, packet) = encode_vector(
+            VECTOR_SECRET,
+            VECTOR_OURS,
+            VECTOR_THEIRS,
+            true,
+            1,
+            "8e",
+            false,
+        );
+        assert_eq!(
+            hex::encode(shared_secret),
+            "c6992a117f5edbea70c3f511d32d26b9798be4b81a62eaee1a5acaa8459a3592"
+        );
+        assert_eq!(
+            hex::encode(keys.send_length),
+            "9a6478b5fbab1f4dd2f78994b774c03211c78312786e602da75a0d1767fb55cf"
+        );
+        assert_eq!(
+            hex::encode(keys.send_packet),
+            "7d0c7820ba6a4d29ce40baf2caa6035e04f1e1cefd59f3e7e59e9e5af84f1f51"
+        );
+        assert_eq!(
+            hex::encode(keys.receive_length),
+            "17bc726421e4054ac6a1d54915085aaa766f4d3cf67bbd168e6080eac289d15e"
+        );
+        assert_eq!(
+            hex::encode(keys.receive_packet),
+            "9f0fc1c0e85fd9a8eee07e6fc41dba2ff54c7729068a239ac97c37c524cca1c0"
+        );
+        assert_eq!(
+            hex::encode(keys.send_terminator),
+            "faef555dfcdb936425d84aba524758f3"
+        );
+        assert_eq!(
+            hex::encode(keys.receive_terminator),
+            "02cb8ff24307a6e27de3b4e7ea3fa65b"
+        );
+        assert_eq!(
+            hex::encode(keys.session_id),
+            "ce72dffb015da62b0d0f5474cab8bc72605225b0cee3f62312ec680ec5f41ba5"
+        );
+        assert_eq!(
+            hex::encode(&packet),
+            "7530d2a18720162ac09c25329a60d75adf36eda3c3"
+        );
+    }
+
+    #[test]
+    fn matches_bip324_encoding_after_rekeys() {
+        // The same keys from the responder's side, two rekeys in. The expected values come from an independent
+        // implementation of the BIP's Python reference code, which reproduces the vector above.
+        let (keys, _, packet) = encode_vector(
+            VECTOR_SECRET,
+            VECTOR_OURS,
+            VECTOR_THEIRS,
+            false,
+            448,
+            "3eb1d4e980",
+            true,
+        );
+        assert_eq!(
+            hex::encode(keys.send_length),
+            "1d2dc501f9205769e56f12c73fdbb4364a51bb846666c428bb0dd837bb15631a"
+        );
+        assert_eq!(
+            hex::encode(keys.send_terminator),
+            "c721077a060c84bc5fe54275712f15b7"
+        );
+        assert_eq!(
+            hex::encode(keys.session_id),
+            "13590dec5c029fae717354e3fe31cbbaed90508071100aa1692fd94c0584548e"
+        );
+        assert_eq!(
+            hex::encode(&packet),
+            "446dcaf889e1207f8e996a8cc9831fa1b20ca02eede72a7eb7"
+        );
+    }
+
+    #[test]
+    fn roundtrips_messages_across_rekeys() {
+        let (mut sender, mut receiver) = codec_pair();
+        let mut wire = BytesMut::new();
+        for i in 0..(REKEY_INTERVAL + 10) {
+            sender.encode(Message::Ping(i), &mut wire).unwrap();
+        }
+        // Ping has a short id: 3 length bytes, the header, the id, the nonce and the tag
+        assert_eq!(
+            wire.len(),
+            (REKEY_INTERVAL as usize + 10) * (LENGTH_LEN + HEADER_LEN + 1 + 8 + TAG_LEN)
+        );
+        for i in 0..(REKEY_INTERVAL + 10) {
+            match receiver.decode(&mut wire).unwrap() {
+                Some(Message::Ping(nonce)) => assert_eq!(nonce, i),
+                other => panic!("Expected a ping, got {:?}", other),
+            }
+        }
+        assert!(receiver.decode(&mut wire).unwrap().is_none());
+    }
+
+    #[test]
+    fn skips_decoys_and_sends_long_commands() {
+        let (mut sender, mut receiver) = codec_pair();
+        let mut wire = BytesMut::new();
+        sender.encode_decoy(20, &mut wire);
+        sender.encode(Message::Verack, &mut wire).unwrap();
+        // addrv2 has a short id, even though we don't implement it
+        let addrv2 = Message::Unknown {
+            command: *b"addrv2\0\0\0\0\0\0",
+            payload: bytes::Bytes::from_static(&[0]),
+        };
+        sender.encode(addrv2, &mut wire).unwrap();
+
+        // Arriving one byte at a time makes no difference
+        let mut partial = BytesMut::new();
+        let mut received = Vec::new();
+        for byte in wire.iter() {
+            partial.put_u8(*byte);
+            if let Some(msg) = receiver.decode(&mut partial).unwrap() {
+                received.push(msg);
+            }
+        }
+        assert!(matches!(received[0], Message::Verack));
+        match &received[1] {
+            Message::Unknown { command, payload } => {
+                assert_eq!(command, b"addrv2\0\0\0\0\0\0");
+                assert_eq!(&payload[..], &[0]);
+            }
+            other => panic!("Expected addrv2, got {:?}", other),
+        }
+        assert_eq!(received.len(), 2);
+    }
+
+    #[test]
+    fn rejects_tampered_and_oversized_packets() {
+        let (mut sender, mut receiver) = codec_pair();
+        let mut wire = BytesMut::new();
+        sender.encode(Message::Verack, &mut wire).unwrap();
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        assert!(receiver.decode(&mut wire).is_err());
+
+        let (mut sender, mut receiver) = codec_pair();
+        let mut wire = BytesMut::new();
+        sender.encode_decoy(2000, &mut wire);
+        assert!(receiver.decode(&mut wire).is_err());
+    }
+
+    #[test]
+    fn short_ids_match_bip324() {
+        assert_eq!(Command::Addr.short_id(), Some(1));
+        assert_eq!(Command::Tx.short_id(), Some(21));
+        assert_eq!(Command::Version.short_id(), None);
+        assert_eq!(Command::from_short_id(18), Some(Command::Ping));
+        assert_eq!(Command::from_short_id(0), None);
+        assert_eq!(Command::from_short_id(29), None);
+        for id in 1..=28 {
+            assert_eq!(Command::from_short_id(id).unwrap().short_id(), Some(id));
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_establishes_a_session() {
+        let magic = config::Config::mainnet().magic();
+        let (mut local, mut remote) = tokio::io::duplex(64 * 1024);
+        let (initiated, responded) = tokio::join!(
+            initiate(&mut local, magic, 1000),
+            respond(&mut remote, magic, 1000)
+        );
+        let (initiator, leftover) = initiated.unwrap();
+        assert!(leftover.is_empty());
+        let (responder, leftover) = match responded.unwrap() {
+            Handshake::V2(codec, leftover) => (codec, leftover),
+            Handshake::V1(_) => panic!("Expected a v2 handshake"),
+        };
+        assert_eq!(initiator.session_id(), responder.session_id());
+
+        let mut local = Framed::new(local, initiator);
+        let mut parts = FramedParts::new::<Message>(remote, *responder);
+        parts.read_buf = leftover;
+        let mut remote = Framed::from_parts(parts);
+        local.send(Message::FeeFilter(1000)).await.unwrap();
+        assert!(matches!(
+            remote.next().await,
+            Some(Ok(Message::FeeFilter(1000)))
+        ));
+        remote.send(Message::SendHeaders).await.unwrap();
+        assert!(matches!(local.next().await, Some(Ok(Message::SendHeaders))));
+    }
+
+    #[tokio::test]
+    async fn detects_v1_peers() {
+        let config = config::Config::mainnet();
+        let (local, mut remote) = tokio::io::duplex(64 * 1024);
+        let mut local = Framed::new(local, BitcoinCodec::new(config.magic()));
+        let version = Message::version(
+            "127.0.0.1:8333".parse().unwrap(),
+            0,
+            "127.0.0.1:8334".parse().unwrap(),
+            0,
+            42,
+            &config,
+        );
+        local.send(version).await.unwrap();
+
+        let mut received = match respond(&mut remote, config.magic(), 1000).await.unwrap() {
+            Handshake::V1(received) => received,
+            Handshake::V2(..) => panic!("Expected a v1 peer"),
+        };
+        // Nothing was consumed, so the v1 codec can pick up where the detection left off
+        let mut codec = BitcoinCodec::new(config.magic());
+        let msg = loop {
+            if let Some(msg) = codec.decode(&mut received).unwrap() {
+                break msg;
+            }
+            let wanted = received.len() + 1;
+            read_at_least(&mut remote, &mut received, wanted)
+                .await
+                .unwrap();
+        };
+        match msg {
+            Message::Version(version) => assert_eq!(version.nonce(), 42),
+            other => panic!("Expected a version, got {:?}", other),
+        }
+    }
+}
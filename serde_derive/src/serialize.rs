@@ -97,9 +97,15 @@ fn serialize_variant(variant: &syn::Variant, name: &syn::Ident) -> quote::__priv
         .collect();
 
     if subfields.len() > 0 {
-        quote! { #name::#ident ( #(#subfields)* ) => {
-            #(#statements)*
-        },}
+        if let syn::Fields::Named(_) = variant.fields {
+            quote! { #name::#ident { #(#subfields)* } => {
+                #(#statements)*
+            },}
+        } else {
+            quote! { #name::#ident ( #(#subfields)* ) => {
+                #(#statements)*
+            },}
+        }
     } else {
         quote! { #name::#ident => {
             #(#statements)*
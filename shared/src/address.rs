@@ -0,0 +1,215 @@
+//! Addresses for output scripts: base58check for pay to pubkey hash and pay to script hash outputs, bech32 for
+//! version 0 witness programs (BIP173) and bech32m for later versions (BIP350).
+use warp_crypto::sha256d;
+
+const OP_0: u8 = 0x00;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_DUP: u8 = 0x76;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_HASH160: u8 = 0xa9;
+const OP_CHECKSIG: u8 = 0xac;
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+/// The address of an output script, or `None` if the script has no address form (like a bare multisig or an
+/// `OP_RETURN` output). `pubkey_prefix` and `script_prefix` are the network's base58 version bytes, and `hrp` its
+/// bech32 human readable part.
+pub fn script_to_address(
+    script: &[u8],
+    pubkey_prefix: u8,
+    script_prefix: u8,
+    hrp: &str,
+) -> Option<String> {
+    match script {
+        [OP_DUP, OP_HASH160, 20, hash @ .., OP_EQUALVERIFY, OP_CHECKSIG] if hash.len() == 20 => {
+            Some(base58check_encode(pubkey_prefix, hash))
+        }
+        [OP_HASH160, 20, hash @ .., OP_EQUAL] if hash.len() == 20 => {
+            Some(base58check_encode(script_prefix, hash))
+        }
+        [version, len, program @ ..]
+            if (*version == OP_0 || (OP_1..=OP_16).contains(version))
+                && program.len() == *len as usize =>
+        {
+            let version = if *version == OP_0 {
+                0
+            } else {
+                version - OP_1 + 1
+            };
+            segwit_encode(hrp, version, program)
+        }
+        _ => None,
+    }
+}
+
+/// Encodes `payload` after a version byte, followed by the first four bytes of the double SHA256 of both
+pub fn base58check_encode(version: u8, payload: &[u8]) -> String {
+    let mut data = Vec::with_capacity(payload.len() + 5);
+    data.push(version);
+    data.extend_from_slice(payload);
+    let checksum = sha256d(&data);
+    data.extend_from_slice(&checksum[..4]);
+
+    // Repeatedly divide the big endian number by 58, keeping the digits least significant first
+    let mut digits: Vec<u8> = Vec::with_capacity(data.len() * 138 / 100 + 1);
+    for &byte in data.iter() {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    // Each leading zero byte is written as a leading '1'
+    let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+    let mut encoded = "1".repeat(zeros);
+    encoded.extend(
+        digits
+            .iter()
+            .rev()
+            .map(|&digit| BASE58_ALPHABET[digit as usize] as char),
+    );
+    encoded
+}
+
+/// Encodes a witness program as a segwit address. Returns `None` if the program isn't valid for its version.
+pub fn segwit_encode(hrp: &str, version: u8, program: &[u8]) -> Option<String> {
+    if version > 16 || program.len() < 2 || program.len() > 40 {
+        return None;
+    }
+    if version == 0 && program.len() != 20 && program.len() != 32 {
+        return None;
+    }
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5));
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    Some(bech32_encode(hrp, &data, constant))
+}
+
+fn bech32_encode(hrp: &str, data: &[u8], constant: u32) -> String {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; 6]);
+    let checksum = bech32_polymod(&values) ^ constant;
+    let mut encoded = String::with_capacity(hrp.len() + 1 + data.len() + 6);
+    encoded.push_str(hrp);
+    encoded.push('1');
+    for &value in data {
+        encoded.push(BECH32_CHARSET[value as usize] as char);
+    }
+    for i in 0..6 {
+        let value = (checksum >> (5 * (5 - i))) & 31;
+        encoded.push(BECH32_CHARSET[value as usize] as char);
+    }
+    encoded
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|c| c & 31));
+    expanded
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut checksum = 1u32;
+    for &value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+/// Regroups bits from `from` bit values into `to` bit values, padding the last group with zeros
+fn convert_bits(data: &[u8], from: u32, to: u32) -> Vec<u8> {
+    let mut accumulator = 0u32;
+    let mut bits = 0;
+    let mut converted = Vec::with_capacity(data.len() * from as usize / to as usize + 1);
+    let mask = (1 << to) - 1;
+    for &value in data {
+        accumulator = (accumulator << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((accumulator >> bits) & mask) as u8);
+        }
+    }
+    if bits > 0 {
+        converted.push(((accumulator << (to - bits)) & mask) as u8);
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(script: &str, hrp: &str) -> Option<String> {
+        script_to_address(&hex::decode(script).unwrap(), 0, 5, hrp)
+    }
+
+    #[test]
+    fn encodes_base58_addresses() {
+        assert_eq!(
+            base58check_encode(0, &[0; 20]),
+            "1111111111111111111114oLvT2"
+        );
+        // The address the genesis block's coinbase would have paid to, had it used pay to pubkey hash
+        assert_eq!(
+            address("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac", "bc").unwrap(),
+            "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"
+        );
+        assert_eq!(
+            address("a914748284390f9e263a4b766a75d0633c50426eb87587", "bc").unwrap(),
+            "3CK4fEwbMP7heJarmU4eqA3sMbVJyEnU3V"
+        );
+    }
+
+    #[test]
+    fn encodes_segwit_addresses() {
+        // Test vectors from BIP173 and BIP350
+        assert_eq!(
+            address("0014751e76e8199196d454941c45d1b3a323f1433bd6", "bc").unwrap(),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert_eq!(
+            address(
+                "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+                "tb"
+            )
+            .unwrap(),
+            "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+        );
+        assert_eq!(
+            address(
+                "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+                "bc"
+            )
+            .unwrap(),
+            "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y"
+        );
+        assert_eq!(address("6002751e", "bc").unwrap(), "bc1sw50qgdz25j");
+        // Version 0 programs must be 20 or 32 bytes
+        assert_eq!(address("0010751e76e8199196d454941c45d1b3a323", "bc"), None);
+        assert_eq!(address("6a0401020304", "bc"), None);
+    }
+}
@@ -5,7 +5,7 @@ use bytes::Buf;
 use std::convert::TryInto;
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Hash, Clone)]
+#[derive(Debug, Hash, Clone, PartialEq, Eq)]
 pub struct u256([u8; 32]);
 
 // fn substring(target: &str, start: usize, end: usize) -> &str {
@@ -156,14 +156,6 @@ impl Deserializable for u256 {
     }
 }
 
-impl PartialEq for u256 {
-    fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
-    }
-}
-
-impl Eq for u256 {}
-
 #[test]
 fn test_u256_ser_deser() {
     use bytes::BytesMut;
@@ -35,3 +35,7 @@ mod merkle_tree;
 pub use merkle_tree::MerkleRoot;
 
 mod hashes;
+
+pub mod address;
+
+pub mod script;
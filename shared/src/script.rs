@@ -0,0 +1,198 @@
+//! Disassembling scripts into the `asm` form Bitcoin Core shows in its RPC output.
+
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1NEGATE: u8 = 0x4f;
+const OP_RESERVED: u8 = 0x50;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_NOP: u8 = 0x61;
+const OP_CHECKSIGADD: u8 = 0xba;
+const OP_INVALIDOPCODE: u8 = 0xff;
+
+/// The names of the opcodes from `OP_NOP` to `OP_CHECKSIGADD`
+const OPCODE_NAMES: [&str; (OP_CHECKSIGADD - OP_NOP + 1) as usize] = [
+    "OP_NOP",
+    "OP_VER",
+    "OP_IF",
+    "OP_NOTIF",
+    "OP_VERIF",
+    "OP_VERNOTIF",
+    "OP_ELSE",
+    "OP_ENDIF",
+    "OP_VERIFY",
+    "OP_RETURN",
+    "OP_TOALTSTACK",
+    "OP_FROMALTSTACK",
+    "OP_2DROP",
+    "OP_2DUP",
+    "OP_3DUP",
+    "OP_2OVER",
+    "OP_2ROT",
+    "OP_2SWAP",
+    "OP_IFDUP",
+    "OP_DEPTH",
+    "OP_DROP",
+    "OP_DUP",
+    "OP_NIP",
+    "OP_OVER",
+    "OP_PICK",
+    "OP_ROLL",
+    "OP_ROT",
+    "OP_SWAP",
+    "OP_TUCK",
+    "OP_CAT",
+    "OP_SUBSTR",
+    "OP_LEFT",
+    "OP_RIGHT",
+    "OP_SIZE",
+    "OP_INVERT",
+    "OP_AND",
+    "OP_OR",
+    "OP_XOR",
+    "OP_EQUAL",
+    "OP_EQUALVERIFY",
+    "OP_RESERVED1",
+    "OP_RESERVED2",
+    "OP_1ADD",
+    "OP_1SUB",
+    "OP_2MUL",
+    "OP_2DIV",
+    "OP_NEGATE",
+    "OP_ABS",
+    "OP_NOT",
+    "OP_0NOTEQUAL",
+    "OP_ADD",
+    "OP_SUB",
+    "OP_MUL",
+    "OP_DIV",
+    "OP_MOD",
+    "OP_LSHIFT",
+    "OP_RSHIFT",
+    "OP_BOOLAND",
+    "OP_BOOLOR",
+    "OP_NUMEQUAL",
+    "OP_NUMEQUALVERIFY",
+    "OP_NUMNOTEQUAL",
+    "OP_LESSTHAN",
+    "OP_GREATERTHAN",
+    "OP_LESSTHANOREQUAL",
+    "OP_GREATERTHANOREQUAL",
+    "OP_MIN",
+    "OP_MAX",
+    "OP_WITHIN",
+    "OP_RIPEMD160",
+    "OP_SHA1",
+    "OP_SHA256",
+    "OP_HASH160",
+    "OP_HASH256",
+    "OP_CODESEPARATOR",
+    "OP_CHECKSIG",
+    "OP_CHECKSIGVERIFY",
+    "OP_CHECKMULTISIG",
+    "OP_CHECKMULTISIGVERIFY",
+    "OP_NOP1",
+    "OP_CHECKLOCKTIMEVERIFY",
+    "OP_CHECKSEQUENCEVERIFY",
+    "OP_NOP4",
+    "OP_NOP5",
+    "OP_NOP6",
+    "OP_NOP7",
+    "OP_NOP8",
+    "OP_NOP9",
+    "OP_NOP10",
+    "OP_CHECKSIGADD",
+];
+
+/// Disassembles a script. Pushes of up to four bytes are shown as numbers and longer ones as hex, as in Core. A
+/// push which runs past the end of the script ends the output with `[error]`.
+pub fn script_to_asm(script: &[u8]) -> String {
+    let mut words = Vec::new();
+    let mut rest = script;
+    while let Some((&opcode, tail)) = rest.split_first() {
+        if opcode > OP_PUSHDATA4 {
+            words.push(opcode_name(opcode));
+            rest = tail;
+            continue;
+        }
+        let (len, tail) = match opcode {
+            OP_PUSHDATA1 if !tail.is_empty() => (tail[0] as usize, &tail[1..]),
+            OP_PUSHDATA2 if tail.len() >= 2 => {
+                (u16::from_le_bytes([tail[0], tail[1]]) as usize, &tail[2..])
+            }
+            OP_PUSHDATA4 if tail.len() >= 4 => (
+                u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) as usize,
+                &tail[4..],
+            ),
+            OP_PUSHDATA1 | OP_PUSHDATA2 | OP_PUSHDATA4 => {
+                words.push(String::from("[error]"));
+                break;
+            }
+            len => (len as usize, tail),
+        };
+        if tail.len() < len {
+            words.push(String::from("[error]"));
+            break;
+        }
+        let (data, tail) = tail.split_at(len);
+        if data.len() <= 4 {
+            words.push(script_num(data).to_string());
+        } else {
+            words.push(hex::encode(data));
+        }
+        rest = tail;
+    }
+    words.join(" ")
+}
+
+fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        OP_1NEGATE => String::from("-1"),
+        OP_RESERVED => String::from("OP_RESERVED"),
+        OP_1..=OP_16 => (opcode - OP_1 + 1).to_string(),
+        OP_NOP..=OP_CHECKSIGADD => String::from(OPCODE_NAMES[(opcode - OP_NOP) as usize]),
+        OP_INVALIDOPCODE => String::from("OP_INVALIDOPCODE"),
+        _ => String::from("OP_UNKNOWN"),
+    }
+}
+
+/// Reads a script number: little endian, with the sign in the top bit of the last byte
+fn script_num(data: &[u8]) -> i64 {
+    let mut value = 0i64;
+    for (i, &byte) in data.iter().enumerate() {
+        value |= (byte as i64) << (8 * i);
+    }
+    match data.last() {
+        Some(&last) if last & 0x80 != 0 => -(value & !(0x80i64 << (8 * (data.len() - 1)))),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disassembles_like_core() {
+        let p2pkh = hex::decode("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        assert_eq!(
+            script_to_asm(&p2pkh),
+            "OP_DUP OP_HASH160 62e907b15cbf27d5425399ebf6f0fb50ebb88f18 OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        // The genesis coinbase's script sig starts with pushes of the bits and the number 4
+        assert_eq!(
+            script_to_asm(&hex::decode("04ffff001d010445").unwrap()),
+            "486604799 4 [error]"
+        );
+        assert_eq!(
+            script_to_asm(&[0x00, 0x51, 0x60, 0x4f, 0x02, 0x81, 0x00]),
+            "0 1 16 -1 129"
+        );
+        assert_eq!(
+            script_to_asm(&[0x01, 0x81, 0xb1, 0xbb]),
+            "-1 OP_CHECKLOCKTIMEVERIFY OP_UNKNOWN"
+        );
+        assert_eq!(script_to_asm(&[0x4c]), "[error]");
+    }
+}
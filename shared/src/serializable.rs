@@ -137,6 +137,15 @@ impl Serializable for &[u8] {
     }
 }
 
+impl Serializable for bytes::Bytes {
+    fn serialize<W>(&self, target: &mut W) -> Result<(), std::io::Error>
+    where
+        W: std::io::Write,
+    {
+        target.write_all(self)
+    }
+}
+
 impl Serializable for [u8; 4] {
     fn serialize<W>(&self, target: &mut W) -> Result<(), std::io::Error>
     where
@@ -6,27 +6,74 @@ use bytes::Buf;
 use serde_derive::{Deserializable, Serializable};
 use warp_crypto::sha256d;
 
-#[derive(Serializable, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct Transaction {
     version: i32,
     inputs: Vec<TxInput>,
     outputs: Vec<TxOutput>,
+    /// The witness stack of each input (BIP141), or nothing if the transaction has no witness data
+    witnesses: Vec<Vec<Vec<u8>>>,
     locktime: u32,
     hash: Cached<TxID>,
+    witness_hash: Cached<TxID>,
+}
+
+/// The marker and flag bytes which introduce witness data in the serialization of BIP144
+const WITNESS_MARKER: u8 = 0;
+const WITNESS_FLAG: u8 = 1;
+
+/// Transactions with witness data are serialized as BIP144 describes, with the witnesses after the outputs.
+impl Serializable for Transaction {
+    fn serialize<W>(&self, target: &mut W) -> Result<(), std::io::Error>
+    where
+        W: std::io::Write,
+    {
+        self.serialize_with_witness(target, self.has_witness())
+    }
 }
 
 /// Deserializes a transaction. Expects to be handed a buffer with at most
 impl Deserializable for Transaction {
     fn deserialize<B: Buf>(mut src: B) -> Result<Self, DeserializationError> {
         let version = i32::deserialize(&mut src)?;
-        let inputs = <Vec<TxInput>>::deserialize(&mut src)?;
+        let mut inputs = <Vec<TxInput>>::deserialize(&mut src)?;
         // if !(inputs.len() == 1 && inputs[0].is_coinbase_in()) {
         //     return Err(DeserializationError::Parse(format!(
         //         "Made it!  Remaining: {}",
         //         src.remaining()
         //     )));
         // }
-        let outputs = <Vec<TxOutput>>::deserialize(&mut src)?;
+        // An empty input vector is the BIP144 marker, and is followed by the flags. Like Core, a transaction which
+        // really has no inputs is read as having no outputs either.
+        let mut flags = 0;
+        let mut outputs = Vec::new();
+        if inputs.is_empty() {
+            flags = u8::deserialize(&mut src)?;
+            if flags != 0 {
+                inputs = <Vec<TxInput>>::deserialize(&mut src)?;
+                outputs = <Vec<TxOutput>>::deserialize(&mut src)?;
+            }
+        } else {
+            outputs = <Vec<TxOutput>>::deserialize(&mut src)?;
+        }
+        let mut witnesses = Vec::new();
+        if flags & WITNESS_FLAG != 0 {
+            flags ^= WITNESS_FLAG;
+            witnesses = inputs
+                .iter()
+                .map(|_| <Vec<Vec<u8>>>::deserialize(&mut src))
+                .collect::<Result<Vec<_>, _>>()?;
+            if witnesses.iter().all(Vec::is_empty) {
+                return Err(DeserializationError::Parse(String::from(
+                    "Superfluous witness record",
+                )));
+            }
+        }
+        if flags != 0 {
+            return Err(DeserializationError::Parse(String::from(
+                "Unknown transaction optional data",
+            )));
+        }
         let locktime = u32::deserialize(&mut src)?;
         // return Err(DeserializationError::Parse(format!(
         //     "Coinbase out:  {:x?}",
@@ -37,8 +84,10 @@ impl Deserializable for Transaction {
             version,
             inputs,
             outputs,
+            witnesses,
             locktime,
             hash,
+            witness_hash: Cached::new(),
         };
         // let mut tx = Transaction {
         //     version: i32::deserialize(&mut src)?,
@@ -49,16 +98,42 @@ impl Deserializable for Transaction {
         // Calculate tx hash
         // FIXME: Find a way to avoid this copy
         let mut out = Vec::with_capacity(tx.len());
-        tx.serialize(&mut out)
+        tx.serialize_with_witness(&mut out, false)
             .expect("Serialization to vec should not fail!");
         let hash_bytes = sha256d(&out[..]);
         let own_hash = TxID::from(hash_bytes);
+        if tx.has_witness() {
+            out.clear();
+            tx.serialize_with_witness(&mut out, true)
+                .expect("Serialization to vec should not fail!");
+            tx.witness_hash = Cached::from(TxID::from(sha256d(&out[..])));
+        }
         tx.hash = Cached::from(own_hash);
         Ok(tx)
     }
 }
 impl Transaction {
+    /// The size of the serialized transaction, including any witness data
     pub fn len(&self) -> usize {
+        if !self.has_witness() {
+            return self.base_len();
+        }
+        let witness_len: usize = self
+            .witnesses
+            .iter()
+            .map(|stack| {
+                CompactInt::size(stack.len())
+                    + stack
+                        .iter()
+                        .map(|item| CompactInt::size(item.len()) + item.len())
+                        .sum::<usize>()
+            })
+            .sum();
+        // The marker and flag take up two bytes
+        self.base_len() + 2 + witness_len
+    }
+    /// The size of the transaction serialized without witness data
+    pub fn base_len(&self) -> usize {
         let mut size = 0;
         size += 4 + CompactInt::size(self.inputs.len());
         for input in self.inputs.iter() {
@@ -70,25 +145,88 @@ impl Transaction {
         }
         size + 4
     }
+    /// The weight defined in BIP141: witness data counts once, and everything else four times
+    pub fn weight(&self) -> usize {
+        self.base_len() * 3 + self.len()
+    }
+    /// The virtual size defined in BIP141, which fee rates and policy limits are measured in
+    pub fn vsize(&self) -> usize {
+        self.weight().div_ceil(4)
+    }
     pub fn new(version: i32, inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Transaction {
         Transaction {
             version,
             inputs,
             outputs,
+            witnesses: Vec::new(),
             // FIXME: Allow setting locktime
             locktime: 0xffffffff,
             hash: Cached::new(),
+            witness_hash: Cached::new(),
+        }
+    }
+
+    /// Serializes the transaction, with its witness data in the BIP144 format if `witness` is set
+    fn serialize_with_witness<W>(&self, target: &mut W, witness: bool) -> Result<(), std::io::Error>
+    where
+        W: std::io::Write,
+    {
+        self.version.serialize(target)?;
+        if witness {
+            target.write_all(&[WITNESS_MARKER, WITNESS_FLAG])?;
         }
+        self.inputs.serialize(target)?;
+        self.outputs.serialize(target)?;
+        if witness {
+            for stack in self.witnesses.iter() {
+                stack.serialize(target)?;
+            }
+        }
+        self.locktime.serialize(target)
     }
     pub fn is_coinbase(&self) -> bool {
         self.inputs.len() == 1 && self.inputs[0].is_coinbase_in()
     }
 
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+    pub fn inputs(&self) -> &Vec<TxInput> {
+        &self.inputs
+    }
+    pub fn outputs(&self) -> &Vec<TxOutput> {
+        &self.outputs
+    }
+    pub fn locktime(&self) -> u32 {
+        self.locktime
+    }
+    /// Whether any input has witness data
+    pub fn has_witness(&self) -> bool {
+        self.witnesses.iter().any(|stack| !stack.is_empty())
+    }
+    /// The transaction stripped of its witness data, as sent to peers which don't ask for witnesses
+    pub fn without_witness(&self) -> Transaction {
+        Transaction {
+            witnesses: Vec::new(),
+            witness_hash: Cached::new(),
+            ..self.clone()
+        }
+    }
+    /// The witness stack of an input, which is empty if it has none
+    pub fn witness(&self, input: usize) -> &[Vec<u8>] {
+        self.witnesses.get(input).map_or(&[], |stack| &stack[..])
+    }
+
     pub fn txid(&self) -> &TxID {
         self.hash
             .ref_value()
             .expect("Must fill txid at construction")
     }
+    /// The witness txid defined in BIP141, which commits to the witness as well as the rest of the transaction.
+    /// The wtxid of a transaction without witnesses is its txid.
+    pub fn wtxid(&self) -> &TxID {
+        self.witness_hash.ref_value().unwrap_or_else(|| self.txid())
+    }
 
     // #[cfg(test)]
     pub fn _test_coinbase() -> Transaction {
@@ -154,8 +292,17 @@ impl TxInput {
     pub fn is_coinbase_in(&self) -> bool {
         self.previous_outpoint.index == std::u32::MAX && self.previous_outpoint.hash.is_zero()
     }
+    pub fn previous_outpoint(&self) -> &TxOutpoint {
+        &self.previous_outpoint
+    }
+    pub fn signature_script(&self) -> &Vec<u8> {
+        &self.signature_script
+    }
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
 }
-#[derive(Deserializable, Serializable, Debug, Clone)]
+#[derive(Deserializable, Serializable, Debug, Clone, PartialEq)]
 pub struct TxOutput {
     value: i64,
     pk_script: Vec<u8>,
@@ -167,8 +314,14 @@ impl TxOutput {
     pub fn new(value: i64, pk_script: Vec<u8>) -> TxOutput {
         TxOutput { value, pk_script }
     }
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+    pub fn pk_script(&self) -> &Vec<u8> {
+        &self.pk_script
+    }
 }
-#[derive(Deserializable, Serializable, Debug, Clone)]
+#[derive(Deserializable, Serializable, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct TxOutpoint {
     hash: u256,
     index: u32,
@@ -180,7 +333,124 @@ impl TxOutpoint {
     pub fn new(hash: u256, index: u32) -> TxOutpoint {
         TxOutpoint { hash, index }
     }
+    pub fn hash(&self) -> &u256 {
+        &self.hash
+    }
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+/// Outpoints are ordered by the raw bytes of their txid, then by index. This matches the
+/// order in which Bitcoin Core stores (and hashes) coins in its chainstate database.
+impl Ord for TxOutpoint {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.hash
+            .to_le_bytes()
+            .cmp(other.hash.to_le_bytes())
+            .then(self.index.cmp(&other.index))
+    }
+}
+impl PartialOrd for TxOutpoint {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 // #[derive(Deserializable, Serializable)]
 // pub struct CoinbaseInput {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `_test_normal`, with a witness stack of two items added to its input
+    fn witness_tx_bytes() -> (Vec<u8>, Vec<u8>) {
+        let mut legacy = Vec::new();
+        Transaction::_test_normal().serialize(&mut legacy).unwrap();
+        let locktime_at = legacy.len() - 4;
+        let mut raw = legacy[..4].to_vec();
+        raw.extend_from_slice(&[WITNESS_MARKER, WITNESS_FLAG]);
+        raw.extend_from_slice(&legacy[4..locktime_at]);
+        raw.extend_from_slice(&[2, 3, 0xaa, 0xbb, 0xcc, 1, 0xdd]);
+        raw.extend_from_slice(&legacy[locktime_at..]);
+        (legacy, raw)
+    }
+
+    #[test]
+    fn reads_and_writes_witness_data() {
+        let (legacy, raw) = witness_tx_bytes();
+        let tx = Transaction::deserialize(&raw[..]).unwrap();
+        assert!(tx.has_witness());
+        assert_eq!(tx.inputs().len(), 1);
+        assert_eq!(tx.outputs().len(), 1);
+        assert_eq!(tx.witness(0), &[vec![0xaa, 0xbb, 0xcc], vec![0xdd]][..]);
+        assert!(tx.witness(1).is_empty());
+
+        let mut written = Vec::new();
+        tx.serialize(&mut written).unwrap();
+        assert_eq!(written, raw);
+        assert_eq!(tx.len(), raw.len());
+        assert_eq!(tx.base_len(), legacy.len());
+        assert_eq!(tx.weight(), legacy.len() * 3 + raw.len());
+        assert_eq!(tx.vsize(), (legacy.len() * 3 + raw.len()).div_ceil(4));
+
+        // The txid ignores the witness, and the wtxid commits to it
+        assert_eq!(tx.txid(), Transaction::_test_normal().txid());
+        assert_eq!(tx.wtxid(), &TxID::from(sha256d(&raw)));
+        assert_ne!(tx.wtxid(), tx.txid());
+
+        let stripped = tx.without_witness();
+        let mut written = Vec::new();
+        stripped.serialize(&mut written).unwrap();
+        assert_eq!(written, legacy);
+        assert_eq!(stripped.txid(), tx.txid());
+        assert_eq!(stripped.wtxid(), tx.txid());
+    }
+
+    #[test]
+    fn transactions_without_witnesses_are_unchanged() {
+        let tx = Transaction::_test_normal();
+        assert!(!tx.has_witness());
+        assert_eq!(tx.wtxid(), tx.txid());
+        assert_eq!(tx.vsize(), tx.len());
+        assert_eq!(tx.weight(), tx.len() * 4);
+    }
+
+    #[test]
+    fn reads_bip143_example() {
+        // The native P2WPKH example of BIP143
+        let raw = hex::decode(
+            "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000049\
+            4830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be022040529b19\
+            4ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804cc89d182d279\
+            655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a914\
+            8280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21\
+            b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5b4542f32a8a0d5447a12fb\
+            1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c212a8caed02de67eebee012102\
+            5476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee635711000000",
+        )
+        .unwrap();
+        let mut rest = &raw[..];
+        let tx = Transaction::deserialize(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(tx.inputs().len(), 2);
+        assert_eq!(tx.outputs().len(), 2);
+        assert!(tx.witness(0).is_empty());
+        assert_eq!(tx.witness(1).len(), 2);
+        assert_eq!(tx.locktime(), 0x11);
+    }
+
+    #[test]
+    fn rejects_malformed_witness_data() {
+        let (_, mut raw) = witness_tx_bytes();
+        // A witness record in which every stack is empty
+        let mut empty = raw.clone();
+        let witness_at = empty.len() - 4 - 7;
+        empty.splice(witness_at..witness_at + 7, [0]);
+        assert!(Transaction::deserialize(&empty[..]).is_err());
+        // Flags we don't know
+        raw[5] = 3;
+        assert!(Transaction::deserialize(&raw[..]).is_err());
+    }
+}
@@ -0,0 +1,686 @@
+//! Raw block and undo data on disk.
+//!
+//! Like Bitcoin Core, blocks are appended to numbered `blkNNNNN.dat` files, and the data needed to disconnect each
+//! block (the coins it spent) is appended to the `revNNNNN.dat` file with the same number. An append-only `index.dat`
+//! records where each block lives, along with its header, so headers remain available after the block itself is pruned.
+use crate::compression::{read_coin, write_coin};
+use crate::{Coin, StorageError};
+use bytes::BytesMut;
+use shared::{
+    Block, BlockHash, BlockHeader, CompactInt, Deserializable, Serializable, Transaction,
+};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use warp_crypto::Sha256dHasher;
+
+/// Block files are rolled over once they reach this size (Core's `MAX_BLOCKFILE_SIZE`)
+pub const MAX_BLOCKFILE_SIZE: u64 = 128 * 1024 * 1024;
+/// Blocks this close to the tip are never pruned, so that we can handle reorgs and serve
+/// BIP159 peers (Core's `MIN_BLOCKS_TO_KEEP`)
+pub const MIN_BLOCKS_TO_KEEP: u32 = 288;
+/// Each record in a block or undo file is prefixed by the network magic and its length
+const RECORD_HEADER_LEN: u64 = 8;
+
+const INDEX_FILE: &str = "index.dat";
+const INDEX_BLOCK: u8 = 0;
+const INDEX_UNDO: u8 = 1;
+
+/// The location of a record within a block or undo file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DiskPos {
+    file: u32,
+    /// The offset of the record's payload, just past its header
+    offset: u64,
+    len: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockIndexEntry {
+    header: BlockHeader,
+    height: u32,
+    data: Option<DiskPos>,
+    undo: Option<DiskPos>,
+}
+
+impl BlockIndexEntry {
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    /// Whether the full block is still on disk. This is false once the block has been pruned.
+    pub fn has_data(&self) -> bool {
+        self.data.is_some()
+    }
+}
+
+/// Bookkeeping for one pair of block and undo files
+#[derive(Debug, Clone, Default)]
+struct FileInfo {
+    size: u64,
+    undo_size: u64,
+    max_height: u32,
+    pruned: bool,
+}
+
+/// Stores raw blocks and their undo data, optionally pruning old files to stay under a size target.
+#[derive(Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+    magic: u32,
+    prune_target: Option<u64>,
+    index: HashMap<BlockHash, BlockIndexEntry>,
+    index_file: File,
+    files: Vec<FileInfo>,
+}
+
+impl BlockStore {
+    /// Opens (or creates) the block store in `dir`.
+    ///
+    /// If `prune_target` is set, old block and undo files are deleted by [`BlockStore::prune`] to keep
+    /// their combined size under that many bytes.
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        magic: u32,
+        prune_target: Option<u64>,
+    ) -> Result<BlockStore, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let index_path = dir.join(INDEX_FILE);
+        let index_contents = match fs::read(&index_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let (mut index, valid_len) = read_index(&index_contents)?;
+        let index_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&index_path)?;
+        // A crash can leave a partially written record at the end of the index
+        if valid_len < index_contents.len() {
+            warn!("Discarding truncated record at the end of the block index");
+            index_file.set_len(valid_len as u64)?;
+        }
+
+        let mut files: Vec<FileInfo> = Vec::new();
+        for entry in index.values_mut() {
+            for (pos, is_undo) in [(&mut entry.data, false), (&mut entry.undo, true)] {
+                let file = match pos {
+                    Some(pos) => pos.file as usize,
+                    None => continue,
+                };
+                if files.len() <= file {
+                    files.resize(file + 1, FileInfo::default());
+                }
+                files[file].max_height = files[file].max_height.max(entry.height);
+                if !file_path(&dir, is_undo, file as u32).exists() {
+                    // The file was pruned
+                    files[file].pruned = true;
+                    *pos = None;
+                }
+            }
+        }
+        for (number, info) in files.iter_mut().enumerate() {
+            if info.pruned {
+                continue;
+            }
+            info.size = file_len(&file_path(&dir, false, number as u32))?;
+            info.undo_size = file_len(&file_path(&dir, true, number as u32))?;
+        }
+        info!(
+            "Opened block store at {:?} with {} blocks in {} files",
+            dir,
+            index.len(),
+            files.len()
+        );
+        Ok(BlockStore {
+            dir,
+            magic,
+            prune_target,
+            index,
+            index_file,
+            files,
+        })
+    }
+
+    pub fn is_pruning(&self) -> bool {
+        self.prune_target.is_some()
+    }
+
+    /// The index entry for a block, which is kept even after the block itself is pruned
+    pub fn entry(&self, hash: &BlockHash) -> Option<&BlockIndexEntry> {
+        self.index.get(hash)
+    }
+
+    pub fn header(&self, hash: &BlockHash) -> Option<&BlockHeader> {
+        self.index.get(hash).map(|entry| entry.header())
+    }
+
+    /// The hashes of the chain ending at `tip`, which is at `height`, starting from genesis. Returns `None` if
+    /// any of the headers along the way are unknown.
+    pub fn chain(&self, tip: &BlockHash, height: u32) -> Option<Vec<BlockHash>> {
+        let mut chain = Vec::with_capacity(height as usize + 1);
+        chain.push(tip.clone());
+        for _ in 0..height {
+            let prev = self.header(chain.last().expect("Never empty"))?.prev_hash();
+            chain.push(prev.clone());
+        }
+        chain.reverse();
+        Some(chain)
+    }
+
+    /// Whether the full block is available on disk
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        self.index.get(hash).is_some_and(|entry| entry.has_data())
+    }
+
+    /// The combined size of all block and undo files currently on disk
+    pub fn disk_usage(&self) -> u64 {
+        self.files
+            .iter()
+            .map(|info| info.size + info.undo_size)
+            .sum()
+    }
+
+    /// Appends a block to the current block file. Blocks which are already stored are ignored.
+    pub fn write_block(&mut self, block: &Block, height: u32) -> Result<(), StorageError> {
+        let hash = block.header().hash();
+        if self.has_block(hash) {
+            return Ok(());
+        }
+        let mut payload = Vec::with_capacity(block.serialized_size());
+        block.serialize(&mut payload)?;
+        let file = self.file_for(payload.len() as u64);
+        let pos = self.append(file, false, &payload)?;
+        let info = &mut self.files[file as usize];
+        info.max_height = info.max_height.max(height);
+
+        let entry = BlockIndexEntry {
+            header: block.header().clone(),
+            height,
+            data: Some(pos),
+            undo: self.index.get(hash).and_then(|entry| entry.undo),
+        };
+        let mut record = vec![INDEX_BLOCK];
+        entry.header.serialize(&mut record)?;
+        height.serialize(&mut record)?;
+        write_pos(&mut record, &pos)?;
+        self.append_index(&record)?;
+        self.index.insert(hash.clone(), entry);
+        Ok(())
+    }
+
+    /// Writes the coins spent by a stored block, in the order they were spent.
+    ///
+    /// Undo data goes into the undo file paired with the block's file, so that the two can be pruned together.
+    pub fn write_undo(&mut self, hash: &BlockHash, undo: &[Coin]) -> Result<(), StorageError> {
+        let file = match self.index.get(hash).and_then(|entry| entry.data) {
+            Some(pos) => pos.file,
+            None => {
+                return Err(StorageError::InvalidBlock(format!(
+                    "cannot write undo data for {:?}, which is not stored",
+                    hash
+                )))
+            }
+        };
+        let mut payload = Vec::new();
+        CompactInt::from(undo.len()).serialize(&mut payload)?;
+        for coin in undo {
+            write_coin(&mut payload, coin);
+        }
+        payload.extend_from_slice(&undo_checksum(hash, &payload));
+        let pos = self.append(file, true, &payload)?;
+
+        let mut record = vec![INDEX_UNDO];
+        hash.serialize(&mut record)?;
+        write_pos(&mut record, &pos)?;
+        self.append_index(&record)?;
+        self.index.get_mut(hash).expect("Checked above").undo = Some(pos);
+        Ok(())
+    }
+
+    /// Reads a block from disk. Returns `None` if the block is unknown or has been pruned.
+    pub fn read_block(&self, hash: &BlockHash) -> Result<Option<Block>, StorageError> {
+        let pos = match self.index.get(hash).and_then(|entry| entry.data) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let mut payload = BytesMut::from(&self.read_record(false, &pos)?[..]);
+        let block = Block::deserialize(&mut payload)?;
+        if block.header().hash() != hash {
+            return Err(StorageError::Corruption(format!(
+                "block file {} does not contain block {:?} at offset {}",
+                pos.file, hash, pos.offset
+            )));
+        }
+        Ok(Some(block))
+    }
+
+    /// Reads the transaction at `offset` bytes into a block, as recorded by the transaction index. Returns `None`
+    /// if the block is unknown or has been pruned.
+    pub fn read_transaction(
+        &self,
+        hash: &BlockHash,
+        offset: u32,
+    ) -> Result<Option<Transaction>, StorageError> {
+        let pos = match self.index.get(hash).and_then(|entry| entry.data) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let payload = self.read_record(false, &pos)?;
+        if offset as usize >= payload.len() {
+            return Err(StorageError::Corruption(format!(
+                "offset {} is past the end of block {:?}",
+                offset, hash
+            )));
+        }
+        Ok(Some(Transaction::deserialize(&payload[offset as usize..])?))
+    }
+
+    /// Reads the undo data for a block. Returns `None` if there is none, or it has been pruned.
+    pub fn read_undo(&self, hash: &BlockHash) -> Result<Option<Vec<Coin>>, StorageError> {
+        let pos = match self.index.get(hash).and_then(|entry| entry.undo) {
+            Some(pos) => pos,
+            None => return Ok(None),
+        };
+        let record = self.read_record(true, &pos)?;
+        if record.len() < 32 {
+            return Err(StorageError::Corruption(String::from(
+                "undo record is too short",
+            )));
+        }
+        let (payload, checksum) = record.split_at(record.len() - 32);
+        if undo_checksum(hash, payload)[..] != checksum[..] {
+            return Err(StorageError::Corruption(format!(
+                "undo data for block {:?} has a bad checksum",
+                hash
+            )));
+        }
+        let mut src = payload;
+        let count = CompactInt::deserialize(&mut src)?.value();
+        let mut coins = Vec::with_capacity(count.min(payload.len() as u64) as usize);
+        for _ in 0..count {
+            coins.push(read_coin(&mut src)?);
+        }
+        Ok(Some(coins))
+    }
+
+    /// Reads back the `depth` highest blocks on disk, along with their undo data, returning an error if any of
+    /// them are corrupt (Core's `-checkblocks`). Returns the number of blocks checked.
+    pub fn check_recent_blocks(&self, depth: usize) -> Result<usize, StorageError> {
+        let mut recent: Vec<_> = self
+            .index
+            .iter()
+            .filter(|(_, entry)| entry.has_data())
+            .collect();
+        recent.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.height));
+        recent.truncate(depth);
+        for (hash, _) in recent.iter() {
+            self.read_block(hash)?;
+            self.read_undo(hash)?;
+        }
+        Ok(recent.len())
+    }
+
+    /// Deletes the oldest block and undo files until disk usage is under the prune target, returning the
+    /// numbers of the deleted files. Files which contain any of the last [`MIN_BLOCKS_TO_KEEP`] blocks
+    /// before `tip_height`, and the file currently being written, are never deleted.
+    pub fn prune(&mut self, tip_height: u32) -> Result<Vec<u32>, StorageError> {
+        let target = match self.prune_target {
+            Some(target) => target,
+            None => return Ok(Vec::new()),
+        };
+        let mut usage = self.disk_usage();
+        if usage <= target || tip_height <= MIN_BLOCKS_TO_KEEP {
+            return Ok(Vec::new());
+        }
+        let last_prunable = tip_height - MIN_BLOCKS_TO_KEEP;
+        let mut pruned = Vec::new();
+        let current = self.files.len().saturating_sub(1);
+        for number in 0..current {
+            if usage <= target {
+                break;
+            }
+            let info = &self.files[number];
+            if info.pruned || info.max_height > last_prunable {
+                continue;
+            }
+            usage -= info.size + info.undo_size;
+            pruned.push(number as u32);
+        }
+        for number in pruned.iter() {
+            for is_undo in [false, true] {
+                let path = file_path(&self.dir, is_undo, *number);
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let info = &mut self.files[*number as usize];
+            info.pruned = true;
+            info.size = 0;
+            info.undo_size = 0;
+        }
+        if !pruned.is_empty() {
+            for entry in self.index.values_mut() {
+                if entry.data.is_some_and(|pos| pruned.contains(&pos.file)) {
+                    entry.data = None;
+                    entry.undo = None;
+                }
+            }
+            info!(
+                "Pruned block files {:?}. Block storage now uses {} MiB",
+                pruned,
+                usage / (1024 * 1024)
+            );
+        }
+        Ok(pruned)
+    }
+
+    /// Picks the file to append a payload of `len` bytes to, starting a new one if the current file is full.
+    fn file_for(&mut self, len: u64) -> u32 {
+        match self.files.last() {
+            Some(info)
+                if info.size + len + RECORD_HEADER_LEN <= MAX_BLOCKFILE_SIZE || info.size == 0 => {}
+            _ => self.files.push(FileInfo::default()),
+        }
+        (self.files.len() - 1) as u32
+    }
+
+    fn append(
+        &mut self,
+        file: u32,
+        is_undo: bool,
+        payload: &[u8],
+    ) -> Result<DiskPos, StorageError> {
+        let mut handle = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path(&self.dir, is_undo, file))?;
+        let mut record = Vec::with_capacity(payload.len() + RECORD_HEADER_LEN as usize);
+        self.magic.serialize(&mut record)?;
+        (payload.len() as u32).serialize(&mut record)?;
+        record.extend_from_slice(payload);
+        handle.write_all(&record)?;
+        // The data must reach the disk before the index record which points at it
+        handle.sync_data()?;
+
+        let info = &mut self.files[file as usize];
+        let size = if is_undo {
+            &mut info.undo_size
+        } else {
+            &mut info.size
+        };
+        let pos = DiskPos {
+            file,
+            offset: *size + RECORD_HEADER_LEN,
+            len: payload.len() as u32,
+        };
+        *size += record.len() as u64;
+        Ok(pos)
+    }
+
+    fn read_record(&self, is_undo: bool, pos: &DiskPos) -> Result<Vec<u8>, StorageError> {
+        let mut file = File::open(file_path(&self.dir, is_undo, pos.file))?;
+        file.seek(SeekFrom::Start(pos.offset - RECORD_HEADER_LEN))?;
+        let mut header = [0u8; RECORD_HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        let mut src = &header[..];
+        let magic = u32::deserialize(&mut src)?;
+        let len = u32::deserialize(&mut src)?;
+        if magic != self.magic || len != pos.len {
+            return Err(StorageError::Corruption(format!(
+                "bad record header at offset {} of file {}",
+                pos.offset, pos.file
+            )));
+        }
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+
+    fn append_index(&mut self, record: &[u8]) -> Result<(), StorageError> {
+        self.index_file.write_all(record)?;
+        self.index_file.sync_data()?;
+        Ok(())
+    }
+}
+
+fn file_path(dir: &Path, is_undo: bool, number: u32) -> PathBuf {
+    let prefix = if is_undo { "rev" } else { "blk" };
+    dir.join(format!("{}{:05}.dat", prefix, number))
+}
+
+fn file_len(path: &Path) -> Result<u64, StorageError> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Core commits to the block hash along with the undo data, so that undo data can't be applied to the wrong block
+fn undo_checksum(hash: &BlockHash, payload: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256dHasher::new();
+    hasher.input(hash.inner());
+    hasher.input(payload);
+    hasher.finish()
+}
+
+fn write_pos(target: &mut Vec<u8>, pos: &DiskPos) -> Result<(), StorageError> {
+    pos.file.serialize(target)?;
+    pos.offset.serialize(target)?;
+    pos.len.serialize(target)?;
+    Ok(())
+}
+
+fn read_pos(src: &mut &[u8]) -> Result<DiskPos, StorageError> {
+    Ok(DiskPos {
+        file: u32::deserialize(&mut *src)?,
+        offset: u64::deserialize(&mut *src)?,
+        len: u32::deserialize(&mut *src)?,
+    })
+}
+
+/// Replays the index log, returning the index and the length of its valid prefix.
+fn read_index(
+    contents: &[u8],
+) -> Result<(HashMap<BlockHash, BlockIndexEntry>, usize), StorageError> {
+    let mut index: HashMap<BlockHash, BlockIndexEntry> = HashMap::new();
+    let mut src = contents;
+    let mut valid_len = 0;
+    while !src.is_empty() {
+        let mut record = |src: &mut &[u8]| -> Result<(), StorageError> {
+            match u8::deserialize(&mut *src)? {
+                INDEX_BLOCK => {
+                    let header = BlockHeader::deserialize(&mut *src)?;
+                    let height = u32::deserialize(&mut *src)?;
+                    let pos = read_pos(src)?;
+                    let undo = index.get(header.hash()).and_then(|entry| entry.undo);
+                    index.insert(
+                        header.hash().clone(),
+                        BlockIndexEntry {
+                            header,
+                            height,
+                            data: Some(pos),
+                            undo,
+                        },
+                    );
+                }
+                INDEX_UNDO => {
+                    let hash = BlockHash::deserialize(&mut *src)?;
+                    let pos = read_pos(src)?;
+                    match index.get_mut(&hash) {
+                        Some(entry) => entry.undo = Some(pos),
+                        None => {
+                            return Err(StorageError::Corruption(String::from(
+                                "block index has undo data for an unknown block",
+                            )))
+                        }
+                    }
+                }
+                other => {
+                    return Err(StorageError::Corruption(format!(
+                        "unknown block index record type {}",
+                        other
+                    )))
+                }
+            }
+            Ok(())
+        };
+        match record(&mut src) {
+            Ok(()) => valid_len = contents.len() - src.len(),
+            // Running out of data part way through a record means the last write was interrupted
+            Err(StorageError::Deserialization(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((index, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use shared::{MerkleRoot, Nbits, TxOutput};
+
+    const MAGIC: u32 = 0xD9B4BEF9;
+
+    /// Builds a chain of `len` blocks, each containing a copy of the same coinbase
+    fn chain(len: u32) -> Vec<Block> {
+        let target = Block::_test_block().header().target().clone();
+        let coinbase = Transaction::_test_coinbase();
+        let mut prev = BlockHash::from([0; 32]);
+        (0..len)
+            .map(|nonce| {
+                let mut header = BlockHeader::new(
+                    1,
+                    prev.clone(),
+                    MerkleRoot::from_vec(vec![coinbase.txid()]),
+                    1_231_006_505 + nonce,
+                    Nbits::new(target.clone()),
+                    nonce,
+                );
+                header.set_hash();
+                prev = header.hash().clone();
+                Block::new(header, vec![coinbase.clone()])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocks_and_undo_survive_reopening() {
+        let dir = temp_dir("block-store");
+        let blocks = chain(3);
+        let undo = vec![Coin::new(TxOutput::new(5000, vec![0x51]), 12, true)];
+        {
+            let mut store = BlockStore::open(&dir, MAGIC, None).unwrap();
+            for (height, block) in blocks.iter().enumerate() {
+                store.write_block(block, height as u32).unwrap();
+            }
+            store.write_undo(blocks[1].header().hash(), &undo).unwrap();
+        }
+        let store = BlockStore::open(&dir, MAGIC, None).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            let hash = block.header().hash();
+            let read = store.read_block(hash).unwrap().unwrap();
+            assert_eq!(read.header().hash(), hash);
+            assert_eq!(store.entry(hash).unwrap().height(), height as u32);
+        }
+        assert_eq!(
+            store.read_undo(blocks[1].header().hash()).unwrap(),
+            Some(undo)
+        );
+        assert_eq!(store.read_undo(blocks[2].header().hash()).unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_truncated_index_record() {
+        let dir = temp_dir("block-store-truncated");
+        let blocks = chain(2);
+        {
+            let mut store = BlockStore::open(&dir, MAGIC, None).unwrap();
+            store.write_block(&blocks[0], 0).unwrap();
+            store.write_block(&blocks[1], 1).unwrap();
+        }
+        let index_path = dir.join(INDEX_FILE);
+        let len = fs::metadata(&index_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&index_path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let mut store = BlockStore::open(&dir, MAGIC, None).unwrap();
+        assert!(store.has_block(blocks[0].header().hash()));
+        assert!(!store.has_block(blocks[1].header().hash()));
+        // The index keeps working after the truncated record is dropped
+        store.write_block(&blocks[1], 1).unwrap();
+        let store = BlockStore::open(&dir, MAGIC, None).unwrap();
+        assert!(store.has_block(blocks[1].header().hash()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_corrupt_recent_blocks() {
+        let dir = temp_dir("block-store-check");
+        let blocks = chain(3);
+        let mut store = BlockStore::open(&dir, MAGIC, None).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            store.write_block(block, height as u32).unwrap();
+        }
+        assert_eq!(store.check_recent_blocks(6).unwrap(), 3);
+
+        // Flip a bit in the newest block's header
+        let pos = store
+            .entry(blocks[2].header().hash())
+            .unwrap()
+            .data
+            .unwrap();
+        let path = file_path(&dir, false, pos.file);
+        let mut contents = fs::read(&path).unwrap();
+        contents[pos.offset as usize + 4] ^= 1;
+        fs::write(&path, contents).unwrap();
+        assert!(matches!(
+            store.check_recent_blocks(1),
+            Err(StorageError::Corruption(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_old_files_but_keeps_headers() {
+        let dir = temp_dir("block-store-prune");
+        let blocks = chain(2);
+        let mut store = BlockStore::open(&dir, MAGIC, Some(0)).unwrap();
+        store.write_block(&blocks[0], 1000).unwrap();
+        // Force the next block into a second file
+        store.files.push(FileInfo::default());
+        store.write_block(&blocks[1], 1001).unwrap();
+
+        // Nothing old enough to prune yet
+        assert!(store.prune(1000).unwrap().is_empty());
+        assert_eq!(store.prune(1000 + MIN_BLOCKS_TO_KEEP).unwrap(), vec![0]);
+        assert!(!file_path(&dir, false, 0).exists());
+
+        let first = blocks[0].header().hash();
+        assert!(!store.has_block(first));
+        assert!(store.read_block(first).unwrap().is_none());
+        assert!(store.header(first).is_some());
+        // The current file is never pruned
+        assert!(store.has_block(blocks[1].header().hash()));
+
+        let store = BlockStore::open(&dir, MAGIC, Some(0)).unwrap();
+        assert!(!store.has_block(first));
+        assert!(store.header(first).is_some());
+        assert!(store.has_block(blocks[1].header().hash()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
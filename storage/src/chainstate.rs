@@ -0,0 +1,200 @@
+use crate::{Coin, StorageError, UtxoSet};
+use shared::{u256, Block, BlockHash, Transaction, TxOutpoint};
+use tracing::trace;
+
+/// Scripts longer than this can never be spent (see `MAX_SCRIPT_SIZE` in Bitcoin Core)
+const MAX_SCRIPT_SIZE: usize = 10_000;
+const OP_RETURN: u8 = 0x6a;
+
+/// A UTXO set, along with the block at which it is valid.
+#[derive(Debug, Clone)]
+pub struct Chainstate {
+    utxos: UtxoSet,
+    tip: BlockHash,
+    height: u32,
+}
+
+impl Chainstate {
+    pub fn new(utxos: UtxoSet, tip: BlockHash, height: u32) -> Chainstate {
+        Chainstate { utxos, tip, height }
+    }
+    /// Creates an empty chainstate at the genesis block. The genesis coinbase is unspendable,
+    /// so it never enters the UTXO set.
+    pub fn genesis(genesis_hash: BlockHash) -> Chainstate {
+        Chainstate::new(UtxoSet::new(), genesis_hash, 0)
+    }
+    pub fn utxos(&self) -> &UtxoSet {
+        &self.utxos
+    }
+    pub fn into_utxos(self) -> UtxoSet {
+        self.utxos
+    }
+    /// The hash of the last block connected to this chainstate
+    pub fn tip(&self) -> &BlockHash {
+        &self.tip
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Applies a block's transactions to the UTXO set, returning the coins it spent (its undo data)
+    /// in the order they were spent.
+    ///
+    /// The block must build on the current tip. Scripts are not checked. If any input is missing,
+    /// the chainstate is left unchanged.
+    pub fn connect_block(&mut self, block: &Block) -> Result<Vec<Coin>, StorageError> {
+        if block.header().prev_hash() != &self.tip {
+            return Err(StorageError::InvalidBlock(format!(
+                "block {:?} does not build on tip {:?}",
+                block.header().hash(),
+                self.tip
+            )));
+        }
+        let height = self.height + 1;
+        let mut spent = Vec::new();
+        for (position, tx) in block.transactions().iter().enumerate() {
+            if !tx.is_coinbase() {
+                for input in tx.inputs() {
+                    match self.utxos.remove(input.previous_outpoint()) {
+                        Some(coin) => spent.push(coin),
+                        None => {
+                            let missing = input.previous_outpoint().clone();
+                            self.undo_transactions(&block.transactions()[..=position], spent);
+                            return Err(StorageError::MissingCoin(missing));
+                        }
+                    }
+                }
+            }
+            add_outputs(&mut self.utxos, tx, height);
+        }
+        trace!(
+            "Connected block {:?} at height {}, spending {} coins",
+            block.header().hash(),
+            height,
+            spent.len()
+        );
+        self.tip = block.header().hash().clone();
+        self.height = height;
+        Ok(spent)
+    }
+
+    /// Reverts the tip block, given the undo data returned when it was connected.
+    pub fn disconnect_block(&mut self, block: &Block, undo: Vec<Coin>) -> Result<(), StorageError> {
+        if block.header().hash() != &self.tip || self.height == 0 {
+            return Err(StorageError::InvalidBlock(format!(
+                "block {:?} is not the tip",
+                block.header().hash()
+            )));
+        }
+        let expected: usize = block
+            .transactions()
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .map(|tx| tx.inputs().len())
+            .sum();
+        if expected != undo.len() {
+            return Err(StorageError::InvalidBlock(format!(
+                "undo data for block {:?} has {} coins, but the block spends {}",
+                block.header().hash(),
+                undo.len(),
+                expected
+            )));
+        }
+        self.undo_transactions(block.transactions(), undo);
+        self.tip = block.header().prev_hash().clone();
+        self.height -= 1;
+        Ok(())
+    }
+
+    /// Removes the outputs of `txs` and restores the coins they spent. `spent` may be shorter than
+    /// the total number of inputs if the last transaction was only partially applied.
+    fn undo_transactions(&mut self, txs: &[Transaction], spent: Vec<Coin>) {
+        let mut spent = spent.into_iter();
+        let inputs = txs
+            .iter()
+            .filter(|tx| !tx.is_coinbase())
+            .flat_map(|tx| tx.inputs());
+        for (input, coin) in inputs.zip(&mut spent) {
+            self.utxos.insert(input.previous_outpoint().clone(), coin);
+        }
+        // Outputs are removed last, since coins created and spent within the block were restored above
+        for tx in txs {
+            for index in 0..tx.outputs().len() {
+                self.utxos.remove(&outpoint(tx, index as u32));
+            }
+        }
+    }
+}
+
+fn outpoint(tx: &Transaction, index: u32) -> TxOutpoint {
+    TxOutpoint::new(u256::from_bytes(*tx.txid().inner()), index)
+}
+
+fn add_outputs(utxos: &mut UtxoSet, tx: &Transaction, height: u32) {
+    for (index, output) in tx.outputs().iter().enumerate() {
+        if is_unspendable(output.pk_script()) {
+            continue;
+        }
+        utxos.insert(
+            outpoint(tx, index as u32),
+            Coin::new(output.clone(), height, tx.is_coinbase()),
+        );
+    }
+}
+
+/// Outputs which can provably never be spent are not added to the UTXO set.
+pub(crate) fn is_unspendable(script: &[u8]) -> bool {
+    script.first() == Some(&OP_RETURN) || script.len() > MAX_SCRIPT_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::TxOutput;
+
+    #[test]
+    fn connect_and_disconnect_block() {
+        let block = Block::_test_block();
+        let mut utxos = UtxoSet::new();
+        for tx in block.transactions().iter().skip(1) {
+            for input in tx.inputs() {
+                let coin = Coin::new(TxOutput::new(50_0000_0000, vec![0x51]), 1, true);
+                utxos.insert(input.previous_outpoint().clone(), coin);
+            }
+        }
+        let before = utxos.hash_serialized();
+        let mut chainstate = Chainstate::new(utxos, block.header().prev_hash().clone(), 100);
+
+        let undo = chainstate.connect_block(&block).unwrap();
+        assert_eq!(undo.len(), 3);
+        assert_eq!(chainstate.height(), 101);
+        assert_eq!(chainstate.tip(), block.header().hash());
+        // One output from the coinbase, one from the spending transaction
+        assert_eq!(chainstate.utxos().len(), 2);
+
+        chainstate.disconnect_block(&block, undo).unwrap();
+        assert_eq!(chainstate.height(), 100);
+        assert_eq!(chainstate.utxos().hash_serialized(), before);
+    }
+
+    #[test]
+    fn failed_connect_leaves_state_unchanged() {
+        let block = Block::_test_block();
+        let mut utxos = UtxoSet::new();
+        // Only the first of the three spent coins exists
+        let first = block.transactions()[1].inputs()[0].previous_outpoint();
+        utxos.insert(
+            first.clone(),
+            Coin::new(TxOutput::new(1, vec![0x51]), 1, false),
+        );
+        let before = utxos.hash_serialized();
+        let mut chainstate = Chainstate::new(utxos, block.header().prev_hash().clone(), 100);
+
+        assert!(matches!(
+            chainstate.connect_block(&block),
+            Err(StorageError::MissingCoin(_))
+        ));
+        assert_eq!(chainstate.height(), 100);
+        assert_eq!(chainstate.utxos().hash_serialized(), before);
+    }
+}
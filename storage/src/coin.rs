@@ -0,0 +1,36 @@
+use shared::TxOutput;
+
+/// An unspent transaction output, along with the metadata needed to validate a spend of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coin {
+    output: TxOutput,
+    height: u32,
+    is_coinbase: bool,
+}
+
+impl Coin {
+    pub fn new(output: TxOutput, height: u32, is_coinbase: bool) -> Coin {
+        Coin {
+            output,
+            height,
+            is_coinbase,
+        }
+    }
+    pub fn output(&self) -> &TxOutput {
+        &self.output
+    }
+    pub fn value(&self) -> i64 {
+        self.output.value()
+    }
+    /// The height of the block which created this coin
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn is_coinbase(&self) -> bool {
+        self.is_coinbase
+    }
+    /// Packs height and coinbase flag into a single integer, the way Bitcoin Core does.
+    pub fn code(&self) -> u32 {
+        (self.height << 1) | self.is_coinbase as u32
+    }
+}
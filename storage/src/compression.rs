@@ -0,0 +1,322 @@
+//! Bitcoin Core's compact encodings for coins, used by its chainstate database and UTXO snapshots.
+//!
+//! See `compressor.h` and `serialize.h` in Bitcoin Core.
+use crate::{Coin, StorageError};
+use bytes::Buf;
+use shared::TxOutput;
+
+/// Scripts longer than this are unspendable, so Core replaces them with a single `OP_RETURN` on disk.
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+const OP_RETURN: u8 = 0x6a;
+/// The number of special script templates. Raw scripts have their size offset by this amount.
+const SPECIAL_SCRIPTS: u64 = 6;
+
+/// Reads Core's `VARINT`, a big-endian base-128 encoding where each continuation adds one.
+/// Unlike LevelDB's varints, this encoding is order-preserving and has exactly one representation for each value.
+pub fn read_varint<B: Buf>(src: &mut B) -> Result<u64, StorageError> {
+    let mut n: u64 = 0;
+    loop {
+        if !src.has_remaining() {
+            return Err(StorageError::Corruption(String::from("truncated VARINT")));
+        }
+        let byte = src.get_u8();
+        if n > (u64::MAX >> 7) {
+            return Err(StorageError::Corruption(String::from(
+                "VARINT is too large",
+            )));
+        }
+        n = (n << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 != 0 {
+            n = n
+                .checked_add(1)
+                .ok_or_else(|| StorageError::Corruption(String::from("VARINT is too large")))?;
+        } else {
+            return Ok(n);
+        }
+    }
+}
+
+/// Writes Core's `VARINT`. See [`read_varint`].
+pub fn write_varint(target: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = [0u8; 10];
+    let mut len = 0;
+    loop {
+        tmp[len] = (n & 0x7f) as u8 | if len > 0 { 0x80 } else { 0x00 };
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+        len += 1;
+    }
+    target.extend(tmp[..=len].iter().rev());
+}
+
+/// Compresses an amount of satoshis, exploiting the fact that most amounts are round numbers in decimal.
+pub fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n.is_multiple_of(10) && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+/// The inverse of [`compress_amount`]
+pub fn decompress_amount(mut x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    x -= 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n = n.wrapping_mul(10);
+        e -= 1;
+    }
+    n
+}
+
+fn take<B: Buf>(src: &mut B, len: usize) -> Result<Vec<u8>, StorageError> {
+    if src.remaining() < len {
+        return Err(StorageError::Corruption(String::from(
+            "truncated compressed script",
+        )));
+    }
+    let mut result = vec![0u8; len];
+    src.copy_to_slice(&mut result);
+    Ok(result)
+}
+
+/// Reads a script in Core's `ScriptCompression` format, expanding the special templates
+/// (P2PKH, P2SH and P2PK) back into full scripts.
+pub fn read_script<B: Buf>(src: &mut B) -> Result<Vec<u8>, StorageError> {
+    let size = read_varint(src)?;
+    let script = match size {
+        // P2PKH: OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+        0 => {
+            let mut script = vec![0x76, 0xa9, 20];
+            script.extend(take(src, 20)?);
+            script.extend([0x88, 0xac].iter());
+            script
+        }
+        // P2SH: OP_HASH160 <20 bytes> OP_EQUAL
+        1 => {
+            let mut script = vec![0xa9, 20];
+            script.extend(take(src, 20)?);
+            script.push(0x87);
+            script
+        }
+        // P2PK with a compressed key: <33 bytes> OP_CHECKSIG
+        2 | 3 => {
+            let mut script = vec![33, size as u8];
+            script.extend(take(src, 32)?);
+            script.push(0xac);
+            script
+        }
+        // P2PK with an uncompressed key, which Core stores in compressed form: <65 bytes> OP_CHECKSIG
+        4 | 5 => {
+            let mut compressed = [0u8; 33];
+            compressed[0] = (size - 2) as u8;
+            compressed[1..].copy_from_slice(&take(src, 32)?);
+            let pubkey = warp_crypto::decompress_pubkey(&compressed).ok_or_else(|| {
+                StorageError::Corruption(String::from("invalid compressed public key"))
+            })?;
+            let mut script = vec![65];
+            script.extend(pubkey.iter());
+            script.push(0xac);
+            script
+        }
+        _ => {
+            let len = size - SPECIAL_SCRIPTS;
+            if len > MAX_SCRIPT_SIZE {
+                if (src.remaining() as u64) < len {
+                    return Err(StorageError::Corruption(String::from(
+                        "truncated compressed script",
+                    )));
+                }
+                src.advance(len as usize);
+                vec![OP_RETURN]
+            } else {
+                take(src, len as usize)?
+            }
+        }
+    };
+    Ok(script)
+}
+
+/// Writes a script in Core's `ScriptCompression` format. See [`read_script`].
+pub fn write_script(target: &mut Vec<u8>, script: &[u8]) {
+    match script {
+        // P2PKH
+        [0x76, 0xa9, 20, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+            target.push(0);
+            target.extend_from_slice(hash);
+        }
+        // P2SH
+        [0xa9, 20, hash @ .., 0x87] if hash.len() == 20 => {
+            target.push(1);
+            target.extend_from_slice(hash);
+        }
+        // P2PK with a compressed key
+        [33, prefix @ (2 | 3), x @ .., 0xac] if x.len() == 32 => {
+            target.push(*prefix);
+            target.extend_from_slice(x);
+        }
+        // P2PK with an uncompressed key. Only valid keys can be stored compressed, since
+        // the y coordinate has to be recovered from the curve when reading them back.
+        [65, 4, key @ .., 0xac]
+            if key.len() == 64 && compress_uncompressed(&script[1..66]).is_some() =>
+        {
+            let compressed = compress_uncompressed(&script[1..66]).expect("Just checked");
+            target.push(compressed[0] + 2);
+            target.extend_from_slice(&compressed[1..]);
+        }
+        _ => {
+            write_varint(target, script.len() as u64 + SPECIAL_SCRIPTS);
+            target.extend_from_slice(script);
+        }
+    }
+}
+
+fn compress_uncompressed(key: &[u8]) -> Option<[u8; 33]> {
+    let mut uncompressed = [0u8; 65];
+    uncompressed.copy_from_slice(key);
+    warp_crypto::compress_pubkey(&uncompressed)
+}
+
+/// Reads a transaction output in Core's `TxOutCompression` format
+pub fn read_txout<B: Buf>(src: &mut B) -> Result<TxOutput, StorageError> {
+    let value = decompress_amount(read_varint(src)?);
+    let script = read_script(src)?;
+    Ok(TxOutput::new(value as i64, script))
+}
+
+/// Writes a transaction output in Core's `TxOutCompression` format
+pub fn write_txout(target: &mut Vec<u8>, output: &TxOutput) {
+    write_varint(target, compress_amount(output.value() as u64));
+    write_script(target, output.pk_script());
+}
+
+/// Reads a coin: `VARINT(height * 2 + coinbase)` followed by a compressed output.
+pub fn read_coin<B: Buf>(src: &mut B) -> Result<Coin, StorageError> {
+    let code = read_varint(src)?;
+    let output = read_txout(src)?;
+    Ok(Coin::new(output, (code >> 1) as u32, code & 1 == 1))
+}
+
+/// Writes a coin in the format read by [`read_coin`]
+pub fn write_coin(target: &mut Vec<u8>, coin: &Coin) {
+    write_varint(target, coin.code() as u64);
+    write_txout(target, coin.output());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varint_bit_patterns() {
+        // Test vectors from Bitcoin Core's serialize_tests.cpp
+        let cases: &[(u64, &str)] = &[
+            (0, "00"),
+            (0x7f, "7f"),
+            (0x80, "8000"),
+            (0x1234, "a334"),
+            (0xffff, "82fe7f"),
+            (0x123456, "c7e756"),
+            (0x80123456, "86ffc7e756"),
+            (0xffffffff, "8efefefe7f"),
+            (0xffffffffffffffff, "80fefefefefefefefe7f"),
+        ];
+        for (value, encoded) in cases {
+            let mut out = Vec::new();
+            write_varint(&mut out, *value);
+            assert_eq!(&hex::encode(&out), encoded);
+            assert_eq!(read_varint(&mut &out[..]).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn amount_compression() {
+        // Test vectors from Bitcoin Core's compress_tests.cpp
+        const COIN: u64 = 100_000_000;
+        let cases: &[(u64, u64)] = &[
+            (0, 0x0),
+            (1, 0x1),
+            (COIN / 100, 0x7),
+            (COIN, 0x9),
+            (50 * COIN, 0x32),
+            (21_000_000 * COIN, 0x1406f40),
+        ];
+        for (amount, compressed) in cases {
+            assert_eq!(compress_amount(*amount), *compressed);
+            assert_eq!(decompress_amount(*compressed), *amount);
+        }
+        for amount in (0..100_000).step_by(7) {
+            assert_eq!(decompress_amount(compress_amount(amount)), amount);
+        }
+    }
+
+    #[test]
+    fn expands_script_templates() {
+        let p2pkh = hex::decode("00a09be8040cbf399926aeb1f470c37d1341f3b465").unwrap();
+        assert_eq!(
+            hex::encode(read_script(&mut &p2pkh[..]).unwrap()),
+            "76a914a09be8040cbf399926aeb1f470c37d1341f3b46588ac"
+        );
+        let p2sh = hex::decode("01a09be8040cbf399926aeb1f470c37d1341f3b465").unwrap();
+        assert_eq!(
+            hex::encode(read_script(&mut &p2sh[..]).unwrap()),
+            "a914a09be8040cbf399926aeb1f470c37d1341f3b46587"
+        );
+        // The generator point, stored as an uncompressed key with an even y coordinate
+        let p2pk =
+            hex::decode("0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        assert_eq!(
+            hex::encode(read_script(&mut &p2pk[..]).unwrap()),
+            "410479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8ac"
+        );
+        // A raw script: size 2 + 6
+        let raw = hex::decode("085152").unwrap();
+        assert_eq!(read_script(&mut &raw[..]).unwrap(), vec![0x51, 0x52]);
+    }
+
+    #[test]
+    fn script_compression_roundtrip() {
+        let scripts = [
+            "76a914a09be8040cbf399926aeb1f470c37d1341f3b46588ac",
+            "a914a09be8040cbf399926aeb1f470c37d1341f3b46587",
+            "2102a09be8040cbf399926aeb1f470c37d1341f3b465a09be8040cbf399926aeb1f4ac",
+            "410479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8ac",
+            // An uncompressed key which is not on the curve must be stored raw
+            "4104ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffac",
+            "0014a09be8040cbf399926aeb1f470c37d1341f3b465",
+            "",
+        ];
+        let expected_lens = [21, 21, 33, 33, 68, 23, 1];
+        for (script, expected_len) in scripts.iter().zip(expected_lens.iter()) {
+            let script = hex::decode(script).unwrap();
+            let mut compressed = Vec::new();
+            write_script(&mut compressed, &script);
+            assert_eq!(compressed.len(), *expected_len);
+            assert_eq!(read_script(&mut &compressed[..]).unwrap(), script);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Low level encodings used by LevelDB's on-disk formats.
+use crate::StorageError;
+
+fn truncated(what: &str) -> StorageError {
+    StorageError::Corruption(format!("truncated {}", what))
+}
+
+pub fn get_fixed32(src: &[u8], pos: &mut usize) -> Result<u32, StorageError> {
+    let end = *pos + 4;
+    if end > src.len() {
+        return Err(truncated("fixed32"));
+    }
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&src[*pos..end]);
+    *pos = end;
+    Ok(u32::from_le_bytes(raw))
+}
+
+pub fn get_fixed64(src: &[u8], pos: &mut usize) -> Result<u64, StorageError> {
+    let end = *pos + 8;
+    if end > src.len() {
+        return Err(truncated("fixed64"));
+    }
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&src[*pos..end]);
+    *pos = end;
+    Ok(u64::from_le_bytes(raw))
+}
+
+/// Reads a little-endian base-128 varint of up to 64 bits
+pub fn get_varint(src: &[u8], pos: &mut usize) -> Result<u64, StorageError> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    while shift <= 63 {
+        let byte = *src.get(*pos).ok_or_else(|| truncated("varint"))?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+    Err(StorageError::Corruption(String::from("varint is too long")))
+}
+
+/// Reads a varint length followed by that many bytes
+pub fn get_length_prefixed<'a>(src: &'a [u8], pos: &mut usize) -> Result<&'a [u8], StorageError> {
+    let len = get_varint(src, pos)? as usize;
+    let end = pos
+        .checked_add(len)
+        .filter(|end| *end <= src.len())
+        .ok_or_else(|| truncated("length prefixed slice"))?;
+    let result = &src[*pos..end];
+    *pos = end;
+    Ok(result)
+}
+
+const CRC32C_TABLE: [u32; 256] = make_crc32c_table();
+
+const fn make_crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Extends a CRC-32C (Castagnoli) checksum with `data`.
+pub fn crc32c_extend(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// LevelDB stores "masked" checksums, since computing the CRC of data which itself contains CRCs is problematic.
+pub fn unmask_crc(masked: u32) -> u32 {
+    let rot = masked.wrapping_sub(0xa282_ead8);
+    rot.rotate_left(15)
+}
+
+#[cfg(test)]
+pub fn mask_crc(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+#[cfg(test)]
+pub fn put_varint(target: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        target.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    target.push(value as u8);
+}
+
+#[test]
+fn crc32c_check_value() {
+    assert_eq!(crc32c_extend(0, b"123456789"), 0xe306_9283);
+    let crc = crc32c_extend(crc32c_extend(0, b"1234"), b"56789");
+    assert_eq!(crc, 0xe306_9283);
+    assert_eq!(unmask_crc(mask_crc(crc)), crc);
+}
+
+#[test]
+fn varint_roundtrip() {
+    for value in [0u64, 1, 127, 128, 300, 1 << 35, u64::MAX].iter() {
+        let mut encoded = Vec::new();
+        put_varint(&mut encoded, *value);
+        let mut pos = 0;
+        assert_eq!(get_varint(&encoded, &mut pos).unwrap(), *value);
+        assert_eq!(pos, encoded.len());
+    }
+}
@@ -0,0 +1,117 @@
+//! A reader for LevelDB's log format, which is used for both the write-ahead log and the MANIFEST.
+//!
+//! See https://github.com/google/leveldb/blob/main/doc/log_format.md
+use super::coding::{crc32c_extend, get_fixed32, get_fixed64, get_length_prefixed, unmask_crc};
+use super::Entry;
+use crate::StorageError;
+use tracing::warn;
+
+const BLOCK_SIZE: usize = 32 * 1024;
+const HEADER_LEN: usize = 7;
+
+const FULL: u8 = 1;
+const FIRST: u8 = 2;
+const MIDDLE: u8 = 3;
+const LAST: u8 = 4;
+
+/// Splits the contents of a log file into its logical records.
+///
+/// A log which ends in a partially written record is what LevelDB leaves behind after a crash,
+/// so the incomplete tail is dropped with a warning rather than treated as an error.
+pub fn read_records(data: &[u8]) -> Result<Vec<Vec<u8>>, StorageError> {
+    let mut records = Vec::new();
+    let mut pending: Option<Vec<u8>> = None;
+    let mut pos = 0;
+    while pos < data.len() {
+        let block_remaining = BLOCK_SIZE - (pos % BLOCK_SIZE);
+        if block_remaining < HEADER_LEN {
+            // The rest of the block is zero padding
+            pos += block_remaining;
+            continue;
+        }
+        if pos + HEADER_LEN > data.len() {
+            warn!("LevelDB log ends in a truncated record header");
+            break;
+        }
+        let mut header_pos = pos;
+        let expected_crc = unmask_crc(get_fixed32(data, &mut header_pos)?);
+        let len = u16::from_le_bytes([data[pos + 4], data[pos + 5]]) as usize;
+        let kind = data[pos + 6];
+        let start = pos + HEADER_LEN;
+        if start + len > data.len() {
+            warn!("LevelDB log ends in a truncated record");
+            break;
+        }
+        // Preallocated files may be padded with zeroes
+        if kind == 0 && len == 0 {
+            pos += block_remaining;
+            continue;
+        }
+        let payload = &data[start..start + len];
+        if crc32c_extend(crc32c_extend(0, &[kind]), payload) != expected_crc {
+            return Err(StorageError::Corruption(String::from(
+                "log record checksum mismatch",
+            )));
+        }
+        pos = start + len;
+
+        match kind {
+            FULL => records.push(payload.to_vec()),
+            FIRST => pending = Some(payload.to_vec()),
+            MIDDLE | LAST => match pending.as_mut() {
+                Some(buf) => {
+                    buf.extend_from_slice(payload);
+                    if kind == LAST {
+                        records.push(pending.take().expect("Just checked that pending is some"));
+                    }
+                }
+                None => {
+                    return Err(StorageError::Corruption(String::from(
+                        "log record fragment without a start",
+                    )))
+                }
+            },
+            other => {
+                return Err(StorageError::Corruption(format!(
+                    "unknown log record type {}",
+                    other
+                )))
+            }
+        }
+    }
+    if pending.is_some() {
+        warn!("LevelDB log ends in a partially written record");
+    }
+    Ok(records)
+}
+
+/// Decodes a `WriteBatch` record from the write-ahead log into the entries it contains
+pub fn parse_write_batch(record: &[u8]) -> Result<Vec<Entry>, StorageError> {
+    let mut pos = 0;
+    let sequence = get_fixed64(record, &mut pos)?;
+    let count = get_fixed32(record, &mut pos)? as u64;
+    let mut entries = Vec::with_capacity(count as usize);
+    for offset in 0..count {
+        let tag = *record
+            .get(pos)
+            .ok_or_else(|| StorageError::Corruption(String::from("truncated write batch")))?;
+        pos += 1;
+        let key = get_length_prefixed(record, &mut pos)?.to_vec();
+        let value = match tag {
+            0 => None,
+            1 => Some(get_length_prefixed(record, &mut pos)?.to_vec()),
+            other => {
+                return Err(StorageError::Corruption(format!(
+                    "unknown write batch tag {}",
+                    other
+                )))
+            }
+        };
+        entries.push(Entry {
+            key,
+            sequence: sequence + offset,
+            value,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,553 @@
+//! A minimal, read-only LevelDB reader.
+//!
+//! Bitcoin Core stores its chainstate in LevelDB. Rather than linking against the C++ library,
+//! we implement just enough of the on-disk format to scan a (closed) database in key order, starting from any key.
+//! Point lookups are scans which stop after one key, so they're only cheap for a handful of keys. Compression and
+//! writes are not supported.
+mod coding;
+mod log;
+mod table;
+
+use crate::StorageError;
+use coding::{get_length_prefixed, get_varint};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::path::{Path, PathBuf};
+use table::Table;
+
+const BYTEWISE_COMPARATOR: &[u8] = b"leveldb.BytewiseComparator";
+
+/// A key and its value
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// A single versioned record. A `value` of `None` marks a deletion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub sequence: u64,
+    pub value: Option<Vec<u8>>,
+}
+
+impl Entry {
+    /// Splits a table's internal key into the user key and its (sequence, type) trailer
+    fn from_internal(mut internal_key: Vec<u8>, value: Vec<u8>) -> Result<Entry, StorageError> {
+        if internal_key.len() < 8 {
+            return Err(StorageError::Corruption(String::from(
+                "internal key is too short",
+            )));
+        }
+        let mut pos = internal_key.len() - 8;
+        let trailer = coding::get_fixed64(&internal_key, &mut pos)?;
+        internal_key.truncate(internal_key.len() - 8);
+        let value = match trailer & 0xff {
+            0 => None,
+            1 => Some(value),
+            other => {
+                return Err(StorageError::Corruption(format!(
+                    "unknown value type {}",
+                    other
+                )))
+            }
+        };
+        Ok(Entry {
+            key: internal_key,
+            sequence: trailer >> 8,
+            value,
+        })
+    }
+}
+
+/// The set of files that make up the current version of a database.
+#[derive(Debug)]
+pub struct Database {
+    tables: Vec<PathBuf>,
+    logs: Vec<PathBuf>,
+}
+
+impl Database {
+    /// Opens the database in `dir` by replaying its MANIFEST.
+    ///
+    /// The database must not be open in another process (i.e. Bitcoin Core must be shut down).
+    pub fn open(dir: &Path) -> Result<Database, StorageError> {
+        let current = std::fs::read_to_string(dir.join("CURRENT"))?;
+        let manifest_name = current.trim_end();
+        if !manifest_name.starts_with("MANIFEST-") {
+            return Err(StorageError::Corruption(format!(
+                "CURRENT points to {:?}",
+                manifest_name
+            )));
+        }
+        let manifest = std::fs::read(dir.join(manifest_name))?;
+
+        let mut live_files = BTreeSet::new();
+        let mut log_number = 0;
+        let mut prev_log_number = 0;
+        for edit in log::read_records(&manifest)? {
+            let mut pos = 0;
+            while pos < edit.len() {
+                match get_varint(&edit, &mut pos)? {
+                    // Comparator
+                    1 => {
+                        let name = get_length_prefixed(&edit, &mut pos)?;
+                        if name != BYTEWISE_COMPARATOR {
+                            return Err(StorageError::Unsupported(format!(
+                                "comparator {}",
+                                String::from_utf8_lossy(name)
+                            )));
+                        }
+                    }
+                    // Log number
+                    2 => log_number = get_varint(&edit, &mut pos)?,
+                    // Next file number and last sequence are irrelevant to a reader
+                    3 | 4 => {
+                        get_varint(&edit, &mut pos)?;
+                    }
+                    // Compaction pointer
+                    5 => {
+                        get_varint(&edit, &mut pos)?;
+                        get_length_prefixed(&edit, &mut pos)?;
+                    }
+                    // Deleted file
+                    6 => {
+                        get_varint(&edit, &mut pos)?;
+                        live_files.remove(&get_varint(&edit, &mut pos)?);
+                    }
+                    // New file
+                    7 => {
+                        get_varint(&edit, &mut pos)?;
+                        live_files.insert(get_varint(&edit, &mut pos)?);
+                        get_varint(&edit, &mut pos)?;
+                        get_length_prefixed(&edit, &mut pos)?;
+                        get_length_prefixed(&edit, &mut pos)?;
+                    }
+                    // Previous log number
+                    9 => prev_log_number = get_varint(&edit, &mut pos)?,
+                    other => {
+                        return Err(StorageError::Corruption(format!(
+                            "unknown MANIFEST tag {}",
+                            other
+                        )))
+                    }
+                }
+            }
+        }
+
+        let mut tables = Vec::with_capacity(live_files.len());
+        for number in live_files {
+            let ldb = dir.join(format!("{:06}.ldb", number));
+            let sst = dir.join(format!("{:06}.sst", number));
+            if ldb.exists() {
+                tables.push(ldb);
+            } else if sst.exists() {
+                tables.push(sst);
+            } else {
+                return Err(StorageError::Corruption(format!(
+                    "missing table {:06}",
+                    number
+                )));
+            }
+        }
+
+        let mut logs = Vec::new();
+        for dir_entry in std::fs::read_dir(dir)? {
+            let name = dir_entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(number) = name
+                .strip_suffix(".log")
+                .and_then(|stem| stem.parse::<u64>().ok())
+            {
+                if number >= log_number || number == prev_log_number {
+                    logs.push((number, dir.join(name.as_ref())));
+                }
+            }
+        }
+        logs.sort();
+
+        Ok(Database {
+            tables,
+            logs: logs.into_iter().map(|(_, path)| path).collect(),
+        })
+    }
+
+    /// Returns an iterator over the live key/value pairs whose keys are at least `start`, in key order.
+    pub fn iter_from(&self, start: &[u8]) -> Result<DatabaseIter, StorageError> {
+        let mut sources: Vec<Box<dyn Iterator<Item = Result<Entry, StorageError>>>> = Vec::new();
+
+        // Entries which haven't been compacted into a table yet live only in the write-ahead log.
+        let mut unflushed = Vec::new();
+        for path in self.logs.iter() {
+            for record in log::read_records(&std::fs::read(path)?)? {
+                unflushed.extend(log::parse_write_batch(&record)?);
+            }
+        }
+        unflushed.retain(|entry| entry.key.as_slice() >= start);
+        unflushed.sort_by(|a, b| a.key.cmp(&b.key).then(b.sequence.cmp(&a.sequence)));
+        sources.push(Box::new(unflushed.into_iter().map(Ok)));
+
+        for path in self.tables.iter() {
+            // The first block read may start before `start`
+            let start = start.to_vec();
+            let entries = Table::open(path.clone())?
+                .entries_from(&start)
+                .skip_while(move |entry| matches!(entry, Ok(entry) if entry.key < start));
+            sources.push(Box::new(entries));
+        }
+        DatabaseIter::new(sources)
+    }
+
+    /// Looks up the live value of `key`
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, StorageError> {
+        match self.iter_from(key)?.next().transpose()? {
+            Some((found, value)) if found == key => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether any live key starts with `prefix`
+    pub fn contains_prefix(&self, prefix: &[u8]) -> Result<bool, StorageError> {
+        Ok(self
+            .iter_from(prefix)?
+            .next()
+            .transpose()?
+            .is_some_and(|(key, _)| key.starts_with(prefix)))
+    }
+}
+
+struct HeapItem {
+    entry: Entry,
+    source: usize,
+}
+
+// BinaryHeap is a max-heap, so "greatest" must mean "smallest key, then newest sequence"
+impl Ord for HeapItem {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .entry
+            .key
+            .cmp(&self.entry.key)
+            .then(self.entry.sequence.cmp(&other.entry.sequence))
+    }
+}
+impl PartialOrd for HeapItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for HeapItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for HeapItem {}
+
+/// Merges the sorted sources of a database, yielding only the newest version of each key and skipping deletions.
+pub struct DatabaseIter {
+    sources: Vec<Box<dyn Iterator<Item = Result<Entry, StorageError>>>>,
+    heap: BinaryHeap<HeapItem>,
+}
+
+impl DatabaseIter {
+    fn new(
+        sources: Vec<Box<dyn Iterator<Item = Result<Entry, StorageError>>>>,
+    ) -> Result<DatabaseIter, StorageError> {
+        let mut iter = DatabaseIter {
+            sources,
+            heap: BinaryHeap::new(),
+        };
+        for source in 0..iter.sources.len() {
+            iter.refill(source)?;
+        }
+        Ok(iter)
+    }
+
+    fn refill(&mut self, source: usize) -> Result<(), StorageError> {
+        if let Some(next) = self.sources[source].next() {
+            self.heap.push(HeapItem {
+                entry: next?,
+                source,
+            });
+        }
+        Ok(())
+    }
+
+    fn next_live(&mut self) -> Result<Option<KeyValue>, StorageError> {
+        while let Some(newest) = self.heap.pop() {
+            self.refill(newest.source)?;
+            // Discard all older versions of the same key
+            while self
+                .heap
+                .peek()
+                .is_some_and(|item| item.entry.key == newest.entry.key)
+            {
+                let stale = self.heap.pop().expect("Just peeked");
+                self.refill(stale.source)?;
+            }
+            if let Some(value) = newest.entry.value {
+                return Ok(Some((newest.entry.key, value)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for DatabaseIter {
+    type Item = Result<(Vec<u8>, Vec<u8>), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_live() {
+            Ok(next) => next.map(Ok),
+            Err(e) => {
+                self.heap.clear();
+                self.sources.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Helpers for writing just enough LevelDB to test the reader against.
+#[cfg(test)]
+pub mod test_writer {
+    use super::coding::{crc32c_extend, mask_crc, put_varint};
+    use std::path::Path;
+
+    fn put_length_prefixed(target: &mut Vec<u8>, data: &[u8]) {
+        put_varint(target, data.len() as u64);
+        target.extend_from_slice(data);
+    }
+
+    fn internal_key(key: &[u8], sequence: u64, is_value: bool) -> Vec<u8> {
+        let mut result = key.to_vec();
+        result.extend_from_slice(&((sequence << 8) | is_value as u64).to_le_bytes());
+        result
+    }
+
+    /// Builds a block with a restart point at every entry
+    fn build_block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut block = Vec::new();
+        let mut restarts = Vec::new();
+        let mut last_key: Vec<u8> = Vec::new();
+        for (i, (key, value)) in entries.iter().enumerate() {
+            // Share a prefix with the previous key on every other entry to exercise prefix decoding
+            let shared = if i % 2 == 1 {
+                key.iter()
+                    .zip(last_key.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            } else {
+                restarts.push(block.len() as u32);
+                0
+            };
+            put_varint(&mut block, shared as u64);
+            put_varint(&mut block, (key.len() - shared) as u64);
+            put_varint(&mut block, value.len() as u64);
+            block.extend_from_slice(&key[shared..]);
+            block.extend_from_slice(value);
+            last_key = key.clone();
+        }
+        if restarts.is_empty() {
+            restarts.push(0);
+        }
+        for restart in restarts.iter() {
+            block.extend_from_slice(&restart.to_le_bytes());
+        }
+        block.extend_from_slice(&(restarts.len() as u32).to_le_bytes());
+        block
+    }
+
+    fn append_block(file: &mut Vec<u8>, block: &[u8]) -> (u64, u64) {
+        let offset = file.len() as u64;
+        file.extend_from_slice(block);
+        file.push(0);
+        let crc = crc32c_extend(crc32c_extend(0, block), &[0]);
+        file.extend_from_slice(&mask_crc(crc).to_le_bytes());
+        (offset, block.len() as u64)
+    }
+
+    /// A (key, sequence, value) triple to be written to a table
+    pub type TableEntry<'a> = (&'a [u8], u64, Option<&'a [u8]>);
+
+    /// Writes a table containing `entries` as (key, sequence, value), which must be sorted.
+    /// Entries are split into blocks of two.
+    pub fn write_table(path: &Path, entries: &[TableEntry]) {
+        let mut file = Vec::new();
+        let mut index_entries = Vec::new();
+        for chunk in entries.chunks(2) {
+            let block: Vec<(Vec<u8>, Vec<u8>)> = chunk
+                .iter()
+                .map(|(key, seq, value)| {
+                    (
+                        internal_key(key, *seq, value.is_some()),
+                        value.unwrap_or(&[]).to_vec(),
+                    )
+                })
+                .collect();
+            let last_key = block.last().unwrap().0.clone();
+            let (offset, size) = append_block(&mut file, &build_block(&block));
+            let mut handle = Vec::new();
+            put_varint(&mut handle, offset);
+            put_varint(&mut handle, size);
+            index_entries.push((last_key, handle));
+        }
+        let metaindex = append_block(&mut file, &build_block(&[]));
+        let index = append_block(&mut file, &build_block(&index_entries));
+        let mut footer = Vec::new();
+        put_varint(&mut footer, metaindex.0);
+        put_varint(&mut footer, metaindex.1);
+        put_varint(&mut footer, index.0);
+        put_varint(&mut footer, index.1);
+        footer.resize(40, 0);
+        footer.extend_from_slice(&0xdb47_7524_8b80_fb57u64.to_le_bytes());
+        file.extend_from_slice(&footer);
+        std::fs::write(path, file).unwrap();
+    }
+
+    /// Encodes records in the log format. Each record must fit in a single block.
+    pub fn encode_log(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = Vec::new();
+        for record in records {
+            let crc = crc32c_extend(crc32c_extend(0, &[1]), record);
+            file.extend_from_slice(&mask_crc(crc).to_le_bytes());
+            file.extend_from_slice(&(record.len() as u16).to_le_bytes());
+            file.push(1);
+            file.extend_from_slice(record);
+        }
+        file
+    }
+
+    pub fn write_batch(sequence: u64, ops: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut batch = Vec::new();
+        batch.extend_from_slice(&sequence.to_le_bytes());
+        batch.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+        for (key, value) in ops {
+            batch.push(value.is_some() as u8);
+            put_length_prefixed(&mut batch, key);
+            if let Some(value) = value {
+                put_length_prefixed(&mut batch, value);
+            }
+        }
+        batch
+    }
+
+    /// Writes CURRENT and a MANIFEST declaring the given table numbers live and `log_number` as the current log
+    pub fn write_manifest(dir: &Path, tables: &[u64], log_number: u64) {
+        let mut edit = Vec::new();
+        put_varint(&mut edit, 1);
+        put_length_prefixed(&mut edit, super::BYTEWISE_COMPARATOR);
+        put_varint(&mut edit, 2);
+        put_varint(&mut edit, log_number);
+        for table in tables {
+            put_varint(&mut edit, 7);
+            put_varint(&mut edit, 0);
+            put_varint(&mut edit, *table);
+            put_varint(&mut edit, 0);
+            put_length_prefixed(&mut edit, b"");
+            put_length_prefixed(&mut edit, b"");
+        }
+        std::fs::write(dir.join("MANIFEST-000002"), encode_log(&[edit])).unwrap();
+        std::fs::write(dir.join("CURRENT"), "MANIFEST-000002\n").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_writer::*;
+    use super::Database;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn merges_tables_and_log() {
+        let dir = temp_dir("leveldb-merge");
+        write_table(
+            &dir.join("000005.ldb"),
+            &[
+                (b"a", 1, Some(b"old a")),
+                (b"b", 2, Some(b"b")),
+                (b"c", 3, Some(b"c")),
+            ],
+        );
+        // A newer table which overwrites "a" and deletes "c"
+        write_table(
+            &dir.join("000007.ldb"),
+            &[(b"a", 4, Some(b"new a")), (b"c", 5, None)],
+        );
+        // A table which the MANIFEST does not list must be ignored
+        write_table(&dir.join("000003.ldb"), &[(b"z", 9, Some(b"obsolete"))]);
+        let log = encode_log(&[write_batch(
+            6,
+            &[(b"b", None), (b"d", Some(b"d")), (b"d", Some(b"newer d"))],
+        )]);
+        std::fs::write(dir.join("000008.log"), log).unwrap();
+        write_manifest(&dir, &[5, 7], 8);
+
+        let db = Database::open(&dir).unwrap();
+        let contents: Vec<(Vec<u8>, Vec<u8>)> =
+            db.iter_from(&[]).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            contents,
+            vec![
+                (b"a".to_vec(), b"new a".to_vec()),
+                (b"d".to_vec(), b"newer d".to_vec()),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seeks_to_keys() {
+        let dir = temp_dir("leveldb-seek");
+        write_table(
+            &dir.join("000005.ldb"),
+            &[
+                (b"a", 1, Some(b"a")),
+                (b"b", 2, Some(b"b")),
+                (b"c1", 3, Some(b"c1")),
+                (b"c2", 4, Some(b"c2")),
+                (b"e", 5, Some(b"e")),
+            ],
+        );
+        // A deletion in the log hides the key in the table, and a log-only key is found too
+        let log = encode_log(&[write_batch(6, &[(b"c1", None), (b"d", Some(b"d"))])]);
+        std::fs::write(dir.join("000008.log"), log).unwrap();
+        write_manifest(&dir, &[5], 8);
+
+        let db = Database::open(&dir).unwrap();
+        let keys = |start: &[u8]| -> Vec<Vec<u8>> {
+            db.iter_from(start).unwrap().map(|r| r.unwrap().0).collect()
+        };
+        assert_eq!(
+            keys(b"b"),
+            vec![b"b".to_vec(), b"c2".to_vec(), b"d".to_vec(), b"e".to_vec()]
+        );
+        assert_eq!(
+            keys(b"c"),
+            vec![b"c2".to_vec(), b"d".to_vec(), b"e".to_vec()]
+        );
+        assert!(keys(b"f").is_empty());
+
+        assert_eq!(db.get(b"b").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(db.get(b"d").unwrap(), Some(b"d".to_vec()));
+        assert_eq!(db.get(b"c1").unwrap(), None);
+        assert_eq!(db.get(b"c").unwrap(), None);
+        assert!(db.contains_prefix(b"c").unwrap());
+        assert!(!db.contains_prefix(b"f").unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_corrupt_blocks() {
+        let dir = temp_dir("leveldb-corrupt");
+        write_table(&dir.join("000005.ldb"), &[(b"a", 1, Some(b"value"))]);
+        let mut contents = std::fs::read(dir.join("000005.ldb")).unwrap();
+        contents[3] ^= 0xff;
+        std::fs::write(dir.join("000005.ldb"), contents).unwrap();
+        write_manifest(&dir, &[5], 6);
+
+        let db = Database::open(&dir).unwrap();
+        let contents = db
+            .iter_from(&[])
+            .and_then(|iter| iter.collect::<Result<Vec<_>, _>>());
+        assert!(contents.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
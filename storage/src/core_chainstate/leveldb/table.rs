@@ -0,0 +1,197 @@
+//! A sequential reader for LevelDB's sorted table (`.ldb`/`.sst`) files.
+//!
+//! See https://github.com/google/leveldb/blob/main/doc/table_format.md
+use super::coding::{crc32c_extend, get_fixed32, get_fixed64, get_varint, unmask_crc};
+use super::{Entry, KeyValue};
+use crate::StorageError;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+const FOOTER_LEN: u64 = 48;
+const TABLE_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+/// One byte of compression type plus a four byte checksum
+const BLOCK_TRAILER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn decode(src: &[u8], pos: &mut usize) -> Result<BlockHandle, StorageError> {
+        Ok(BlockHandle {
+            offset: get_varint(src, pos)?,
+            size: get_varint(src, pos)?,
+        })
+    }
+}
+
+/// An open table. Only the index is held in memory; data blocks are read one at a time while iterating.
+#[derive(Debug)]
+pub struct Table {
+    path: PathBuf,
+    /// Each data block, with a user key at least as large as any key in it
+    data_blocks: Vec<(Vec<u8>, BlockHandle)>,
+}
+
+impl Table {
+    pub fn open(path: PathBuf) -> Result<Table, StorageError> {
+        let mut file = File::open(&path)?;
+        let file_len = file.metadata()?.len();
+        if file_len < FOOTER_LEN {
+            return Err(StorageError::Corruption(format!(
+                "{:?} is too short to be a table",
+                path
+            )));
+        }
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(file_len - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+
+        let mut pos = 40;
+        if get_fixed64(&footer, &mut pos)? != TABLE_MAGIC {
+            return Err(StorageError::Corruption(format!(
+                "{:?} has a bad table magic number",
+                path
+            )));
+        }
+        let mut pos = 0;
+        let _metaindex = BlockHandle::decode(&footer, &mut pos)?;
+        let index_handle = BlockHandle::decode(&footer, &mut pos)?;
+
+        let index = read_block(&mut file, index_handle)?;
+        let data_blocks = parse_block(&index)?
+            .into_iter()
+            .map(|(mut key, value)| {
+                // Index keys are internal keys, so drop the sequence and type
+                key.truncate(key.len().saturating_sub(8));
+                Ok((key, BlockHandle::decode(&value, &mut 0)?))
+            })
+            .collect::<Result<Vec<_>, StorageError>>()?;
+        Ok(Table { path, data_blocks })
+    }
+
+    /// Iterates over the entries in the table whose keys are at least `start`, in order. Blocks which end
+    /// before `start` are never read.
+    pub fn entries_from(self, start: &[u8]) -> TableEntries {
+        let first = self
+            .data_blocks
+            .iter()
+            .position(|(last_key, _)| last_key.as_slice() >= start)
+            .unwrap_or(self.data_blocks.len());
+        TableEntries {
+            path: self.path,
+            data_blocks: self
+                .data_blocks
+                .into_iter()
+                .skip(first)
+                .map(|(_, handle)| handle)
+                .collect::<Vec<_>>()
+                .into_iter(),
+            current: Vec::new().into_iter(),
+        }
+    }
+}
+
+pub struct TableEntries {
+    path: PathBuf,
+    data_blocks: std::vec::IntoIter<BlockHandle>,
+    current: std::vec::IntoIter<KeyValue>,
+}
+
+impl TableEntries {
+    fn next_raw(&mut self) -> Result<Option<KeyValue>, StorageError> {
+        loop {
+            if let Some(entry) = self.current.next() {
+                return Ok(Some(entry));
+            }
+            let handle = match self.data_blocks.next() {
+                Some(handle) => handle,
+                None => return Ok(None),
+            };
+            // Reopen the file for each block rather than holding it open. A chainstate can have thousands
+            // of tables, which would otherwise exhaust the process' file descriptors during the merge.
+            let mut file = File::open(&self.path)?;
+            self.current = parse_block(&read_block(&mut file, handle)?)?.into_iter();
+        }
+    }
+}
+
+impl Iterator for TableEntries {
+    type Item = Result<Entry, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_raw() {
+            Ok(Some((internal_key, value))) => Some(Entry::from_internal(internal_key, value)),
+            Ok(None) => None,
+            Err(e) => {
+                // Stop iterating after the first error
+                self.data_blocks = Vec::new().into_iter();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn read_block(file: &mut File, handle: BlockHandle) -> Result<Vec<u8>, StorageError> {
+    let mut contents = vec![0u8; handle.size as usize + BLOCK_TRAILER_LEN];
+    file.seek(SeekFrom::Start(handle.offset))?;
+    file.read_exact(&mut contents)?;
+
+    let mut pos = handle.size as usize + 1;
+    let expected = unmask_crc(get_fixed32(&contents, &mut pos)?);
+    let actual = crc32c_extend(0, &contents[..handle.size as usize + 1]);
+    if expected != actual {
+        return Err(StorageError::Corruption(String::from(
+            "table block checksum mismatch",
+        )));
+    }
+    match contents[handle.size as usize] {
+        0 => {}
+        1 => {
+            return Err(StorageError::Unsupported(String::from(
+                "snappy compressed table blocks (Bitcoin Core never writes these)",
+            )))
+        }
+        other => {
+            return Err(StorageError::Corruption(format!(
+                "unknown block compression type {}",
+                other
+            )))
+        }
+    }
+    contents.truncate(handle.size as usize);
+    Ok(contents)
+}
+
+/// Decodes every key/value pair in a block, undoing LevelDB's key prefix compression.
+fn parse_block(block: &[u8]) -> Result<Vec<KeyValue>, StorageError> {
+    if block.len() < 4 {
+        return Err(StorageError::Corruption(String::from("block is too short")));
+    }
+    let mut pos = block.len() - 4;
+    let num_restarts = get_fixed32(block, &mut pos)? as usize;
+    let entries_end = (block.len() - 4)
+        .checked_sub(num_restarts * 4)
+        .ok_or_else(|| StorageError::Corruption(String::from("bad restart count")))?;
+
+    let mut result = Vec::new();
+    let mut key: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < entries_end {
+        let shared = get_varint(block, &mut pos)? as usize;
+        let non_shared = get_varint(block, &mut pos)? as usize;
+        let value_len = get_varint(block, &mut pos)? as usize;
+        if shared > key.len() || pos + non_shared + value_len > entries_end {
+            return Err(StorageError::Corruption(String::from("bad block entry")));
+        }
+        key.truncate(shared);
+        key.extend_from_slice(&block[pos..pos + non_shared]);
+        pos += non_shared;
+        result.push((key.clone(), block[pos..pos + value_len].to_vec()));
+        pos += value_len;
+    }
+    Ok(result)
+}
@@ -0,0 +1,314 @@
+//! An importer for Bitcoin Core's `chainstate` database.
+//!
+//! Lets a new node bootstrap its UTXO set from an existing Core installation instead of
+//! validating the whole chain. Core must be shut down cleanly before importing.
+mod leveldb;
+
+use crate::compression::{read_coin, read_varint};
+use crate::{Coin, StorageError, UtxoSet, UtxoSetHasher};
+use leveldb::{Database, DatabaseIter};
+use shared::{u256, BlockHash, TxOutpoint};
+use std::convert::TryInto;
+use std::path::Path;
+use tracing::info;
+
+/// Prefix of the key under which Core stores the value obfuscation key (`"\x0e\x00obfuscate_key"`).
+const OBFUSCATE_KEY_KEY: &[u8] = b"\x0e\x00obfuscate_key";
+/// Prefix of coin entries (`DB_COIN`)
+const DB_COIN: u8 = b'C';
+/// Key of the hash of the block to which the chainstate is consistent (`DB_BEST_BLOCK`)
+const DB_BEST_BLOCK: &[u8] = b"B";
+/// Key which is present only while Core is part way through a flush (`DB_HEAD_BLOCKS`)
+const DB_HEAD_BLOCKS: &[u8] = b"H";
+/// Prefix of per-transaction entries in the pre-0.15 chainstate format
+const DB_LEGACY_COINS: u8 = b'c';
+
+/// An open Bitcoin Core chainstate.
+///
+/// The flush marker (`H`) and pre-0.15 coins (`c`) sort after the coins (`C`), so they're looked up before the
+/// coins are read rather than found at the end of the scan.
+pub struct CoreChainstate {
+    db: Database,
+    obfuscation_key: Vec<u8>,
+    best_block: BlockHash,
+}
+
+impl CoreChainstate {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<CoreChainstate, StorageError> {
+        let path = path.as_ref();
+        info!("Opening Bitcoin Core chainstate at {:?}", path);
+        let db = Database::open(path)?;
+
+        if db.get(DB_HEAD_BLOCKS)?.is_some() {
+            return Err(StorageError::Unsupported(String::from(
+                "chainstate is part way through a flush. Restart Bitcoin Core once to complete it",
+            )));
+        }
+        if db.contains_prefix(&[DB_LEGACY_COINS])? {
+            return Err(StorageError::Unsupported(String::from(
+                "pre-0.15 chainstate format. Upgrade Bitcoin Core and let it migrate the database first",
+            )));
+        }
+        // The key itself is stored as a length-prefixed vector, and is not obfuscated
+        let obfuscation_key = db
+            .get(OBFUSCATE_KEY_KEY)?
+            .map(|value| value.get(1..).unwrap_or(&[]).to_vec())
+            .unwrap_or_default();
+        let best_block = db.get(DB_BEST_BLOCK)?.ok_or_else(|| {
+            StorageError::Corruption(String::from(
+                "chainstate has no best block. Was Bitcoin Core shut down cleanly?",
+            ))
+        })?;
+        let best_block: [u8; 32] =
+            xor(&obfuscation_key, best_block)[..]
+                .try_into()
+                .map_err(|_| {
+                    StorageError::Corruption(String::from("best block hash is not 32 bytes"))
+                })?;
+        let best_block = BlockHash::from(best_block);
+        info!("Chainstate is consistent with block {:?}", best_block);
+        Ok(CoreChainstate {
+            db,
+            obfuscation_key,
+            best_block,
+        })
+    }
+
+    /// The hash of the block at which the imported UTXO set is valid
+    pub fn best_block(&self) -> &BlockHash {
+        &self.best_block
+    }
+
+    /// Consumes the chainstate, returning an iterator over its coins in outpoint order.
+    pub fn coins(self) -> Result<CoreCoins, StorageError> {
+        Ok(CoreCoins {
+            entries: self.db.iter_from(&[DB_COIN])?,
+            obfuscation_key: self.obfuscation_key,
+            done: false,
+        })
+    }
+
+    /// Imports every coin into `utxos`, returning the `hash_serialized_3` of the imported coins.
+    ///
+    /// For a chainstate imported into an empty set, the result can be checked against `bitcoin-cli gettxoutsetinfo`
+    /// (which displays the hash in reverse byte order).
+    pub fn import_into(self, utxos: &mut UtxoSet) -> Result<[u8; 32], StorageError> {
+        let mut hasher = UtxoSetHasher::new();
+        for coin in self.coins()? {
+            let (outpoint, coin) = coin?;
+            hasher.add(&outpoint, &coin);
+            utxos.insert(outpoint, coin);
+            if utxos.len().is_multiple_of(1_000_000) {
+                info!("Imported {} coins", utxos.len());
+            }
+        }
+        info!("Finished importing {} coins", utxos.len());
+        Ok(hasher.finish())
+    }
+}
+
+/// An iterator over the coins in a Bitcoin Core chainstate. See [`CoreChainstate::coins`].
+pub struct CoreCoins {
+    entries: DatabaseIter,
+    obfuscation_key: Vec<u8>,
+    done: bool,
+}
+
+impl CoreCoins {
+    fn next_coin(&mut self) -> Result<Option<(TxOutpoint, Coin)>, StorageError> {
+        let (key, value) = match self.entries.next() {
+            Some(entry) => entry?,
+            None => return Ok(None),
+        };
+        // All coins have been read
+        if key.first() != Some(&DB_COIN) {
+            return Ok(None);
+        }
+        Ok(Some((
+            decode_outpoint(&key[1..])?,
+            read_coin(&mut &xor(&self.obfuscation_key, value)[..])?,
+        )))
+    }
+}
+
+impl Iterator for CoreCoins {
+    type Item = Result<(TxOutpoint, Coin), StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.next_coin().transpose();
+        if !matches!(next, Some(Ok(_))) {
+            self.done = true;
+        }
+        next
+    }
+}
+
+fn xor(obfuscation_key: &[u8], mut value: Vec<u8>) -> Vec<u8> {
+    if !obfuscation_key.is_empty() {
+        for (byte, key_byte) in value.iter_mut().zip(obfuscation_key.iter().cycle()) {
+            *byte ^= key_byte;
+        }
+    }
+    value
+}
+
+/// Decodes a coin key (minus its prefix): a txid followed by the output index as a `VARINT`.
+fn decode_outpoint(mut key: &[u8]) -> Result<TxOutpoint, StorageError> {
+    if key.len() < 32 {
+        return Err(StorageError::Corruption(String::from(
+            "coin key is too short",
+        )));
+    }
+    let txid: [u8; 32] = key[..32].try_into().expect("Length checked above");
+    key = &key[32..];
+    let index = read_varint(&mut key)?;
+    if index > u32::MAX as u64 {
+        return Err(StorageError::Corruption(String::from(
+            "coin output index is out of range",
+        )));
+    }
+    Ok(TxOutpoint::new(u256::from_bytes(txid), index as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::leveldb::test_writer::*;
+    use super::*;
+    use crate::compression::{compress_amount, write_varint};
+    use crate::test_util::temp_dir;
+
+    fn coin_key(txid: u8, index: u64) -> Vec<u8> {
+        let mut key = vec![DB_COIN];
+        key.extend_from_slice(&[txid; 32]);
+        write_varint(&mut key, index);
+        key
+    }
+
+    #[test]
+    fn imports_obfuscated_coins() {
+        let dir = temp_dir("core-chainstate");
+        let obfuscation_key = [0x5a, 0x11, 0x00, 0xff, 0x01, 0x02, 0x03, 0x04];
+        let mut obfuscate_value = vec![8u8];
+        obfuscate_value.extend_from_slice(&obfuscation_key);
+
+        // height 100, coinbase, 50 BTC to P2PKH
+        let mut coinbase = Vec::new();
+        write_varint(&mut coinbase, 100 * 2 + 1);
+        write_varint(&mut coinbase, compress_amount(50 * 100_000_000));
+        coinbase.extend_from_slice(&[0u8; 21]);
+        // height 7, not coinbase, 1 satoshi to a raw `OP_TRUE` script
+        let raw = hex::decode("0e010751").unwrap();
+        let best_block = [0xabu8; 32];
+
+        let first = coin_key(1, 0);
+        let second = coin_key(1, 200);
+        let spent = coin_key(2, 0);
+        write_table(
+            &dir.join("000004.ldb"),
+            &[
+                (OBFUSCATE_KEY_KEY, 1, Some(&obfuscate_value[..])),
+                (
+                    DB_BEST_BLOCK,
+                    2,
+                    Some(&xor(&obfuscation_key, best_block.to_vec())[..]),
+                ),
+                (&first[..], 3, Some(&xor(&obfuscation_key, coinbase)[..])),
+                (
+                    &second[..],
+                    4,
+                    Some(&xor(&obfuscation_key, raw.clone())[..]),
+                ),
+                (&spent[..], 5, Some(&xor(&obfuscation_key, raw)[..])),
+            ],
+        );
+        // The coin at `spent` is spent in the log, which hasn't been compacted yet
+        std::fs::write(
+            dir.join("000006.log"),
+            encode_log(&[write_batch(6, &[(&spent[..], None)])]),
+        )
+        .unwrap();
+        write_manifest(&dir, &[4], 6);
+
+        let chainstate = CoreChainstate::open(&dir).unwrap();
+        assert_eq!(chainstate.best_block(), &BlockHash::from(best_block));
+        let mut utxos = UtxoSet::new();
+        let hash = chainstate.import_into(&mut utxos).unwrap();
+        assert_eq!(hash, utxos.hash_serialized());
+        assert_eq!(utxos.len(), 2);
+
+        let coin = utxos
+            .get(&TxOutpoint::new(u256::from_bytes([1; 32]), 0))
+            .unwrap();
+        assert_eq!(coin.height(), 100);
+        assert!(coin.is_coinbase());
+        assert_eq!(coin.value(), 50 * 100_000_000);
+        assert_eq!(coin.output().pk_script().len(), 25);
+
+        let coin = utxos
+            .get(&TxOutpoint::new(u256::from_bytes([1; 32]), 200))
+            .unwrap();
+        assert_eq!(coin.height(), 7);
+        assert!(!coin.is_coinbase());
+        assert_eq!(coin.value(), 1);
+        assert_eq!(coin.output().pk_script(), &vec![0x51]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_chainstate_without_best_block() {
+        let dir = temp_dir("core-chainstate-no-best");
+        let key = coin_key(1, 0);
+        write_table(
+            &dir.join("000004.ldb"),
+            &[(&key[..], 3, Some(&b"\x0e\x01\x07\x51"[..]))],
+        );
+        write_manifest(&dir, &[4], 5);
+        assert!(CoreChainstate::open(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_chainstate_mid_flush() {
+        let dir = temp_dir("core-chainstate-mid-flush");
+        let key = coin_key(1, 0);
+        // `H` sorts after the coins, so it must be found without reading them first
+        write_table(
+            &dir.join("000004.ldb"),
+            &[
+                (DB_BEST_BLOCK, 1, Some(&[0xab; 32][..])),
+                (&key[..], 2, Some(&b"\x0e\x01\x07\x51"[..])),
+                (DB_HEAD_BLOCKS, 3, Some(&[0xcd; 64][..])),
+            ],
+        );
+        write_manifest(&dir, &[4], 5);
+        assert!(matches!(
+            CoreChainstate::open(&dir),
+            Err(StorageError::Unsupported(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_legacy_chainstate() {
+        let dir = temp_dir("core-chainstate-legacy");
+        let mut legacy_key = vec![DB_LEGACY_COINS];
+        legacy_key.extend_from_slice(&[1; 32]);
+        write_table(
+            &dir.join("000004.ldb"),
+            &[
+                (DB_BEST_BLOCK, 1, Some(&[0xab; 32][..])),
+                (&legacy_key[..], 2, Some(&b"\x01\x01\x07\x51"[..])),
+            ],
+        );
+        write_manifest(&dir, &[4], 5);
+        // Rather than importing an empty UTXO set
+        assert!(matches!(
+            CoreChainstate::open(&dir),
+            Err(StorageError::Unsupported(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
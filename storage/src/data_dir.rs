@@ -0,0 +1,154 @@
+//! The data directory, and writing the files in it without leaving them half written after a crash.
+//!
+//! Only one process may use a data directory at a time. [`DataDir::open`] takes an exclusive lock on its `.lock`
+//! file, which also records the PID of the process holding it. The lock is released when the [`DataDir`] is
+//! dropped, or when the process exits, however it exits.
+//!
+//! While a process has the directory open, a `.running` marker exists in it. The marker is only removed by
+//! [`DataDir::close`], so finding it when opening the directory means the last process to use it crashed.
+use crate::StorageError;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const LOCK_FILE: &str = ".lock";
+const RUNNING_MARKER: &str = ".running";
+const BLOCKS_DIR: &str = "blocks";
+
+/// Replaces the file at `path` with the output of `write`, so that after a crash the file either has its old
+/// contents or its new ones. The new contents are written to a temporary file, flushed to disk, and then renamed
+/// over the old file.
+pub fn write_atomically<P, F>(path: P, write: F) -> Result<(), StorageError>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut BufWriter<File>) -> Result<(), StorageError>,
+{
+    let path = path.as_ref();
+    let temp_path = path.with_extension("incomplete");
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    write(&mut file)?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sync_dir(path.parent())
+}
+
+/// Flushes a directory, so that a file renamed into it stays renamed after a power failure
+fn sync_dir(dir: Option<&Path>) -> Result<(), StorageError> {
+    // Windows can't open directories as files, but makes renames durable on its own
+    if cfg!(unix) {
+        let dir = match dir {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// A locked data directory
+#[derive(Debug)]
+pub struct DataDir {
+    path: PathBuf,
+    /// Held open to keep the lock
+    lock: File,
+    crashed: bool,
+}
+
+impl DataDir {
+    /// Creates `path` if needed and locks it. Fails with [`StorageError::Locked`] if another process has it open.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<DataDir, StorageError> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+        let mut lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join(LOCK_FILE))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(StorageError::Locked(path)),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+        lock.set_len(0)?;
+        writeln!(lock, "{}", std::process::id())?;
+        lock.sync_all()?;
+
+        let marker = path.join(RUNNING_MARKER);
+        let crashed = marker.exists();
+        File::create(&marker)?.sync_all()?;
+        sync_dir(Some(&path))?;
+        info!("Using data directory {:?}", path);
+        Ok(DataDir {
+            path,
+            lock,
+            crashed,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the last process to use the directory exited without calling [`DataDir::close`]
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Where the block and undo files go
+    pub fn blocks_dir(&self) -> PathBuf {
+        self.path.join(BLOCKS_DIR)
+    }
+
+    /// Records a clean shutdown and releases the lock. Everything in the directory should be saved first.
+    pub fn close(self) -> Result<(), StorageError> {
+        fs::remove_file(self.path.join(RUNNING_MARKER))?;
+        sync_dir(Some(&self.path))?;
+        self.lock.unlock()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn atomic_writes_replace_the_whole_file() {
+        let dir = temp_dir("atomic-write");
+        let path = dir.join("state.dat");
+        write_atomically(&path, |file| Ok(file.write_all(b"old")?)).unwrap();
+        // A failed write leaves the old contents in place
+        let failed = write_atomically(&path, |file| {
+            file.write_all(b"partial")?;
+            Err(StorageError::Corruption(String::from("interrupted")))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        write_atomically(&path, |file| Ok(file.write_all(b"new")?)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn locks_the_directory_and_detects_crashes() {
+        let dir = temp_dir("data-dir");
+        let data_dir = DataDir::open(&dir).unwrap();
+        assert!(!data_dir.crashed());
+        assert!(matches!(DataDir::open(&dir), Err(StorageError::Locked(_))));
+        let pid = fs::read_to_string(dir.join(LOCK_FILE)).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+        data_dir.close().unwrap();
+
+        // Dropping the directory without closing it is what a crash looks like
+        drop(DataDir::open(&dir).unwrap());
+        let data_dir = DataDir::open(&dir).unwrap();
+        assert!(data_dir.crashed());
+        data_dir.close().unwrap();
+        assert!(!DataDir::open(&dir).unwrap().crashed());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
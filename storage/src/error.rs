@@ -0,0 +1,64 @@
+use shared::{DeserializationError, TxOutpoint};
+use std::path::PathBuf;
+use std::{fmt, io};
+
+/// An enumeration of the errors that can occur while reading or writing persistent state.
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    Deserialization(DeserializationError),
+    /// The data on disk is not in the format we expected.
+    Corruption(String),
+    /// The data on disk is valid, but uses a feature we don't support.
+    Unsupported(String),
+    /// A block could not be applied to the chainstate.
+    InvalidBlock(String),
+    /// A block tried to spend a coin which is not in the UTXO set.
+    MissingCoin(TxOutpoint),
+    /// Another process is using the data directory.
+    Locked(PathBuf),
+}
+
+impl From<io::Error> for StorageError {
+    fn from(kind: io::Error) -> StorageError {
+        StorageError::Io(kind)
+    }
+}
+impl From<DeserializationError> for StorageError {
+    fn from(kind: DeserializationError) -> StorageError {
+        StorageError::Deserialization(kind)
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(err) => Some(err),
+            StorageError::Deserialization(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(cause) => cause.fmt(f),
+            StorageError::Deserialization(cause) => cause.fmt(f),
+            StorageError::Corruption(cause) => write!(f, "corrupted data: {}", cause),
+            StorageError::Unsupported(cause) => write!(f, "unsupported: {}", cause),
+            StorageError::InvalidBlock(cause) => write!(f, "invalid block: {}", cause),
+            StorageError::Locked(path) => write!(
+                f,
+                "cannot lock the data directory {}. warpd is probably already running",
+                path.display()
+            ),
+            StorageError::MissingCoin(outpoint) => {
+                // Txids are conventionally displayed in reverse byte order
+                let mut txid = *outpoint.hash().to_le_bytes();
+                txid.reverse();
+                write!(f, "missing coin {}:{}", hex::encode(txid), outpoint.index())
+            }
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Optional indexes over the block chain.
+//!
+//! Each index is built from the blocks of the active chain (plus their undo data), and persisted as an append-only
+//! journal of the entries added by each connected block and removed by each disconnected one. Because disconnecting
+//! a block removes exactly the entries that connecting it added, an index stays consistent across reorgs. An index
+//! which is enabled on an existing data directory starts out behind the chain tip and is brought up to date
+//! a few blocks at a time with [`PersistentIndex::sync`].
+//!
+//! The journal is synced to disk after every block, or every batch of blocks while catching up, so a power failure
+//! loses at most the last batch, which `sync` then redoes. A block which is disconnected leaves two dead records
+//! behind, so when opening a journal with more dead records than live ones, it's rewritten without them.
+mod script_index;
+mod tx_index;
+pub use script_index::{script_hash, ScriptEvent, ScriptIndex};
+pub use tx_index::{TxIndex, TxLocation};
+
+use crate::{write_atomically, BlockStore, Coin, StorageError};
+use shared::{Block, BlockHash, Deserializable, Serializable};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use tracing::{info, warn};
+
+const CONNECT: u8 = 1;
+const DISCONNECT: u8 = 2;
+
+/// An index which can be derived entry-by-entry from the blocks of the chain.
+pub trait Index: Default {
+    type Entry: Serializable + Deserializable;
+    /// A short name for log messages
+    const NAME: &'static str;
+
+    /// Computes the entries contributed by `block`, given the coins it spent.
+    fn entries(block: &Block, height: u32, undo: &[Coin]) -> Vec<Self::Entry>;
+    fn insert(&mut self, entry: Self::Entry);
+    fn remove(&mut self, entry: &Self::Entry);
+}
+
+/// An [`Index`] along with its journal on disk and the block up to which it is synced.
+#[derive(Debug)]
+pub struct PersistentIndex<I: Index> {
+    index: I,
+    journal: File,
+    best_block: Option<BlockHash>,
+    height: u32,
+}
+
+impl<I: Index> PersistentIndex<I> {
+    /// Opens the index journal at `path`, replaying it to rebuild the index in memory.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PersistentIndex<I>, StorageError> {
+        let path = path.as_ref();
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let journal = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut result = PersistentIndex {
+            index: I::default(),
+            journal,
+            best_block: None,
+            height: 0,
+        };
+        // The records of the blocks which are still connected
+        let mut live: Vec<Range<usize>> = Vec::new();
+        let mut src = &contents[..];
+        let mut valid_len = 0;
+        while !src.is_empty() {
+            match result.replay(&mut src) {
+                Ok(CONNECT) => live.push(valid_len..contents.len() - src.len()),
+                // Disconnecting the tip cancels the record which connected it
+                Ok(_) => {
+                    live.pop();
+                }
+                // Running out of data part way through a record means the last write was interrupted
+                Err(StorageError::Deserialization(_)) => {
+                    warn!("Discarding truncated record at the end of the {}", I::NAME);
+                    result.journal.set_len(valid_len as u64)?;
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+            valid_len = contents.len() - src.len();
+        }
+
+        let live_len: usize = live.iter().map(|record| record.len()).sum();
+        if valid_len - live_len > live_len {
+            info!(
+                "Compacting the {} journal from {} to {} bytes",
+                I::NAME,
+                valid_len,
+                live_len
+            );
+            write_atomically(path, |file| {
+                for record in live {
+                    file.write_all(&contents[record])?;
+                }
+                Ok(())
+            })?;
+            result.journal = OpenOptions::new().append(true).open(path)?;
+        }
+        info!(
+            "Opened {} synced to height {} ({:?})",
+            I::NAME,
+            result.height,
+            result.best_block
+        );
+        Ok(result)
+    }
+
+    pub fn index(&self) -> &I {
+        &self.index
+    }
+
+    /// The last block connected to this index, or `None` if the index is empty
+    pub fn best_block(&self) -> Option<&BlockHash> {
+        self.best_block.as_ref()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Adds the entries of a block, which must build on the index's best block.
+    pub fn connect_block(
+        &mut self,
+        block: &Block,
+        height: u32,
+        undo: &[Coin],
+    ) -> Result<(), StorageError> {
+        self.connect(block, height, undo)?;
+        self.journal.sync_data()?;
+        Ok(())
+    }
+
+    /// Removes the entries of the index's best block.
+    pub fn disconnect_block(&mut self, block: &Block, undo: &[Coin]) -> Result<(), StorageError> {
+        self.disconnect(block, undo)?;
+        self.journal.sync_data()?;
+        Ok(())
+    }
+
+    /// Moves the index up to `max_blocks` blocks closer to `chain`, the hashes of the active chain from genesis.
+    /// Blocks which are no longer on the chain are disconnected first. Returns `true` once the index is synced.
+    pub fn sync(
+        &mut self,
+        store: &BlockStore,
+        chain: &[BlockHash],
+        max_blocks: usize,
+    ) -> Result<bool, StorageError> {
+        let synced = self.sync_batch(store, chain, max_blocks);
+        // Sync the journal once per batch rather than once per block
+        self.journal.sync_data()?;
+        synced
+    }
+
+    fn connect(&mut self, block: &Block, height: u32, undo: &[Coin]) -> Result<(), StorageError> {
+        let prev = block.header().prev_hash();
+        if self.best_block.as_ref().is_some_and(|best| best != prev) {
+            return Err(StorageError::InvalidBlock(format!(
+                "block {:?} does not build on the {} tip",
+                block.header().hash(),
+                I::NAME
+            )));
+        }
+        let entries = I::entries(block, height, undo);
+        self.write(CONNECT, block.header().hash(), prev, height, &entries)?;
+        for entry in entries {
+            self.index.insert(entry);
+        }
+        self.best_block = Some(block.header().hash().clone());
+        self.height = height;
+        Ok(())
+    }
+
+    fn disconnect(&mut self, block: &Block, undo: &[Coin]) -> Result<(), StorageError> {
+        if self.best_block.as_ref() != Some(block.header().hash()) {
+            return Err(StorageError::InvalidBlock(format!(
+                "block {:?} is not the {} tip",
+                block.header().hash(),
+                I::NAME
+            )));
+        }
+        let entries = I::entries(block, self.height, undo);
+        let prev = block.header().prev_hash();
+        self.write(
+            DISCONNECT,
+            block.header().hash(),
+            prev,
+            self.height - 1,
+            &entries,
+        )?;
+        for entry in entries.iter() {
+            self.index.remove(entry);
+        }
+        self.best_block = Some(prev.clone());
+        self.height -= 1;
+        Ok(())
+    }
+
+    fn sync_batch(
+        &mut self,
+        store: &BlockStore,
+        chain: &[BlockHash],
+        max_blocks: usize,
+    ) -> Result<bool, StorageError> {
+        let mut budget = max_blocks;
+        // Undo any blocks which were reorged out while we weren't watching
+        while let Some(best) = self.best_block.clone() {
+            if chain.get(self.height as usize) == Some(&best) {
+                break;
+            }
+            if budget == 0 {
+                return Ok(false);
+            }
+            let block = read_block(store, &best)?;
+            let undo = store.read_undo(&best)?.unwrap_or_default();
+            self.disconnect(&block, &undo)?;
+            budget -= 1;
+        }
+        // The genesis block's coinbase can't be spent, so like Core we never index it
+        let mut next = match self.best_block {
+            Some(_) => self.height as usize + 1,
+            None => 1,
+        };
+        while next < chain.len() {
+            if budget == 0 {
+                return Ok(false);
+            }
+            let block = read_block(store, &chain[next])?;
+            let undo = store.read_undo(&chain[next])?.unwrap_or_default();
+            self.connect(&block, next as u32, &undo)?;
+            next += 1;
+            budget -= 1;
+        }
+        Ok(true)
+    }
+
+    fn write(
+        &mut self,
+        kind: u8,
+        hash: &BlockHash,
+        prev: &BlockHash,
+        height: u32,
+        entries: &[I::Entry],
+    ) -> Result<(), StorageError> {
+        let mut record = vec![kind];
+        hash.serialize(&mut record)?;
+        prev.serialize(&mut record)?;
+        height.serialize(&mut record)?;
+        (entries.len() as u32).serialize(&mut record)?;
+        for entry in entries {
+            entry.serialize(&mut record)?;
+        }
+        self.journal.write_all(&record)?;
+        Ok(())
+    }
+
+    /// Applies the next record of a journal, returning its kind
+    fn replay(&mut self, src: &mut &[u8]) -> Result<u8, StorageError> {
+        let kind = u8::deserialize(&mut *src)?;
+        let hash = BlockHash::deserialize(&mut *src)?;
+        let prev = BlockHash::deserialize(&mut *src)?;
+        let height = u32::deserialize(&mut *src)?;
+        let count = u32::deserialize(&mut *src)?;
+        // Parse the whole record before applying it, so a truncated record leaves the index untouched
+        let mut entries = Vec::with_capacity((count as usize).min(src.len()));
+        for _ in 0..count {
+            entries.push(I::Entry::deserialize(&mut *src)?);
+        }
+        match kind {
+            CONNECT => {
+                for entry in entries {
+                    self.index.insert(entry);
+                }
+                self.best_block = Some(hash);
+            }
+            DISCONNECT => {
+                for entry in entries.iter() {
+                    self.index.remove(entry);
+                }
+                self.best_block = Some(prev);
+            }
+            other => {
+                return Err(StorageError::Corruption(format!(
+                    "unknown {} record type {}",
+                    I::NAME,
+                    other
+                )))
+            }
+        }
+        self.height = height;
+        Ok(kind)
+    }
+}
+
+fn read_block(store: &BlockStore, hash: &BlockHash) -> Result<Block, StorageError> {
+    store.read_block(hash)?.ok_or_else(|| {
+        StorageError::Unsupported(format!(
+            "block {:?} is needed to build an index, but has been pruned",
+            hash
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use shared::{
+        u256, BlockHeader, MerkleRoot, Nbits, Transaction, TxInput, TxOutpoint, TxOutput,
+    };
+
+    const MAGIC: u32 = 0xD9B4BEF9;
+
+    /// Builds a transaction with its txid filled in
+    fn tx(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Transaction {
+        let mut raw = Vec::new();
+        Transaction::new(1, inputs, outputs)
+            .serialize(&mut raw)
+            .unwrap();
+        Transaction::deserialize(&raw[..]).unwrap()
+    }
+
+    fn coinbase(tag: u8, script: Vec<u8>) -> Transaction {
+        let input = TxInput::new(
+            TxOutpoint::new(u256::new(), u32::MAX),
+            vec![tag],
+            0xffffffff,
+        );
+        tx(vec![input], vec![TxOutput::new(50_0000_0000, script)])
+    }
+
+    fn block(prev: &BlockHash, txs: Vec<Transaction>) -> Block {
+        let target = Block::_test_block().header().target().clone();
+        let mut header = BlockHeader::new(
+            1,
+            prev.clone(),
+            MerkleRoot::from_vec(txs.iter().map(|tx| tx.txid()).collect()),
+            1_231_006_505,
+            Nbits::new(target),
+            0,
+        );
+        header.set_hash();
+        Block::new(header, txs)
+    }
+
+    fn outpoint(tx: &Transaction, index: u32) -> TxOutpoint {
+        TxOutpoint::new(u256::from_bytes(*tx.txid().inner()), index)
+    }
+
+    #[test]
+    fn indexes_follow_reorgs_and_survive_reopening() {
+        let dir = temp_dir("indexes");
+        let alice = vec![0x51];
+        let bob = vec![0x52];
+
+        let genesis = block(&BlockHash::from([0; 32]), vec![coinbase(0, alice.clone())]);
+        let funding = coinbase(1, alice.clone());
+        let first = block(genesis.header().hash(), vec![funding.clone()]);
+        let spend = tx(
+            vec![TxInput::new(outpoint(&funding, 0), vec![], 0xffffffff)],
+            vec![TxOutput::new(49_0000_0000, bob.clone())],
+        );
+        let second = block(
+            first.header().hash(),
+            vec![coinbase(2, vec![0x53]), spend.clone()],
+        );
+        let competing = block(first.header().hash(), vec![coinbase(3, vec![0x53])]);
+
+        let mut store = BlockStore::open(dir.join("blocks"), MAGIC, None).unwrap();
+        for (height, block) in [&genesis, &first, &second, &competing].iter().enumerate() {
+            store.write_block(block, height.min(2) as u32).unwrap();
+        }
+        let spent = Coin::new(funding.outputs()[0].clone(), 1, true);
+        store.write_undo(second.header().hash(), &[spent]).unwrap();
+
+        let chain: Vec<BlockHash> = [&genesis, &first, &second]
+            .iter()
+            .map(|block| block.header().hash().clone())
+            .collect();
+        let mut txindex = PersistentIndex::<TxIndex>::open(dir.join("txindex.dat")).unwrap();
+        let mut scripts = PersistentIndex::<ScriptIndex>::open(dir.join("scripts.dat")).unwrap();
+        // Indexes catch up a batch at a time
+        assert!(!txindex.sync(&store, &chain, 1).unwrap());
+        assert_eq!(txindex.height(), 1);
+        assert!(txindex.sync(&store, &chain, 10).unwrap());
+        assert!(scripts.sync(&store, &chain, 10).unwrap());
+
+        let location = txindex.index().get(spend.txid()).unwrap().clone();
+        assert_eq!(location.block(), second.header().hash());
+        let read = store
+            .read_transaction(location.block(), location.offset())
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.txid(), spend.txid());
+        // The genesis coinbase is never indexed
+        assert_eq!(txindex.index().len(), 3);
+        let history = scripts.index().history(&alice);
+        assert_eq!(history.len(), 2);
+        assert!(history[1].is_spend());
+        assert_eq!(history[1].txid(), spend.txid());
+        assert!(scripts.index().unspent(&alice).is_empty());
+        assert_eq!(scripts.index().unspent(&bob).len(), 1);
+
+        // Reorg onto the competing block, which doesn't contain the spend
+        let mut chain = chain;
+        chain[2] = competing.header().hash().clone();
+        assert!(txindex.sync(&store, &chain, 10).unwrap());
+        assert!(scripts.sync(&store, &chain, 10).unwrap());
+        // The records of the reorged out block outweigh the live ones, so reopening compacts the journals
+        let journal_len = |name: &str| fs::metadata(dir.join(name)).unwrap().len();
+        let before = (journal_len("txindex.dat"), journal_len("scripts.dat"));
+        for reopened in 0..2 {
+            if reopened == 1 {
+                assert!(journal_len("txindex.dat") < before.0);
+                assert!(journal_len("scripts.dat") < before.1);
+            }
+            assert!(txindex.index().get(spend.txid()).is_none());
+            assert_eq!(txindex.best_block(), Some(competing.header().hash()));
+            assert_eq!(txindex.height(), 2);
+            assert_eq!(scripts.index().unspent(&alice).len(), 1);
+            assert!(scripts.index().history(&bob).is_empty());
+            txindex = PersistentIndex::open(dir.join("txindex.dat")).unwrap();
+            scripts = PersistentIndex::open(dir.join("scripts.dat")).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
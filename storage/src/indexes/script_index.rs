@@ -0,0 +1,141 @@
+use super::Index;
+use crate::chainstate::is_unspendable;
+use crate::Coin;
+use bytes::Buf;
+use serde_derive::{Deserializable, Serializable};
+use shared::{u256, Block, TxID, TxOutpoint};
+use std::collections::HashMap;
+
+/// The key under which a script's history is stored: its single SHA256, as used by Electrum servers.
+pub fn script_hash(script: &[u8]) -> [u8; 32] {
+    warp_crypto::sha256(script)
+}
+
+/// A transaction output paying to a script, or a spend of one.
+#[derive(Serializable, Deserializable, Debug, Clone, PartialEq)]
+pub struct ScriptEvent {
+    /// The output which was funded or spent
+    outpoint: TxOutpoint,
+    /// The transaction which created the output, or which spent it
+    txid: TxID,
+    height: u32,
+    value: i64,
+    is_spend: bool,
+}
+
+impl ScriptEvent {
+    pub fn outpoint(&self) -> &TxOutpoint {
+        &self.outpoint
+    }
+    pub fn txid(&self) -> &TxID {
+        &self.txid
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn value(&self) -> i64 {
+        self.value
+    }
+    pub fn is_spend(&self) -> bool {
+        self.is_spend
+    }
+}
+
+#[derive(Serializable, Deserializable, Debug, Clone)]
+pub struct ScriptIndexEntry {
+    script_hash: [u8; 32],
+    event: ScriptEvent,
+}
+
+/// Maps each output script to the outputs which paid to it and the inputs which spent them.
+#[derive(Debug, Default)]
+pub struct ScriptIndex {
+    histories: HashMap<[u8; 32], Vec<ScriptEvent>>,
+}
+
+impl ScriptIndex {
+    /// Every funding and spending event for `script`, in chain order
+    pub fn history(&self, script: &[u8]) -> &[ScriptEvent] {
+        self.history_by_hash(&script_hash(script))
+    }
+    pub fn history_by_hash(&self, script_hash: &[u8; 32]) -> &[ScriptEvent] {
+        self.histories
+            .get(script_hash)
+            .map(|history| &history[..])
+            .unwrap_or(&[])
+    }
+    /// The funding events for outputs to `script` which have not been spent
+    pub fn unspent(&self, script: &[u8]) -> Vec<&ScriptEvent> {
+        let history = self.history(script);
+        history
+            .iter()
+            .filter(|event| {
+                !event.is_spend
+                    && !history
+                        .iter()
+                        .any(|other| other.is_spend && other.outpoint == event.outpoint)
+            })
+            .collect()
+    }
+}
+
+impl Index for ScriptIndex {
+    type Entry = ScriptIndexEntry;
+    const NAME: &'static str = "script index";
+
+    fn entries(block: &Block, height: u32, undo: &[Coin]) -> Vec<ScriptIndexEntry> {
+        let mut entries = Vec::new();
+        let mut spent = undo.iter();
+        for tx in block.transactions() {
+            let txid = tx.txid();
+            if !tx.is_coinbase() {
+                for (input, coin) in tx.inputs().iter().zip(&mut spent) {
+                    entries.push(ScriptIndexEntry {
+                        script_hash: script_hash(coin.output().pk_script()),
+                        event: ScriptEvent {
+                            outpoint: input.previous_outpoint().clone(),
+                            txid: txid.clone(),
+                            height,
+                            value: coin.value(),
+                            is_spend: true,
+                        },
+                    });
+                }
+            }
+            for (index, output) in tx.outputs().iter().enumerate() {
+                if is_unspendable(output.pk_script()) {
+                    continue;
+                }
+                entries.push(ScriptIndexEntry {
+                    script_hash: script_hash(output.pk_script()),
+                    event: ScriptEvent {
+                        outpoint: TxOutpoint::new(u256::from_bytes(*txid.inner()), index as u32),
+                        txid: txid.clone(),
+                        height,
+                        value: output.value(),
+                        is_spend: false,
+                    },
+                });
+            }
+        }
+        entries
+    }
+
+    fn insert(&mut self, entry: ScriptIndexEntry) {
+        self.histories
+            .entry(entry.script_hash)
+            .or_default()
+            .push(entry.event);
+    }
+
+    fn remove(&mut self, entry: &ScriptIndexEntry) {
+        if let Some(history) = self.histories.get_mut(&entry.script_hash) {
+            if let Some(position) = history.iter().rposition(|event| event == &entry.event) {
+                history.remove(position);
+            }
+            if history.is_empty() {
+                self.histories.remove(&entry.script_hash);
+            }
+        }
+    }
+}
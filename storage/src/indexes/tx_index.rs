@@ -0,0 +1,83 @@
+use super::Index;
+use crate::Coin;
+use bytes::Buf;
+use serde_derive::{Deserializable, Serializable};
+use shared::{Block, BlockHash, BlockHeader, CompactInt, TxID};
+use std::collections::HashMap;
+
+/// Where a transaction can be found on disk
+#[derive(Serializable, Deserializable, Debug, Clone, PartialEq)]
+pub struct TxLocation {
+    block: BlockHash,
+    /// The offset of the transaction from the start of the serialized block
+    offset: u32,
+}
+
+impl TxLocation {
+    pub fn block(&self) -> &BlockHash {
+        &self.block
+    }
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+}
+
+#[derive(Serializable, Deserializable, Debug, Clone)]
+pub struct TxIndexEntry {
+    txid: TxID,
+    location: TxLocation,
+}
+
+/// Maps each transaction in the chain to the block that contains it (Core's `-txindex`)
+#[derive(Debug, Default)]
+pub struct TxIndex {
+    locations: HashMap<TxID, TxLocation>,
+}
+
+impl TxIndex {
+    pub fn get(&self, txid: &TxID) -> Option<&TxLocation> {
+        self.locations.get(txid)
+    }
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+}
+
+impl Index for TxIndex {
+    type Entry = TxIndexEntry;
+    const NAME: &'static str = "transaction index";
+
+    fn entries(block: &Block, _: u32, _: &[Coin]) -> Vec<TxIndexEntry> {
+        let mut offset = BlockHeader::len() + CompactInt::size(block.transactions().len());
+        block
+            .transactions()
+            .iter()
+            .map(|tx| {
+                let entry = TxIndexEntry {
+                    txid: tx.txid().clone(),
+                    location: TxLocation {
+                        block: block.header().hash().clone(),
+                        offset: offset as u32,
+                    },
+                };
+                offset += tx.len();
+                entry
+            })
+            .collect()
+    }
+
+    fn insert(&mut self, entry: TxIndexEntry) {
+        self.locations.insert(entry.txid, entry.location);
+    }
+
+    fn remove(&mut self, entry: &TxIndexEntry) {
+        // Only remove the txid if it still points into this block. Duplicate txids (BIP30) are otherwise
+        // indexed at their most recent location.
+        if self.locations.get(&entry.txid) == Some(&entry.location) {
+            self.locations.remove(&entry.txid);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! The storage layer of Bitcoin Warp.
+//!
+//! For now, this crate provides
+//! 1. an in-memory [`UtxoSet`] and the [`Coin`] type it stores, and a [`Chainstate`] which applies blocks to it.
+//! 1. a [`BlockStore`] for raw blocks and undo data, with optional pruning.
+//! 1. a locked [`DataDir`] to keep it in, and [`write_atomically`] for the files which are rewritten in place.
+//! 1. optional transaction and script [`indexes`], kept consistent across reorgs.
+//! 1. UTXO set snapshots which can be dumped and loaded in our own format or Bitcoin Core's ([`snapshot`]).
+//! 1. a read-only importer for Bitcoin Core's LevelDB `chainstate` directory ([`CoreChainstate`]), so that a fresh
+//!    node can bootstrap its UTXO set from an existing Core installation.
+mod compression;
+
+mod error;
+pub use error::StorageError;
+
+mod coin;
+pub use coin::Coin;
+
+mod utxo_set;
+pub use utxo_set::{UtxoSet, UtxoSetHasher};
+
+mod chainstate;
+pub use chainstate::Chainstate;
+
+mod block_store;
+pub use block_store::{BlockIndexEntry, BlockStore, MAX_BLOCKFILE_SIZE, MIN_BLOCKS_TO_KEEP};
+
+mod data_dir;
+pub use data_dir::{write_atomically, DataDir};
+
+pub mod indexes;
+
+pub mod snapshot;
+
+mod core_chainstate;
+pub use core_chainstate::{CoreChainstate, CoreCoins};
+
+#[cfg(test)]
+mod test_util;
@@ -0,0 +1,670 @@
+//! UTXO set snapshots, in the spirit of Bitcoin Core's assumeutxo.
+//!
+//! A snapshot is the full UTXO set at some block, written in outpoint order so that the same set
+//! always produces the same file. A node can load a snapshot to start serving at the tip within minutes,
+//! while a [`BackgroundValidation`] chain replays every block from genesis and checks the snapshot's
+//! hash once it reaches the snapshot's base block.
+//!
+//! Two formats are supported:
+//! 1. [`SnapshotFormat::Warp`], our own format, which records the base height and ends with the
+//!    `hash_serialized_3` of the set so that truncation or corruption is detected on load.
+//! 1. [`SnapshotFormat::Core`], the (version 2) format written by Bitcoin Core's `dumptxoutset` and read
+//!    by `loadtxoutset`.
+use crate::compression::{read_coin, write_coin};
+use crate::{Chainstate, Coin, StorageError, UtxoSet, UtxoSetHasher};
+use shared::{u256, Block, BlockHash, CompactInt, Deserializable, Serializable, TxOutpoint};
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use tracing::{info, warn};
+
+const WARP_MAGIC: &[u8; 8] = b"warputxo";
+const WARP_VERSION: u16 = 1;
+const CORE_MAGIC: &[u8; 5] = b"utxo\xff";
+const CORE_VERSION: u16 = 2;
+/// No single coin record comes close to this size, since scripts over 10,000 bytes are unspendable
+/// and never enter the UTXO set.
+const MAX_RECORD_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Warp,
+    Core,
+}
+
+/// Describes the UTXO set contained in a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotMetadata {
+    format: SnapshotFormat,
+    network_magic: u32,
+    base_block: BlockHash,
+    base_height: u32,
+    coins_count: u64,
+}
+
+impl SnapshotMetadata {
+    pub fn format(&self) -> SnapshotFormat {
+        self.format
+    }
+    pub fn network_magic(&self) -> u32 {
+        self.network_magic
+    }
+    /// The hash of the block at which the snapshot was taken
+    pub fn base_block(&self) -> &BlockHash {
+        &self.base_block
+    }
+    /// The height of the base block. Core's format does not record this, so for Core snapshots it is
+    /// looked up from the base block when the snapshot is read.
+    pub fn base_height(&self) -> u32 {
+        self.base_height
+    }
+    pub fn coins_count(&self) -> u64 {
+        self.coins_count
+    }
+}
+
+/// A snapshot which has been read into memory.
+#[derive(Debug)]
+pub struct LoadedSnapshot {
+    pub metadata: SnapshotMetadata,
+    pub chainstate: Chainstate,
+    /// The `hash_serialized_3` of the loaded set
+    pub hash: [u8; 32],
+}
+
+/// Writes the UTXO set of `chainstate` to `target` and returns its `hash_serialized_3`.
+///
+/// The output depends only on the contents of the chainstate, so two nodes with the same UTXO set
+/// produce byte-for-byte identical snapshots.
+pub fn write_snapshot<W: Write>(
+    chainstate: &Chainstate,
+    network_magic: u32,
+    format: SnapshotFormat,
+    mut target: W,
+) -> Result<[u8; 32], StorageError> {
+    let utxos = chainstate.utxos();
+    info!(
+        "Writing {:?} snapshot of {} coins at block {:?} (height {})",
+        format,
+        utxos.len(),
+        chainstate.tip(),
+        chainstate.height()
+    );
+    let mut header = Vec::new();
+    match format {
+        SnapshotFormat::Warp => {
+            header.extend_from_slice(WARP_MAGIC);
+            WARP_VERSION.serialize(&mut header)?;
+            network_magic.serialize(&mut header)?;
+            chainstate.tip().serialize(&mut header)?;
+            chainstate.height().serialize(&mut header)?;
+        }
+        SnapshotFormat::Core => {
+            header.extend_from_slice(CORE_MAGIC);
+            CORE_VERSION.serialize(&mut header)?;
+            network_magic.serialize(&mut header)?;
+            chainstate.tip().serialize(&mut header)?;
+        }
+    }
+    (utxos.len() as u64).serialize(&mut header)?;
+    target.write_all(&header)?;
+
+    let mut hasher = UtxoSetHasher::new();
+    let mut record = Vec::new();
+    let mut coins = utxos.iter().peekable();
+    while let Some((outpoint, coin)) = coins.next() {
+        match format {
+            SnapshotFormat::Warp => {
+                hasher.add(outpoint, coin);
+                outpoint.serialize(&mut record)?;
+                write_coin(&mut record, coin);
+            }
+            SnapshotFormat::Core => {
+                // Core groups the coins of each transaction under a single txid
+                let mut group = vec![(outpoint, coin)];
+                while let Some(next) = coins.next_if(|(next, _)| next.hash() == outpoint.hash()) {
+                    group.push(next);
+                }
+                outpoint.hash().serialize(&mut record)?;
+                CompactInt::from(group.len()).serialize(&mut record)?;
+                for (outpoint, coin) in group {
+                    hasher.add(outpoint, coin);
+                    CompactInt::from(outpoint.index() as usize).serialize(&mut record)?;
+                    write_coin(&mut record, coin);
+                }
+            }
+        }
+        target.write_all(&record)?;
+        record.clear();
+    }
+    let hash = hasher.finish();
+    if format == SnapshotFormat::Warp {
+        target.write_all(&hash)?;
+    }
+    target.flush()?;
+    Ok(hash)
+}
+
+/// Reads a snapshot in either format, rejecting snapshots for networks other than `network_magic`.
+///
+/// Core's format doesn't record the height of the base block, so for Core snapshots it comes from `base_height`,
+/// which should look the block up in our block index. Like Core, we refuse snapshots whose base block we don't know.
+pub fn read_snapshot<R, F>(
+    source: R,
+    network_magic: u32,
+    base_height: F,
+) -> Result<LoadedSnapshot, StorageError>
+where
+    R: Read,
+    F: FnOnce(&BlockHash) -> Option<u32>,
+{
+    let mut reader = RecordReader::new(source);
+    reader.fill(CORE_MAGIC.len())?;
+    let format = if reader.remaining().starts_with(WARP_MAGIC) {
+        reader.consume(WARP_MAGIC.len());
+        SnapshotFormat::Warp
+    } else if reader.remaining().starts_with(CORE_MAGIC) {
+        reader.consume(CORE_MAGIC.len());
+        SnapshotFormat::Core
+    } else {
+        return Err(StorageError::Corruption(String::from(
+            "file is not a UTXO snapshot",
+        )));
+    };
+
+    let (version, magic, base_block, recorded_height, coins_count) = reader.record(|src| {
+        let version = u16::deserialize(&mut *src)?;
+        let magic = u32::deserialize(&mut *src)?;
+        let base_block = BlockHash::deserialize(&mut *src)?;
+        let recorded_height = match format {
+            SnapshotFormat::Warp => Some(u32::deserialize(&mut *src)?),
+            SnapshotFormat::Core => None,
+        };
+        let coins_count = u64::deserialize(&mut *src)?;
+        Ok((version, magic, base_block, recorded_height, coins_count))
+    })?;
+    let supported = match format {
+        SnapshotFormat::Warp => WARP_VERSION,
+        SnapshotFormat::Core => CORE_VERSION,
+    };
+    if version != supported {
+        return Err(StorageError::Unsupported(format!(
+            "{:?} snapshot version {}",
+            format, version
+        )));
+    }
+    if magic != network_magic {
+        return Err(StorageError::Corruption(format!(
+            "snapshot is for network {:#x}, but we are on {:#x}",
+            magic, network_magic
+        )));
+    }
+    let base_height = match recorded_height {
+        Some(height) => height,
+        None => base_height(&base_block).ok_or_else(|| {
+            StorageError::Unsupported(format!(
+                "the snapshot's base block {:?} is not in our block index",
+                base_block
+            ))
+        })?,
+    };
+    info!(
+        "Loading {:?} snapshot of {} coins at block {:?}",
+        format, coins_count, base_block
+    );
+
+    let mut utxos = UtxoSet::new();
+    let mut hasher = UtxoSetHasher::new();
+    let mut add = |utxos: &mut UtxoSet, outpoint: TxOutpoint, coin: Coin| {
+        hasher.add(&outpoint, &coin);
+        utxos.insert(outpoint, coin);
+        if utxos.len().is_multiple_of(1_000_000) {
+            info!("Loaded {} of {} coins", utxos.len(), coins_count);
+        }
+    };
+    let mut last: Option<TxOutpoint> = None;
+    // Coins must be strictly increasing, which also rules out duplicates
+    let mut check_order = |outpoint: &TxOutpoint| {
+        if last.as_ref().is_some_and(|last| last >= outpoint) {
+            return Err(StorageError::Corruption(String::from(
+                "snapshot coins are not in order",
+            )));
+        }
+        last = Some(outpoint.clone());
+        Ok(())
+    };
+    while (utxos.len() as u64) < coins_count {
+        match format {
+            SnapshotFormat::Warp => {
+                let (outpoint, coin) = reader
+                    .record(|src| Ok((TxOutpoint::deserialize(&mut *src)?, read_coin(src)?)))?;
+                check_order(&outpoint)?;
+                add(&mut utxos, outpoint, coin);
+            }
+            SnapshotFormat::Core => {
+                let (txid, count) = reader.record(|src| {
+                    let txid = u256::deserialize(&mut *src)?;
+                    Ok((txid, CompactInt::deserialize(&mut *src)?.value()))
+                })?;
+                if count == 0 || utxos.len() as u64 + count > coins_count {
+                    return Err(StorageError::Corruption(String::from(
+                        "bad coin count for transaction",
+                    )));
+                }
+                for _ in 0..count {
+                    let (index, coin) = reader.record(|src| {
+                        let index = CompactInt::deserialize(&mut *src)?.value();
+                        Ok((index, read_coin(src)?))
+                    })?;
+                    let index: u32 = index.try_into().map_err(|_| {
+                        StorageError::Corruption(String::from("coin output index is out of range"))
+                    })?;
+                    let outpoint = TxOutpoint::new(txid.clone(), index);
+                    check_order(&outpoint)?;
+                    add(&mut utxos, outpoint, coin);
+                }
+            }
+        }
+    }
+    let hash = hasher.finish();
+
+    if format == SnapshotFormat::Warp {
+        let expected = reader.record(|src| Ok(<[u8; 32]>::deserialize(&mut *src)?))?;
+        if expected != hash {
+            return Err(StorageError::Corruption(String::from(
+                "snapshot hash does not match its contents",
+            )));
+        }
+    }
+    reader.fill(1)?;
+    if !reader.remaining().is_empty() {
+        return Err(StorageError::Corruption(String::from(
+            "snapshot has trailing data",
+        )));
+    }
+
+    info!("Finished loading snapshot with {} coins", utxos.len());
+    Ok(LoadedSnapshot {
+        metadata: SnapshotMetadata {
+            format,
+            network_magic: magic,
+            base_block: base_block.clone(),
+            base_height,
+            coins_count,
+        },
+        chainstate: Chainstate::new(utxos, base_block, base_height),
+        hash,
+    })
+}
+
+/// Buffers a reader so that each record can be parsed from a contiguous slice.
+struct RecordReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> RecordReader<R> {
+    fn new(inner: R) -> RecordReader<R> {
+        RecordReader {
+            inner,
+            buf: Vec::with_capacity(2 * MAX_RECORD_LEN),
+            pos: 0,
+            eof: false,
+        }
+    }
+
+    fn remaining(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.pos += len;
+    }
+
+    /// Ensures at least `len` bytes are buffered, unless the underlying reader runs out first.
+    fn fill(&mut self, len: usize) -> Result<(), StorageError> {
+        if self.buf.len() - self.pos >= len || self.eof {
+            return Ok(());
+        }
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let mut chunk = [0u8; 8192];
+        while self.buf.len() < len.max(MAX_RECORD_LEN) {
+            match self.inner.read(&mut chunk) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(read) => self.buf.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the next record with `parse`, which must not read more than [`MAX_RECORD_LEN`] bytes.
+    fn record<T>(
+        &mut self,
+        parse: impl FnOnce(&mut &[u8]) -> Result<T, StorageError>,
+    ) -> Result<T, StorageError> {
+        self.fill(MAX_RECORD_LEN)?;
+        let mut src = &self.buf[self.pos..];
+        let available = src.len();
+        let result = parse(&mut src).map_err(|e| match e {
+            StorageError::Deserialization(_) if self.eof => {
+                StorageError::Corruption(String::from("snapshot is truncated"))
+            }
+            e => e,
+        })?;
+        self.pos += available - src.len();
+        Ok(result)
+    }
+}
+
+/// The outcome of connecting a block to a [`BackgroundValidation`] chain.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationStatus {
+    /// The background chain has not reached the snapshot's base block yet.
+    InProgress,
+    /// The background chain reached the base block and its UTXO set matched the snapshot.
+    Validated,
+    /// The background chain's UTXO set at the base block differed from the snapshot.
+    Invalid {
+        expected: [u8; 32],
+        actual: [u8; 32],
+    },
+}
+
+/// Replays the chain from genesis up to a snapshot's base block, then checks that the resulting UTXO set
+/// hashes to the same value as the snapshot.
+///
+/// Until this reports [`ValidationStatus::Validated`], the snapshot chainstate is only assumed valid.
+#[derive(Debug)]
+pub struct BackgroundValidation {
+    chainstate: Chainstate,
+    base_block: BlockHash,
+    base_height: u32,
+    expected: [u8; 32],
+}
+
+impl BackgroundValidation {
+    /// Starts validating the snapshot described by `metadata` and `expected_hash` from `chainstate`,
+    /// which is usually an empty chainstate at genesis.
+    pub fn new(
+        chainstate: Chainstate,
+        metadata: &SnapshotMetadata,
+        expected_hash: [u8; 32],
+    ) -> BackgroundValidation {
+        BackgroundValidation {
+            chainstate,
+            base_block: metadata.base_block().clone(),
+            base_height: metadata.base_height(),
+            expected: expected_hash,
+        }
+    }
+    pub fn chainstate(&self) -> &Chainstate {
+        &self.chainstate
+    }
+    pub fn into_chainstate(self) -> Chainstate {
+        self.chainstate
+    }
+    pub fn base_block(&self) -> &BlockHash {
+        &self.base_block
+    }
+    /// The fraction of blocks up to the base block which have been validated
+    pub fn progress(&self) -> f64 {
+        if self.base_height == 0 {
+            return 1.0;
+        }
+        (self.chainstate.height() as f64 / self.base_height as f64).min(1.0)
+    }
+
+    /// Connects the next block of the background chain.
+    pub fn connect_block(&mut self, block: &Block) -> Result<ValidationStatus, StorageError> {
+        if self.chainstate.tip() == &self.base_block {
+            return Err(StorageError::InvalidBlock(String::from(
+                "background validation has already reached the snapshot base",
+            )));
+        }
+        self.chainstate.connect_block(block)?;
+        Ok(self.status())
+    }
+
+    /// Whether the background chain has reached the base block, and if so, whether the snapshot matched it
+    pub fn status(&self) -> ValidationStatus {
+        if self.chainstate.tip() != &self.base_block {
+            return ValidationStatus::InProgress;
+        }
+        let actual = self.chainstate.utxos().hash_serialized();
+        if actual == self.expected {
+            info!(
+                "Background validation reached block {:?}. Snapshot is valid",
+                self.base_block
+            );
+            ValidationStatus::Validated
+        } else {
+            warn!(
+                "Background validation reached block {:?}, but the UTXO set does not match the snapshot!",
+                self.base_block
+            );
+            ValidationStatus::Invalid {
+                expected: self.expected,
+                actual,
+            }
+        }
+    }
+
+    /// Writes the snapshot's base block, base height and hash, so that validation can carry on after a restart
+    /// with [`BackgroundValidation::resume`]. The background chainstate has to be saved separately.
+    pub fn write_state<W: Write>(&self, mut target: W) -> Result<(), StorageError> {
+        let mut state = Vec::new();
+        self.base_block.serialize(&mut state)?;
+        self.base_height.serialize(&mut state)?;
+        self.expected.serialize(&mut state)?;
+        target.write_all(&state)?;
+        target.flush()?;
+        Ok(())
+    }
+
+    /// Carries on validating from `chainstate`, the background chainstate saved alongside `source`
+    pub fn resume<R: Read>(
+        chainstate: Chainstate,
+        mut source: R,
+    ) -> Result<BackgroundValidation, StorageError> {
+        let mut state = Vec::new();
+        source.read_to_end(&mut state)?;
+        let mut src = &state[..];
+        let base_block = BlockHash::deserialize(&mut src)?;
+        let base_height = u32::deserialize(&mut src)?;
+        let expected = <[u8; 32]>::deserialize(&mut src)?;
+        if !src.is_empty() {
+            return Err(StorageError::Corruption(String::from(
+                "snapshot validation state has trailing data",
+            )));
+        }
+        Ok(BackgroundValidation {
+            chainstate,
+            base_block,
+            base_height,
+            expected,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::TxOutput;
+
+    const MAGIC: u32 = 0xD9B4BEF9;
+
+    fn sample_chainstate() -> Chainstate {
+        let mut utxos = UtxoSet::new();
+        let p2pkh = hex::decode("76a914a09be8040cbf399926aeb1f470c37d1341f3b46588ac").unwrap();
+        for (txid, index, height) in [(3u8, 0u32, 7u32), (1, 5, 3), (1, 0, 3), (2, 1000, 9)].iter()
+        {
+            let coin = Coin::new(
+                TxOutput::new(*height as i64 * 1000, p2pkh.clone()),
+                *height,
+                *index == 0,
+            );
+            utxos.insert(TxOutpoint::new(u256::from_bytes([*txid; 32]), *index), coin);
+        }
+        Chainstate::new(utxos, BlockHash::from([0xbb; 32]), 9)
+    }
+
+    #[test]
+    fn roundtrip_both_formats() {
+        let chainstate = sample_chainstate();
+        for format in [SnapshotFormat::Warp, SnapshotFormat::Core].iter() {
+            let mut file = Vec::new();
+            let hash = write_snapshot(&chainstate, MAGIC, *format, &mut file).unwrap();
+            assert_eq!(hash, chainstate.utxos().hash_serialized());
+
+            let base_height = |hash: &BlockHash| Some(9).filter(|_| hash == chainstate.tip());
+            let loaded = read_snapshot(&file[..], MAGIC, base_height).unwrap();
+            assert_eq!(loaded.metadata.format(), *format);
+            assert_eq!(loaded.metadata.base_block(), chainstate.tip());
+            assert_eq!(loaded.metadata.base_height(), 9);
+            assert_eq!(loaded.metadata.coins_count(), 4);
+            assert_eq!(loaded.hash, hash);
+            let coins: Vec<_> = loaded.chainstate.utxos().iter().collect();
+            let expected: Vec<_> = chainstate.utxos().iter().collect();
+            assert_eq!(coins, expected);
+
+            // Snapshots are deterministic
+            let mut again = Vec::new();
+            write_snapshot(&loaded.chainstate, MAGIC, *format, &mut again).unwrap();
+            assert_eq!(file, again);
+        }
+    }
+
+    #[test]
+    fn core_snapshots_need_a_known_base_block() {
+        let chainstate = sample_chainstate();
+        let mut file = Vec::new();
+        write_snapshot(&chainstate, MAGIC, SnapshotFormat::Core, &mut file).unwrap();
+        assert!(matches!(
+            read_snapshot(&file[..], MAGIC, |_| None),
+            Err(StorageError::Unsupported(_))
+        ));
+        // The height comes from the block index, not from the coins
+        let loaded = read_snapshot(&file[..], MAGIC, |_| Some(20)).unwrap();
+        assert_eq!(loaded.metadata.base_height(), 20);
+        assert_eq!(loaded.chainstate.height(), 20);
+    }
+
+    #[test]
+    fn core_format_layout() {
+        let mut utxos = UtxoSet::new();
+        let coin = Coin::new(TxOutput::new(1, vec![0x51]), 1, true);
+        utxos.insert(TxOutpoint::new(u256::from_bytes([1; 32]), 0), coin.clone());
+        utxos.insert(TxOutpoint::new(u256::from_bytes([1; 32]), 2), coin);
+        let chainstate = Chainstate::new(utxos, BlockHash::from([0xbb; 32]), 1);
+        let mut file = Vec::new();
+        write_snapshot(&chainstate, MAGIC, SnapshotFormat::Core, &mut file).unwrap();
+
+        let mut expected = hex::decode("7574786fff0200f9beb4d9").unwrap();
+        expected.extend_from_slice(&[0xbb; 32]);
+        expected.extend_from_slice(&2u64.to_le_bytes());
+        expected.extend_from_slice(&[1; 32]);
+        // Two coins: vout 0 and vout 2, each with code 3, amount 1 and a raw `OP_TRUE` script
+        expected.extend_from_slice(&hex::decode("0200030107510203010751").unwrap());
+        assert_eq!(hex::encode(&file), hex::encode(&expected));
+    }
+
+    #[test]
+    fn rejects_bad_snapshots() {
+        let chainstate = sample_chainstate();
+        let mut file = Vec::new();
+        write_snapshot(&chainstate, MAGIC, SnapshotFormat::Warp, &mut file).unwrap();
+
+        assert!(read_snapshot(&file[..], 0x0709110B, |_| None).is_err());
+        assert!(read_snapshot(&file[..file.len() - 1], MAGIC, |_| None).is_err());
+        let mut corrupted = file.clone();
+        let last = corrupted.len() - 40;
+        corrupted[last] ^= 1;
+        assert!(read_snapshot(&corrupted[..], MAGIC, |_| None).is_err());
+        let mut trailing = file;
+        trailing.push(0);
+        assert!(read_snapshot(&trailing[..], MAGIC, |_| None).is_err());
+    }
+
+    #[test]
+    fn background_validation_checks_snapshot_hash() {
+        let block = Block::_test_block();
+        let mut utxos = UtxoSet::new();
+        for tx in block.transactions().iter().skip(1) {
+            for input in tx.inputs() {
+                let coin = Coin::new(TxOutput::new(50_0000_0000, vec![0x51]), 1, true);
+                utxos.insert(input.previous_outpoint().clone(), coin);
+            }
+        }
+        let start = Chainstate::new(utxos, block.header().prev_hash().clone(), 100);
+        let mut expected = start.clone();
+        expected.connect_block(&block).unwrap();
+
+        let mut file = Vec::new();
+        write_snapshot(&expected, MAGIC, SnapshotFormat::Warp, &mut file).unwrap();
+        let snapshot = read_snapshot(&file[..], MAGIC, |_| None).unwrap();
+        let mut validation =
+            BackgroundValidation::new(start.clone(), &snapshot.metadata, snapshot.hash);
+        assert_eq!(
+            validation.connect_block(&block).unwrap(),
+            ValidationStatus::Validated
+        );
+        assert_eq!(validation.progress(), 1.0);
+
+        // A snapshot with a coin missing should be caught
+        let mut tampered = expected.into_utxos();
+        let first = tampered
+            .iter()
+            .next()
+            .map(|(outpoint, _)| outpoint.clone())
+            .unwrap();
+        tampered.remove(&first);
+        let tampered = Chainstate::new(tampered, block.header().hash().clone(), 101);
+        let mut file = Vec::new();
+        write_snapshot(&tampered, MAGIC, SnapshotFormat::Core, &mut file).unwrap();
+        let snapshot = read_snapshot(&file[..], MAGIC, |_| Some(101)).unwrap();
+        let mut validation = BackgroundValidation::new(start, &snapshot.metadata, snapshot.hash);
+        assert!(matches!(
+            validation.connect_block(&block).unwrap(),
+            ValidationStatus::Invalid { .. }
+        ));
+    }
+
+    #[test]
+    fn background_validation_resumes_from_saved_state() {
+        let block = Block::_test_block();
+        let mut utxos = UtxoSet::new();
+        for tx in block.transactions().iter().skip(1) {
+            for input in tx.inputs() {
+                let coin = Coin::new(TxOutput::new(50_0000_0000, vec![0x51]), 1, true);
+                utxos.insert(input.previous_outpoint().clone(), coin);
+            }
+        }
+        let start = Chainstate::new(utxos, block.header().prev_hash().clone(), 100);
+        let mut expected = start.clone();
+        expected.connect_block(&block).unwrap();
+        let mut file = Vec::new();
+        write_snapshot(&expected, MAGIC, SnapshotFormat::Warp, &mut file).unwrap();
+        let snapshot = read_snapshot(&file[..], MAGIC, |_| None).unwrap();
+        let validation =
+            BackgroundValidation::new(start.clone(), &snapshot.metadata, snapshot.hash);
+
+        let mut state = Vec::new();
+        validation.write_state(&mut state).unwrap();
+        assert!(BackgroundValidation::resume(start.clone(), &state[..state.len() - 1]).is_err());
+        let mut resumed = BackgroundValidation::resume(start, &state[..]).unwrap();
+        assert_eq!(resumed.base_block(), block.header().hash());
+        assert_eq!(resumed.status(), ValidationStatus::InProgress);
+        assert_eq!(resumed.progress(), 100.0 / 101.0);
+        assert_eq!(
+            resumed.connect_block(&block).unwrap(),
+            ValidationStatus::Validated
+        );
+    }
+}
@@ -0,0 +1,10 @@
+//! Helpers shared by the tests of this crate.
+use std::path::PathBuf;
+
+/// Creates an empty, unique temporary directory
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("warp-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
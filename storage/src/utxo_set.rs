@@ -0,0 +1,118 @@
+use crate::Coin;
+use shared::{Serializable, TxOutpoint};
+use std::collections::BTreeMap;
+use warp_crypto::Sha256dHasher;
+
+/// The set of all unspent transaction outputs.
+///
+/// Coins are kept sorted by outpoint, which is the same order Bitcoin Core uses in its chainstate.
+/// This lets us compute a UTXO set hash that can be compared directly against `gettxoutsetinfo`.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    coins: BTreeMap<TxOutpoint, Coin>,
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet {
+            coins: BTreeMap::new(),
+        }
+    }
+    pub fn insert(&mut self, outpoint: TxOutpoint, coin: Coin) -> Option<Coin> {
+        self.coins.insert(outpoint, coin)
+    }
+    pub fn remove(&mut self, outpoint: &TxOutpoint) -> Option<Coin> {
+        self.coins.remove(outpoint)
+    }
+    pub fn get(&self, outpoint: &TxOutpoint) -> Option<&Coin> {
+        self.coins.get(outpoint)
+    }
+    pub fn contains(&self, outpoint: &TxOutpoint) -> bool {
+        self.coins.contains_key(outpoint)
+    }
+    pub fn len(&self) -> usize {
+        self.coins.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.coins.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&TxOutpoint, &Coin)> {
+        self.coins.iter()
+    }
+    /// The sum of the values of all coins in the set, in satoshis
+    pub fn total_amount(&self) -> i64 {
+        self.coins.values().map(|coin| coin.value()).sum()
+    }
+    /// Computes the equivalent of Bitcoin Core's `hash_serialized_3` over the whole set.
+    pub fn hash_serialized(&self) -> [u8; 32] {
+        let mut hasher = UtxoSetHasher::new();
+        for (outpoint, coin) in self.coins.iter() {
+            hasher.add(outpoint, coin);
+        }
+        hasher.finish()
+    }
+}
+
+/// Incrementally computes Bitcoin Core's `hash_serialized_3` UTXO set commitment.
+///
+/// Coins must be added in outpoint order. Note that the hash is displayed by Core in reversed byte order.
+pub struct UtxoSetHasher {
+    hasher: Sha256dHasher,
+}
+
+impl UtxoSetHasher {
+    pub fn new() -> UtxoSetHasher {
+        UtxoSetHasher {
+            hasher: Sha256dHasher::new(),
+        }
+    }
+    pub fn add(&mut self, outpoint: &TxOutpoint, coin: &Coin) {
+        // Writing into a hasher cannot fail
+        outpoint
+            .serialize(&mut self.hasher)
+            .expect("Serialization to hasher should not fail");
+        coin.code()
+            .serialize(&mut self.hasher)
+            .expect("Serialization to hasher should not fail");
+        coin.output()
+            .serialize(&mut self.hasher)
+            .expect("Serialization to hasher should not fail");
+    }
+    pub fn finish(self) -> [u8; 32] {
+        self.hasher.finish()
+    }
+}
+
+impl Default for UtxoSetHasher {
+    fn default() -> Self {
+        UtxoSetHasher::new()
+    }
+}
+
+#[test]
+fn hash_is_independent_of_insertion_order() {
+    use shared::{u256, TxOutput};
+    let first = TxOutpoint::new(u256::from(1), 7);
+    let second = TxOutpoint::new(u256::from(1), 300);
+    let third = TxOutpoint::new(u256::from(2), 0);
+    let coin = Coin::new(TxOutput::new(5000, vec![0x51]), 12, false);
+
+    let mut forward = UtxoSet::new();
+    forward.insert(first.clone(), coin.clone());
+    forward.insert(second.clone(), coin.clone());
+    forward.insert(third.clone(), coin.clone());
+
+    let mut backward = UtxoSet::new();
+    backward.insert(third.clone(), coin.clone());
+    backward.insert(second.clone(), coin.clone());
+    backward.insert(first.clone(), coin.clone());
+
+    let mut hasher = UtxoSetHasher::new();
+    hasher.add(&first, &coin);
+    hasher.add(&second, &coin);
+    hasher.add(&third, &coin);
+
+    assert_eq!(forward.hash_serialized(), backward.hash_serialized());
+    assert_eq!(forward.hash_serialized(), hasher.finish());
+    assert_eq!(forward.total_amount(), 15000);
+}
@@ -1,7 +1,9 @@
 extern crate hex;
 extern crate serde_derive;
+use config::Args;
+use tracing::warn;
 use tracing_subscriber::{filter::LevelFilter, fmt};
-use warpd::run_shell;
+use warpd::{run_daemon, run_shell};
 // #[derive(Serializable, Deserializable, Debug)]
 // pub struct MyTestStruct {
 //     identifier: u32,
@@ -51,7 +53,37 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let subscriber = fmt().with_max_level(LevelFilter::TRACE).finish();
     let _ = tracing::subscriber::set_global_default(subscriber)
         .map_err(|_err| eprintln!("Unable to set global default subscriber"));
-    run_shell().await?;
+    let args = Args::load(std::env::args().skip(1), std::env::vars()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if args.help_requested() {
+        print!("{}", config::help_message());
+        return Ok(());
+    }
+    if let Some(arg) = args.positional().first() {
+        eprintln!("Unexpected argument {}", arg);
+        std::process::exit(1);
+    }
+    let config = args.to_config().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let daemon = args.daemon().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    for ignored in args.ignored() {
+        warn!("Ignoring unknown setting {}", ignored);
+    }
+    if daemon {
+        if let Err(e) = run_daemon(config).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    run_shell(config).await?;
     // // test();
 
     // // println!("{:?}", warpd);
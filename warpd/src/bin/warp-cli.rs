@@ -0,0 +1,462 @@
+//! `warp-cli`, a command line client for the JSON-RPC interface of `warpd -daemon`, in the style of Core's
+//! `bitcoin-cli`.
+//!
+//! The network, data directory, config file and RPC credentials are read the same way warpd reads them, so with
+//! the same options (or none) warp-cli finds the node's port and cookie file by itself.
+use config::{Args, Config};
+use serde_json::{json, Value};
+use std::io::{BufRead, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+const DEFAULT_RPC_CONNECT: &str = "127.0.0.1";
+/// How long to wait for a response, in seconds (Core's `DEFAULT_HTTP_CLIENT_TIMEOUT`)
+const DEFAULT_CLIENT_TIMEOUT: u64 = 900;
+
+const USAGE: &str = "\
+Usage: warp-cli [options] <command> [params]
+       warp-cli [options] -named <command> [name=value]...
+
+Sends a command to a running warpd. Results are printed as JSON, except for strings, which are printed as they
+are.
+
+Options:
+  -named
+       Pass parameters by name, as name=value, rather than by position
+  -stdin
+       Read extra parameters from standard input, one per line, after those on the command line. Useful for
+       large raw transactions.
+  -rpcconnect=<ip>
+       Connect to the node at <ip>, which may include a port (default: 127.0.0.1)
+  -rpcclienttimeout=<n>
+       Wait at most <n> seconds for a response (default: 900)
+
+warp-cli also takes warpd's -datadir, -conf, -chain, -testnet, -regtest, -signet, -rpcport, -rpcuser,
+-rpcpassword and -rpccookiefile options. See warpd -help.
+";
+
+/// The parameters which are sent as JSON values rather than strings, by method, position and name (Core's
+/// `vRPCConvertParams`). Every other parameter is sent as a string.
+const JSON_PARAMS: &[(&str, usize, &str)] = &[
+    ("getblockhash", 0, "height"),
+    ("getblock", 1, "verbosity"),
+    ("getblockheader", 1, "verbose"),
+    ("getrawtransaction", 1, "verbose"),
+    ("sendrawtransaction", 1, "maxfeerate"),
+    ("getrawmempool", 0, "verbose"),
+    ("getrawmempool", 1, "mempool_sequence"),
+    ("disconnectnode", 1, "nodeid"),
+    ("setban", 2, "bantime"),
+    ("setban", 3, "absolute"),
+];
+
+/// The options only warp-cli takes. The rest are left for [`Args`].
+struct ClientOptions {
+    named: bool,
+    stdin: bool,
+    connect: Option<String>,
+    timeout: Duration,
+}
+
+/// Why warp-cli failed, and its exit status
+#[derive(Debug)]
+enum Failure {
+    /// A problem before or while talking to the node
+    Client(String),
+    /// The node answered with an error
+    Rpc { code: i64, message: String },
+}
+
+fn main() {
+    match run() {
+        Ok(()) => {}
+        Err(Failure::Client(message)) => {
+            eprintln!("error: {}", message);
+            std::process::exit(1);
+        }
+        Err(Failure::Rpc { code, message }) => {
+            eprintln!("error code: {}\nerror message:\n{}", code, message);
+            // Like bitcoin-cli, exit with the magnitude of the error code
+            std::process::exit(code.unsigned_abs().clamp(1, i32::MAX as u64) as i32);
+        }
+    }
+}
+
+fn run() -> Result<(), Failure> {
+    let (options, rest) = split_client_options(std::env::args().skip(1))?;
+    let args = Args::load(rest, std::env::vars()).map_err(|e| Failure::Client(e.to_string()))?;
+    if args.help_requested() {
+        print!("{}", USAGE);
+        return Ok(());
+    }
+    let config = args
+        .to_config()
+        .map_err(|e| Failure::Client(e.to_string()))?;
+
+    let (method, params) = request_params(
+        &options,
+        args.positional().to_vec(),
+        std::io::stdin().lock(),
+    )?;
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let reply = call(&config, &options, &request)?;
+    if let Some(error) = reply.get("error").filter(|error| !error.is_null()) {
+        return Err(Failure::Rpc {
+            code: error.get("code").and_then(Value::as_i64).unwrap_or(-1),
+            message: error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        });
+    }
+    match reply.get("result") {
+        None | Some(Value::Null) => {}
+        Some(Value::String(result)) => println!("{}", result),
+        Some(result) => println!(
+            "{}",
+            serde_json::to_string_pretty(result).expect("Values always serialize")
+        ),
+    }
+    Ok(())
+}
+
+/// Takes warp-cli's own options from the front of the command line, returning them and the other arguments
+fn split_client_options<I: Iterator<Item = String>>(
+    command_line: I,
+) -> Result<(ClientOptions, Vec<String>), Failure> {
+    let mut options = ClientOptions {
+        named: false,
+        stdin: false,
+        connect: None,
+        timeout: Duration::from_secs(DEFAULT_CLIENT_TIMEOUT),
+    };
+    let mut rest = Vec::new();
+    let mut command_line = command_line.peekable();
+    while let Some(arg) = command_line.next_if(|arg| arg.starts_with('-')) {
+        let option = arg.trim_start_matches('-');
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        let flag = |value: Option<&str>| match value {
+            None | Some("1") => Ok(true),
+            Some("0") => Ok(false),
+            Some(value) => Err(Failure::Client(format!(
+                "invalid value for -{}: {}",
+                name, value
+            ))),
+        };
+        match name {
+            "named" => options.named = flag(value)?,
+            "stdin" => options.stdin = flag(value)?,
+            "rpcconnect" => options.connect = value.map(String::from),
+            "rpcclienttimeout" => {
+                let seconds = value.and_then(|value| value.parse().ok()).ok_or_else(|| {
+                    Failure::Client(String::from("-rpcclienttimeout takes a number of seconds"))
+                })?;
+                options.timeout = Duration::from_secs(seconds);
+            }
+            _ => rest.push(arg),
+        }
+    }
+    rest.extend(command_line);
+    Ok((options, rest))
+}
+
+/// Splits the positional arguments, plus the lines of `stdin` with `-stdin`, into the method and its parameters
+fn request_params<R: BufRead>(
+    options: &ClientOptions,
+    mut positional: Vec<String>,
+    stdin: R,
+) -> Result<(String, Value), Failure> {
+    if options.stdin {
+        for line in stdin.lines() {
+            let line = line.map_err(|e| Failure::Client(format!("could not read stdin: {}", e)))?;
+            positional.push(line);
+        }
+    }
+    let (method, params) = match positional.split_first() {
+        Some((method, params)) => (method.clone(), params),
+        None => return Err(Failure::Client(String::from("too few parameters"))),
+    };
+    let params = if options.named {
+        named_params(&method, params)?
+    } else {
+        Value::Array(
+            params
+                .iter()
+                .enumerate()
+                .map(|(index, param)| convert_param(&method, index, None, param))
+                .collect::<Result<_, _>>()?,
+        )
+    };
+    Ok((method, params))
+}
+
+/// Builds an object of named parameters from `name=value` arguments
+fn named_params(method: &str, params: &[String]) -> Result<Value, Failure> {
+    let mut named = serde_json::Map::new();
+    for param in params {
+        let (name, value) = param.split_once('=').ok_or_else(|| {
+            Failure::Client(format!(
+                "no '=' in named argument '{}'. Named parameters are passed as name=value",
+                param
+            ))
+        })?;
+        let value = convert_param(method, usize::MAX, Some(name), value)?;
+        named.insert(name.to_string(), value);
+    }
+    Ok(Value::Object(named))
+}
+
+/// Parses a parameter as JSON if the method takes a JSON value there, and passes it as a string otherwise
+fn convert_param(
+    method: &str,
+    index: usize,
+    name: Option<&str>,
+    param: &str,
+) -> Result<Value, Failure> {
+    let is_json = JSON_PARAMS
+        .iter()
+        .any(|(json_method, json_index, json_name)| {
+            *json_method == method && (*json_index == index || Some(*json_name) == name)
+        });
+    if !is_json {
+        return Ok(Value::String(param.to_string()));
+    }
+    serde_json::from_str(param)
+        .map_err(|_| Failure::Client(format!("Error parsing JSON: {}", param)))
+}
+
+/// Sends `request` to the node and returns its reply
+fn call(config: &Config, options: &ClientOptions, request: &Value) -> Result<Value, Failure> {
+    let (host, port) = match options.connect.as_deref() {
+        Some(connect) => split_host_port(connect, config.rpc_port() as u16),
+        None => (DEFAULT_RPC_CONNECT.to_string(), config.rpc_port() as u16),
+    };
+    let credentials = match config.rpc_credentials() {
+        Some((user, password)) => format!("{}:{}", user, password),
+        None => {
+            let path = config.rpc_cookie_file();
+            std::fs::read_to_string(&path)
+                .map_err(|e| {
+                    Failure::Client(format!(
+                        "Could not read the cookie file {}: {}\n\nMake sure warpd is running, or set -rpcuser \
+                         and -rpcpassword.",
+                        path.display(),
+                        e
+                    ))
+                })?
+                .trim()
+                .to_string()
+        }
+    };
+
+    let unreachable = |e: std::io::Error| {
+        Failure::Client(format!(
+            "Could not connect to the server {}:{} ({})\n\nMake sure warpd is running with -daemon, and that \
+             you are connecting to the correct RPC port.",
+            host, port, e
+        ))
+    };
+    let mut stream = TcpStream::connect((host.as_str(), port)).map_err(unreachable)?;
+    stream
+        .set_read_timeout(Some(options.timeout))
+        .map_err(unreachable)?;
+    let body = request.to_string();
+    let head = format!(
+        "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        host,
+        base64_encode(credentials.as_bytes()),
+        body.len()
+    );
+    let mut response = Vec::new();
+    stream
+        .write_all(head.as_bytes())
+        .and_then(|_| stream.write_all(body.as_bytes()))
+        .and_then(|_| stream.read_to_end(&mut response))
+        .map_err(|e| Failure::Client(format!("Could not talk to the server: {}", e)))?;
+
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(|| Failure::Client(String::from("no response from server")))?;
+    let status = std::str::from_utf8(&response[..split])
+        .ok()
+        .and_then(|head| head.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| Failure::Client(String::from("malformed response from server")))?;
+    let body = &response[split + 4..];
+    match status {
+        401 => Err(Failure::Client(String::from(
+            "Authorization failed: Incorrect rpcuser or rpcpassword",
+        ))),
+        // Errors from legacy requests come with these statuses, but the body still holds the reply
+        200 | 400 | 404 | 500 => serde_json::from_slice(body)
+            .map_err(|_| Failure::Client(String::from("couldn't parse reply from server"))),
+        _ => Err(Failure::Client(format!(
+            "server returned HTTP error {}",
+            status
+        ))),
+    }
+}
+
+/// Splits `host:port` or `[ipv6]:port`, using `default_port` if there's no port
+fn split_host_port(address: &str, default_port: u16) -> (String, u16) {
+    if let Ok(addr) = address.parse::<std::net::SocketAddr>() {
+        return (addr.ip().to_string(), addr.port());
+    }
+    if let Some((host, port)) = address.rsplit_once(':') {
+        if let Ok(port) = port.parse() {
+            if !host.contains(':') {
+                return (host.to_string(), port);
+            }
+        }
+    }
+    let host = address.trim_start_matches('[').trim_end_matches(']');
+    (host.to_string(), default_port)
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .fold(0u32, |bits, &byte| (bits << 8) | byte as u32)
+            << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn options(args: &[&str]) -> (ClientOptions, Vec<String>) {
+        split_client_options(strings(args).into_iter()).unwrap()
+    }
+
+    #[test]
+    fn client_options_are_taken_from_the_front() {
+        let (options, rest) = options(&[
+            "-named",
+            "-regtest",
+            "-rpcconnect=10.0.0.1:8332",
+            "-rpcclienttimeout=5",
+            "getblock",
+            "-stdin",
+        ]);
+        assert!(options.named);
+        assert!(!options.stdin);
+        assert_eq!(options.connect.as_deref(), Some("10.0.0.1:8332"));
+        assert_eq!(options.timeout, Duration::from_secs(5));
+        // Options warpd understands are left for Args, and nothing after the method is an option
+        assert_eq!(rest, strings(&["-regtest", "getblock", "-stdin"]));
+
+        assert!(split_client_options(strings(&["-named=2"]).into_iter()).is_err());
+        assert!(split_client_options(strings(&["-rpcclienttimeout=soon"]).into_iter()).is_err());
+    }
+
+    #[test]
+    fn positional_params_are_converted_by_position() {
+        let (options, _) = options(&[]);
+        let (method, params) =
+            request_params(&options, strings(&["getblock", "00ff", "2"]), &b""[..]).unwrap();
+        assert_eq!(method, "getblock");
+        assert_eq!(params, json!(["00ff", 2]));
+
+        // A string where JSON is expected is an error rather than being sent as a string
+        assert!(request_params(&options, strings(&["getblockhash", "tip"]), &b""[..]).is_err());
+        assert!(request_params(&options, Vec::new(), &b""[..]).is_err());
+    }
+
+    #[test]
+    fn named_params_are_converted_by_name() {
+        let (options, _) = options(&["-named"]);
+        let (_, params) = request_params(
+            &options,
+            strings(&["getrawmempool", "mempool_sequence=true", "verbose=false"]),
+            &b""[..],
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            json!({ "verbose": false, "mempool_sequence": true })
+        );
+
+        let (_, params) = request_params(
+            &options,
+            strings(&["sendrawtransaction", "hexstring=0102"]),
+            &b""[..],
+        )
+        .unwrap();
+        assert_eq!(params, json!({ "hexstring": "0102" }));
+
+        assert!(request_params(&options, strings(&["getblock", "00ff"]), &b""[..]).is_err());
+    }
+
+    #[test]
+    fn stdin_params_follow_the_command_line() {
+        let (options, _) = options(&["-stdin"]);
+        let (method, params) =
+            request_params(&options, strings(&["getblock"]), &b"00ff\n0\n"[..]).unwrap();
+        assert_eq!(method, "getblock");
+        assert_eq!(params, json!(["00ff", 0]));
+
+        // The method itself can come from stdin too
+        let (method, params) =
+            request_params(&options, Vec::new(), &b"getblockcount\n"[..]).unwrap();
+        assert_eq!(method, "getblockcount");
+        assert_eq!(params, json!([]));
+
+        // Without -stdin, standard input is ignored
+        let (without_stdin, _) = split_client_options(Vec::new().into_iter()).unwrap();
+        let (_, params) =
+            request_params(&without_stdin, strings(&["getblock"]), &b"00ff\n"[..]).unwrap();
+        assert_eq!(params, json!([]));
+    }
+
+    #[test]
+    fn splits_hosts_and_ports() {
+        assert_eq!(
+            split_host_port("10.0.0.1", 8332),
+            (String::from("10.0.0.1"), 8332)
+        );
+        assert_eq!(
+            split_host_port("10.0.0.1:18443", 8332),
+            (String::from("10.0.0.1"), 18443)
+        );
+        assert_eq!(
+            split_host_port("[::1]:18443", 8332),
+            (String::from("::1"), 18443)
+        );
+        assert_eq!(split_host_port("::1", 8332), (String::from("::1"), 8332));
+        assert_eq!(
+            split_host_port("node.local", 8332),
+            (String::from("node.local"), 8332)
+        );
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"user:pass"), "dXNlcjpwYXNz");
+    }
+}
@@ -0,0 +1,1152 @@
+//! Running warpd as a service, without the interactive shell (`warpd -daemon`).
+//!
+//! The node's work is split between long running tasks, each of which is supervised: when a task returns an error
+//! or panics, the failure is logged and the task is restarted after a delay which doubles with every failure in a
+//! row. A task which keeps failing is reported, and the daemon shuts down rather than carrying on without it.
+//!
+//! - `connections` keeps [`OUTBOUND_PEERS`] outbound connections open with peers found by the networking crate's
+//!   crawler, which tries the peers saved by the last run before the network's DNS seeds.
+//! - `listener` accepts inbound connections on the Core and Warp ports.
+//! - `sync` downloads the blocks peers announce and asks peers which are ahead of us for more. See [`crate::sync`].
+//! - `relay` announces mempool transactions to peers.
+//! - `storage` catches the indexes up with the block store, and flushes state to the data directory.
+//! - `rpc` serves the JSON-RPC interface, unless it's disabled with `-server=0`. See [`crate::rpc`].
+//! - `zmq` publishes notifications of new blocks and transactions, if any `-zmqpub<topic>` options are set. See
+//!   [`crate::zmq`].
+//!
+//! Every connected peer gets a task of its own, which hands the transactions it relays to the mempool and the blocks
+//! it sends to `sync`, and answers its requests for transactions, blocks and headers. The tasks keep the connected
+//! peers in [`Node`], and requests go to the peers the inventory registry says have what we want.
+//!
+//! On SIGINT or SIGTERM every task is told to stop. Once they have, or after [`SHUTDOWN_TIMEOUT`], the chainstate,
+//! peers, ban list and fee estimates are saved and the data directory is closed.
+use crate::rpc;
+use crate::sync::BlockSync;
+use crate::zmq;
+use crate::{is_warp_port, peer_limit, unix_time, Warpd};
+use config::{Config, NODE_P2P_V2};
+use futures::StreamExt;
+use networking::{
+    crawl, AddressBook, Change, EvictionCandidate, GetBlocks, InventoryHash, Message, Misbehavior,
+    Peer, PeerAddress, PeerError,
+};
+use rand::seq::SliceRandom;
+use shared::{
+    u256, Block, BlockHash, BlockHeader, InventoryData, InventoryType, Transaction, TxID,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use storage::StorageError;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, warn};
+
+/// The number of outbound connections to keep open
+const OUTBOUND_PEERS: usize = 8;
+/// How often to check for missing outbound connections
+const CONNECT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for an outbound connection and its handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to request blocks, and check for peers which stall the download
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to collect inventory announcements. The relay adds its own random delays on top.
+const RELAY_INTERVAL: Duration = Duration::from_millis(500);
+/// How often to catch the indexes up with the block store
+const INDEX_INTERVAL: Duration = Duration::from_secs(10);
+/// The number of blocks to index at a time, so that other tasks aren't locked out for long
+const INDEX_BATCH: usize = 100;
+/// How often to save the chainstate and peers, so that less is lost if the process is killed
+const FLUSH_INTERVAL: Duration = Duration::from_secs(600);
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// The number of failures in a row after which a task is given up on
+const MAX_FAILURES: u32 = 8;
+/// How long to wait for tasks to stop before saving state anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most headers sent in reply to one `getheaders` (Core's `MAX_HEADERS_RESULTS`)
+const MAX_HEADERS_RESULTS: usize = 2000;
+/// The most blocks announced in reply to one `getblocks`
+const MAX_BLOCKS_RESULTS: usize = 500;
+
+pub(crate) type TaskResult = Result<(), String>;
+
+/// The state shared by the daemon's tasks
+pub(crate) struct Node {
+    pub(crate) config: Config,
+    pub(crate) warpd: Mutex<Warpd>,
+    /// Channels to the tasks of the connected peers, for commands they should carry out
+    peers: std::sync::Mutex<HashMap<SocketAddr, PeerHandle>>,
+    /// Peers added with the `addnode` RPC, which are reconnected to whenever they disconnect
+    pub(crate) added_nodes: std::sync::Mutex<Vec<PeerAddress>>,
+    /// The blocks being downloaded. Locked after `warpd` when both are needed.
+    sync: std::sync::Mutex<BlockSync>,
+    next_peer_id: AtomicU64,
+    shutdown: watch::Sender<bool>,
+}
+
+struct PeerHandle {
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    info: PeerInfo,
+    /// What eviction weighs up, kept current by the peer's task
+    eviction: EvictionCandidate,
+}
+
+enum PeerCommand {
+    Send(Box<Message>),
+    Disconnect,
+}
+
+/// What the RPC interface reports about a connected peer
+#[derive(Debug, Clone)]
+pub(crate) struct PeerInfo {
+    /// A number which identifies the connection for as long as the daemon runs
+    pub(crate) id: u64,
+    pub(crate) addr: SocketAddr,
+    /// The address the peer was dialed at, which may be an onion address
+    pub(crate) address: PeerAddress,
+    pub(crate) local_addr: SocketAddr,
+    pub(crate) inbound: bool,
+    pub(crate) warp: bool,
+    /// Whether the peer was added with `addnode`
+    pub(crate) manual: bool,
+    pub(crate) v2: bool,
+    pub(crate) session_id: Option<[u8; 32]>,
+    pub(crate) services: u64,
+    pub(crate) version: u32,
+    pub(crate) user_agent: String,
+    pub(crate) start_height: u32,
+    pub(crate) relay_txs: bool,
+    /// The unix time the connection was made
+    pub(crate) connected: u64,
+    pub(crate) min_ping: Option<Duration>,
+}
+
+impl Node {
+    /// A node with no peers yet, running `warpd`
+    pub(crate) fn new(config: Config, warpd: Warpd) -> Node {
+        let (shutdown, _) = watch::channel(false);
+        Node {
+            config,
+            warpd: Mutex::new(warpd),
+            peers: std::sync::Mutex::new(HashMap::new()),
+            added_nodes: std::sync::Mutex::new(Vec::new()),
+            sync: std::sync::Mutex::new(BlockSync::new()),
+            next_peer_id: AtomicU64::new(0),
+            shutdown,
+        }
+    }
+
+    pub(crate) fn request_shutdown(&self) {
+        let _ = self.shutdown.send(true);
+    }
+
+    pub(crate) fn shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once shutdown has been requested
+    pub(crate) fn stopped(&self) -> impl Future<Output = ()> {
+        let mut shutdown = self.shutdown.subscribe();
+        async move {
+            let _ = shutdown.wait_for(|stop| *stop).await;
+        }
+    }
+
+    fn num_peers(&self, inbound: bool, warp: bool) -> usize {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| {
+                peer.info.inbound == inbound && peer.info.warp == warp && !peer.info.manual
+            })
+            .count()
+    }
+
+    fn num_connections(&self, warp: bool) -> usize {
+        self.num_peers(true, warp) + self.num_peers(false, warp)
+    }
+
+    /// The inbound peers which could be evicted to make room for a new Core or Warp connection
+    fn eviction_candidates(&self, warp: bool) -> Vec<EvictionCandidate> {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|peer| peer.info.inbound && peer.info.warp == warp && !peer.info.manual)
+            .map(|peer| peer.eviction.clone())
+            .collect()
+    }
+
+    fn is_connected(&self, peer: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().contains_key(peer)
+    }
+
+    fn send(&self, peer: &SocketAddr, msg: Message) {
+        if let Some(handle) = self.peers.lock().unwrap().get(peer) {
+            let _ = handle.commands.send(PeerCommand::Send(Box::new(msg)));
+        }
+    }
+
+    /// The connected peers, ordered by id
+    pub(crate) fn peer_info(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| handle.info.clone())
+            .collect();
+        peers.sort_by_key(|info| info.id);
+        peers
+    }
+
+    /// Disconnects every peer matching `filter`, returning how many there were
+    pub(crate) fn disconnect<F: Fn(&PeerInfo) -> bool>(&self, filter: F) -> usize {
+        let peers = self.peers.lock().unwrap();
+        let matching = peers.values().filter(|handle| filter(&handle.info));
+        let mut count = 0;
+        for handle in matching {
+            let _ = handle.commands.send(PeerCommand::Disconnect);
+            count += 1;
+        }
+        count
+    }
+}
+
+/// Runs the node until it receives SIGINT or SIGTERM, then saves its state and returns.
+pub async fn run_daemon(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let mut warpd = Warpd::with_config(config.clone());
+    warpd.open_data_dir()?;
+    // Changes to the chain and mempool are only collected if something publishes them
+    let notifications = if config.zmq_notifications().is_empty() {
+        None
+    } else {
+        let (notifier, notifications) = mpsc::unbounded_channel();
+        warpd.notify(notifier);
+        Some(Arc::new(Mutex::new(notifications)))
+    };
+    let node = Arc::new(Node::new(config, warpd));
+    info!("Running as a daemon on {}", node.config.network());
+
+    let mut tasks = vec![
+        supervise("connections", node.clone(), maintain_connections),
+        supervise("listener", node.clone(), listen),
+        supervise("sync", node.clone(), sync_blocks),
+        supervise("relay", node.clone(), relay),
+        supervise("storage", node.clone(), maintain_storage),
+    ];
+    let auth = if node.config.rpc_server() {
+        let auth = Arc::new(rpc::Auth::new(&node.config)?);
+        let server_auth = auth.clone();
+        tasks.push(supervise("rpc", node.clone(), move |node| {
+            rpc::serve(node, server_auth.clone())
+        }));
+        Some(auth)
+    } else {
+        None
+    };
+    if let Some(notifications) = notifications {
+        tasks.push(supervise("zmq", node.clone(), move |node| {
+            zmq::publish(node, notifications.clone())
+        }));
+    }
+    tokio::select! {
+        signal = shutdown_signal() => match signal {
+            Ok(name) => info!("Received {}, shutting down", name),
+            Err(e) => {
+                error!("Could not listen for signals: {}", e);
+                node.request_shutdown();
+            }
+        },
+        _ = node.stopped() => {}
+    }
+    node.request_shutdown();
+
+    let stopped = async {
+        for task in tasks {
+            let _ = task.await;
+        }
+        // Peer tasks hold no lock while waiting, so once they see the shutdown they deregister promptly
+        while !node.peers.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, stopped)
+        .await
+        .is_err()
+    {
+        warn!("Tasks did not stop within {:?}", SHUTDOWN_TIMEOUT);
+    }
+    if let Some(auth) = auth {
+        auth.remove_cookie();
+    }
+    node.warpd.lock().await.shutdown()?;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+/// Waits for SIGINT, or SIGTERM on Unix, returning the name of the signal
+async fn shutdown_signal() -> std::io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.map(|_| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.map(|_| "Ctrl-C")
+    }
+}
+
+/// Runs `task` until shutdown, restarting it whenever it fails or panics
+fn supervise<F, T>(name: &'static str, node: Arc<Node>, task: F) -> JoinHandle<()>
+where
+    F: Fn(Arc<Node>) -> T + Send + 'static,
+    T: Future<Output = TaskResult> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut failures = 0;
+        let mut delay = MIN_RESTART_DELAY;
+        loop {
+            let started = Instant::now();
+            // Spawning the task catches its panics, and turns them into errors
+            let error = match tokio::spawn(task(node.clone())).await {
+                Ok(Ok(())) if node.shutting_down() => return,
+                Ok(Ok(())) => String::from("stopped unexpectedly"),
+                Ok(Err(e)) => e,
+                Err(e) => e.to_string(),
+            };
+            if node.shutting_down() {
+                warn!("Task {} failed while shutting down: {}", name, error);
+                return;
+            }
+            // A task which ran for a while before failing gets a fresh start
+            if started.elapsed() > MAX_RESTART_DELAY {
+                failures = 0;
+                delay = MIN_RESTART_DELAY;
+            }
+            failures += 1;
+            if failures >= MAX_FAILURES {
+                error!(
+                    "Task {} failed {} times in a row, last with: {}. Shutting down",
+                    name, failures, error
+                );
+                node.request_shutdown();
+                return;
+            }
+            error!("Task {} failed: {}. Restarting in {:?}", name, error, delay);
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = node.stopped() => return,
+            }
+            delay = std::cmp::min(delay * 2, MAX_RESTART_DELAY);
+        }
+    })
+}
+
+/// Keeps [`OUTBOUND_PEERS`] outbound connections open with peers found by the networking crate's crawler, and
+/// reconnects to peers added with `addnode`
+async fn maintain_connections(node: Arc<Node>) -> TaskResult {
+    let saved = node.warpd.lock().await.saved_peers().to_vec();
+    let (mut needs_peers_tx, needs_peers_rx) = futures::channel::mpsc::channel(OUTBOUND_PEERS);
+    let (discovered_peers_tx, mut discovered_peers_rx) =
+        futures::channel::mpsc::channel(OUTBOUND_PEERS);
+    let crawler = crawl(
+        needs_peers_rx,
+        discovered_peers_tx,
+        CONNECT_INTERVAL,
+        AddressBook::new(saved),
+        node.config.clone(),
+    );
+    tokio::pin!(crawler);
+    let target = std::cmp::min(OUTBOUND_PEERS, node.config.max_core_peers());
+    let mut handshakes = JoinSet::new();
+    // Peers we asked the crawler for which it hasn't found yet
+    let mut requested = 0;
+    let mut timer = tokio::time::interval(CONNECT_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {
+                let added = node.added_nodes.lock().unwrap().clone();
+                for address in added {
+                    if !node.is_connected(&address.socket_addr()) {
+                        tokio::spawn(open_connection(node.clone(), address, true));
+                    }
+                }
+                let open = node.num_peers(false, false) + handshakes.len() + requested;
+                for _ in open..target {
+                    if needs_peers_tx.try_send(()).is_ok() {
+                        requested += 1;
+                    }
+                }
+            }
+            Some(discovered) = discovered_peers_rx.next() => match discovered {
+                Ok(Change::Insert(addr, peer)) => {
+                    requested -= 1;
+                    let banned = node
+                        .warpd
+                        .lock()
+                        .await
+                        .ban_list()
+                        .should_refuse(&addr.ip(), unix_time());
+                    if !banned && !node.is_connected(&addr) {
+                        handshakes.spawn(handshake(node.clone(), peer));
+                    }
+                }
+                // The crawler retries failed candidates by itself
+                Ok(Change::Remove(_)) | Err(_) => {}
+            },
+            Some(handshake) = handshakes.join_next() => match handshake {
+                Ok(Ok(peer)) => {
+                    let address = peer.get_address().clone();
+                    tokio::spawn(run_peer(node.clone(), peer, Some(address), false));
+                }
+                Ok(Err(e)) => debug!("{}", e),
+                Err(e) => warn!("Handshake task failed: {}", e),
+            },
+            result = &mut crawler => {
+                return Err(match result {
+                    Ok(()) => String::from("the crawler stopped"),
+                    Err(e) => format!("the crawler failed: {}", e),
+                });
+            }
+            _ = node.stopped() => return Ok(()),
+        }
+    }
+}
+
+/// Performs the version handshake with a peer the crawler connected to
+async fn handshake(node: Arc<Node>, mut peer: Peer) -> Result<Peer, String> {
+    let height = node.warpd.lock().await.chainstate().height();
+    match tokio::time::timeout(CONNECT_TIMEOUT, peer.perform_handshake(Some(height))).await {
+        Ok(Ok(())) => Ok(peer),
+        Ok(Err(e)) => Err(format!(
+            "Handshake with {} failed: {}",
+            peer.get_address(),
+            e
+        )),
+        Err(_) => Err(format!("Handshake with {} timed out", peer.get_address())),
+    }
+}
+
+/// Connects to `address` and, once the handshake is done, hands the peer to a task of its own. `manual` peers
+/// were asked for with `addnode`, and don't take up one of the [`OUTBOUND_PEERS`] slots.
+pub(crate) async fn open_connection(
+    node: Arc<Node>,
+    address: PeerAddress,
+    manual: bool,
+) -> Result<(), String> {
+    // We don't know which services automatic candidates offer, but like Core we try v2 with peers the user asked for
+    let services = if manual { NODE_P2P_V2 } else { 0 };
+    let connect = async {
+        let mut peer = Peer::at_address(address.clone(), services, node.config.clone()).await?;
+        let height = node.warpd.lock().await.chainstate().height();
+        peer.perform_handshake(Some(height)).await?;
+        Ok::<Peer, PeerError>(peer)
+    };
+    match tokio::time::timeout(CONNECT_TIMEOUT, connect).await {
+        Ok(Ok(peer)) => {
+            tokio::spawn(run_peer(node, peer, Some(address), manual));
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(String::from("timed out")),
+    }
+}
+
+/// Accepts inbound connections on the Core port, and the Warp port if it's different
+async fn listen(node: Arc<Node>) -> TaskResult {
+    let ip = node.config.ip_address();
+    let bind = |port: usize| {
+        let addr = SocketAddr::new(ip, port as u16);
+        async move {
+            TcpListener::bind(addr)
+                .await
+                .map_err(|e| format!("could not listen on {}: {}", addr, e))
+        }
+    };
+    let core = bind(node.config.core_port()).await?;
+    let warp = if node.config.warp_port() != node.config.core_port() {
+        Some(bind(node.config.warp_port()).await?)
+    } else {
+        None
+    };
+    info!(
+        "Listening for peers on {}",
+        core.local_addr().map_err(|e| e.to_string())?
+    );
+    loop {
+        let accept_warp = async {
+            match warp.as_ref() {
+                Some(listener) => listener.accept().await,
+                None => std::future::pending().await,
+            }
+        };
+        let (connection, remote) = tokio::select! {
+            accepted = core.accept() => accepted,
+            accepted = accept_warp => accepted,
+            _ = node.stopped() => return Ok(()),
+        }
+        .map_err(|e| e.to_string())?;
+        let port = connection.local_addr().map_err(|e| e.to_string())?.port();
+        let warp = is_warp_port(&node.config, port as usize);
+        if node
+            .warpd
+            .lock()
+            .await
+            .ban_list()
+            .should_refuse(&remote.ip(), unix_time())
+        {
+            debug!("Refusing connection from banned address {}", remote);
+            continue;
+        }
+        if node.num_connections(warp) >= peer_limit(&node.config, warp) {
+            let candidates = node.eviction_candidates(warp);
+            let evicted = node.warpd.lock().await.select_peer_to_evict(candidates);
+            match evicted {
+                Some(evicted) => {
+                    info!("Evicting {} to make room for {}", evicted, remote);
+                    node.disconnect(|peer| peer.addr == evicted);
+                }
+                None => {
+                    debug!("Refusing connection from {}: no free slots", remote);
+                    continue;
+                }
+            }
+        }
+        tokio::spawn(accept(node.clone(), connection, warp));
+    }
+}
+
+async fn accept(node: Arc<Node>, connection: TcpStream, warp: bool) {
+    let result = async {
+        let mut peer = Peer::from_connection(0, connection, node.config.clone()).await?;
+        peer.set_warp(warp);
+        let height = node.warpd.lock().await.chainstate().height();
+        peer.accept_handshake(Some(height)).await?;
+        Ok::<Peer, PeerError>(peer)
+    };
+    match result.await {
+        Ok(peer) => run_peer(node, peer, None, false).await,
+        Err(e) => debug!("Inbound handshake failed: {}", e),
+    }
+}
+
+/// Requests announced blocks from the peers which have them, and asks a peer which is ahead of us for more
+/// announcements once there's nothing left to download. See [`crate::sync`].
+async fn sync_blocks(node: Arc<Node>) -> TaskResult {
+    let mut timer = tokio::time::interval(SYNC_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {}
+            _ = node.stopped() => return Ok(()),
+        }
+        let now = Instant::now();
+        let stalled = node.sync.lock().unwrap().stalled_peers(now);
+        for peer in stalled {
+            info!("Disconnecting {}: block download stalled", peer);
+            node.disconnect(|info| info.addr == peer);
+        }
+        let connected: Vec<PeerInfo> = node.peer_info();
+        let warpd = node.warpd.lock().await;
+        let mut sync = node.sync.lock().unwrap();
+        sync.discard_unconnectable(&chain_tips(&warpd));
+        let requests = sync.next_requests(now, |hash| {
+            warpd.peer_with_inventory(&[InventoryHash::Block(hash.clone())], |peer| {
+                connected.iter().any(|info| info.addr == *peer)
+            })
+        });
+        for (peer, hashes) in requests {
+            // Scripts and witness commitments aren't checked, so blocks are asked for without their witnesses
+            let items = hashes
+                .into_iter()
+                .map(|hash| {
+                    InventoryData::from(InventoryType::Block, u256::from_bytes(*hash.inner()))
+                })
+                .collect();
+            node.send(&peer, Message::GetData(items));
+        }
+        if !sync.wants_blocks(now) {
+            continue;
+        }
+        // The active chain comes first. Once no peer is ahead of it, a snapshot's background chain catches up.
+        let active = warpd.chainstate().height();
+        let background = warpd
+            .background_validation()
+            .map(|validation| validation.chainstate().height());
+        let height = match background {
+            Some(height) if connected.iter().all(|info| info.start_height <= active) => height,
+            _ => active,
+        };
+        let ahead: Vec<&PeerInfo> = connected
+            .iter()
+            .filter(|info| info.start_height > height)
+            .collect();
+        if let Some(info) = ahead.choose(&mut rand::thread_rng()) {
+            let locator = match warpd.background_block_locator() {
+                Some(locator) if height != active => locator,
+                _ => warpd.block_locator(),
+            };
+            node.send(
+                &info.addr,
+                Message::GetBlocks(GetBlocks::new(locator, true, &node.config)),
+            );
+            sync.locator_sent(info.addr, now);
+        }
+    }
+}
+
+/// The tip of the active chain, and of the background chain if a snapshot is being validated
+fn chain_tips(warpd: &Warpd) -> Vec<&BlockHash> {
+    let background = warpd
+        .background_validation()
+        .map(|validation| validation.chainstate().tip());
+    std::iter::once(warpd.chainstate().tip())
+        .chain(background)
+        .collect()
+}
+
+/// Connects the downloaded blocks which build on the tip of a chain, one after another
+async fn connect_blocks(node: &Node) {
+    let mut warpd = node.warpd.lock().await;
+    loop {
+        let next = node.sync.lock().unwrap().take_child(&chain_tips(&warpd));
+        let (sender, block) = match next {
+            Some(next) => next,
+            None => return,
+        };
+        match warpd.connect_block(&block) {
+            Ok(()) => debug!(
+                "Connected block {:?} at height {}",
+                block.header().hash(),
+                warpd.chainstate().height()
+            ),
+            Err(e @ StorageError::InvalidBlock(_)) | Err(e @ StorageError::MissingCoin(_)) => {
+                warn!(
+                    "Invalid block {:?} from {}: {}",
+                    block.header().hash(),
+                    sender,
+                    e
+                );
+                if warpd.misbehaving(sender, Misbehavior::InvalidBlock) {
+                    node.disconnect(|info| info.addr == sender);
+                }
+            }
+            Err(e) => {
+                error!("Could not connect block {:?}: {}", block.header().hash(), e);
+                return;
+            }
+        }
+    }
+}
+
+/// Sends due inventory announcements to peers
+async fn relay(node: Arc<Node>) -> TaskResult {
+    let mut timer = tokio::time::interval(RELAY_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = timer.tick() => {}
+            _ = node.stopped() => return Ok(()),
+        }
+        let announcements = node.warpd.lock().await.poll_relay(Instant::now());
+        for (peer, msg) in announcements {
+            node.send(&peer, msg);
+        }
+    }
+}
+
+/// Catches the indexes up with the block store, and flushes state to disk every [`FLUSH_INTERVAL`]
+async fn maintain_storage(node: Arc<Node>) -> TaskResult {
+    let mut index_timer = tokio::time::interval(INDEX_INTERVAL);
+    let mut flush_timer = tokio::time::interval(FLUSH_INTERVAL);
+    // Both timers fire straight away, and there's nothing to flush yet
+    flush_timer.tick().await;
+    loop {
+        tokio::select! {
+            _ = index_timer.tick() => loop {
+                let synced = node
+                    .warpd
+                    .lock()
+                    .await
+                    .sync_indexes(INDEX_BATCH)
+                    .map_err(|e| format!("could not update indexes: {}", e))?;
+                if synced || node.shutting_down() {
+                    break;
+                }
+            },
+            _ = flush_timer.tick() => {
+                node.warpd
+                    .lock()
+                    .await
+                    .flush()
+                    .map_err(|e| format!("could not save state: {}", e))?;
+                debug!("Saved state to the data directory");
+            }
+            _ = node.stopped() => return Ok(()),
+        }
+    }
+}
+
+/// Exchanges messages with a connected peer until it disconnects or the daemon shuts down. `dialed` is the address
+/// we connected to, for outbound peers.
+async fn run_peer(node: Arc<Node>, mut peer: Peer, dialed: Option<PeerAddress>, manual: bool) {
+    let addr = peer.get_ip_address();
+    let (commands, mut received_commands) = mpsc::unbounded_channel();
+    {
+        let mut peers = node.peers.lock().unwrap();
+        if peers.contains_key(&addr) || node.shutting_down() {
+            return;
+        }
+        let info = PeerInfo {
+            id: node.next_peer_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            address: peer.get_address().clone(),
+            local_addr: peer.get_daemon_address(),
+            inbound: peer.is_inbound(),
+            warp: peer.is_warp(),
+            manual,
+            v2: peer.is_v2(),
+            session_id: peer.session_id().cloned(),
+            services: peer.services(),
+            version: peer.protocol_version(),
+            user_agent: String::from(peer.user_agent()),
+            start_height: peer.get_best_block(),
+            relay_txs: peer.relay_txs(),
+            connected: unix_time(),
+            min_ping: peer.min_ping(),
+        };
+        let eviction = peer.eviction_candidate();
+        peers.insert(
+            addr,
+            PeerHandle {
+                commands,
+                info,
+                eviction,
+            },
+        );
+    }
+    {
+        let mut warpd = node.warpd.lock().await;
+        warpd.peer_connected(
+            addr,
+            peer.is_inbound(),
+            peer.relay_txs(),
+            peer.wtxid_relay(),
+        );
+        if let Some(address) = dialed {
+            warpd.remember_outbound_peer(addr, address);
+        }
+    }
+    info!("Connected to {}", addr);
+
+    let stopped = node.stopped();
+    tokio::pin!(stopped);
+    let result = loop {
+        let result = tokio::select! {
+            received = peer.receive(None) => match received {
+                Ok(msg) => handle_message(&node, &mut peer, msg).await,
+                Err(e) => Err(e),
+            },
+            Some(command) = received_commands.recv() => match command {
+                PeerCommand::Send(msg) => peer.send(*msg).await,
+                PeerCommand::Disconnect => break Ok(()),
+            },
+            _ = &mut stopped => break Ok(()),
+        };
+        if let Err(e) = result {
+            // Misbehavior short of the discouragement threshold only costs the peer points
+            let discouraged = match Misbehavior::from_error(&e) {
+                Some(misbehavior) => node.warpd.lock().await.misbehaving(addr, misbehavior),
+                None => break Err(e),
+            };
+            if discouraged {
+                break Err(e);
+            }
+            debug!("Ignoring message from {}: {}", addr, e);
+        }
+        if let Some(handle) = node.peers.lock().unwrap().get_mut(&addr) {
+            handle.info.min_ping = peer.min_ping();
+            handle.info.relay_txs = peer.relay_txs();
+            handle.eviction = peer.eviction_candidate();
+        }
+    };
+    match result {
+        Ok(()) => info!("Disconnected from {}", addr),
+        Err(e) => info!("Disconnected from {}: {}", addr, e),
+    }
+    node.peers.lock().unwrap().remove(&addr);
+    node.warpd.lock().await.peer_disconnected(&addr);
+    node.sync.lock().unwrap().peer_disconnected(&addr);
+}
+
+async fn handle_message(node: &Node, peer: &mut Peer, msg: Message) -> Result<(), PeerError> {
+    let addr = peer.get_ip_address();
+    match msg {
+        Message::Tx(tx) => {
+            let txid = tx.txid().clone();
+            let result = node.warpd.lock().await.process_transaction(tx, addr);
+            match result {
+                Ok(processed) => {
+                    if !processed.accepted.is_empty() {
+                        peer.record_tx_relay(Instant::now());
+                    }
+                    if let Some(get_parents) = processed.get_parents {
+                        peer.send(get_parents).await?;
+                    }
+                }
+                Err(e) => debug!("Rejected transaction {:?} from {}: {}", txid, addr, e),
+            }
+        }
+        Message::FeeFilter(fee_rate) => {
+            node.warpd
+                .lock()
+                .await
+                .relay_mut()
+                .set_fee_filter(&addr, fee_rate);
+        }
+        Message::Inv(inventory) => {
+            let requests = {
+                let mut warpd = node.warpd.lock().await;
+                warpd.inventory_announced(addr, &inventory, Instant::now());
+                let blocks = inventory
+                    .iter()
+                    .filter(|item| {
+                        matches!(
+                            item.inventory_type,
+                            InventoryType::Block | InventoryType::WitnessBlock
+                        )
+                    })
+                    .map(|item| block_hash(&item.hash))
+                    .collect();
+                node.sync
+                    .lock()
+                    .unwrap()
+                    .blocks_announced(addr, blocks, |hash| warpd.has_block(hash));
+                let wanted = inventory
+                    .into_iter()
+                    .filter(|item| wants_transaction(&warpd, item))
+                    .collect();
+                transaction_requests(node, &warpd, wanted, Some(addr))
+            };
+            for (target, items) in requests {
+                if target == addr {
+                    peer.send(Message::GetData(items)).await?;
+                } else {
+                    node.send(&target, Message::GetData(items));
+                }
+            }
+        }
+        Message::GetData(inventory) => {
+            let mut found = Vec::new();
+            let mut not_found = Vec::new();
+            {
+                let warpd = node.warpd.lock().await;
+                for item in inventory {
+                    let id = inventory_id(&item.hash);
+                    // Peers using wtxid relay ask for transactions by wtxid, and get them with their witnesses.
+                    // Otherwise they're sent without, as BIP144 has it for MSG_TX. The same goes for blocks.
+                    let msg = match item.inventory_type {
+                        InventoryType::Tx => warpd
+                            .mempool()
+                            .get(&id)
+                            .map(|entry| Message::Tx(entry.tx().without_witness())),
+                        InventoryType::WitnessTx => warpd
+                            .mempool()
+                            .get_by_wtxid(&id)
+                            .map(|entry| Message::Tx(entry.tx().clone())),
+                        InventoryType::Block => {
+                            stored_block(&warpd, &block_hash(&item.hash), false).map(Message::Block)
+                        }
+                        InventoryType::WitnessBlock => {
+                            stored_block(&warpd, &block_hash(&item.hash), true).map(Message::Block)
+                        }
+                        _ => None,
+                    };
+                    match msg {
+                        Some(msg) => found.push(msg),
+                        None => not_found.push(item),
+                    }
+                }
+            }
+            for msg in found {
+                peer.send(msg).await?;
+            }
+            if !not_found.is_empty() {
+                peer.send(Message::NotFound(not_found)).await?;
+            }
+        }
+        Message::GetHeaders(request) => {
+            let headers = {
+                let warpd = node.warpd.lock().await;
+                headers_after(&warpd, request.locator(), request.stop_hash())
+            };
+            peer.send(Message::Headers(headers)).await?;
+        }
+        Message::GetBlocks(request) => {
+            let inventory = {
+                let warpd = node.warpd.lock().await;
+                blocks_after(&warpd, request.locator(), request.stop_hash())
+            };
+            if !inventory.is_empty() {
+                peer.send(Message::Inv(inventory)).await?;
+            }
+        }
+        Message::Block(block) => {
+            let hash = block.header().hash().clone();
+            if !node.sync.lock().unwrap().block_received(addr, block) {
+                debug!("Ignoring unrequested block {:?} from {}", hash, addr);
+                return Ok(());
+            }
+            peer.record_block_relay(Instant::now());
+            connect_blocks(node).await;
+        }
+        Message::NotFound(inventory) => {
+            let blocks: Vec<BlockHash> = inventory
+                .iter()
+                .filter(|item| matches!(item.inventory_type, InventoryType::Block))
+                .map(|item| block_hash(&item.hash))
+                .collect();
+            node.sync.lock().unwrap().not_found(addr, &blocks);
+            // Transactions are asked for again from other peers which announced them, if there are any
+            let requests = {
+                let mut warpd = node.warpd.lock().await;
+                warpd.inventory_not_found(&addr, &inventory);
+                let wanted = inventory
+                    .into_iter()
+                    .filter(|item| wants_transaction(&warpd, item))
+                    .collect();
+                transaction_requests(node, &warpd, wanted, None)
+            };
+            for (target, items) in requests {
+                node.send(&target, Message::GetData(items));
+            }
+        }
+        msg => debug!("Ignoring {:?} from {}", msg.command(), addr),
+    }
+    Ok(())
+}
+
+/// Whether `item` is a transaction we don't have yet
+fn wants_transaction(warpd: &Warpd, item: &InventoryData) -> bool {
+    match item.inventory_type {
+        InventoryType::Tx => {
+            let id = inventory_id(&item.hash);
+            !warpd.mempool().contains(&id) && !warpd.orphans().contains(&id)
+        }
+        InventoryType::WitnessTx => !warpd.mempool().contains_wtxid(&inventory_id(&item.hash)),
+        _ => false,
+    }
+}
+
+/// Splits requests for transactions between the connected peers which the inventory registry says have them.
+/// Those which no connected peer is known to have go to `fallback`, or are dropped if there is none.
+fn transaction_requests(
+    node: &Node,
+    warpd: &Warpd,
+    items: Vec<InventoryData>,
+    fallback: Option<SocketAddr>,
+) -> HashMap<SocketAddr, Vec<InventoryData>> {
+    let mut requests: HashMap<SocketAddr, Vec<InventoryData>> = HashMap::new();
+    for item in items {
+        let target = warpd
+            .peer_with_inventory(&[InventoryHash::from(&item)], |peer| {
+                node.is_connected(peer)
+            })
+            .or(fallback);
+        if let Some(target) = target {
+            requests.entry(target).or_default().push(item);
+        }
+    }
+    requests
+}
+
+/// A block from the block store, with or without its witnesses. `None` if it's unknown or has been pruned.
+fn stored_block(warpd: &Warpd, hash: &BlockHash, witness: bool) -> Option<Block> {
+    let block = match warpd.block_store()?.read_block(hash) {
+        Ok(block) => block?,
+        Err(e) => {
+            warn!("Couldn't read block {:?} for a peer: {}", hash, e);
+            return None;
+        }
+    };
+    if witness {
+        return Some(block);
+    }
+    let txs = block
+        .transactions()
+        .iter()
+        .map(Transaction::without_witness)
+        .collect();
+    Some(Block::new(block.header().clone(), txs))
+}
+
+/// The headers of the active chain after the fork point of a peer's locator, up to and including `stop`. An empty
+/// locator asks for the header of `stop` alone.
+fn headers_after(warpd: &Warpd, locator: &[BlockHash], stop: &BlockHash) -> Vec<BlockHeader> {
+    let hashes = if locator.is_empty() {
+        vec![stop.clone()]
+    } else {
+        let hashes = warpd.block_hashes(warpd.locator_fork(locator) + 1, MAX_HEADERS_RESULTS);
+        let end = hashes
+            .iter()
+            .position(|hash| hash == stop)
+            .map_or(hashes.len(), |index| index + 1);
+        hashes[..end].to_vec()
+    };
+    hashes
+        .iter()
+        .filter_map(|hash| warpd.block_header(hash))
+        .map(|(header, _)| header)
+        .collect()
+}
+
+/// Announcements of the blocks of the active chain after the fork point of a peer's locator, up to but not
+/// including `stop`. Like Core, this stops early at a block which has been pruned.
+fn blocks_after(warpd: &Warpd, locator: &[BlockHash], stop: &BlockHash) -> Vec<InventoryData> {
+    let blocks = match warpd.block_store() {
+        Some(blocks) => blocks,
+        None => return Vec::new(),
+    };
+    warpd
+        .block_hashes(warpd.locator_fork(locator) + 1, MAX_BLOCKS_RESULTS)
+        .into_iter()
+        .take_while(|hash| hash != stop && blocks.has_block(hash))
+        .map(|hash| InventoryData::from(InventoryType::Block, u256::from_bytes(*hash.inner())))
+        .collect()
+}
+
+/// The txid or wtxid an inventory item refers to
+fn inventory_id(hash: &u256) -> TxID {
+    TxID::from(*hash.to_le_bytes())
+}
+
+fn block_hash(hash: &u256) -> BlockHash {
+    BlockHash::from(*hash.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{next_block, temp_dir};
+    use networking::GetHeaders;
+    use shared::{Deserializable, Serializable};
+
+    /// `block` with the BIP141 witness reserved value added to its coinbase
+    fn with_coinbase_witness(block: &Block) -> Block {
+        let mut raw = Vec::new();
+        block.transactions()[0].serialize(&mut raw).unwrap();
+        let locktime = raw.split_off(raw.len() - 4);
+        let mut witness = raw.split_off(4);
+        witness.splice(0..0, [0, 1]);
+        witness.push(1);
+        witness.push(32);
+        witness.extend_from_slice(&[0; 32]);
+        raw.extend(witness);
+        raw.extend(locktime);
+        let coinbase = Transaction::deserialize(&raw[..]).unwrap();
+        Block::new(block.header().clone(), vec![coinbase])
+    }
+
+    async fn receive(peer: &mut Peer) -> Message {
+        peer.receive(Some(Duration::from_secs(1))).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_blocks_and_headers() {
+        let dir = temp_dir("serve-blocks");
+        let mut config = Config::regtest();
+        config.set_v2_transport(false);
+        let mut warpd = Warpd::with_config(config.clone());
+        warpd.open_block_store(&dir).unwrap();
+        let mut hashes = vec![warpd.chainstate().tip().clone()];
+        for nonce in 0..5 {
+            let mut block = next_block(warpd.chainstate().tip(), nonce);
+            if nonce == 0 {
+                block = with_coinbase_witness(&block);
+            }
+            warpd.connect_block(&block).unwrap();
+            hashes.push(block.header().hash().clone());
+        }
+        let node = Node::new(config.clone(), warpd);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (peer, remote) = tokio::join!(
+            Peer::at_address(listener.local_addr().unwrap(), 0, config.clone()),
+            async {
+                let (connection, _) = listener.accept().await.unwrap();
+                Peer::from_connection(1, connection, config.clone()).await
+            }
+        );
+        let (mut peer, mut remote) = (peer.unwrap(), remote.unwrap());
+
+        // Headers follow the first locator hash on the active chain, or the genesis block if none are
+        for (locator, first) in [(&hashes[2], 3), (&BlockHash::from_u64(1), 1)] {
+            let request = GetHeaders::new(vec![locator.clone()], true, &config);
+            handle_message(&node, &mut peer, Message::GetHeaders(request))
+                .await
+                .unwrap();
+            match receive(&mut remote).await {
+                Message::Headers(headers) => {
+                    let received: Vec<&BlockHash> = headers.iter().map(|h| h.hash()).collect();
+                    assert_eq!(received, hashes[first..].iter().collect::<Vec<_>>());
+                }
+                other => panic!("Expected headers, got {:?}", other),
+            }
+        }
+
+        let request = GetBlocks::new(vec![hashes[3].clone()], true, &config);
+        handle_message(&node, &mut peer, Message::GetBlocks(request))
+            .await
+            .unwrap();
+        match receive(&mut remote).await {
+            Message::Inv(inventory) => {
+                let announced: Vec<BlockHash> = inventory
+                    .iter()
+                    .map(|item| block_hash(&item.hash))
+                    .collect();
+                assert_eq!(announced, hashes[4..]);
+            }
+            other => panic!("Expected an inv, got {:?}", other),
+        }
+
+        // Blocks are sent with witnesses only if they're asked for with them
+        let item = |inventory_type, hash: &BlockHash| {
+            InventoryData::from(inventory_type, u256::from_bytes(*hash.inner()))
+        };
+        let request = vec![
+            item(InventoryType::WitnessBlock, &hashes[1]),
+            item(InventoryType::Block, &hashes[1]),
+            item(InventoryType::Block, &BlockHash::from_u64(1)),
+        ];
+        handle_message(&node, &mut peer, Message::GetData(request))
+            .await
+            .unwrap();
+        for witness in [true, false] {
+            match receive(&mut remote).await {
+                Message::Block(block) => {
+                    assert_eq!(block.header().hash(), &hashes[1]);
+                    assert_eq!(block.transactions()[0].has_witness(), witness);
+                }
+                other => panic!("Expected a block, got {:?}", other),
+            }
+        }
+        match receive(&mut remote).await {
+            Message::NotFound(inventory) => {
+                assert_eq!(inventory.len(), 1);
+                assert_eq!(block_hash(&inventory[0].hash), BlockHash::from_u64(1));
+            }
+            other => panic!("Expected a notfound, got {:?}", other),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
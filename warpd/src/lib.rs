@@ -1,47 +1,227 @@
 // //! ![BitcoinWarp Logo](/Users/prestonevans/Downloads/BitcoinWarpLogoMock.png)
 
+mod daemon;
+mod rpc;
 mod shell;
-use config::Config;
-use networking::{Peer, PeerError};
+mod sync;
+mod zmq;
+use config::{Config, NODE_P2P_V2};
+pub use daemon::run_daemon;
+use mempool::{
+    missing_parents, EstimateMode, FeeEstimate, FeeEstimator, FeeRate, Mempool, MempoolError,
+    MempoolEvent, OrphanPool, RemovalReason,
+};
+use networking::{
+    select_peer_to_evict, BanList, EvictionCandidate, InventoryHash, InventoryRegistry, Message,
+    Misbehavior, Peer, PeerAddress, PeerError, PeerScores, Relay, Subnet, BAN_LIST_FILE,
+};
+use shared::{
+    u256, Block, BlockHash, BlockHeader, Deserializable, InventoryData, InventoryType, MerkleRoot,
+    Nbits, Transaction, TxID,
+};
 pub use shell::shell::run_shell;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
+use storage::indexes::{Index, PersistentIndex, ScriptEvent, ScriptIndex, TxIndex};
+use storage::snapshot::{
+    read_snapshot, write_snapshot, BackgroundValidation, LoadedSnapshot, SnapshotFormat,
+    SnapshotMetadata, ValidationStatus,
+};
+use storage::{write_atomically, BlockStore, Chainstate, Coin, DataDir, StorageError};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+const FEE_ESTIMATES_FILE: &str = "fee_estimates.dat";
+/// The UTXO set, saved as a snapshot on shutdown
+const CHAINSTATE_FILE: &str = "chainstate.dat";
+/// While a loaded snapshot is being validated, the background chainstate which replays the chain up to its base
+const BACKGROUND_CHAINSTATE_FILE: &str = "chainstate_background.dat";
+/// While a loaded snapshot is being validated, what's needed to carry on validating it
+const SNAPSHOT_VALIDATION_FILE: &str = "snapshot_validation.dat";
+/// The addresses of our outbound peers, one per line, saved on shutdown so that we can reconnect to them
+const PEERS_FILE: &str = "peers.dat";
+/// The number of recent blocks to read back after a crash (Core's `DEFAULT_CHECKBLOCKS`)
+const CHECK_BLOCKS: usize = 6;
 
 /// The Bitcoin Warp Daemon
 #[derive(Debug)]
 pub struct Warpd {
     pub config: Config,
     conn_man: ConnectionManager,
+    chainstate: Chainstate,
+    blocks: Option<BlockStore>,
+    txindex: Option<PersistentIndex<TxIndex>>,
+    script_index: Option<PersistentIndex<ScriptIndex>>,
+    mempool: Mempool,
+    orphans: OrphanPool,
+    fee_estimator: FeeEstimator,
+    relay: Relay,
+    /// Which peers recently announced which blocks and transactions, so we know who to ask for them
+    inventory: InventoryRegistry,
+    ban_list: BanList,
+    /// Where the ban list is saved whenever it changes
+    ban_list_path: Option<PathBuf>,
+    peer_scores: PeerScores,
+    /// Where the fee estimates are saved on shutdown
+    fee_estimates_path: Option<PathBuf>,
+    /// Present while a UTXO snapshot loaded with `load_utxo_snapshot` is still being validated
+    background_validation: Option<BackgroundValidation>,
+    /// The locked data directory, once `open_data_dir` has been called
+    data_dir: Option<DataDir>,
+    /// Outbound peers from the last run, loaded from the data directory
+    saved_peers: Vec<PeerAddress>,
+    /// Outbound peers connected outside the connection manager (by the daemon), to be saved with the others
+    outbound_peers: HashMap<SocketAddr, PeerAddress>,
+    /// Where to send notifications of changes to the chain and mempool, once `notify` has been called
+    notifier: Option<mpsc::UnboundedSender<Notification>>,
+}
+
+/// A change to the active chain or the mempool, which the daemon publishes over ZMQ
+#[derive(Debug, Clone)]
+pub enum Notification {
+    /// A block was connected to the tip of the active chain
+    BlockConnected(Block),
+    /// The tip of the active chain was disconnected
+    BlockDisconnected(Block),
+    /// A transaction entered the mempool, with the mempool sequence number it was assigned
+    TransactionAdded {
+        tx: Transaction,
+        mempool_sequence: u64,
+    },
+    /// A transaction left the mempool for some reason other than being confirmed
+    TransactionRemoved { txid: TxID, mempool_sequence: u64 },
+}
+
+/// The result of processing a transaction relayed by a peer
+#[derive(Debug, Default)]
+pub struct ProcessedTransaction {
+    /// The transactions added to the mempool: the relayed transaction, plus any orphans it allowed us to accept
+    pub accepted: Vec<TxID>,
+    /// A request for the parents of the transaction, if it was an orphan. This should be sent to the peer which
+    /// relayed it.
+    pub get_parents: Option<Message>,
 }
 
 /// An initial pass at a connection manager. Soon to be deprecated. Its replacement will live in the `networking` crate.
+///
+/// Connection slots are split between Core and Warp peers, using the limits from the [`Config`]. Peers which
+/// connect on the Warp port count as Warp peers.
 #[derive(Debug)]
 pub struct ConnectionManager {
     peers: Vec<Peer>,
+    /// A secret which stops attackers predicting which network groups eviction will protect
+    netgroup_key: u64,
 }
 impl ConnectionManager {
     pub fn new() -> ConnectionManager {
-        ConnectionManager { peers: Vec::new() }
+        ConnectionManager {
+            peers: Vec::new(),
+            netgroup_key: RandomState::new().build_hasher().finish(),
+        }
     }
-    pub async fn add(&mut self, addr: SocketAddr, config: &Config) -> Result<(), PeerError> {
-        let peer = Peer::at_address(addr, config.clone()).await?;
+    pub async fn add(
+        &mut self,
+        addr: PeerAddress,
+        config: &Config,
+        bans: &BanList,
+    ) -> Result<(), PeerError> {
+        // Onion peers have no IP to ban
+        if let PeerAddress::Ip(ip_addr) = addr {
+            if bans.should_refuse(&ip_addr.ip(), unix_time()) {
+                return Err(PeerError::Banned(ip_addr.ip()));
+            }
+        }
+        let warp = is_warp_port(config, addr.port() as usize);
+        if self.num_peers_of_kind(warp) >= peer_limit(config, warp) {
+            return Err(PeerError::NoConnectionSlots);
+        }
+        // Peers are added by hand, so like Core's addnode we try v2 with them
+        let mut peer = Peer::at_address(addr, NODE_P2P_V2, config.clone()).await?;
+        peer.set_warp(warp);
         self.peers.push(peer);
         Ok(())
     }
-    pub async fn accept(&mut self, port: &str, config: &Config) -> Result<(), PeerError> {
-        let addr = format!("127.0.0.1:{}", port);
+
+    /// Accepts a connection on `port`, at the address the config binds to. If every slot for that kind of peer is
+    /// taken, an inbound peer is evicted to make room, and its address returned. If no peer can be evicted, the new
+    /// connection is dropped.
+    pub async fn accept(
+        &mut self,
+        port: &str,
+        config: &Config,
+        bans: &BanList,
+        scores: &PeerScores,
+    ) -> Result<Option<SocketAddr>, PeerError> {
+        let addr = SocketAddr::new(
+            config.ip_address(),
+            port.trim().parse().map_err(|_| {
+                PeerError::Unexpected(format!("Could not interpret {} as a port", port.trim()))
+            })?,
+        );
         let listener = tokio::net::TcpListener::bind(&addr)
             .await
             .expect(&format!("Could not create listener on {}", addr));
-        let (connection, _) = listener.accept().await?;
-        let peer = Peer::from_connection(self.num_peers(), connection, config.clone()).await;
+        let (connection, remote) = listener.accept().await?;
+        if bans.should_refuse(&remote.ip(), unix_time()) {
+            return Err(PeerError::Banned(remote.ip()));
+        }
+        let warp = port
+            .trim()
+            .parse()
+            .is_ok_and(|port| is_warp_port(config, port));
+        let mut evicted = None;
+        if self.num_peers_of_kind(warp) >= peer_limit(config, warp) {
+            let peer = self
+                .select_peer_to_evict(warp, scores)
+                .ok_or(PeerError::NoConnectionSlots)?;
+            info!("Evicting {} to make room for {}", peer, remote);
+            self.peers.retain(|p| p.get_ip_address() != peer);
+            evicted = Some(peer);
+        }
+        let mut peer = Peer::from_connection(self.num_peers(), connection, config.clone()).await?;
+        peer.set_warp(warp);
         self.peers.push(peer);
-        Ok(())
+        Ok(evicted)
     }
 
     pub fn num_peers(&self) -> usize {
         self.peers.len()
     }
+
+    fn num_peers_of_kind(&self, warp: bool) -> usize {
+        self.peers
+            .iter()
+            .filter(|peer| peer.is_warp() == warp)
+            .count()
+    }
+
+    fn select_peer_to_evict(&self, warp: bool, scores: &PeerScores) -> Option<SocketAddr> {
+        let candidates = self
+            .peers
+            .iter()
+            .filter(|peer| peer.is_inbound() && peer.is_warp() == warp)
+            .map(|peer| peer.eviction_candidate())
+            .collect();
+        self.select_from(candidates, scores)
+    }
+
+    /// Picks one of `candidates` to evict, preferring peers which have misbehaved
+    fn select_from(
+        &self,
+        mut candidates: Vec<EvictionCandidate>,
+        scores: &PeerScores,
+    ) -> Option<SocketAddr> {
+        for candidate in candidates.iter_mut() {
+            candidate.prefer_evict = scores.score(&candidate.peer) > 0;
+        }
+        select_peer_to_evict(candidates, self.netgroup_key)
+    }
 }
 // #[derive(Debug)]
 // struct Peer {
@@ -51,22 +231,1143 @@ impl ConnectionManager {
 
 impl Warpd {
     pub fn new() -> Warpd {
+        Warpd::with_config(Config::mainnet())
+    }
+
+    /// A node for the network `config` is set up for, starting from that network's genesis block
+    pub fn with_config(config: Config) -> Warpd {
+        let chainstate = Chainstate::genesis(BlockHash::from(config.genesis_hash()));
         Warpd {
-            config: Config::mainnet(),
+            config,
             conn_man: ConnectionManager::new(),
+            chainstate,
+            blocks: None,
+            txindex: None,
+            script_index: None,
+            mempool: Mempool::default(),
+            orphans: OrphanPool::default(),
+            fee_estimator: FeeEstimator::new(),
+            relay: Relay::new(),
+            inventory: InventoryRegistry::new(),
+            ban_list: BanList::new(),
+            ban_list_path: None,
+            peer_scores: PeerScores::new(),
+            fee_estimates_path: None,
+            background_validation: None,
+            data_dir: None,
+            saved_peers: Vec::new(),
+            outbound_peers: HashMap::new(),
+            notifier: None,
+        }
+    }
+
+    /// Locks the network's data directory and loads everything saved in it: the chainstate, the block store and
+    /// indexes, the ban list and the peers from the last run.
+    ///
+    /// If the last process to use the directory crashed, the most recent blocks are read back and the chainstate
+    /// and indexes are checked against the block store before continuing.
+    pub fn open_data_dir(&mut self) -> Result<(), StorageError> {
+        let data_dir = DataDir::open(self.config.data_dir())?;
+        let path = data_dir.path().to_path_buf();
+        if let Some(chainstate) = self.read_chainstate(&path.join(CHAINSTATE_FILE))? {
+            self.chainstate = chainstate;
+            info!("Loaded chainstate at height {}", self.chainstate.height());
+        }
+        match std::fs::File::open(path.join(SNAPSHOT_VALIDATION_FILE)) {
+            Ok(state) => {
+                let background = self
+                    .read_chainstate(&path.join(BACKGROUND_CHAINSTATE_FILE))?
+                    .ok_or_else(|| {
+                        StorageError::Corruption(String::from(
+                            "a snapshot was being validated, but the background chainstate is missing",
+                        ))
+                    })?;
+                let validation = BackgroundValidation::resume(background, state)?;
+                info!(
+                    "Resuming validation of the UTXO snapshot at block {:?}",
+                    validation.base_block()
+                );
+                // We may have stopped right after validation finished, but before cleaning up
+                let status = validation.status();
+                self.background_validation = Some(validation);
+                self.finish_validation(status);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.open_block_store(data_dir.blocks_dir())?;
+        self.open_ban_list(&path)?;
+        match std::fs::read_to_string(path.join(PEERS_FILE)) {
+            Ok(contents) => {
+                self.saved_peers = contents
+                    .lines()
+                    .filter_map(|line| line.parse().ok())
+                    .collect()
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if data_dir.crashed() {
+            warn!("warpd did not shut down cleanly. Checking the data directory");
+            self.check_consistency()?;
+        }
+        self.data_dir = Some(data_dir);
+        Ok(())
+    }
+
+    /// Reads a chainstate saved by [`Warpd::flush`], if there is one
+    fn read_chainstate(&self, path: &Path) -> Result<Option<Chainstate>, StorageError> {
+        match std::fs::File::open(path) {
+            // We only save snapshots in our own format, which records the base height
+            Ok(file) => Ok(Some(
+                read_snapshot(std::io::BufReader::new(file), self.config.magic(), |_| None)?
+                    .chainstate,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Checks that the most recent blocks are readable, and that the chainstate and indexes are at blocks we have.
+    fn check_consistency(&self) -> Result<(), StorageError> {
+        let blocks = self
+            .blocks
+            .as_ref()
+            .expect("Opened with the data directory");
+        let checked = blocks.check_recent_blocks(CHECK_BLOCKS)?;
+        let genesis = BlockHash::from(self.config.genesis_hash());
+        let tips = [
+            ("chainstate", Some(self.chainstate.tip())),
+            (
+                "transaction index",
+                self.txindex.as_ref().and_then(|index| index.best_block()),
+            ),
+            (
+                "script index",
+                self.script_index
+                    .as_ref()
+                    .and_then(|index| index.best_block()),
+            ),
+        ];
+        for (name, tip) in tips.iter() {
+            match tip {
+                Some(tip) if **tip != genesis && blocks.entry(tip).is_none() => {
+                    return Err(StorageError::Corruption(format!(
+                        "the {} is at block {:?}, which is missing from the block store",
+                        name, tip
+                    )))
+                }
+                _ => {}
+            }
+        }
+        info!("Checked the last {} blocks. No problems found", checked);
+        Ok(())
+    }
+
+    /// The outbound peers we were connected to when we last shut down
+    pub fn saved_peers(&self) -> &[PeerAddress] {
+        &self.saved_peers
+    }
+
+    /// Opens the block store in `dir`, pruning it to the configured target if pruning is enabled.
+    ///
+    /// Any indexes enabled in the config are opened from `dir/indexes`. Indexes need every block, so like
+    /// Bitcoin Core we refuse to build them on a pruned node.
+    pub fn open_block_store<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), StorageError> {
+        let dir = dir.as_ref();
+        let wants_indexes = self.config.txindex() || self.config.script_index();
+        if wants_indexes && self.config.prune_target_mb().is_some() {
+            return Err(StorageError::Unsupported(String::from(
+                "pruning is incompatible with the transaction and script indexes",
+            )));
+        }
+        let mut blocks =
+            BlockStore::open(dir, self.config.magic(), self.config.prune_target_bytes())?;
+        blocks.prune(self.chainstate.height())?;
+        if wants_indexes {
+            std::fs::create_dir_all(dir.join("indexes"))?;
+        }
+        if self.config.txindex() {
+            self.txindex = Some(PersistentIndex::open(
+                dir.join("indexes").join("txindex.dat"),
+            )?);
+        }
+        if self.config.script_index() {
+            self.script_index = Some(PersistentIndex::open(
+                dir.join("indexes").join("scriptindex.dat"),
+            )?);
+        }
+        self.blocks = Some(blocks);
+
+        let fee_estimates_path = dir.join(FEE_ESTIMATES_FILE);
+        match std::fs::File::open(&fee_estimates_path) {
+            Ok(file) => match FeeEstimator::read(std::io::BufReader::new(file)) {
+                Ok(estimator) => self.fee_estimator = estimator,
+                // Fee estimates are easily rebuilt, so don't refuse to start over them
+                Err(e) => warn!("Discarding unreadable fee estimates: {}", e),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.fee_estimates_path = Some(fee_estimates_path);
+        Ok(())
+    }
+
+    pub fn block_store(&self) -> Option<&BlockStore> {
+        self.blocks.as_ref()
+    }
+
+    /// The header of a known block and its height. The genesis block isn't kept in the block store, so its
+    /// header is rebuilt from the chain parameters.
+    pub fn block_header(&self, hash: &BlockHash) -> Option<(BlockHeader, u32)> {
+        let genesis = &self.config.chain_params().genesis;
+        if hash.inner() == &genesis.hash {
+            let bits = Nbits::deserialize(&genesis.bits.to_le_bytes()[..]).ok()?;
+            let mut header = BlockHeader::new(
+                genesis.version,
+                BlockHash::from([0; 32]),
+                MerkleRoot::from(genesis.merkle_root),
+                genesis.time,
+                bits,
+                genesis.nonce,
+            );
+            header.set_hash();
+            return Some((header, 0));
+        }
+        let entry = self.blocks.as_ref()?.entry(hash)?;
+        Some((entry.header().clone(), entry.height()))
+    }
+
+    /// The hash of the block at `height` on the active chain. Walks back from the tip, so lookups far below it
+    /// take a while.
+    pub fn block_hash(&self, height: u32) -> Option<BlockHash> {
+        if height == 0 {
+            return Some(BlockHash::from(self.config.chain_params().genesis.hash));
+        }
+        self.block_hashes(height, 1).pop()
+    }
+
+    /// The hashes of up to `count` blocks of the active chain, starting at `height`. Like [`Warpd::block_hash`],
+    /// this walks back from the tip.
+    pub fn block_hashes(&self, height: u32, count: usize) -> Vec<BlockHash> {
+        let tip_height = self.chainstate.height();
+        if height > tip_height || count == 0 {
+            return Vec::new();
+        }
+        let last = tip_height.min(height.saturating_add((count - 1).min(u32::MAX as usize) as u32));
+        let mut hashes = Vec::with_capacity((last - height) as usize + 1);
+        let mut hash = self.chainstate.tip().clone();
+        for current in (height..=tip_height).rev() {
+            if current <= last {
+                hashes.push(hash.clone());
+            }
+            if current == height {
+                break;
+            }
+            hash = match self.blocks.as_ref().and_then(|blocks| blocks.header(&hash)) {
+                Some(header) => header.prev_hash().clone(),
+                None => return Vec::new(),
+            };
+        }
+        hashes.reverse();
+        hashes
+    }
+
+    /// Whether we have the block `hash`, either in the block store or as the tip of the active chain
+    pub fn has_block(&self, hash: &BlockHash) -> bool {
+        hash == self.chainstate.tip()
+            || self
+                .background_validation
+                .as_ref()
+                .is_some_and(|validation| validation.chainstate().tip() == hash)
+            || hash.inner() == &self.config.chain_params().genesis.hash
+            || self
+                .blocks
+                .as_ref()
+                .is_some_and(|blocks| blocks.entry(hash).is_some())
+    }
+
+    /// A block locator for `getblocks`: the hashes of the last ten blocks of the active chain, then of blocks
+    /// exponentially further apart, ending with the genesis block. Blocks below a loaded snapshot's base aren't
+    /// known until the background chain reaches them, so the locator skips from there to the genesis block.
+    pub fn block_locator(&self) -> Vec<BlockHash> {
+        self.locator_from(self.chainstate.tip(), self.chainstate.height())
+    }
+
+    /// A block locator for the background chain of a snapshot which is being validated
+    pub fn background_block_locator(&self) -> Option<Vec<BlockHash>> {
+        let chainstate = self.background_validation.as_ref()?.chainstate();
+        Some(self.locator_from(chainstate.tip(), chainstate.height()))
+    }
+
+    fn locator_from(&self, tip: &BlockHash, mut height: u32) -> Vec<BlockHash> {
+        let genesis = BlockHash::from(self.config.chain_params().genesis.hash);
+        let mut locator = Vec::new();
+        let mut hash = tip.clone();
+        let mut step = 1;
+        while height > 0 {
+            locator.push(hash.clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            let target = height.saturating_sub(step);
+            while height > target {
+                hash = match self.blocks.as_ref().and_then(|blocks| blocks.header(&hash)) {
+                    Some(header) => header.prev_hash().clone(),
+                    None => {
+                        locator.push(genesis);
+                        return locator;
+                    }
+                };
+                height -= 1;
+            }
+        }
+        locator.push(genesis);
+        locator
+    }
+
+    /// Whether `hash`, at `height`, is on the active chain
+    pub fn is_active(&self, hash: &BlockHash, height: u32) -> bool {
+        self.block_hash(height).as_ref() == Some(hash)
+    }
+
+    /// The height of the first block of a peer's locator which is on our active chain, or 0 if none of them are,
+    /// as in that case we only share the genesis block
+    pub fn locator_fork(&self, locator: &[BlockHash]) -> u32 {
+        locator
+            .iter()
+            .filter_map(|hash| self.block_header(hash))
+            .find(|(header, height)| self.is_active(header.hash(), *height))
+            .map_or(0, |(_, height)| height)
+    }
+
+    /// The block containing a confirmed transaction. Requires the transaction index.
+    pub fn transaction_block(&self, txid: &TxID) -> Result<Option<BlockHash>, StorageError> {
+        match self.txindex.as_ref() {
+            Some(index) => Ok(index
+                .index()
+                .get(txid)
+                .map(|location| location.block().clone())),
+            None => Err(StorageError::Unsupported(String::from(
+                "the transaction index is not enabled",
+            ))),
+        }
+    }
+
+    /// Connects a block to the active chainstate, storing it and its undo data if a block store is open.
+    ///
+    /// If the block can't be stored, it is disconnected again so the chainstate never gets ahead of the store.
+    /// While a loaded snapshot is being validated, blocks which build on the background chain go to it instead.
+    pub fn connect_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        let background_tip = self
+            .background_validation
+            .as_ref()
+            .map(|validation| validation.chainstate().tip());
+        if background_tip == Some(block.header().prev_hash()) {
+            return self.connect_background_block(block);
+        }
+        let height = self.chainstate.height() + 1;
+        // Validate before storing, so that invalid blocks never reach the block store
+        let undo = self.chainstate.connect_block(block)?;
+        if let Some(blocks) = self.blocks.as_mut() {
+            let stored = blocks
+                .write_block(block, height)
+                .and_then(|_| blocks.write_undo(block.header().hash(), &undo));
+            if let Err(e) = stored {
+                self.chainstate.disconnect_block(block, undo)?;
+                return Err(e);
+            }
+            blocks.prune(height)?;
+        }
+        // Indexes which are still catching up get this block from `sync_indexes` instead
+        if let Some(index) = self.txindex.as_mut() {
+            connect_if_synced(index, block, height, &undo)?;
+        }
+        if let Some(index) = self.script_index.as_mut() {
+            connect_if_synced(index, block, height, &undo)?;
+        }
+        let confirmed = self.mempool.remove_for_block(block);
+        self.fee_estimator
+            .process_block(height, &confirmed, &self.mempool);
+        self.orphans.remove_for_block(block);
+        self.send_mempool_notifications();
+        self.send_notification(Notification::BlockConnected(block.clone()));
+        Ok(())
+    }
+
+    /// Disconnects the tip of the active chain, using the block and undo data from the block store.
+    pub fn disconnect_tip(&mut self) -> Result<(), StorageError> {
+        let blocks = self.blocks.as_ref().ok_or_else(|| {
+            StorageError::Unsupported(String::from("disconnecting blocks requires a block store"))
+        })?;
+        let tip = self.chainstate.tip().clone();
+        let (block, undo) = match (blocks.read_block(&tip)?, blocks.read_undo(&tip)?) {
+            (Some(block), Some(undo)) => (block, undo),
+            _ => {
+                return Err(StorageError::Unsupported(format!(
+                    "block {:?} or its undo data is not available",
+                    tip
+                )))
+            }
+        };
+        if let Some(index) = self.txindex.as_mut() {
+            disconnect_if_tip(index, &block, &undo)?;
+        }
+        if let Some(index) = self.script_index.as_mut() {
+            disconnect_if_tip(index, &block, &undo)?;
+        }
+        self.chainstate.disconnect_block(&block, undo)?;
+        self.send_notification(Notification::BlockDisconnected(block));
+        Ok(())
+    }
+
+    /// Brings any indexes which are behind the active chain up to `max_blocks` blocks closer to the tip, so that
+    /// indexes enabled on an existing data directory are built in the background. Returns `true` once all
+    /// enabled indexes are synced.
+    pub fn sync_indexes(&mut self, max_blocks: usize) -> Result<bool, StorageError> {
+        if self.txindex.is_none() && self.script_index.is_none() {
+            return Ok(true);
+        }
+        let blocks = match self.blocks.as_ref() {
+            Some(blocks) => blocks,
+            None => return Ok(true),
+        };
+        let chain = blocks
+            .chain(self.chainstate.tip(), self.chainstate.height())
+            .ok_or_else(|| {
+                StorageError::Unsupported(String::from(
+                    "indexes can't be built without the headers of the active chain",
+                ))
+            })?;
+        let mut synced = true;
+        if let Some(index) = self.txindex.as_mut() {
+            synced &= index.sync(blocks, &chain, max_blocks)?;
+        }
+        if let Some(index) = self.script_index.as_mut() {
+            synced &= index.sync(blocks, &chain, max_blocks)?;
+        }
+        Ok(synced)
+    }
+
+    /// Looks up a confirmed transaction by its id. Requires the transaction index.
+    pub fn get_transaction(&self, txid: &TxID) -> Result<Option<Transaction>, StorageError> {
+        let (index, blocks) = match (self.txindex.as_ref(), self.blocks.as_ref()) {
+            (Some(index), Some(blocks)) => (index, blocks),
+            _ => {
+                return Err(StorageError::Unsupported(String::from(
+                    "the transaction index is not enabled",
+                )))
+            }
+        };
+        match index.index().get(txid) {
+            Some(location) => blocks.read_transaction(location.block(), location.offset()),
+            None => Ok(None),
+        }
+    }
+
+    /// The funding and spending history of an output script. Requires the script index.
+    pub fn script_history(&self, script: &[u8]) -> Result<&[ScriptEvent], StorageError> {
+        match self.script_index.as_ref() {
+            Some(index) => Ok(index.index().history(script)),
+            None => Err(StorageError::Unsupported(String::from(
+                "the script index is not enabled",
+            ))),
+        }
+    }
+
+    pub fn chainstate(&self) -> &Chainstate {
+        &self.chainstate
+    }
+
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
+    }
+
+    /// Adds a transaction to the mempool, returning the txids of any transactions it replaced. It's rejected if
+    /// it pays more than `max_fee_rate`.
+    pub fn accept_transaction(
+        &mut self,
+        tx: Transaction,
+        max_fee_rate: Option<FeeRate>,
+    ) -> Result<Vec<TxID>, MempoolError> {
+        let now = unix_time();
+        self.mempool.expire(now);
+        let txid = tx.txid().clone();
+        let replaced = self.admit(tx, now, max_fee_rate)?;
+        self.relay.advertise_transactions(vec![txid]);
+        Ok(replaced)
+    }
+
+    /// Estimates the fee rate needed to confirm within `target` blocks, from the transactions we've seen confirm.
+    pub fn estimate_smart_fee(&self, target: u32, mode: EstimateMode) -> Option<FeeEstimate> {
+        self.fee_estimator.estimate_smart_fee(target, mode)
+    }
+
+    /// Saves state which is kept in memory while running. This should be called before exiting.
+    ///
+    /// If the data directory is open, the shutdown is recorded as clean before the directory is unlocked.
+    pub fn shutdown(&mut self) -> Result<(), StorageError> {
+        self.flush()?;
+        match self.data_dir.take() {
+            Some(data_dir) => data_dir.close(),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves state which is kept in memory while running, without stopping. If the data directory is open, the
+    /// chainstate and outbound peers are saved to it.
+    pub fn flush(&mut self) -> Result<(), StorageError> {
+        if let Some(path) = self.fee_estimates_path.as_ref() {
+            write_atomically(path, |file| self.fee_estimator.write(file))?;
         }
+        self.save_ban_list()?;
+        let data_dir = match self.data_dir.as_ref() {
+            Some(data_dir) => data_dir,
+            None => return Ok(()),
+        };
+        let magic = self.config.magic();
+        let save = |name: &str, chainstate: &Chainstate| {
+            write_atomically(data_dir.path().join(name), |file| {
+                write_snapshot(chainstate, magic, SnapshotFormat::Warp, file).map(|_| ())
+            })
+        };
+        // The background chainstate is saved before the snapshot chainstate, so that we never end up with the
+        // snapshot's coins but no record of them still needing validation
+        if let Some(validation) = self.background_validation.as_ref() {
+            save(BACKGROUND_CHAINSTATE_FILE, validation.chainstate())?;
+            write_atomically(data_dir.path().join(SNAPSHOT_VALIDATION_FILE), |file| {
+                validation.write_state(file)
+            })?;
+        }
+        save(CHAINSTATE_FILE, &self.chainstate)?;
+        if self.background_validation.is_none() {
+            for name in [SNAPSHOT_VALIDATION_FILE, BACKGROUND_CHAINSTATE_FILE].iter() {
+                match std::fs::remove_file(data_dir.path().join(name)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        let peers: Vec<String> = self
+            .conn_man
+            .peers
+            .iter()
+            .filter(|peer| !peer.is_inbound())
+            .map(|peer| peer.get_address().to_string())
+            .chain(self.outbound_peers.values().map(PeerAddress::to_string))
+            .collect();
+        write_atomically(data_dir.path().join(PEERS_FILE), |file| {
+            for peer in peers {
+                writeln!(file, "{}", peer)?;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn orphans(&self) -> &OrphanPool {
+        &self.orphans
+    }
+
+    /// Handles a transaction relayed by the peer at `from`.
+    ///
+    /// If the transaction spends outputs we haven't seen, it is held in the orphan pool and we ask the peer for
+    /// its parents. Once a transaction is accepted, any orphans waiting on it are processed again.
+    pub fn process_transaction(
+        &mut self,
+        tx: Transaction,
+        from: SocketAddr,
+    ) -> Result<ProcessedTransaction, MempoolError> {
+        let now = unix_time();
+        self.mempool.expire(now);
+        self.orphans.expire(now);
+        self.relay
+            .mark_transaction_known(&from, tx.txid(), tx.wtxid());
+        let mut processed = ProcessedTransaction::default();
+        match self.admit(tx.clone(), now, None) {
+            Ok(_) => processed.accepted.push(tx.txid().clone()),
+            Err(MempoolError::MissingInputs(missing)) => {
+                let inventory: Vec<InventoryData> = missing_parents(&missing)
+                    .into_iter()
+                    .filter(|parent| !self.orphans.contains(parent))
+                    .map(|parent| {
+                        InventoryData::from(InventoryType::Tx, u256::from_bytes(*parent.inner()))
+                    })
+                    .collect();
+                self.orphans.add(tx, from, now);
+                if !inventory.is_empty() {
+                    processed.get_parents = Some(Message::GetData(inventory));
+                }
+                return Ok(processed);
+            }
+            Err(e) => return Err(e),
+        }
+
+        let mut queue = vec![tx];
+        while let Some(parent) = queue.pop() {
+            for txid in self.orphans.children_of(&parent) {
+                let from = self
+                    .orphans
+                    .relayed_by(&txid)
+                    .expect("Orphan was just found");
+                let expires = self.orphans.expires(&txid).expect("Orphan was just found");
+                let orphan = self.orphans.remove(&txid).expect("Orphan was just found");
+                match self.admit(orphan.clone(), now, None) {
+                    Ok(_) => {
+                        processed.accepted.push(txid);
+                        queue.push(orphan);
+                    }
+                    // The orphan has other parents which still haven't arrived
+                    Err(MempoolError::MissingInputs(_)) => {
+                        self.orphans.add_expiring(orphan, from, expires, now);
+                    }
+                    Err(e) => info!("Dropping orphan {:?}: {}", txid, e),
+                }
+            }
+        }
+        self.relay
+            .advertise_transactions(processed.accepted.iter().cloned());
+        Ok(processed)
+    }
+
+    /// Adds a transaction to the mempool, and starts tracking it for fee estimation.
+    fn admit(
+        &mut self,
+        tx: Transaction,
+        now: u64,
+        max_fee_rate: Option<FeeRate>,
+    ) -> Result<Vec<TxID>, MempoolError> {
+        let txid = tx.txid().clone();
+        let accepted =
+            self.mempool
+                .accept_with_max_fee_rate(tx, &self.chainstate, now, max_fee_rate);
+        // Expiry and evictions change the mempool even when the transaction is rejected
+        self.send_mempool_notifications();
+        let replaced = accepted?;
+        let entry = self
+            .mempool
+            .get(&txid)
+            .expect("Transaction was just accepted");
+        self.fee_estimator.process_transaction(entry);
+        Ok(replaced)
+    }
+
+    /// Sends a [`Notification`] to `notifier` for every change to the active chain and the mempool from now on.
+    pub fn notify(&mut self, notifier: mpsc::UnboundedSender<Notification>) {
+        self.mempool.record_events();
+        self.notifier = Some(notifier);
+    }
+
+    fn send_notification(&self, notification: Notification) {
+        if let Some(notifier) = self.notifier.as_ref() {
+            let _ = notifier.send(notification);
+        }
+    }
+
+    /// Passes on the changes the mempool has recorded since the last call
+    fn send_mempool_notifications(&mut self) {
+        for event in self.mempool.take_events() {
+            let notification = match event {
+                MempoolEvent::Added { tx, sequence } => Notification::TransactionAdded {
+                    tx,
+                    mempool_sequence: sequence,
+                },
+                // As in Core, confirmed transactions are announced with their block rather than as removals
+                MempoolEvent::Removed {
+                    reason: RemovalReason::Block,
+                    ..
+                } => continue,
+                MempoolEvent::Removed { txid, sequence, .. } => Notification::TransactionRemoved {
+                    txid,
+                    mempool_sequence: sequence,
+                },
+            };
+            self.send_notification(notification);
+        }
+    }
+
+    /// Starts relaying inventory to a peer once its handshake completes. `relay_txs` is the `relay` flag from its
+    /// `Version` message, and `wtxid_relay` is whether it negotiated wtxid relay.
+    pub fn peer_connected(
+        &mut self,
+        peer: SocketAddr,
+        inbound: bool,
+        relay_txs: bool,
+        wtxid_relay: bool,
+    ) {
+        self.relay
+            .add_peer(peer, inbound, relay_txs, Instant::now());
+        self.relay.set_wtxid_relay(&peer, wtxid_relay);
+    }
+
+    /// Records the address we dialed to reach an outbound peer connected outside the connection manager, so that
+    /// we reconnect to it after a restart. It is forgotten when the peer disconnects.
+    pub fn remember_outbound_peer(&mut self, peer: SocketAddr, address: PeerAddress) {
+        self.outbound_peers.insert(peer, address);
+    }
+
+    /// Forgets the orphans relayed by a peer which has disconnected.
+    pub fn peer_disconnected(&mut self, peer: &SocketAddr) {
+        self.outbound_peers.remove(peer);
+        self.orphans.remove_for_peer(peer);
+        self.relay.remove_peer(peer);
+        self.inventory.remove_peer(peer);
+        self.peer_scores.remove_peer(peer);
+    }
+
+    /// Picks an inbound peer to evict to make room for a new connection, out of `candidates`. Peers which have
+    /// misbehaved are evicted first. `None` if every candidate is protected.
+    pub fn select_peer_to_evict(&self, candidates: Vec<EvictionCandidate>) -> Option<SocketAddr> {
+        self.conn_man.select_from(candidates, &self.peer_scores)
+    }
+
+    /// Records the blocks and transactions a peer announced in an `Inv`, so that we ask it for them and don't
+    /// announce them back to it.
+    pub fn inventory_announced(
+        &mut self,
+        peer: SocketAddr,
+        inventory: &[InventoryData],
+        now: Instant,
+    ) {
+        self.inventory.expire(now);
+        self.inventory.register_inv(peer, inventory);
+        for item in inventory {
+            let hash = InventoryHash::from(item);
+            if let InventoryHash::Wtx(wtxid) = &hash {
+                // Pending announcements are kept by txid
+                if let Some(entry) = self.mempool.get_by_wtxid(wtxid) {
+                    self.relay
+                        .mark_transaction_known(&peer, entry.txid(), wtxid);
+                }
+            }
+            self.relay.mark_known(&peer, hash);
+        }
+    }
+
+    /// Records that `peer` doesn't have the items of a `notfound` it sent, so that they're asked for elsewhere
+    pub fn inventory_not_found(&mut self, peer: &SocketAddr, inventory: &[InventoryData]) {
+        self.inventory.register_missing(peer, inventory);
+    }
+
+    /// The peer to ask for `items`: the one which announced the most of them, out of those `usable` accepts.
+    /// `None` if no usable peer announced any of them.
+    pub fn peer_with_inventory<F>(&self, items: &[InventoryHash], usable: F) -> Option<SocketAddr>
+    where
+        F: Fn(&SocketAddr) -> bool,
+    {
+        self.inventory.best_peer(items, usable)
+    }
+
+    /// Penalizes a peer for violating the protocol. Returns `true` if the peer has crossed the discouragement
+    /// threshold, in which case its address has been discouraged and the caller should disconnect it.
+    pub fn misbehaving(&mut self, peer: SocketAddr, misbehavior: Misbehavior) -> bool {
+        if self.peer_scores.misbehaving(peer, &misbehavior) {
+            self.ban_list.discourage(peer.ip(), unix_time());
+            return true;
+        }
+        false
+    }
+
+    /// Loads the ban list from `dir`, which is also where it will be saved.
+    pub fn open_ban_list<P: AsRef<Path>>(&mut self, dir: P) -> Result<(), StorageError> {
+        let path = dir.as_ref().join(BAN_LIST_FILE);
+        if path.exists() {
+            self.ban_list = BanList::read(std::io::BufReader::new(std::fs::File::open(&path)?))?;
+            self.ban_list.sweep(unix_time());
+            info!("Loaded {} bans", self.ban_list.banned().len());
+        }
+        self.ban_list_path = Some(path);
+        Ok(())
+    }
+
+    /// Bans a subnet for `duration` seconds, disconnecting from it and refusing new connections.
+    pub fn ban(&mut self, subnet: Subnet, duration: u64) -> Result<(), StorageError> {
+        self.ban_list
+            .ban(subnet, unix_time().saturating_add(duration));
+        self.save_ban_list()
+    }
+
+    /// Lifts a ban. Returns `false` if the subnet wasn't banned.
+    pub fn unban(&mut self, subnet: &Subnet) -> Result<bool, StorageError> {
+        let removed = self.ban_list.unban(subnet);
+        self.save_ban_list()?;
+        Ok(removed)
     }
 
-    pub async fn add_peer(&mut self, addr: SocketAddr) -> Result<(), PeerError> {
-        if self.conn_man.num_peers() >= self.config.max_peers() {
-            unimplemented!()
+    pub fn ban_list(&self) -> &BanList {
+        &self.ban_list
+    }
+
+    fn save_ban_list(&mut self) -> Result<(), StorageError> {
+        self.ban_list.sweep(unix_time());
+        if let Some(path) = self.ban_list_path.as_ref() {
+            write_atomically(path, |file| Ok(self.ban_list.write(file)?))?;
         }
-        self.conn_man.add(addr, &self.config).await
+        Ok(())
+    }
+
+    /// The relay state, for recording peers' fee filters and the inventory they announce
+    pub fn relay_mut(&mut self) -> &mut Relay {
+        &mut self.relay
+    }
+
+    /// Collects the `Inv` announcements which are due, along with the peers they should be sent to.
+    pub fn poll_relay(&mut self, now: Instant) -> Vec<(SocketAddr, Message)> {
+        let mempool = &self.mempool;
+        self.relay.poll(now, |txid| {
+            mempool
+                .get(txid)
+                .map(|entry| (entry.fee_rate().sat_per_kvb(), entry.tx().wtxid().clone()))
+        })
+    }
+
+    /// Writes the active UTXO set to `path`, returning its `hash_serialized_3`.
+    ///
+    /// The snapshot is written to a temporary file first, so an interrupted dump never leaves a partial snapshot behind.
+    pub fn dump_utxo_snapshot<P: AsRef<Path>>(
+        &self,
+        path: P,
+        format: SnapshotFormat,
+    ) -> Result<[u8; 32], StorageError> {
+        let path = path.as_ref();
+        let mut hash = [0; 32];
+        write_atomically(path, |file| {
+            hash = write_snapshot(&self.chainstate, self.config.magic(), format, file)?;
+            Ok(())
+        })?;
+        info!("Wrote UTXO snapshot to {:?}", path);
+        Ok(hash)
+    }
+
+    /// Loads a UTXO snapshot and makes it the active chainstate. The previous chainstate keeps syncing
+    /// in the background until it reaches the snapshot's base block, at which point the snapshot is checked.
+    pub fn load_utxo_snapshot<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<SnapshotMetadata, StorageError> {
+        if self.background_validation.is_some() {
+            return Err(StorageError::Unsupported(String::from(
+                "a snapshot is already being validated",
+            )));
+        }
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let LoadedSnapshot {
+            metadata,
+            chainstate,
+            hash,
+        } = read_snapshot(file, self.config.magic(), |hash| {
+            self.block_header(hash).map(|(_, height)| height)
+        })?;
+        if metadata.base_height() <= self.chainstate.height() {
+            return Err(StorageError::Unsupported(format!(
+                "snapshot at height {} is not ahead of our chain at height {}",
+                metadata.base_height(),
+                self.chainstate.height()
+            )));
+        }
+        let background = std::mem::replace(&mut self.chainstate, chainstate);
+        self.background_validation = Some(BackgroundValidation::new(background, &metadata, hash));
+        info!(
+            "Loaded UTXO snapshot at height {}. Validating it in the background",
+            metadata.base_height()
+        );
+        Ok(metadata)
+    }
+
+    pub fn background_validation(&self) -> Option<&BackgroundValidation> {
+        self.background_validation.as_ref()
+    }
+
+    /// Connects the next block of the background validation chain, and stores it once it has been validated.
+    fn connect_background_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        let validation = self
+            .background_validation
+            .as_mut()
+            .expect("Only called while validating a snapshot");
+        let height = validation.chainstate().height() + 1;
+        let status = validation.connect_block(block)?;
+        self.finish_validation(status);
+        if let Some(blocks) = self.blocks.as_mut() {
+            blocks.write_block(block, height)?;
+        }
+        Ok(())
+    }
+
+    /// Stops validating the snapshot once the background chain has reached its base block. If the snapshot turns
+    /// out to be invalid, it is discarded and the background chainstate becomes active again.
+    fn finish_validation(&mut self, status: ValidationStatus) {
+        match status {
+            ValidationStatus::InProgress => {}
+            ValidationStatus::Validated => self.background_validation = None,
+            ValidationStatus::Invalid { .. } => {
+                warn!("UTXO snapshot is invalid! Falling back to the background chainstate");
+                let validation = self.background_validation.take().expect("Just checked");
+                self.chainstate = validation.into_chainstate();
+            }
+        }
+    }
+
+    pub async fn add_peer(&mut self, addr: PeerAddress) -> Result<(), PeerError> {
+        self.conn_man.add(addr, &self.config, &self.ban_list).await
     }
     pub async fn accept_peer(&mut self, port: &str) -> Result<(), PeerError> {
-        if self.conn_man.num_peers() >= self.config.max_peers() {
-            unimplemented!()
+        let evicted = self
+            .conn_man
+            .accept(port, &self.config, &self.ban_list, &self.peer_scores)
+            .await?;
+        if let Some(peer) = evicted {
+            self.peer_disconnected(&peer);
         }
-        self.conn_man.accept(port, &self.config).await
+        Ok(())
+    }
+}
+
+fn connect_if_synced<I: Index>(
+    index: &mut PersistentIndex<I>,
+    block: &Block,
+    height: u32,
+    undo: &[Coin],
+) -> Result<(), StorageError> {
+    let synced = match index.best_block() {
+        Some(best) => best == block.header().prev_hash(),
+        None => height == 1,
+    };
+    if synced {
+        index.connect_block(block, height, undo)?;
+    }
+    Ok(())
+}
+
+fn disconnect_if_tip<I: Index>(
+    index: &mut PersistentIndex<I>,
+    block: &Block,
+    undo: &[Coin],
+) -> Result<(), StorageError> {
+    if index.best_block() == Some(block.header().hash()) {
+        index.disconnect_block(block, undo)?;
+    }
+    Ok(())
+}
+
+/// Whether connections on `port` are from Warp peers. If the Warp and Core ports are the same, we can't tell, so
+/// every peer counts as a Core peer.
+fn is_warp_port(config: &Config, port: usize) -> bool {
+    port == config.warp_port() && config.warp_port() != config.core_port()
+}
+
+fn peer_limit(config: &Config, warp: bool) -> usize {
+    if warp {
+        config.max_warp_peers()
+    } else {
+        config.max_core_peers()
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use networking::DISCOURAGEMENT_THRESHOLD;
+    use shared::{Serializable, TxInput, TxOutpoint, TxOutput};
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("warpd-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A block on top of `prev` whose coinbase pays to `OP_TRUE`
+    pub(crate) fn next_block(prev: &BlockHash, nonce: u32) -> Block {
+        let coinbase = Transaction::new(
+            1,
+            vec![TxInput::new(
+                TxOutpoint::new(u256::new(), u32::MAX),
+                nonce.to_le_bytes().to_vec(),
+                u32::MAX,
+            )],
+            vec![TxOutput::new(50_0000_0000, vec![0x51])],
+        );
+        // Deserializing fills in the txid
+        let mut raw = Vec::new();
+        coinbase.serialize(&mut raw).unwrap();
+        let coinbase = Transaction::deserialize(&raw[..]).unwrap();
+        let mut header = BlockHeader::new(
+            1,
+            prev.clone(),
+            MerkleRoot::from_iter(std::iter::once(coinbase.txid())),
+            0,
+            Nbits::new(u256::from(1)),
+            nonce,
+        );
+        header.set_hash();
+        Block::new(header, vec![coinbase])
+    }
+
+    #[test]
+    fn invalid_blocks_are_not_stored() {
+        let dir = temp_dir("invalid-blocks");
+        let mut warpd = Warpd::new();
+        warpd.open_block_store(&dir).unwrap();
+        // Doesn't build on the genesis block, so it can't be connected
+        let block = Block::_test_block();
+        assert!(warpd.connect_block(&block).is_err());
+        assert_eq!(warpd.chainstate.height(), 0);
+        let blocks = warpd.blocks.as_ref().unwrap();
+        assert!(blocks.entry(block.header().hash()).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bad_checksums_lead_to_discouragement() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::mainnet();
+        config.set_v2_transport(false);
+        let (peer, remote) = tokio::join!(
+            Peer::at_address(listener.local_addr().unwrap(), 0, config),
+            async { listener.accept().await.unwrap().0 }
+        );
+        let (mut peer, mut remote) = (peer.unwrap(), remote);
+        let addr = peer.get_ip_address();
+        // An empty `sendheaders`, whose checksum should be 5df6e0e2
+        let mut corrupted = Config::mainnet().magic().to_le_bytes().to_vec();
+        corrupted.extend_from_slice(b"sendheaders\0");
+        corrupted.extend_from_slice(&[0; 8]);
+
+        let mut warpd = Warpd::new();
+        let needed = DISCOURAGEMENT_THRESHOLD / Misbehavior::BadChecksum.score();
+        for sent in 1..=needed {
+            remote.write_all(&corrupted).await.unwrap();
+            let error = peer
+                .receive(Some(Duration::from_secs(1)))
+                .await
+                .unwrap_err();
+            let misbehavior = Misbehavior::from_error(&error).unwrap();
+            assert_eq!(warpd.misbehaving(addr, misbehavior), sent == needed);
+        }
+        assert!(warpd.ban_list().is_discouraged(&addr.ip(), unix_time()));
+    }
+
+    #[test]
+    fn block_locators_thin_out_towards_genesis() {
+        let dir = temp_dir("block-locator");
+        let mut warpd = Warpd::new();
+        warpd.open_block_store(&dir).unwrap();
+        assert_eq!(warpd.block_locator(), vec![warpd.chainstate.tip().clone()]);
+        for nonce in 0..30 {
+            let block = next_block(warpd.chainstate.tip(), nonce);
+            warpd.connect_block(&block).unwrap();
+        }
+        let heights = [30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0];
+        let expected: Vec<BlockHash> = heights
+            .iter()
+            .map(|height| warpd.block_hash(*height).unwrap())
+            .collect();
+        assert_eq!(warpd.block_locator(), expected);
+        assert!(warpd.has_block(&expected[5]));
+        assert!(warpd.has_block(&expected[13]));
+        assert!(!warpd.has_block(&BlockHash::from_u64(1)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_are_validated_by_connecting_blocks_across_restarts() {
+        let dir = temp_dir("snapshot-validation");
+        let mut source = Warpd::new();
+        let mut blocks = Vec::new();
+        for nonce in 0..3 {
+            let block = next_block(source.chainstate.tip(), nonce);
+            source.connect_block(&block).unwrap();
+            blocks.push(block);
+        }
+        let snapshot = dir.join("snapshot.dat");
+        source
+            .dump_utxo_snapshot(&snapshot, SnapshotFormat::Warp)
+            .unwrap();
+
+        let mut config = Config::mainnet();
+        config.set_data_dir(dir.join("data"));
+        let mut warpd = Warpd::with_config(config.clone());
+        warpd.open_data_dir().unwrap();
+        warpd.load_utxo_snapshot(&snapshot).unwrap();
+        assert_eq!(warpd.chainstate.height(), 3);
+        warpd.connect_block(&blocks[0]).unwrap();
+        assert!(warpd.has_block(blocks[0].header().hash()));
+        warpd.shutdown().unwrap();
+
+        // The snapshot is still active after a restart, and so is its validation
+        let mut warpd = Warpd::with_config(config.clone());
+        warpd.open_data_dir().unwrap();
+        assert_eq!(warpd.chainstate.tip(), blocks[2].header().hash());
+        let validation = warpd.background_validation().unwrap();
+        assert_eq!(validation.chainstate().height(), 1);
+        let genesis = BlockHash::from(config.genesis_hash());
+        assert_eq!(
+            warpd.background_block_locator().unwrap(),
+            vec![blocks[0].header().hash().clone(), genesis]
+        );
+        warpd.connect_block(&blocks[1]).unwrap();
+        warpd.connect_block(&blocks[2]).unwrap();
+        assert!(warpd.background_validation().is_none());
+        assert_eq!(warpd.chainstate.height(), 3);
+        warpd.shutdown().unwrap();
+        assert!(!config.data_dir().join(SNAPSHOT_VALIDATION_FILE).exists());
+        assert!(!config.data_dir().join(BACKGROUND_CHAINSTATE_FILE).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remembers_announcements_until_peers_disconnect() {
+        let mut warpd = Warpd::new();
+        let peer = SocketAddr::from(([127, 0, 0, 1], 8333));
+        warpd.peer_connected(peer, false, true, false);
+        let hash = [7; 32];
+        let announced = [
+            InventoryData::from(InventoryType::Block, u256::from_bytes(hash)),
+            InventoryData::from(InventoryType::Tx, u256::from_bytes([8; 32])),
+        ];
+        warpd.inventory_announced(peer, &announced, Instant::now());
+        let wanted = [InventoryHash::Block(BlockHash::from(hash))];
+        assert_eq!(warpd.peer_with_inventory(&wanted, |_| true), Some(peer));
+        assert_eq!(warpd.peer_with_inventory(&wanted, |_| false), None);
+        // We won't announce the transaction back to the peer
+        let tx = InventoryHash::Tx(TxID::from([8; 32]));
+        assert!(warpd.relay_mut().is_known(&peer, &tx));
+
+        warpd.peer_disconnected(&peer);
+        assert_eq!(warpd.peer_with_inventory(&wanted, |_| true), None);
+    }
+
+    #[test]
+    fn evicts_misbehaving_peers_first() {
+        let mut warpd = Warpd::new();
+        let start = Instant::now();
+        // One network group, so only connection times decide who is protected
+        let peer = |i: u8| SocketAddr::from(([10, 0, 0, i], 8333));
+        let candidates: Vec<EvictionCandidate> = (0..40)
+            .map(|i| EvictionCandidate::new(peer(i), start + Duration::from_secs(i as u64)))
+            .collect();
+        assert_eq!(
+            warpd.select_peer_to_evict(candidates.clone()),
+            Some(peer(39))
+        );
+
+        assert!(!warpd.misbehaving(peer(38), Misbehavior::UnrequestedData));
+        assert_eq!(warpd.select_peer_to_evict(candidates), Some(peer(38)));
+    }
+
+    #[test]
+    fn huge_bans_do_not_overflow() {
+        let mut warpd = Warpd::new();
+        let subnet: Subnet = "10.0.0.0/8".parse().unwrap();
+        warpd.ban(subnet, u64::MAX).unwrap();
+        assert_eq!(warpd.ban_list().banned(), vec![(subnet, u64::MAX)]);
     }
 }
@@ -0,0 +1,354 @@
+//! Just enough HTTP/1.1 for the RPC interface: requests with a `Content-Length` body (or none), and responses
+//! written in one piece. Connections are kept alive unless the client asks otherwise.
+use std::fmt;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+
+/// The most a request line plus headers may take up
+const MAX_HEADERS_SIZE: u64 = 8 * 1024;
+/// The largest body an authenticated request may have, which leaves room for a maximum size block (Core's
+/// `MAX_SIZE`)
+pub(crate) const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// The request target, like `/` or `/rest/chaininfo.json`
+    pub(crate) target: String,
+    /// Whether the request was made over HTTP/1.0, where connections close by default
+    http_10: bool,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// The value of a header, compared case insensitively
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The length of the body, from `Content-Length`
+    fn content_length(&self) -> Result<usize, HttpError> {
+        match self.header("Content-Length") {
+            Some(length) => length
+                .parse()
+                .map_err(|_| HttpError::BadRequest(format!("invalid Content-Length {}", length))),
+            None => Ok(0),
+        }
+    }
+
+    /// Whether the client wants the connection kept open after the response
+    pub(crate) fn keep_alive(&self) -> bool {
+        match self.header("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => !self.http_10,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum HttpError {
+    Io(std::io::Error),
+    BadRequest(String),
+    TooLarge,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(e) => write!(f, "{}", e),
+            HttpError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            HttpError::TooLarge => write!(f, "request too large"),
+        }
+    }
+}
+
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> HttpError {
+        HttpError::Io(e)
+    }
+}
+
+impl HttpError {
+    /// The status to answer with, if the connection is still usable for a response
+    pub(crate) fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::Io(_) => None,
+            HttpError::BadRequest(_) => Some(400),
+            HttpError::TooLarge => Some(413),
+        }
+    }
+}
+
+/// Reads the request line and headers of the next request from a connection, leaving the body to
+/// [`read_body`]. Returns `None` if the client closed the connection between requests.
+pub(crate) async fn read_head<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<Option<Request>, HttpError> {
+    let mut head = (&mut *reader).take(MAX_HEADERS_SIZE);
+    let mut line = String::new();
+    if head.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => {
+            return Err(HttpError::BadRequest(String::from(
+                "malformed request line",
+            )))
+        }
+    };
+    let http_10 = match version {
+        "HTTP/1.0" => true,
+        "HTTP/1.1" => false,
+        _ => {
+            return Err(HttpError::BadRequest(format!(
+                "unsupported version {}",
+                version
+            )))
+        }
+    };
+    let (method, target) = (String::from(method), String::from(target));
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(if head.limit() == 0 {
+                HttpError::TooLarge
+            } else {
+                HttpError::BadRequest(String::from("connection closed in headers"))
+            });
+        }
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::BadRequest(format!("malformed header {}", line)))?;
+        headers.push((String::from(name.trim()), String::from(value.trim())));
+    }
+
+    let request = Request {
+        method,
+        target,
+        http_10,
+        headers,
+        body: Vec::new(),
+    };
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(HttpError::BadRequest(String::from(
+            "chunked bodies are not supported",
+        )));
+    }
+    request.content_length()?;
+    Ok(Some(request))
+}
+
+/// Reads the body of a request whose head [`read_head`] returned, if it's no longer than `limit`. The buffer
+/// grows as data arrives, so a client can't make us allocate more than it actually sends.
+pub(crate) async fn read_body<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    request: &mut Request,
+    limit: usize,
+) -> Result<(), HttpError> {
+    let length = request.content_length()?;
+    if length > limit {
+        return Err(HttpError::TooLarge);
+    }
+    let mut body = Vec::new();
+    (&mut *reader)
+        .take(length as u64)
+        .read_to_end(&mut body)
+        .await?;
+    if body.len() < length {
+        return Err(HttpError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+    }
+    request.body = body;
+    Ok(())
+}
+
+#[derive(Debug)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) headers: Vec<(&'static str, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", String::from(content_type))],
+            body,
+        }
+    }
+
+    pub(crate) fn json(status: u16, value: &serde_json::Value) -> Response {
+        let mut body = value.to_string().into_bytes();
+        body.push(b'\n');
+        Response::new(status, "application/json", body)
+    }
+
+    pub(crate) fn text(status: u16, text: &str) -> Response {
+        Response::new(status, "text/plain", format!("{}\r\n", text).into_bytes())
+    }
+
+    /// A response with no body, like `401 Unauthorized` or `204 No Content`
+    pub(crate) fn empty(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+pub(crate) async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+    keep_alive: bool,
+) -> std::io::Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason(response.status)
+    );
+    for (name, value) in response.headers.iter() {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    if !keep_alive {
+        head.push_str("Connection: close\r\n");
+    }
+    head.push_str("\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut raw: &[u8], limit: usize) -> Result<Option<Request>, HttpError> {
+        let mut request = match read_head(&mut raw).await? {
+            Some(request) => request,
+            None => return Ok(None),
+        };
+        read_body(&mut raw, &mut request, limit).await?;
+        Ok(Some(request))
+    }
+
+    #[tokio::test]
+    async fn parses_requests() {
+        let raw = b"POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: 4\r\n\r\nbody";
+        let request = read(raw, MAX_BODY_SIZE).await.unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/");
+        assert_eq!(request.header("Content-Length"), Some("4"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert_eq!(request.body, b"body");
+        assert!(request.keep_alive());
+
+        let raw = b"GET /rest/chaininfo.json HTTP/1.0\r\n\r\n";
+        let request = read(raw, MAX_BODY_SIZE).await.unwrap().unwrap();
+        assert!(request.body.is_empty());
+        assert!(!request.keep_alive());
+        let raw = b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        assert!(read(raw, MAX_BODY_SIZE)
+            .await
+            .unwrap()
+            .unwrap()
+            .keep_alive());
+        let raw = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(!read(raw, MAX_BODY_SIZE)
+            .await
+            .unwrap()
+            .unwrap()
+            .keep_alive());
+
+        assert!(read(b"", MAX_BODY_SIZE).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests() {
+        for raw in [
+            &b"GET /\r\n\r\n"[..],
+            b"GET / HTTP/2\r\n\r\n",
+            b"GET / HTTP/1.1\r\nno colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: four\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+        ] {
+            let error = read(raw, MAX_BODY_SIZE).await.unwrap_err();
+            assert_eq!(error.status(), Some(400), "{:?}", error);
+        }
+        // The client hung up partway through the body
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort";
+        let error = read(raw, MAX_BODY_SIZE).await.unwrap_err();
+        assert!(matches!(error, HttpError::Io(_)));
+    }
+
+    #[tokio::test]
+    async fn limits_request_sizes() {
+        let mut raw = b"GET / HTTP/1.1\r\n".to_vec();
+        while raw.len() as u64 <= MAX_HEADERS_SIZE {
+            raw.extend_from_slice(b"X-Padding: padding\r\n");
+        }
+        raw.extend_from_slice(b"\r\n");
+        let error = read(&raw, MAX_BODY_SIZE).await.unwrap_err();
+        assert!(matches!(error, HttpError::TooLarge));
+
+        // The limit is checked before anything is read or allocated
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n";
+        let error = read(raw, MAX_BODY_SIZE).await.unwrap_err();
+        assert_eq!(error.status(), Some(413));
+        let raw = b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(matches!(read(raw, 4).await, Err(HttpError::TooLarge)));
+        assert_eq!(read(raw, 5).await.unwrap().unwrap().body, b"hello");
+    }
+
+    #[tokio::test]
+    async fn writes_responses() {
+        let mut written = Vec::new();
+        let response = Response::json(200, &serde_json::json!({ "result": 1 }));
+        write_response(&mut written, &response, true).await.unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 13\r\n\r\n{\"result\":1}\n"
+        );
+
+        let mut written = Vec::new();
+        write_response(&mut written, &Response::empty(401), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
@@ -0,0 +1,862 @@
+//! The RPC methods, with Core's names, parameters and result formats. Fields Core reports which warpd doesn't
+//! track (like `chainwork`, or the bytes sent to each peer) are left out rather than made up.
+use super::*;
+use crate::daemon::{open_connection, PeerInfo};
+use crate::{unix_time, Warpd};
+use config::{NetworkKind, NODE_NETWORK, NODE_NETWORK_LIMITED, NODE_P2P_V2};
+use mempool::policy::{classify, ScriptType};
+use mempool::{FeeRate, MempoolError};
+use networking::{PeerAddress, Subnet, DEFAULT_BAN_TIME, MAX_BAN_TIME};
+use shared::address::script_to_address;
+use shared::script::script_to_asm;
+use shared::{
+    u256, Block, BlockHash, BlockHeader, Deserializable, Serializable, Transaction, TxID,
+    TxOutpoint,
+};
+
+/// Every method, with the names of its parameters in order
+pub(super) const METHODS: &[(&str, &[&str])] = &[
+    ("getbestblockhash", &[]),
+    ("getblockchaininfo", &[]),
+    ("getblockcount", &[]),
+    ("getblockhash", &["height"]),
+    ("getblock", &["blockhash", "verbosity"]),
+    ("getblockheader", &["blockhash", "verbose"]),
+    ("getrawtransaction", &["txid", "verbose", "blockhash"]),
+    ("sendrawtransaction", &["hexstring", "maxfeerate"]),
+    ("getmempoolinfo", &[]),
+    ("getrawmempool", &["verbose", "mempool_sequence"]),
+    ("getpeerinfo", &[]),
+    ("getnetworkinfo", &[]),
+    ("addnode", &["node", "command"]),
+    ("disconnectnode", &["address", "nodeid"]),
+    ("setban", &["subnet", "command", "bantime", "absolute"]),
+    ("listbanned", &[]),
+    ("stop", &[]),
+];
+
+/// Core treats a tip older than this as a sign that we're still syncing (`DEFAULT_MAX_TIP_AGE`)
+const MAX_TIP_AGE: u64 = 24 * 60 * 60;
+/// The fee rate `sendrawtransaction` refuses to pay more than by default, 0.10 BTC/kvB in satoshis (Core's
+/// `DEFAULT_MAX_RAW_TX_FEE_RATE`)
+const DEFAULT_MAX_RAW_TX_FEE_RATE: i64 = 10_000_000;
+/// The number of blocks whose median time is a block's median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+pub(super) async fn call(
+    node: &Arc<Node>,
+    method: &str,
+    params: Params,
+) -> Result<Value, RpcError> {
+    match method {
+        "getbestblockhash" => {
+            let warpd = node.warpd.lock().await;
+            Ok(json!(hash_hex(warpd.chainstate().tip().inner())))
+        }
+        "getblockchaininfo" => Ok(getblockchaininfo(&*node.warpd.lock().await)),
+        "getblockcount" => Ok(json!(node.warpd.lock().await.chainstate().height())),
+        "getblockhash" => getblockhash(&*node.warpd.lock().await, &params),
+        "getblock" => getblock(&*node.warpd.lock().await, &params),
+        "getblockheader" => getblockheader(&*node.warpd.lock().await, &params),
+        "getrawtransaction" => getrawtransaction(&*node.warpd.lock().await, &params),
+        "sendrawtransaction" => sendrawtransaction(&mut *node.warpd.lock().await, &params),
+        "getmempoolinfo" => Ok(getmempoolinfo(&*node.warpd.lock().await)),
+        "getrawmempool" => getrawmempool(&*node.warpd.lock().await, &params),
+        "getpeerinfo" => Ok(getpeerinfo(node)),
+        "getnetworkinfo" => Ok(getnetworkinfo(node).await),
+        "addnode" => addnode(node, &params),
+        "disconnectnode" => disconnectnode(node, &params),
+        "setban" => setban(node, &params).await,
+        "listbanned" => Ok(listbanned(&*node.warpd.lock().await)),
+        "stop" => {
+            node.request_shutdown();
+            Ok(json!("Bitcoin Warp stopping"))
+        }
+        _ => Err(RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found")),
+    }
+}
+
+pub(super) fn getblockchaininfo(warpd: &Warpd) -> Value {
+    let height = warpd.chainstate().height();
+    let tip = warpd.chainstate().tip();
+    let header = warpd.block_header(tip).map(|(header, _)| header);
+    let time = header.as_ref().map_or(0, |header| header.raw_time());
+    let bits = header.as_ref().map_or(0, compact_bits);
+    let now = unix_time();
+    let mut info = json!({
+        "chain": warpd.config.network().name(),
+        "blocks": height,
+        // Headers aren't synced ahead of blocks yet
+        "headers": height,
+        "bestblockhash": hash_hex(tip.inner()),
+        "bits": format!("{:08x}", bits),
+        "difficulty": difficulty(bits),
+        "time": time,
+        "mediantime": header.as_ref().map_or(0, |header| median_time(warpd, header)),
+        "verificationprogress": verification_progress(warpd, time as u64, now),
+        "initialblockdownload": (time as u64) + MAX_TIP_AGE < now,
+        "size_on_disk": warpd.block_store().map_or(0, |blocks| blocks.disk_usage()),
+        "pruned": warpd.config.prune_target_mb().is_some(),
+        "warnings": [],
+    });
+    if let Some(target) = warpd.config.prune_target_bytes() {
+        info["automatic_pruning"] = json!(true);
+        info["prune_target_size"] = json!(target);
+    }
+    info
+}
+
+fn getblockhash(warpd: &Warpd, params: &Params) -> Result<Value, RpcError> {
+    let height = params.required_int(0)?;
+    let out_of_range = || RpcError::new(RPC_INVALID_PARAMETER, "Block height out of range");
+    if height < 0 || height > warpd.chainstate().height() as i64 {
+        return Err(out_of_range());
+    }
+    let hash = warpd.block_hash(height as u32).ok_or_else(out_of_range)?;
+    Ok(json!(hash_hex(hash.inner())))
+}
+
+fn getblock(warpd: &Warpd, params: &Params) -> Result<Value, RpcError> {
+    let hash = BlockHash::from(parse_hash(params, 0)?);
+    let verbosity = params.verbosity(1, 1)?;
+    if !(0..=2).contains(&verbosity) {
+        return Err(RpcError::new(
+            RPC_INVALID_PARAMETER,
+            "Verbosity must be 0, 1 or 2",
+        ));
+    }
+    let (_, height) = warpd
+        .block_header(&hash)
+        .ok_or_else(|| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"))?;
+    let block = read_block(warpd, &hash)?;
+    if verbosity == 0 {
+        return Ok(json!(hex::encode(serialize(&block))));
+    }
+    Ok(block_json(warpd, &block, height, verbosity == 2))
+}
+
+fn getblockheader(warpd: &Warpd, params: &Params) -> Result<Value, RpcError> {
+    let hash = BlockHash::from(parse_hash(params, 0)?);
+    let verbose = params.bool(1)?.unwrap_or(true);
+    let (header, height) = warpd
+        .block_header(&hash)
+        .ok_or_else(|| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, "Block not found"))?;
+    if !verbose {
+        return Ok(json!(hex::encode(serialize(&header))));
+    }
+    Ok(header_json(warpd, &header, height))
+}
+
+fn getrawtransaction(warpd: &Warpd, params: &Params) -> Result<Value, RpcError> {
+    let txid = TxID::from(parse_hash(params, 0)?);
+    let verbosity = params.verbosity(1, 0)?;
+    let block_hash = match params.get(2) {
+        Some(_) => Some(BlockHash::from(parse_hash(params, 2)?)),
+        None => None,
+    };
+
+    let (tx, block_hash) = match block_hash {
+        Some(block_hash) => {
+            if warpd.block_header(&block_hash).is_none() {
+                return Err(RpcError::new(
+                    RPC_INVALID_ADDRESS_OR_KEY,
+                    "Block hash not found",
+                ));
+            }
+            let block = read_block(warpd, &block_hash)?;
+            let tx = block
+                .transactions()
+                .iter()
+                .find(|tx| tx.txid() == &txid)
+                .cloned()
+                .ok_or_else(|| {
+                    RpcError::new(
+                        RPC_INVALID_ADDRESS_OR_KEY,
+                        "No such transaction found in the provided block",
+                    )
+                })?;
+            (tx, Some(block_hash))
+        }
+        None => match warpd.mempool().get(&txid) {
+            Some(entry) => (entry.tx().clone(), None),
+            None => {
+                let not_found = |message: &str| RpcError::new(RPC_INVALID_ADDRESS_OR_KEY, message);
+                let tx = match warpd.get_transaction(&txid) {
+                    Ok(Some(tx)) => tx,
+                    Ok(None) => {
+                        return Err(not_found("No such mempool or blockchain transaction"))
+                    }
+                    Err(storage::StorageError::Unsupported(_)) => {
+                        return Err(not_found(
+                            "No such mempool transaction. Use -txindex or provide a block hash to enable \
+                             blockchain transaction queries",
+                        ))
+                    }
+                    Err(e) => return Err(RpcError::new(RPC_DATABASE_ERROR, e.to_string())),
+                };
+                let block_hash = warpd
+                    .transaction_block(&txid)
+                    .map_err(|e| RpcError::new(RPC_DATABASE_ERROR, e.to_string()))?;
+                (tx, block_hash)
+            }
+        },
+    };
+
+    if verbosity == 0 {
+        return Ok(json!(hex::encode(serialize(&tx))));
+    }
+    let mut result = tx_json(warpd, &tx);
+    if let Some(block_hash) = block_hash {
+        result["blockhash"] = json!(hash_hex(block_hash.inner()));
+        if let Some((header, height)) = warpd.block_header(&block_hash) {
+            if warpd.is_active(&block_hash, height) {
+                result["in_active_chain"] = json!(true);
+                result["confirmations"] = json!(warpd.chainstate().height() - height + 1);
+                result["time"] = json!(header.raw_time());
+                result["blocktime"] = json!(header.raw_time());
+            } else {
+                result["in_active_chain"] = json!(false);
+                result["confirmations"] = json!(0);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Submits a transaction to the mempool and relays it, unless it pays more than `maxfeerate` BTC/kvB. A
+/// `maxfeerate` of 0 allows any fee.
+fn sendrawtransaction(warpd: &mut Warpd, params: &Params) -> Result<Value, RpcError> {
+    let bytes = hex::decode(params.required_str(0)?)
+        .map_err(|_| RpcError::new(RPC_DESERIALIZATION_ERROR, "TX decode failed"))?;
+    let max_fee_rate = match params.amount(1)?.unwrap_or(DEFAULT_MAX_RAW_TX_FEE_RATE) {
+        0 => None,
+        rate => Some(FeeRate::from_sat_per_kvb(rate as u64)),
+    };
+    let mut rest = &bytes[..];
+    let tx = match Transaction::deserialize(&mut rest) {
+        Ok(tx) if rest.is_empty() => tx,
+        _ => {
+            return Err(RpcError::new(
+                RPC_DESERIALIZATION_ERROR,
+                "TX decode failed. Make sure the tx has at least one input.",
+            ))
+        }
+    };
+    let txid = json!(hash_hex(tx.txid().inner()));
+    match warpd.accept_transaction(tx, max_fee_rate) {
+        Ok(_) | Err(MempoolError::AlreadyKnown) => Ok(txid),
+        Err(MempoolError::MissingInputs(_)) => Err(RpcError::new(
+            RPC_VERIFY_ERROR,
+            "bad-txns-inputs-missingorspent",
+        )),
+        Err(MempoolError::MaxFeeExceeded(_)) => Err(RpcError::new(
+            RPC_VERIFY_ERROR,
+            "Fee exceeds maximum configured by user (e.g. -maxtxfee, maxfeerate)",
+        )),
+        Err(e) => Err(RpcError::new(RPC_VERIFY_REJECTED, e.to_string())),
+    }
+}
+
+fn getmempoolinfo(warpd: &Warpd) -> Value {
+    let mempool = warpd.mempool();
+    let limits = mempool.limits();
+    let min_fee_rate = std::cmp::max(mempool.min_fee_rate(unix_time()), limits.min_relay_fee_rate);
+    json!({
+        "loaded": true,
+        "size": mempool.len(),
+        "bytes": mempool.total_size(),
+        "usage": mempool.total_size(),
+        "total_fee": btc(mempool.iter().map(|entry| entry.fee()).sum()),
+        "maxmempool": limits.max_size,
+        "mempoolminfee": fee_rate_btc(min_fee_rate),
+        "minrelaytxfee": fee_rate_btc(limits.min_relay_fee_rate),
+        "incrementalrelayfee": fee_rate_btc(limits.incremental_relay_fee_rate),
+        "unbroadcastcount": 0,
+    })
+}
+
+fn getrawmempool(warpd: &Warpd, params: &Params) -> Result<Value, RpcError> {
+    let verbose = params.bool(0)?.unwrap_or(false);
+    let mempool_sequence = params.bool(1)?.unwrap_or(false);
+    if verbose && mempool_sequence {
+        return Err(RpcError::new(
+            RPC_INVALID_PARAMETER,
+            "Verbose results cannot contain mempool sequence values.",
+        ));
+    }
+    let mempool = warpd.mempool();
+    if !verbose {
+        let txids: Value = mempool
+            .iter()
+            .map(|entry| json!(hash_hex(entry.txid().inner())))
+            .collect();
+        if mempool_sequence {
+            // The sequence the ZMQ `sequence` topic continues from, for subscribers syncing the mempool
+            return Ok(json!({ "txids": txids, "mempool_sequence": mempool.sequence() }));
+        }
+        return Ok(txids);
+    }
+    let mut result = Map::new();
+    for entry in mempool.iter() {
+        let tx = entry.tx();
+        let mut depends: Vec<Value> = Vec::new();
+        for input in tx.inputs() {
+            let parent = TxID::from(*input.previous_outpoint().hash().to_le_bytes());
+            if mempool.contains(&parent) {
+                let parent = json!(hash_hex(parent.inner()));
+                if !depends.contains(&parent) {
+                    depends.push(parent);
+                }
+            }
+        }
+        let spent_by: Vec<Value> = (0..tx.outputs().len() as u32)
+            .filter_map(|index| {
+                let outpoint = TxOutpoint::new(u256::from_bytes(*entry.txid().inner()), index);
+                mempool
+                    .spender(&outpoint)
+                    .map(|spender| json!(hash_hex(spender.inner())))
+            })
+            .collect();
+        let fee = btc(entry.fee());
+        result.insert(
+            hash_hex(entry.txid().inner()),
+            json!({
+                "vsize": entry.size(),
+                "weight": entry.size() * 4,
+                "time": entry.time(),
+                "height": entry.height(),
+                "descendantcount": entry.descendant_count(),
+                "descendantsize": entry.descendant_size(),
+                "fees": {
+                    "base": fee,
+                    "modified": fee,
+                    "descendant": btc(entry.descendant_fee()),
+                },
+                "depends": depends,
+                "spentby": spent_by,
+                "wtxid": hash_hex(tx.wtxid().inner()),
+                "unbroadcast": false,
+            }),
+        );
+    }
+    Ok(Value::Object(result))
+}
+
+fn getpeerinfo(node: &Node) -> Value {
+    node.peer_info().iter().map(peer_json).collect()
+}
+
+fn peer_json(peer: &PeerInfo) -> Value {
+    let connection_type = if peer.inbound {
+        "inbound"
+    } else if peer.manual {
+        "manual"
+    } else {
+        "outbound-full-relay"
+    };
+    let mut result = json!({
+        "id": peer.id,
+        "addr": peer.address.to_string(),
+        "addrbind": peer.local_addr.to_string(),
+        "network": network_name(&peer.address),
+        "services": format!("{:016x}", peer.services),
+        "servicesnames": service_names(peer.services),
+        "relaytxes": peer.relay_txs,
+        "conntime": peer.connected,
+        "timeoffset": 0,
+        "version": peer.version,
+        "subver": peer.user_agent,
+        "inbound": peer.inbound,
+        "startingheight": peer.start_height,
+        "connection_type": connection_type,
+        "transport_protocol_type": if peer.v2 { "v2" } else { "v1" },
+        "session_id": peer.session_id.map(hex::encode).unwrap_or_default(),
+        // Not part of Core's output: whether the peer connected on the Warp port
+        "warp": peer.warp,
+    });
+    if let Some(ping) = peer.min_ping {
+        result["minping"] = json!(ping.as_secs_f64());
+    }
+    result
+}
+
+async fn getnetworkinfo(node: &Node) -> Value {
+    let config = &node.config;
+    let peers = node.peer_info();
+    let inbound = peers.iter().filter(|peer| peer.inbound).count();
+    let only_net = config.only_net();
+    let networks: Vec<Value> = [
+        (NetworkKind::Ipv4, "ipv4", config.proxy()),
+        (NetworkKind::Ipv6, "ipv6", config.proxy()),
+        (NetworkKind::Onion, "onion", config.onion_proxy()),
+    ]
+    .iter()
+    .map(|(kind, name, proxy)| {
+        let limited = !only_net.is_empty() && !only_net.contains(kind);
+        // Onion peers can only be reached through a proxy
+        let reachable = !limited && (*kind != NetworkKind::Onion || proxy.is_some());
+        json!({
+            "name": name,
+            "limited": limited,
+            "reachable": reachable,
+            "proxy": proxy.map(|proxy| proxy.to_string()).unwrap_or_default(),
+            "proxy_randomize_credentials": config.proxy_randomize(),
+        })
+    })
+    .collect();
+    let limits = node.warpd.lock().await.mempool().limits().clone();
+    json!({
+        "version": client_version(),
+        "subversion": config.user_agent(),
+        "protocolversion": config.get_protocol_version(),
+        "localservices": format!("{:016x}", config.get_services()),
+        "localservicesnames": service_names(config.get_services()),
+        "localrelay": true,
+        "timeoffset": 0,
+        "networkactive": true,
+        "connections": peers.len(),
+        "connections_in": inbound,
+        "connections_out": peers.len() - inbound,
+        "networks": networks,
+        "relayfee": fee_rate_btc(limits.min_relay_fee_rate),
+        "incrementalfee": fee_rate_btc(limits.incremental_relay_fee_rate),
+        "localaddresses": [],
+        "warnings": [],
+    })
+}
+
+fn addnode(node: &Arc<Node>, params: &Params) -> Result<Value, RpcError> {
+    let address = parse_node_address(node, params.required_str(0)?)?;
+    let command = params.required_str(1)?;
+    let mut added = node.added_nodes.lock().unwrap();
+    match command {
+        "add" => {
+            if added.contains(&address) {
+                return Err(RpcError::new(
+                    RPC_CLIENT_NODE_ALREADY_ADDED,
+                    "Error: Node already added",
+                ));
+            }
+            // The connections task connects to it on its next round
+            added.push(address);
+        }
+        "remove" => {
+            let before = added.len();
+            added.retain(|known| known != &address);
+            if added.len() == before {
+                return Err(RpcError::new(
+                    RPC_CLIENT_NODE_NOT_ADDED,
+                    "Error: Node could not be removed. It has not been added previously.",
+                ));
+            }
+        }
+        "onetry" => {
+            tokio::spawn(open_connection(node.clone(), address, true));
+        }
+        _ => {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                "command must be one of \"add\", \"remove\" or \"onetry\"",
+            ))
+        }
+    }
+    Ok(Value::Null)
+}
+
+/// Parses a node address for `addnode`, which may leave out the port
+fn parse_node_address(node: &Node, address: &str) -> Result<PeerAddress, RpcError> {
+    address
+        .parse()
+        .or_else(|_| format!("{}:{}", address, node.config.core_port()).parse())
+        .or_else(|_| format!("[{}]:{}", address, node.config.core_port()).parse())
+        .map_err(|e: String| RpcError::new(RPC_INVALID_PARAMETER, e))
+}
+
+fn disconnectnode(node: &Node, params: &Params) -> Result<Value, RpcError> {
+    let address = params.str(0)?.filter(|address| !address.is_empty());
+    let id = params.int(1)?;
+    let disconnected = match (address, id) {
+        (Some(address), None) => node.disconnect(|peer| {
+            peer.address.to_string() == address || peer.addr.to_string() == address
+        }),
+        (None, Some(id)) => node.disconnect(|peer| peer.id as i64 == id),
+        _ => {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                "Only one of address and nodeid should be provided.",
+            ))
+        }
+    };
+    if disconnected == 0 {
+        return Err(RpcError::new(
+            RPC_CLIENT_NODE_NOT_CONNECTED,
+            "Node not found in connected nodes",
+        ));
+    }
+    Ok(Value::Null)
+}
+
+async fn setban(node: &Node, params: &Params) -> Result<Value, RpcError> {
+    let subnet: Subnet = params
+        .required_str(0)?
+        .parse()
+        .map_err(|_| RpcError::new(RPC_CLIENT_INVALID_IP_OR_SUBNET, "Error: Invalid IP/Subnet"))?;
+    let command = params.required_str(1)?;
+    let mut warpd = node.warpd.lock().await;
+    match command {
+        "add" => {
+            if warpd
+                .ban_list()
+                .banned()
+                .iter()
+                .any(|(banned, _)| *banned == subnet)
+            {
+                return Err(RpcError::new(
+                    RPC_CLIENT_NODE_ALREADY_ADDED,
+                    "Error: IP/Subnet already banned",
+                ));
+            }
+            let ban_time = params.int(2)?.filter(|&time| time > 0);
+            let duration = if params.bool(3)?.unwrap_or(false) {
+                let until = ban_time.ok_or_else(|| {
+                    RpcError::new(RPC_INVALID_PARAMETER, "An absolute ban needs a bantime")
+                })? as u64;
+                until.saturating_sub(unix_time())
+            } else {
+                ban_time.map_or(DEFAULT_BAN_TIME, |time| time as u64)
+            };
+            if duration > MAX_BAN_TIME {
+                return Err(RpcError::new(
+                    RPC_INVALID_PARAMETER,
+                    format!("bantime must be at most {} seconds", MAX_BAN_TIME),
+                ));
+            }
+            warpd
+                .ban(subnet, duration)
+                .map_err(|e| RpcError::new(RPC_DATABASE_ERROR, e.to_string()))?;
+            drop(warpd);
+            node.disconnect(|peer| subnet.contains(&peer.addr.ip()));
+        }
+        "remove" => {
+            let removed = warpd
+                .unban(&subnet)
+                .map_err(|e| RpcError::new(RPC_DATABASE_ERROR, e.to_string()))?;
+            if !removed {
+                return Err(RpcError::new(
+                    RPC_CLIENT_INVALID_IP_OR_SUBNET,
+                    "Error: Unban failed. Requested address/subnet was not previously manually banned.",
+                ));
+            }
+        }
+        _ => {
+            return Err(RpcError::new(
+                RPC_INVALID_PARAMETER,
+                "command must be \"add\" or \"remove\"",
+            ))
+        }
+    }
+    Ok(Value::Null)
+}
+
+fn listbanned(warpd: &Warpd) -> Value {
+    warpd
+        .ban_list()
+        .banned()
+        .iter()
+        .map(|(subnet, until)| json!({ "address": subnet.to_string(), "banned_until": until }))
+        .collect()
+}
+
+/// Displays a hash the way Core does: byte reversed, in hex
+pub(super) fn hash_hex(hash: &[u8; 32]) -> String {
+    let mut hash = *hash;
+    hash.reverse();
+    hex::encode(hash)
+}
+
+/// Parses a hash parameter, given in display order, into internal byte order
+fn parse_hash(params: &Params, index: usize) -> Result<[u8; 32], RpcError> {
+    let name = params.names[index];
+    let value = params.required_str(index)?;
+    if value.len() != 64 {
+        return Err(RpcError::new(
+            RPC_INVALID_PARAMETER,
+            format!(
+                "{} must be of length 64 (not {}, for '{}')",
+                name,
+                value.len(),
+                value
+            ),
+        ));
+    }
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(value, &mut hash).map_err(|_| {
+        RpcError::new(
+            RPC_INVALID_PARAMETER,
+            format!("{} must be hexadecimal string (not '{}')", name, value),
+        )
+    })?;
+    hash.reverse();
+    Ok(hash)
+}
+
+pub(super) fn serialize<T: Serializable>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    value
+        .serialize(&mut bytes)
+        .expect("Serialization to vec shouldn't fail");
+    bytes
+}
+
+pub(super) fn read_block(warpd: &Warpd, hash: &BlockHash) -> Result<Block, RpcError> {
+    let blocks = warpd.block_store();
+    match blocks.map(|blocks| blocks.read_block(hash)) {
+        Some(Ok(Some(block))) => Ok(block),
+        Some(Err(e)) => Err(RpcError::new(RPC_DATABASE_ERROR, e.to_string())),
+        _ if blocks.is_some_and(|blocks| blocks.is_pruning()) => Err(RpcError::new(
+            RPC_MISC_ERROR,
+            "Block not available (pruned data)",
+        )),
+        // The genesis block is never written to the block store
+        _ => Err(RpcError::new(RPC_MISC_ERROR, "Block not available")),
+    }
+}
+
+pub(super) fn header_json(warpd: &Warpd, header: &BlockHeader, height: u32) -> Value {
+    let hash = header.hash();
+    let active = warpd.is_active(hash, height);
+    let bits = compact_bits(header);
+    let confirmations = if active {
+        (warpd.chainstate().height() - height) as i64 + 1
+    } else {
+        -1
+    };
+    let mut result = json!({
+        "hash": hash_hex(hash.inner()),
+        "confirmations": confirmations,
+        "height": height,
+        "version": header.version(),
+        "versionHex": format!("{:08x}", header.version()),
+        "merkleroot": hash_hex(header.merkle_root().inner()),
+        "time": header.raw_time(),
+        "mediantime": median_time(warpd, header),
+        "nonce": header.nonce(),
+        "bits": format!("{:08x}", bits),
+        "difficulty": difficulty(bits),
+    });
+    if height > 0 {
+        result["previousblockhash"] = json!(hash_hex(header.prev_hash().inner()));
+    }
+    if active {
+        if let Some(next) = warpd.block_hash(height + 1) {
+            result["nextblockhash"] = json!(hash_hex(next.inner()));
+        }
+    }
+    result
+}
+
+/// A block as `getblock` shows it, with its transactions decoded if `tx_details` is set and as txids otherwise
+pub(super) fn block_json(warpd: &Warpd, block: &Block, height: u32, tx_details: bool) -> Value {
+    let mut result = header_json(warpd, block.header(), height);
+    let size = block.serialized_size();
+    result["size"] = json!(size);
+    // Witnesses aren't parsed yet, so every block is treated as having none
+    result["strippedsize"] = json!(size);
+    result["weight"] = json!(size * 4);
+    result["nTx"] = json!(block.transactions().len());
+    result["tx"] = if tx_details {
+        block
+            .transactions()
+            .iter()
+            .map(|tx| tx_json(warpd, tx))
+            .collect()
+    } else {
+        block
+            .transactions()
+            .iter()
+            .map(|tx| json!(hash_hex(tx.txid().inner())))
+            .collect()
+    };
+    result
+}
+
+pub(super) fn tx_json(warpd: &Warpd, tx: &Transaction) -> Value {
+    let inputs: Vec<Value> = tx
+        .inputs()
+        .iter()
+        .enumerate()
+        .map(|(i, input)| {
+            let mut result = if input.is_coinbase_in() {
+                json!({ "coinbase": hex::encode(input.signature_script()) })
+            } else {
+                let outpoint = input.previous_outpoint();
+                json!({
+                    "txid": hash_hex(outpoint.hash().to_le_bytes()),
+                    "vout": outpoint.index(),
+                    "scriptSig": {
+                        "asm": script_to_asm(input.signature_script()),
+                        "hex": hex::encode(input.signature_script()),
+                    },
+                })
+            };
+            if !tx.witness(i).is_empty() {
+                result["txinwitness"] = tx.witness(i).iter().map(hex::encode).collect();
+            }
+            result["sequence"] = json!(input.sequence());
+            result
+        })
+        .collect();
+    let outputs: Vec<Value> = tx
+        .outputs()
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            json!({
+                "value": btc(output.value()),
+                "n": n,
+                "scriptPubKey": script_pubkey_json(warpd, output.pk_script()),
+            })
+        })
+        .collect();
+    json!({
+        "txid": hash_hex(tx.txid().inner()),
+        "hash": hash_hex(tx.wtxid().inner()),
+        "version": tx.version(),
+        "size": tx.len(),
+        "vsize": tx.vsize(),
+        "weight": tx.weight(),
+        "locktime": tx.locktime(),
+        "vin": inputs,
+        "vout": outputs,
+        "hex": hex::encode(serialize(tx)),
+    })
+}
+
+/// An output script, disassembled, with its type and address if it has one
+pub(super) fn script_pubkey_json(warpd: &Warpd, script: &[u8]) -> Value {
+    let params = warpd.config.chain_params();
+    let mut result = json!({
+        "asm": script_to_asm(script),
+        "hex": hex::encode(script),
+        "type": script_type_name(classify(script)),
+    });
+    if let Some(address) = script_to_address(
+        script,
+        params.pubkey_address_prefix,
+        params.script_address_prefix,
+        params.bech32_hrp,
+    ) {
+        result["address"] = json!(address);
+    }
+    result
+}
+
+fn script_type_name(script_type: Option<ScriptType>) -> &'static str {
+    match script_type {
+        Some(ScriptType::PubKey) => "pubkey",
+        Some(ScriptType::PubKeyHash) => "pubkeyhash",
+        Some(ScriptType::ScriptHash) => "scripthash",
+        Some(ScriptType::Multisig) => "multisig",
+        Some(ScriptType::NullData) => "nulldata",
+        Some(ScriptType::WitnessV0KeyHash) => "witness_v0_keyhash",
+        Some(ScriptType::WitnessV0ScriptHash) => "witness_v0_scripthash",
+        Some(ScriptType::Taproot) => "witness_v1_taproot",
+        Some(ScriptType::WitnessUnknown) => "witness_unknown",
+        None => "nonstandard",
+    }
+}
+
+fn network_name(address: &PeerAddress) -> &'static str {
+    match address {
+        PeerAddress::Onion { .. } => "onion",
+        PeerAddress::Ip(addr) if addr.is_ipv4() => "ipv4",
+        PeerAddress::Ip(_) => "ipv6",
+    }
+}
+
+fn service_names(services: u64) -> Vec<&'static str> {
+    const NAMES: [(u64, &str); 6] = [
+        (NODE_NETWORK, "NETWORK"),
+        (1 << 2, "BLOOM"),
+        (1 << 3, "WITNESS"),
+        (1 << 6, "COMPACT_FILTERS"),
+        (NODE_NETWORK_LIMITED, "NETWORK_LIMITED"),
+        (NODE_P2P_V2, "P2P_V2"),
+    ];
+    NAMES
+        .iter()
+        .filter(|(bit, _)| services & bit != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+/// Our version in Core's format, where 0.1.2 is 102
+fn client_version() -> u64 {
+    env!("CARGO_PKG_VERSION")
+        .split('.')
+        .take(3)
+        .fold(0, |version, part| {
+            version * 100 + part.parse::<u64>().unwrap_or(0)
+        })
+}
+
+/// An amount in satoshis, in BTC
+pub(super) fn btc(sats: i64) -> Value {
+    json!(sats as f64 / 100_000_000.0)
+}
+
+fn fee_rate_btc(rate: FeeRate) -> Value {
+    btc(rate.sat_per_kvb() as i64)
+}
+
+/// The compact `nBits` encoding of a header's target
+fn compact_bits(header: &BlockHeader) -> u32 {
+    let bytes = serialize(header);
+    u32::from_le_bytes([bytes[72], bytes[73], bytes[74], bytes[75]])
+}
+
+/// How many times harder than the minimum difficulty a target is, computed as in Core's `GetDifficulty`
+fn difficulty(bits: u32) -> f64 {
+    let mantissa = bits & 0x00ff_ffff;
+    if mantissa == 0 {
+        return 0.0;
+    }
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000_ffff as f64 / mantissa as f64;
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+    difficulty
+}
+
+/// The median timestamp of a block and the ten before it
+fn median_time(warpd: &Warpd, header: &BlockHeader) -> u32 {
+    let mut times = vec![header.raw_time()];
+    let mut prev = header.prev_hash().clone();
+    while times.len() < MEDIAN_TIME_SPAN {
+        match warpd.block_header(&prev) {
+            Some((header, _)) => {
+                times.push(header.raw_time());
+                prev = header.prev_hash().clone();
+            }
+            None => break,
+        }
+    }
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// Estimates how far through the chain we are from the tip's timestamp. Core estimates from transaction counts,
+/// which warpd doesn't keep.
+fn verification_progress(warpd: &Warpd, tip_time: u64, now: u64) -> f64 {
+    let genesis_time = warpd.config.chain_params().genesis.time as u64;
+    if tip_time + MAX_TIP_AGE >= now || now <= genesis_time {
+        return 1.0;
+    }
+    tip_time.saturating_sub(genesis_time) as f64 / (now - genesis_time) as f64
+}
@@ -0,0 +1,720 @@
+//! A JSON-RPC server compatible with Bitcoin Core's, so that `bitcoin-cli` and other Core tooling can talk to
+//! warpd unchanged.
+//!
+//! Requests are HTTP POSTs to `/`, authenticated with HTTP Basic auth. The credentials are `-rpcuser` and
+//! `-rpcpassword` if they're set. Otherwise a random password is written to the cookie file (`.cookie` in the
+//! network's data directory) as `__cookie__:<password>`, where `bitcoin-cli` finds it, and the file is deleted on
+//! shutdown. Credentials are checked before a request's body is read, so that unauthenticated clients can't make
+//! us buffer large bodies, and at most [`MAX_CONNECTIONS`] clients are served at once.
+//!
+//! Both JSON-RPC 1.0 and 2.0 are spoken, following Core: a request with `"jsonrpc": "2.0"` gets a 2.0 response
+//! and HTTP 200 even when it fails, while legacy requests which fail get an error status. Batches of requests
+//! are answered with an array of responses.
+//!
+//! The methods are implemented in [`methods`]. With `-rest`, the same port also serves the unauthenticated,
+//! read-only REST interface in [`rest`].
+mod http;
+mod methods;
+mod rest;
+
+use http::{read_body, read_head, write_response, HttpError, Request, Response};
+
+use crate::daemon::{Node, TaskResult};
+use config::Config;
+use mempool::MAX_MONEY;
+use rand::RngCore;
+use serde_json::{json, Map, Value};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tracing::{debug, info, warn};
+
+/// The user name of cookie authentication, as in Core
+const COOKIE_USER: &str = "__cookie__";
+/// How long a connection may sit idle, or take to send a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to wait before answering a request with the wrong credentials, to slow down guessing
+const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(250);
+/// The most connections served at once. Further clients wait to be accepted until one closes.
+const MAX_CONNECTIONS: usize = 32;
+
+// Error codes, from Core's `rpc/protocol.h`
+pub(crate) const RPC_INVALID_REQUEST: i32 = -32600;
+pub(crate) const RPC_METHOD_NOT_FOUND: i32 = -32601;
+pub(crate) const RPC_PARSE_ERROR: i32 = -32700;
+pub(crate) const RPC_MISC_ERROR: i32 = -1;
+pub(crate) const RPC_TYPE_ERROR: i32 = -3;
+pub(crate) const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
+pub(crate) const RPC_INVALID_PARAMETER: i32 = -8;
+pub(crate) const RPC_DATABASE_ERROR: i32 = -20;
+pub(crate) const RPC_DESERIALIZATION_ERROR: i32 = -22;
+pub(crate) const RPC_VERIFY_ERROR: i32 = -25;
+pub(crate) const RPC_VERIFY_REJECTED: i32 = -26;
+pub(crate) const RPC_CLIENT_NODE_ALREADY_ADDED: i32 = -23;
+pub(crate) const RPC_CLIENT_NODE_NOT_ADDED: i32 = -24;
+pub(crate) const RPC_CLIENT_NODE_NOT_CONNECTED: i32 = -29;
+pub(crate) const RPC_CLIENT_INVALID_IP_OR_SUBNET: i32 = -30;
+
+/// An error returned to the client in the response's `error` field
+#[derive(Debug)]
+pub(crate) struct RpcError {
+    pub(crate) code: i32,
+    pub(crate) message: String,
+}
+
+impl RpcError {
+    pub(crate) fn new<S: Into<String>>(code: i32, message: S) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "code": self.code, "message": self.message })
+    }
+}
+
+/// The credentials requests must carry
+pub(crate) struct Auth {
+    user: String,
+    password: String,
+    /// The cookie file, if we wrote one
+    cookie: Option<PathBuf>,
+}
+
+impl Auth {
+    /// Uses the configured credentials, or writes a cookie file with a random password if there are none
+    pub(crate) fn new(config: &Config) -> std::io::Result<Auth> {
+        if let Some((user, password)) = config.rpc_credentials() {
+            return Ok(Auth {
+                user: String::from(user),
+                password: String::from(password),
+                cookie: None,
+            });
+        }
+        let mut secret = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let password = hex::encode(secret);
+        let path = config.rpc_cookie_file();
+        write_cookie(&path, &format!("{}:{}", COOKIE_USER, password))?;
+        info!("Wrote the RPC cookie to {}", path.display());
+        Ok(Auth {
+            user: String::from(COOKIE_USER),
+            password,
+            cookie: Some(path),
+        })
+    }
+
+    /// Checks the `Authorization` header of a request
+    fn check(&self, authorization: Option<&str>) -> bool {
+        let credentials = match authorization
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| base64_decode(encoded.trim()))
+        {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        let expected = format!("{}:{}", self.user, self.password);
+        constant_time_eq(&credentials, expected.as_bytes())
+    }
+
+    pub(crate) fn remove_cookie(&self) {
+        if let Some(path) = self.cookie.as_ref() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Could not remove the RPC cookie {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Writes the cookie so that only the user running warpd can read it
+fn write_cookie(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let tmp = path.with_extension("tmp");
+    options.open(&tmp)?.write_all(contents.as_bytes())?;
+    std::fs::rename(&tmp, path)
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a' + 26) as u32),
+            b'0'..=b'9' => Some((c - b'0' + 52) as u32),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    if encoded.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        let mut bits = 0u32;
+        for &c in chunk {
+            bits = (bits << 6) | value(c)?;
+        }
+        bits <<= 6 * (4 - chunk.len()) as u32;
+        decoded.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(decoded)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Answers RPC requests on `-rpcbind`:`-rpcport` until shutdown
+pub(crate) async fn serve(node: Arc<Node>, auth: Arc<Auth>) -> TaskResult {
+    let addr = SocketAddr::new(node.config.rpc_bind(), node.config.rpc_port() as u16);
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("could not listen for RPC requests on {}: {}", addr, e))?;
+    info!("Listening for RPC requests on {}", addr);
+    let connections = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+    loop {
+        let (permit, (connection, remote)) = tokio::select! {
+            accepted = async {
+                let permit = connections.clone().acquire_owned().await.expect("The semaphore is never closed");
+                Ok::<_, std::io::Error>((permit, listener.accept().await?))
+            } => accepted.map_err(|e| e.to_string())?,
+            _ = node.stopped() => return Ok(()),
+        };
+        let (node, auth) = (node.clone(), auth.clone());
+        tokio::spawn(async move {
+            handle_connection(node, auth, connection, remote).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Who a request is from, which decides how much of a body we're willing to read
+enum Access {
+    /// A request to the REST interface, which needs no credentials
+    Rest,
+    /// An RPC call with the right credentials
+    Authorized,
+    /// An RPC call without them, whose body is never read
+    Denied,
+}
+
+/// Reads the next request, checking its credentials before its body. Returns `None` if the client closed the
+/// connection between requests.
+async fn read_request<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    auth: &Auth,
+    rest: bool,
+) -> Result<Option<(Request, Access)>, HttpError> {
+    let mut request = match read_head(reader).await? {
+        Some(request) => request,
+        None => return Ok(None),
+    };
+    let access = if rest && request.target.starts_with("/rest/") {
+        read_body(reader, &mut request, rest::MAX_BODY_SIZE).await?;
+        Access::Rest
+    } else if auth.check(request.header("Authorization")) {
+        read_body(reader, &mut request, http::MAX_BODY_SIZE).await?;
+        Access::Authorized
+    } else {
+        Access::Denied
+    };
+    Ok(Some((request, access)))
+}
+
+async fn handle_connection(
+    node: Arc<Node>,
+    auth: Arc<Auth>,
+    connection: TcpStream,
+    remote: SocketAddr,
+) {
+    let (reader, mut writer) = connection.into_split();
+    let mut reader = BufReader::new(reader);
+    loop {
+        let read = tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            read_request(&mut reader, &auth, node.config.rest()),
+        );
+        let (request, access) = tokio::select! {
+            read = read => match read {
+                Ok(Ok(Some(read))) => read,
+                Ok(Ok(None)) | Err(_) => return,
+                Ok(Err(e)) => {
+                    debug!("Bad RPC request from {}: {}", remote, e);
+                    if let Some(status) = e.status() {
+                        let _ = write_response(&mut writer, &Response::text(status, &e.to_string()), false).await;
+                    }
+                    return;
+                }
+            },
+            _ = node.stopped() => return,
+        };
+        let mut keep_alive = request.keep_alive();
+        let response = match access {
+            Access::Rest => rest::handle(&node, &request).await,
+            Access::Authorized => handle_request(&node, request).await,
+            Access::Denied => {
+                warn!("Incorrect RPC password from {}", remote);
+                tokio::time::sleep(AUTH_FAILURE_DELAY).await;
+                // The body is still unread, so the connection can't be used for another request
+                keep_alive = false;
+                let mut response = Response::empty(401);
+                response
+                    .headers
+                    .push(("WWW-Authenticate", String::from("Basic realm=\"jsonrpc\"")));
+                response
+            }
+        };
+        if write_response(&mut writer, &response, keep_alive)
+            .await
+            .is_err()
+            || !keep_alive
+        {
+            return;
+        }
+    }
+}
+
+async fn handle_request(node: &Arc<Node>, request: Request) -> Response {
+    if request.method != "POST" {
+        return Response::text(405, "JSONRPC server handles only POST requests");
+    }
+    // Core serves wallet requests under /wallet/<name>; warpd has no wallet, so they fail as unknown methods
+    if request.target != "/" && !request.target.starts_with("/wallet/") {
+        return Response::empty(404);
+    }
+    let body: Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(_) => {
+            let error = RpcError::new(RPC_PARSE_ERROR, "Parse error");
+            return Response::json(500, &legacy_reply(Value::Null, Err(error)));
+        }
+    };
+    match body {
+        Value::Array(batch) => {
+            let mut replies = Vec::with_capacity(batch.len());
+            for call in batch {
+                if let Some((_, reply)) = handle_call(node, call).await {
+                    replies.push(reply);
+                }
+            }
+            Response::json(200, &Value::Array(replies))
+        }
+        call => match handle_call(node, call).await {
+            Some((status, reply)) => Response::json(status, &reply),
+            None => Response::empty(204),
+        },
+    }
+}
+
+/// Runs one call, returning the HTTP status and reply. JSON-RPC 2.0 notifications get no reply.
+async fn handle_call(node: &Arc<Node>, call: Value) -> Option<(u16, Value)> {
+    let mut call = match call {
+        Value::Object(call) => call,
+        _ => {
+            let error = RpcError::new(RPC_INVALID_REQUEST, "Invalid Request object");
+            return Some((400, legacy_reply(Value::Null, Err(error))));
+        }
+    };
+    let v2 = call.get("jsonrpc").and_then(Value::as_str) == Some("2.0");
+    let id = call.remove("id");
+    if v2 && id.is_none() {
+        // A notification: the caller doesn't want to hear back, even about failures
+        let _ = execute(node, call).await;
+        return None;
+    }
+    let id = id.unwrap_or(Value::Null);
+    let result = execute(node, call).await;
+    if v2 {
+        return Some((200, v2_reply(id, result)));
+    }
+    let status = match result {
+        Ok(_) => 200,
+        Err(RpcError {
+            code: RPC_INVALID_REQUEST,
+            ..
+        }) => 400,
+        Err(RpcError {
+            code: RPC_METHOD_NOT_FOUND,
+            ..
+        }) => 404,
+        Err(_) => 500,
+    };
+    Some((status, legacy_reply(id, result)))
+}
+
+async fn execute(node: &Arc<Node>, mut call: Map<String, Value>) -> Result<Value, RpcError> {
+    let method = match call.remove("method") {
+        Some(Value::String(method)) => method,
+        Some(_) => {
+            return Err(RpcError::new(
+                RPC_INVALID_REQUEST,
+                "Method must be a string",
+            ))
+        }
+        None => return Err(RpcError::new(RPC_INVALID_REQUEST, "Missing method")),
+    };
+    let names = methods::METHODS
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map(|(_, params)| *params)
+        .ok_or_else(|| RpcError::new(RPC_METHOD_NOT_FOUND, "Method not found"))?;
+    let params = Params::new(&method, names, call.remove("params"))?;
+    debug!("RPC call {}", method);
+    methods::call(node, &method, params).await
+}
+
+fn legacy_reply(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result, "error": null, "id": id }),
+        Err(error) => json!({ "result": null, "error": error.to_json(), "id": id }),
+    }
+}
+
+fn v2_reply(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => json!({ "jsonrpc": "2.0", "error": error.to_json(), "id": id }),
+    }
+}
+
+/// A call's parameters, in the order the method declares them. Named parameters are put in their place, and
+/// missing ones are left `null`.
+pub(crate) struct Params {
+    names: &'static [&'static str],
+    values: Vec<Value>,
+}
+
+impl Params {
+    fn new(
+        method: &str,
+        names: &'static [&'static str],
+        params: Option<Value>,
+    ) -> Result<Params, RpcError> {
+        let values = match params {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(values)) => {
+                if values.len() > names.len() {
+                    return Err(RpcError::new(
+                        RPC_MISC_ERROR,
+                        format!(
+                            "{} takes at most {} parameters, but was given {}",
+                            method,
+                            names.len(),
+                            values.len()
+                        ),
+                    ));
+                }
+                values
+            }
+            Some(Value::Object(named)) => {
+                let mut values = vec![Value::Null; names.len()];
+                for (name, value) in named {
+                    let position =
+                        names
+                            .iter()
+                            .position(|&known| known == name)
+                            .ok_or_else(|| {
+                                RpcError::new(
+                                    RPC_INVALID_PARAMETER,
+                                    format!("Unknown named parameter {}", name),
+                                )
+                            })?;
+                    values[position] = value;
+                }
+                values
+            }
+            Some(_) => {
+                return Err(RpcError::new(
+                    RPC_INVALID_REQUEST,
+                    "Params must be an array or object",
+                ))
+            }
+        };
+        Ok(Params { names, values })
+    }
+
+    /// A parameter, or `None` if it's missing or `null`
+    pub(crate) fn get(&self, index: usize) -> Option<&Value> {
+        self.values.get(index).filter(|value| !value.is_null())
+    }
+
+    fn missing(&self, index: usize) -> RpcError {
+        RpcError::new(
+            RPC_MISC_ERROR,
+            format!("Missing required parameter {}", self.names[index]),
+        )
+    }
+
+    pub(crate) fn str(&self, index: usize) -> Result<Option<&str>, RpcError> {
+        match self.get(index) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value)),
+            Some(value) => Err(type_error(value, "string")),
+        }
+    }
+
+    pub(crate) fn required_str(&self, index: usize) -> Result<&str, RpcError> {
+        self.str(index)?.ok_or_else(|| self.missing(index))
+    }
+
+    pub(crate) fn int(&self, index: usize) -> Result<Option<i64>, RpcError> {
+        match self.get(index) {
+            None => Ok(None),
+            Some(value) => value
+                .as_i64()
+                .map(Some)
+                .ok_or_else(|| type_error(value, "number")),
+        }
+    }
+
+    pub(crate) fn required_int(&self, index: usize) -> Result<i64, RpcError> {
+        self.int(index)?.ok_or_else(|| self.missing(index))
+    }
+
+    pub(crate) fn bool(&self, index: usize) -> Result<Option<bool>, RpcError> {
+        match self.get(index) {
+            None => Ok(None),
+            Some(Value::Bool(value)) => Ok(Some(*value)),
+            Some(value) => Err(type_error(value, "bool")),
+        }
+    }
+
+    /// An amount of BTC in satoshis, which Core accepts as a number or a string
+    pub(crate) fn amount(&self, index: usize) -> Result<Option<i64>, RpcError> {
+        let btc = match self.get(index) {
+            None => return Ok(None),
+            Some(Value::Number(btc)) => btc.as_f64(),
+            Some(Value::String(btc)) => btc.parse::<f64>().ok(),
+            Some(value) => return Err(type_error(value, "number")),
+        };
+        let sats = btc
+            .map(|btc| (btc * 100_000_000.0).round())
+            .ok_or_else(|| RpcError::new(RPC_TYPE_ERROR, "Invalid amount"))?;
+        if !(0.0..=MAX_MONEY as f64).contains(&sats) {
+            return Err(RpcError::new(RPC_TYPE_ERROR, "Amount out of range"));
+        }
+        Ok(Some(sats as i64))
+    }
+
+    /// A verbosity level, which Core accepts as a number or a bool
+    pub(crate) fn verbosity(&self, index: usize, default: i64) -> Result<i64, RpcError> {
+        match self.get(index) {
+            None => Ok(default),
+            Some(Value::Bool(verbose)) => Ok(*verbose as i64),
+            Some(value) => value.as_i64().ok_or_else(|| type_error(value, "number")),
+        }
+    }
+}
+
+fn type_error(value: &Value, expected: &str) -> RpcError {
+    let actual = match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    };
+    RpcError::new(
+        RPC_TYPE_ERROR,
+        format!(
+            "JSON value of type {} is not of expected type {}",
+            actual, expected
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Warpd;
+
+    fn auth() -> Auth {
+        Auth {
+            user: String::from("user"),
+            password: String::from("pass"),
+            cookie: None,
+        }
+    }
+
+    fn node() -> Arc<Node> {
+        let config = Config::regtest();
+        Arc::new(Node::new(config.clone(), Warpd::with_config(config)))
+    }
+
+    async fn post(node: &Arc<Node>, body: &str) -> Response {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let (request, access) = read_request(&mut raw.as_bytes(), &auth(), false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(access, Access::Authorized));
+        handle_request(node, request).await
+    }
+
+    fn reply(response: &Response) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(base64_decode("dXNlcjpwYXNz").unwrap(), b"user:pass");
+        assert_eq!(base64_decode("YQ==").unwrap(), b"a");
+        assert_eq!(base64_decode("YWI=").unwrap(), b"ab");
+        assert_eq!(base64_decode("YWJj").unwrap(), b"abc");
+        assert_eq!(base64_decode("+/8=").unwrap(), [0xfb, 0xff]);
+        assert_eq!(base64_decode("").unwrap(), b"");
+        assert!(base64_decode("YWJjZ").is_none());
+        assert!(base64_decode("YW*j").is_none());
+    }
+
+    #[test]
+    fn checks_credentials() {
+        let auth = auth();
+        assert!(auth.check(Some("Basic dXNlcjpwYXNz")));
+        assert!(auth.check(Some("Basic dXNlcjpwYXNz ")));
+        // user:wrong, and the right credentials under another scheme
+        assert!(!auth.check(Some("Basic dXNlcjp3cm9uZw==")));
+        assert!(!auth.check(Some("Bearer dXNlcjpwYXNz")));
+        assert!(!auth.check(Some("Basic not base64!")));
+        assert!(!auth.check(None));
+    }
+
+    #[tokio::test]
+    async fn reads_bodies_only_for_allowed_requests() {
+        // Without credentials, even a huge body is never read
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 1000000000\r\n\r\n";
+        let (request, access) = read_request(&mut raw.as_bytes(), &auth(), true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(access, Access::Denied));
+        assert!(request.body.is_empty());
+
+        let raw = "POST / HTTP/1.1\r\nAuthorization: Basic dXNlcjpwYXNz\r\nContent-Length: 1000000000\r\n\r\n";
+        let read = read_request(&mut raw.as_bytes(), &auth(), true).await;
+        assert!(matches!(read, Err(HttpError::TooLarge)));
+
+        // The REST interface needs no credentials, but gets a much smaller limit
+        let raw = format!(
+            "POST /rest/getutxos.bin HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            rest::MAX_BODY_SIZE + 1
+        );
+        let read = read_request(&mut raw.as_bytes(), &auth(), true).await;
+        assert!(matches!(read, Err(HttpError::TooLarge)));
+        // ...and is only served with -rest
+        let (_, access) = read_request(&mut raw.as_bytes(), &auth(), false)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(access, Access::Denied));
+    }
+
+    #[test]
+    fn places_named_and_positional_params() {
+        let names = &["hexstring", "maxfeerate"];
+        let params = Params::new("sendrawtransaction", names, Some(json!(["00", 0.1]))).unwrap();
+        assert_eq!(params.required_str(0).unwrap(), "00");
+        assert_eq!(params.amount(1).unwrap(), Some(10_000_000));
+
+        let params = Params::new(
+            "sendrawtransaction",
+            names,
+            Some(json!({ "maxfeerate": "0.5", "hexstring": "00" })),
+        )
+        .unwrap();
+        assert_eq!(params.required_str(0).unwrap(), "00");
+        assert_eq!(params.amount(1).unwrap(), Some(50_000_000));
+
+        let params = Params::new("sendrawtransaction", names, Some(json!({}))).unwrap();
+        assert_eq!(params.amount(1).unwrap(), None);
+        assert_eq!(params.required_str(0).unwrap_err().code, RPC_MISC_ERROR);
+
+        let error = Params::new("sendrawtransaction", names, Some(json!(["00", 0, 1])));
+        assert_eq!(error.err().unwrap().code, RPC_MISC_ERROR);
+        let error = Params::new("sendrawtransaction", names, Some(json!({ "fee": 1 })));
+        assert_eq!(error.err().unwrap().code, RPC_INVALID_PARAMETER);
+        let error = Params::new("sendrawtransaction", names, Some(json!("00")));
+        assert_eq!(error.err().unwrap().code, RPC_INVALID_REQUEST);
+
+        let params = Params::new("sendrawtransaction", names, Some(json!([1, -1]))).unwrap();
+        assert_eq!(params.required_str(0).unwrap_err().code, RPC_TYPE_ERROR);
+        assert_eq!(params.amount(1).unwrap_err().code, RPC_TYPE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn maps_failures_to_statuses() {
+        let node = node();
+        let response = post(&node, r#"{"method":"getblockcount","id":1}"#).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            reply(&response),
+            json!({ "result": 0, "error": null, "id": 1 })
+        );
+
+        // JSON-RPC 1.0 failures get an error status...
+        let response = post(&node, r#"{"method":"nonexistent","id":1}"#).await;
+        assert_eq!(response.status, 404);
+        assert_eq!(reply(&response)["error"]["code"], RPC_METHOD_NOT_FOUND);
+        let response = post(&node, r#"{"method":"getblockhash","params":[1],"id":1}"#).await;
+        assert_eq!(response.status, 500);
+        let response = post(&node, r#"{"method":1,"id":1}"#).await;
+        assert_eq!(response.status, 400);
+        let response = post(&node, "not json").await;
+        assert_eq!(response.status, 500);
+        assert_eq!(reply(&response)["error"]["code"], RPC_PARSE_ERROR);
+
+        // ...while 2.0 ones are still 200, and notifications aren't answered at all
+        let response = post(&node, r#"{"jsonrpc":"2.0","method":"nonexistent","id":1}"#).await;
+        assert_eq!(response.status, 200);
+        let reply = reply(&response);
+        assert_eq!(reply["jsonrpc"], "2.0");
+        assert_eq!(reply["error"]["code"], RPC_METHOD_NOT_FOUND);
+        assert!(reply.get("result").is_none());
+        let response = post(&node, r#"{"jsonrpc":"2.0","method":"getblockcount"}"#).await;
+        assert_eq!(response.status, 204);
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_batches() {
+        let node = node();
+        let response = post(
+            &node,
+            r#"[
+                {"method":"getblockcount","id":"a"},
+                {"jsonrpc":"2.0","method":"getblockcount"},
+                {"method":"nonexistent","id":"b"},
+                1
+            ]"#,
+        )
+        .await;
+        // Failures in a batch don't change its status
+        assert_eq!(response.status, 200);
+        let replies = reply(&response);
+        let replies = replies.as_array().unwrap();
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0], json!({ "result": 0, "error": null, "id": "a" }));
+        assert_eq!(replies[1]["id"], "b");
+        assert_eq!(replies[1]["error"]["code"], RPC_METHOD_NOT_FOUND);
+        assert_eq!(replies[2]["error"]["code"], RPC_INVALID_REQUEST);
+    }
+}
@@ -0,0 +1,598 @@
+//! A read-only REST interface modeled on Core's `/rest/`, for clients like indexers which fetch a lot of raw
+//! data and would rather skip JSON-RPC. It's served on the RPC port when `-rest` is set, without
+//! authentication, so it only exposes public data.
+//!
+//! Every resource ends in its format: `.bin` for the consensus serialization, `.hex` for the same in hex and
+//! `.json` for what the matching RPC method returns.
+//!
+//! - `/rest/block/<hash>.<format>`, or `/rest/block/notxdetails/<hash>.<format>` to list only txids
+//! - `/rest/headers/<hash>.<format>?count=<n>`, or `/rest/headers/<n>/<hash>.<format>`: up to `n` headers of
+//!   the active chain, starting at `hash`
+//! - `/rest/tx/<txid>.<format>`, from the mempool, or the transaction index for confirmed transactions
+//! - `/rest/getutxos[/checkmempool]/<txid>-<n>/....<format>`: which outpoints are unspent, as in BIP64. With
+//!   `.bin` and `.hex`, the request may instead be POSTed as the serialized `checkmempool` flag and outpoints.
+//! - `/rest/chaininfo.json`, as `getblockchaininfo`
+use super::methods::{
+    block_json, btc, getblockchaininfo, hash_hex, header_json, read_block, script_pubkey_json,
+    serialize, tx_json,
+};
+use super::*;
+use crate::Warpd;
+use shared::{
+    u256, BlockHash, CompactInt, Deserializable, Serializable, TxID, TxOutpoint, TxOutput,
+};
+
+/// The most headers one request returns (Core's `MAX_REST_HEADERS_RESULTS`)
+const MAX_HEADERS_RESULTS: usize = 2000;
+/// The number of headers returned if the request doesn't say
+const DEFAULT_HEADERS_COUNT: &str = "5";
+/// The most outpoints one `getutxos` request may ask about (Core's `MAX_GETUTXOS_OUTPOINTS`)
+const MAX_GETUTXOS_OUTPOINTS: usize = 15;
+/// The largest body a request may have, which is plenty for a hex encoded `getutxos` request. The REST interface
+/// is unauthenticated, so this is kept far below what RPC calls may send.
+pub(super) const MAX_BODY_SIZE: usize = 4 * 1024;
+/// The height reported for outputs of mempool transactions (Core's `MEMPOOL_HEIGHT`)
+const MEMPOOL_HEIGHT: u32 = 0x7fff_ffff;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Binary,
+    Hex,
+    Json,
+}
+
+/// Answers a request whose target starts with `/rest/`
+pub(super) async fn handle(node: &Node, request: &Request) -> Response {
+    if request.method != "GET" && request.method != "POST" {
+        return Response::text(405, "REST requests must be GETs");
+    }
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (request.target.as_str(), None),
+    };
+    let path = path.strip_prefix("/rest/").unwrap_or(path);
+    let (resource, format) = match path.rsplit_once('.') {
+        Some((resource, "bin")) => (resource, Format::Binary),
+        Some((resource, "hex")) => (resource, Format::Hex),
+        Some((resource, "json")) => (resource, Format::Json),
+        _ => {
+            return error(
+                404,
+                "output format not found (available: .bin, .hex, .json)",
+            )
+        }
+    };
+    let warpd = node.warpd.lock().await;
+    let (kind, rest) = resource.split_once('/').unwrap_or((resource, ""));
+    match kind {
+        "block" => match rest.strip_prefix("notxdetails/") {
+            Some(hash) => block(&warpd, hash, format, false),
+            None => block(&warpd, rest, format, true),
+        },
+        "headers" => {
+            let (count, hash) = match rest.split_once('/') {
+                Some((count, hash)) => (count, hash),
+                None => (
+                    query_param(query, "count").unwrap_or(DEFAULT_HEADERS_COUNT),
+                    rest,
+                ),
+            };
+            headers(&warpd, count, hash, format)
+        }
+        "tx" => tx(&warpd, rest, format),
+        "getutxos" => getutxos(&warpd, rest, &request.body, format),
+        "chaininfo" if rest.is_empty() => match format {
+            Format::Json => Response::json(200, &getblockchaininfo(&warpd)),
+            _ => error(404, "output format not found (available: json)"),
+        },
+        _ => Response::empty(404),
+    }
+}
+
+fn block(warpd: &Warpd, hash: &str, format: Format, tx_details: bool) -> Response {
+    let hash = match parse_hash(hash) {
+        Some(hash) => BlockHash::from(hash),
+        None => return error(400, &format!("Invalid hash: {}", hash)),
+    };
+    let height = match warpd.block_header(&hash) {
+        Some((_, height)) => height,
+        None => return error(404, &format!("{} not found", hash_hex(hash.inner()))),
+    };
+    let block = match read_block(warpd, &hash) {
+        Ok(block) => block,
+        Err(e) if e.code == RPC_DATABASE_ERROR => return error(500, &e.message),
+        Err(e) => return error(404, &e.message),
+    };
+    reply(
+        format,
+        || serialize(&block),
+        || block_json(warpd, &block, height, tx_details),
+    )
+}
+
+fn headers(warpd: &Warpd, count: &str, hash: &str, format: Format) -> Response {
+    let count = match count.parse::<usize>() {
+        Ok(count) if (1..=MAX_HEADERS_RESULTS).contains(&count) => count,
+        _ => {
+            return error(
+                400,
+                &format!(
+                    "Header count is invalid or out of acceptable range (1-{}): {}",
+                    MAX_HEADERS_RESULTS, count
+                ),
+            )
+        }
+    };
+    let hash = match parse_hash(hash) {
+        Some(hash) => BlockHash::from(hash),
+        None => return error(400, &format!("Invalid hash: {}", hash)),
+    };
+    // Like Core, a block which isn't on the active chain has no headers to follow it
+    let headers: Vec<_> = match warpd.block_header(&hash) {
+        Some((_, height)) => {
+            let hashes = warpd.block_hashes(height, count);
+            if hashes.first() == Some(&hash) {
+                hashes
+                    .iter()
+                    .filter_map(|hash| warpd.block_header(hash))
+                    .collect()
+            } else {
+                Vec::new()
+            }
+        }
+        None => Vec::new(),
+    };
+    reply(
+        format,
+        || {
+            headers
+                .iter()
+                .flat_map(|(header, _)| serialize(header))
+                .collect()
+        },
+        || {
+            headers
+                .iter()
+                .map(|(header, height)| header_json(warpd, header, *height))
+                .collect()
+        },
+    )
+}
+
+fn tx(warpd: &Warpd, txid: &str, format: Format) -> Response {
+    let txid = match parse_hash(txid) {
+        Some(txid) => TxID::from(txid),
+        None => return error(400, &format!("Invalid hash: {}", txid)),
+    };
+    let not_found = || error(404, &format!("{} not found", hash_hex(txid.inner())));
+    let (tx, block_hash) = match warpd.mempool().get(&txid) {
+        Some(entry) => (entry.tx().clone(), None),
+        None => match warpd.get_transaction(&txid) {
+            Ok(Some(tx)) => (tx, warpd.transaction_block(&txid).ok().flatten()),
+            Ok(None) | Err(storage::StorageError::Unsupported(_)) => return not_found(),
+            Err(e) => return error(500, &e.to_string()),
+        },
+    };
+    reply(
+        format,
+        || serialize(&tx),
+        || {
+            let mut result = tx_json(warpd, &tx);
+            if let Some(block_hash) = block_hash.as_ref() {
+                result["blockhash"] = json!(hash_hex(block_hash.inner()));
+            }
+            result
+        },
+    )
+}
+
+/// Reports which of the requested outpoints are unspent, as described in BIP64
+fn getutxos(warpd: &Warpd, path: &str, body: &[u8], format: Format) -> Response {
+    let mut parts = path.split('/').filter(|part| !part.is_empty()).peekable();
+    let mut check_mempool = parts.next_if_eq(&"checkmempool").is_some();
+    let mut outpoints = Vec::new();
+    for part in parts {
+        match parse_outpoint(part) {
+            Some(outpoint) => outpoints.push(outpoint),
+            None => return error(400, "Parse error"),
+        }
+    }
+
+    // Binary and hex requests may be POSTed instead, serialized as the checkmempool flag and the outpoints
+    if format != Format::Json && !body.is_empty() {
+        if !outpoints.is_empty() {
+            return error(
+                400,
+                "Combination of URI scheme inputs and raw post data is not allowed",
+            );
+        }
+        let decoded;
+        let mut body = match format {
+            Format::Hex => match std::str::from_utf8(body)
+                .ok()
+                .and_then(|body| hex::decode(body.trim()).ok())
+            {
+                Some(bytes) => {
+                    decoded = bytes;
+                    &decoded[..]
+                }
+                None => return error(400, "Parse error"),
+            },
+            _ => body,
+        };
+        match parse_outpoints(&mut body) {
+            Ok((check, posted)) => {
+                check_mempool = check;
+                outpoints = posted;
+            }
+            Err(response) => return response,
+        }
+    }
+
+    if outpoints.is_empty() {
+        return error(400, "Error: empty request");
+    }
+    if outpoints.len() > MAX_GETUTXOS_OUTPOINTS {
+        return too_many_outpoints(outpoints.len());
+    }
+
+    let mempool = warpd.mempool();
+    let coins: Vec<Option<(u32, TxOutput)>> = outpoints
+        .iter()
+        .map(|outpoint| {
+            if check_mempool && mempool.spender(outpoint).is_some() {
+                return None;
+            }
+            if let Some(coin) = warpd.chainstate().utxos().get(outpoint) {
+                return Some((coin.height(), coin.output().clone()));
+            }
+            if !check_mempool {
+                return None;
+            }
+            let txid = TxID::from(*outpoint.hash().to_le_bytes());
+            mempool
+                .get(&txid)
+                .and_then(|entry| entry.tx().outputs().get(outpoint.index() as usize))
+                .map(|output| (MEMPOOL_HEIGHT, output.clone()))
+        })
+        .collect();
+
+    let height = warpd.chainstate().height();
+    let tip = warpd.chainstate().tip();
+    let unspent = || coins.iter().flatten();
+    reply(
+        format,
+        || {
+            let mut bitmap = vec![0u8; coins.len().div_ceil(8)];
+            for (i, coin) in coins.iter().enumerate() {
+                if coin.is_some() {
+                    bitmap[i / 8] |= 1 << (i % 8);
+                }
+            }
+            let mut bytes = Vec::new();
+            (height as i32).serialize(&mut bytes).unwrap();
+            tip.serialize(&mut bytes).unwrap();
+            bitmap.serialize(&mut bytes).unwrap();
+            CompactInt::from(unspent().count())
+                .serialize(&mut bytes)
+                .unwrap();
+            for (height, output) in unspent() {
+                // Core's CCoin starts with an unused transaction version
+                0u32.serialize(&mut bytes).unwrap();
+                height.serialize(&mut bytes).unwrap();
+                output.serialize(&mut bytes).unwrap();
+            }
+            bytes
+        },
+        || {
+            let bitmap: String = coins
+                .iter()
+                .map(|coin| if coin.is_some() { '1' } else { '0' })
+                .collect();
+            let utxos: Vec<Value> = unspent()
+                .map(|(height, output)| {
+                    json!({
+                        "height": height,
+                        "value": btc(output.value()),
+                        "scriptPubKey": script_pubkey_json(warpd, output.pk_script()),
+                    })
+                })
+                .collect();
+            json!({
+                "chainHeight": height,
+                "chaintipHash": hash_hex(tip.inner()),
+                "bitmap": bitmap,
+                "utxos": utxos,
+            })
+        },
+    )
+}
+
+/// Reads a POSTed `getutxos` request: the checkmempool flag, then a vector of outpoints
+fn parse_outpoints(body: &mut &[u8]) -> Result<(bool, Vec<TxOutpoint>), Response> {
+    let parse_error = |_| error(400, "Parse error");
+    let check_mempool = bool::deserialize(&mut *body).map_err(parse_error)?;
+    let count = CompactInt::deserialize(&mut *body)
+        .map_err(parse_error)?
+        .value() as usize;
+    // Checked before reading, so that a huge count can't make us allocate
+    if count > MAX_GETUTXOS_OUTPOINTS {
+        return Err(too_many_outpoints(count));
+    }
+    let outpoints = (0..count)
+        .map(|_| TxOutpoint::deserialize(&mut *body))
+        .collect::<Result<_, _>>()
+        .map_err(parse_error)?;
+    Ok((check_mempool, outpoints))
+}
+
+fn too_many_outpoints(count: usize) -> Response {
+    error(
+        400,
+        &format!(
+            "Error: max outpoints exceeded (max: {}, tried: {})",
+            MAX_GETUTXOS_OUTPOINTS, count
+        ),
+    )
+}
+
+/// Encodes a resource in the requested format. Only the encoding which is needed gets built.
+fn reply<B, J>(format: Format, bytes: B, json: J) -> Response
+where
+    B: FnOnce() -> Vec<u8>,
+    J: FnOnce() -> Value,
+{
+    match format {
+        Format::Binary => Response::new(200, "application/octet-stream", bytes()),
+        Format::Hex => {
+            let mut body = hex::encode(bytes()).into_bytes();
+            body.push(b'\n');
+            Response::new(200, "text/plain", body)
+        }
+        Format::Json => Response::json(200, &json()),
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::text(status, message)
+}
+
+/// Parses a hash given in display order into internal byte order
+fn parse_hash(hash: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    hex::decode_to_slice(hash, &mut bytes).ok()?;
+    bytes.reverse();
+    Some(bytes)
+}
+
+/// Parses an outpoint written as `<txid>-<n>`
+fn parse_outpoint(outpoint: &str) -> Option<TxOutpoint> {
+    let (txid, index) = outpoint.split_once('-')?;
+    Some(TxOutpoint::new(
+        u256::from_bytes(parse_hash(txid)?),
+        index.parse().ok()?,
+    ))
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::{Chainstate, Coin, UtxoSet};
+
+    /// A regtest node at genesis, with one unspent output: `outpoint()`, which pays 1 BTC at height 0
+    fn node() -> Arc<Node> {
+        let config = Config::regtest();
+        let mut warpd = Warpd::with_config(config.clone());
+        let mut utxos = UtxoSet::new();
+        utxos.insert(
+            outpoint(),
+            Coin::new(TxOutput::new(100_000_000, vec![0x51]), 0, false),
+        );
+        let tip = warpd.chainstate().tip().clone();
+        warpd.chainstate = Chainstate::new(utxos, tip, 0);
+        Arc::new(Node::new(config, warpd))
+    }
+
+    fn outpoint() -> TxOutpoint {
+        TxOutpoint::new(u256::from_bytes([1; 32]), 0)
+    }
+
+    fn genesis() -> String {
+        hash_hex(&Config::regtest().chain_params().genesis.hash)
+    }
+
+    async fn request(node: &Node, method: &str, target: &str, body: &[u8]) -> Response {
+        let mut raw = format!(
+            "{} {} HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            method,
+            target,
+            body.len()
+        )
+        .into_bytes();
+        raw.extend_from_slice(body);
+        let mut raw = &raw[..];
+        let mut request = http::read_head(&mut raw).await.unwrap().unwrap();
+        http::read_body(&mut raw, &mut request, MAX_BODY_SIZE)
+            .await
+            .unwrap();
+        handle(node, &request).await
+    }
+
+    async fn get(node: &Node, target: &str) -> Response {
+        request(node, "GET", target, b"").await
+    }
+
+    fn json_body(response: &Response) -> Value {
+        assert_eq!(response.status, 200);
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn parses_paths_and_formats() {
+        let node = node();
+        let response = get(&node, "/rest/chaininfo.json").await;
+        assert_eq!(json_body(&response)["chain"], "regtest");
+        assert_eq!(get(&node, "/rest/chaininfo.bin").await.status, 404);
+        assert_eq!(get(&node, "/rest/chaininfo").await.status, 404);
+        assert_eq!(get(&node, "/rest/chaininfo.xml").await.status, 404);
+        assert_eq!(get(&node, "/rest/nonexistent.json").await.status, 404);
+        assert_eq!(
+            request(&node, "PUT", "/rest/chaininfo.json", b"")
+                .await
+                .status,
+            405
+        );
+
+        let response = get(&node, &format!("/rest/block/{}.json", "zz")).await;
+        assert_eq!(response.status, 400);
+        let response = get(&node, &format!("/rest/tx/{}.hex", genesis())).await;
+        assert_eq!(response.status, 404);
+
+        assert_eq!(
+            parse_hash(&genesis()),
+            Some(Config::regtest().chain_params().genesis.hash)
+        );
+        assert_eq!(parse_hash("00"), None);
+        assert_eq!(
+            parse_outpoint(&format!("{}-1", genesis())).unwrap().index(),
+            1
+        );
+        assert!(parse_outpoint(&genesis()).is_none());
+        assert!(parse_outpoint(&format!("{}-x", genesis())).is_none());
+        assert_eq!(query_param(Some("a=1&count=7"), "count"), Some("7"));
+        assert_eq!(query_param(Some("counter=7"), "count"), None);
+        assert_eq!(query_param(None, "count"), None);
+    }
+
+    #[tokio::test]
+    async fn serves_headers_by_path_or_query() {
+        let node = node();
+        let by_query = get(&node, &format!("/rest/headers/{}.json?count=3", genesis())).await;
+        let by_path = get(&node, &format!("/rest/headers/3/{}.json", genesis())).await;
+        // There's only the genesis block to return
+        let headers = json_body(&by_query);
+        assert_eq!(headers, json_body(&by_path));
+        assert_eq!(headers.as_array().unwrap().len(), 1);
+        assert_eq!(headers[0]["hash"], genesis());
+        let default = get(&node, &format!("/rest/headers/{}.json", genesis())).await;
+        assert_eq!(json_body(&default), headers);
+
+        let binary = get(&node, &format!("/rest/headers/1/{}.bin", genesis())).await;
+        assert_eq!(binary.status, 200);
+        assert_eq!(binary.body.len(), 80);
+        let hex = get(&node, &format!("/rest/headers/1/{}.hex", genesis())).await;
+        assert_eq!(
+            hex.body,
+            format!("{}\n", hex::encode(&binary.body)).into_bytes()
+        );
+
+        for count in ["0", "2001", "many"] {
+            let target = format!("/rest/headers/{}/{}.json", count, genesis());
+            assert_eq!(get(&node, &target).await.status, 400);
+            let target = format!("/rest/headers/{}.json?count={}", genesis(), count);
+            assert_eq!(get(&node, &target).await.status, 400);
+        }
+        // Unknown blocks have no headers
+        let target = format!("/rest/headers/1/{}.json", hash_hex(&[2; 32]));
+        assert_eq!(json_body(&get(&node, &target).await), json!([]));
+    }
+
+    /// A POSTed `getutxos` request for `outpoints`
+    fn getutxos_body(check_mempool: bool, outpoints: &[TxOutpoint]) -> Vec<u8> {
+        let mut body = Vec::new();
+        check_mempool.serialize(&mut body).unwrap();
+        CompactInt::from(outpoints.len())
+            .serialize(&mut body)
+            .unwrap();
+        for outpoint in outpoints {
+            outpoint.serialize(&mut body).unwrap();
+        }
+        body
+    }
+
+    #[tokio::test]
+    async fn decodes_posted_getutxos_requests() {
+        let node = node();
+        let spent = TxOutpoint::new(u256::from_bytes([2; 32]), 0);
+        let body = getutxos_body(false, &[outpoint(), spent.clone(), outpoint()]);
+        let response = request(&node, "POST", "/rest/getutxos.bin", &body).await;
+        assert_eq!(response.status, 200);
+        let mut reply = &response.body[..];
+        assert_eq!(i32::deserialize(&mut reply).unwrap(), 0);
+        assert_eq!(
+            BlockHash::deserialize(&mut reply).unwrap().inner(),
+            &Config::regtest().chain_params().genesis.hash
+        );
+        assert_eq!(Vec::<u8>::deserialize(&mut reply).unwrap(), vec![0b101]);
+        assert_eq!(CompactInt::deserialize(&mut reply).unwrap().value(), 2);
+        for _ in 0..2 {
+            assert_eq!(u32::deserialize(&mut reply).unwrap(), 0);
+            assert_eq!(u32::deserialize(&mut reply).unwrap(), 0);
+            let output = TxOutput::deserialize(&mut reply).unwrap();
+            assert_eq!(output.value(), 100_000_000);
+        }
+        assert!(reply.is_empty());
+
+        // The same request in hex gets the same answer in hex
+        let hex_body = hex::encode(&body);
+        let hex = request(&node, "POST", "/rest/getutxos.hex", hex_body.as_bytes()).await;
+        assert_eq!(
+            hex.body,
+            format!("{}\n", hex::encode(&response.body)).into_bytes()
+        );
+
+        // ...and the same request in the path gets the same answer
+        let target = format!(
+            "/rest/getutxos/{}-0/{}-0/{}-0.bin",
+            hash_hex(&[1; 32]),
+            hash_hex(&[2; 32]),
+            hash_hex(&[1; 32])
+        );
+        assert_eq!(get(&node, &target).await.body, response.body);
+        let target = format!("/rest/getutxos/checkmempool/{}-0.json", hash_hex(&[1; 32]));
+        let reply = json_body(&get(&node, &target).await);
+        assert_eq!(reply["bitmap"], "1");
+        assert_eq!(reply["utxos"][0]["value"], 1.0);
+
+        let single = getutxos_body(false, &[outpoint()]);
+        let target = format!("/rest/getutxos/{}-0.bin", hash_hex(&[1; 32]));
+        assert_eq!(request(&node, "POST", &target, &single).await.status, 400);
+        let too_many = getutxos_body(false, &vec![outpoint(); MAX_GETUTXOS_OUTPOINTS + 1]);
+        let response = request(&node, "POST", "/rest/getutxos.bin", &too_many).await;
+        assert_eq!(response.status, 400);
+        // The count is checked before the outpoints are read
+        let mut huge = Vec::new();
+        false.serialize(&mut huge).unwrap();
+        CompactInt::from(usize::MAX >> 1)
+            .serialize(&mut huge)
+            .unwrap();
+        assert_eq!(
+            request(&node, "POST", "/rest/getutxos.bin", &huge)
+                .await
+                .status,
+            400
+        );
+        let truncated = &body[..body.len() - 1];
+        assert_eq!(
+            request(&node, "POST", "/rest/getutxos.bin", truncated)
+                .await
+                .status,
+            400
+        );
+        assert_eq!(
+            request(&node, "POST", "/rest/getutxos.hex", b"zz")
+                .await
+                .status,
+            400
+        );
+        assert_eq!(get(&node, "/rest/getutxos.bin").await.status, 400);
+    }
+}
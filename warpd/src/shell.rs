@@ -1,9 +1,13 @@
 pub mod shell {
     use crate::Warpd;
+    use config::Config;
     use networking::Message;
     use networking::Peer;
+    use networking::{Subnet, DEFAULT_BAN_TIME};
+    use shared::TxID;
     use std::io::BufRead;
     use std::io::Write;
+    use storage::snapshot::SnapshotFormat;
     use tokio::sync::mpsc::UnboundedSender;
     struct StdReader {
         instream: std::io::BufReader<std::io::Stdin>,
@@ -92,7 +96,7 @@ pub mod shell {
         }
     }
 
-    pub async fn run_shell() -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn run_shell(config: Config) -> Result<(), Box<dyn std::error::Error>> {
         // let mut raw_input = String::new();
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
         let (quitter, quit_recv) = tokio::sync::oneshot::channel();
@@ -104,22 +108,30 @@ pub mod shell {
         let h1 = tokio::task::spawn_blocking(|| async move {
             std_reader.run();
         });
-        let h2 = tokio::spawn(async move { main_loop(rx, quitter).await });
+        let h2 = tokio::spawn(async move { main_loop(config, rx, quitter).await });
         let (_, _) = tokio::join!(h2, h1.await.expect("Couldn't run stdReader"));
         Ok(())
     }
 
     pub async fn main_loop(
+        config: Config,
         mut rx: tokio::sync::mpsc::UnboundedReceiver<String>,
         quitter: tokio::sync::oneshot::Sender<()>,
     ) {
-        let mut warpd = Warpd::new();
+        let mut warpd = Warpd::with_config(config);
+        if let Err(e) = warpd.open_data_dir() {
+            eprintln!("could not open the data directory: {}", e);
+            std::process::exit(1);
+        }
         print!("          <Welcome to ");
         loop {
             write_prompt("warp shell>");
             let input = rx.recv().await.expect("Nothing received");
             match input.trim_end() {
                 "exit" | "q" => {
+                    if let Err(e) = warpd.shutdown() {
+                        println!("could not save state: {}", e);
+                    }
                     let _ = quitter.send(());
                     break;
                 }
@@ -130,14 +142,98 @@ pub mod shell {
                     println!("   a: add a peer (outbound)");
                     println!("   l: listen for connection (add peer - inbound)");
                     println!("   p: switch to peer mode");
+                    println!("   dumptxoutset: write a snapshot of the UTXO set");
+                    println!(
+                        "   loadtxoutset: load a UTXO snapshot and validate it in the background"
+                    );
+                    println!(
+                        "   gettransaction: look up a confirmed transaction (requires the txindex)"
+                    );
+                    println!("   setban: ban or unban a subnet");
+                    println!("   listbanned: list banned subnets");
                     println!("");
                 }
+                "dumptxoutset" => {
+                    write_prompt("  enter a path for the snapshot:");
+                    let path = rx.recv().await.expect("Nothing received");
+                    write_prompt("  format ('warp' or 'core'):");
+                    let format = match rx.recv().await.expect("Nothing received").trim_end() {
+                        "core" => SnapshotFormat::Core,
+                        _ => SnapshotFormat::Warp,
+                    };
+                    match warpd.dump_utxo_snapshot(path.trim_end(), format) {
+                        Ok(mut hash) => {
+                            hash.reverse();
+                            println!("  wrote snapshot with hash {}\n", hex::encode(hash));
+                        }
+                        Err(e) => println!("could not write snapshot: {}\n", e),
+                    }
+                }
+                "loadtxoutset" => {
+                    write_prompt("  enter the path of the snapshot:");
+                    let path = rx.recv().await.expect("Nothing received");
+                    match warpd.load_utxo_snapshot(path.trim_end()) {
+                        Ok(metadata) => println!(
+                            "  loaded {} coins at height {}\n",
+                            metadata.coins_count(),
+                            metadata.base_height()
+                        ),
+                        Err(e) => println!("could not load snapshot: {}\n", e),
+                    }
+                }
+                "gettransaction" => {
+                    write_prompt("  enter a txid:");
+                    let input = rx.recv().await.expect("Nothing received");
+                    let mut txid = [0u8; 32];
+                    if hex::decode_to_slice(input.trim_end(), &mut txid).is_err() {
+                        println!("could not interpret {} as a txid\n", input.trim_end());
+                        continue;
+                    }
+                    txid.reverse();
+                    match warpd.get_transaction(&TxID::from(txid)) {
+                        Ok(Some(tx)) => println!("  {:#?}\n", tx),
+                        Ok(None) => println!("  transaction not found\n"),
+                        Err(e) => println!("could not look up transaction: {}\n", e),
+                    }
+                }
+                "setban" => {
+                    write_prompt("  enter a subnet (i.e. 192.168.0.0/16):");
+                    let input = rx.recv().await.expect("Nothing received");
+                    let subnet: Subnet = match input.trim_end().parse() {
+                        Ok(subnet) => subnet,
+                        Err(e) => {
+                            println!("{}\n", e);
+                            continue;
+                        }
+                    };
+                    write_prompt("  'add' or 'remove':");
+                    let result = match rx.recv().await.expect("Nothing received").trim_end() {
+                        "remove" => warpd.unban(&subnet).map(|removed| {
+                            if !removed {
+                                println!("  {} was not banned", subnet);
+                            }
+                        }),
+                        _ => warpd.ban(subnet, DEFAULT_BAN_TIME),
+                    };
+                    match result {
+                        Ok(()) => println!(),
+                        Err(e) => println!("could not save ban list: {}\n", e),
+                    }
+                }
+                "listbanned" => {
+                    for (subnet, until) in warpd.ban_list().banned() {
+                        println!("  {} until {}", subnet, until);
+                    }
+                    println!();
+                }
                 "add" | "a" | "connect" | "c" => {
-                    write_prompt("  enter an ip address and port (i.e. 127.0.0.1:8333):");
+                    write_prompt(
+                        "  enter an ip address or onion address and port (i.e. 127.0.0.1:8333):",
+                    );
                     let input = rx.recv().await.expect("Nothing received");
-                    if let Ok(addr) = input.trim_end().parse() {
+                    if let Ok(addr) = input.trim_end().parse::<networking::PeerAddress>() {
                         println!("  connecting...");
-                        if let Err(e) = warpd.add_peer(addr).await {
+                        if let Err(e) = warpd.add_peer(addr.clone()).await {
                             println!("could not connect to peer: {}", e.to_string());
                             continue;
                         } else {
@@ -0,0 +1,322 @@
+//! Downloading blocks from peers.
+//!
+//! Peers announce blocks with `inv` messages, either unprompted or in answer to a `getblocks` carrying our block
+//! locator. [`BlockSync`] queues the announced blocks we don't have, asks the peers which announced them for up to
+//! [`MAX_BLOCKS_IN_FLIGHT`] at a time, and holds on to blocks which arrive before their parents have been connected.
+//! A peer which doesn't deliver a block within [`BLOCK_TIMEOUT`] is reported as stalling, and the block is asked
+//! for again.
+//!
+//! While a loaded UTXO snapshot is being validated, blocks are connected to the background chain as well as the
+//! active one. Blocks which build on neither tip are dropped, so reorgs aren't handled.
+use shared::{Block, BlockHash};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The number of blocks which may be requested but not yet connected at any time
+pub(crate) const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+/// How long a peer has to deliver a block we asked it for
+pub(crate) const BLOCK_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for the answer to a `getblocks` before asking again
+pub(crate) const LOCATOR_TIMEOUT: Duration = Duration::from_secs(30);
+/// The number of announced blocks to remember. Two answers to `getblocks` fit, and peers announcing hashes which
+/// don't exist can't make the queue grow without bound.
+const MAX_QUEUED_BLOCKS: usize = 1000;
+
+#[derive(Debug, Default)]
+pub(crate) struct BlockSync {
+    /// Announced blocks which haven't been requested yet, in the order they were announced
+    queue: VecDeque<BlockHash>,
+    /// Requested blocks, with the peer they were requested from and when
+    in_flight: HashMap<BlockHash, (SocketAddr, Instant)>,
+    /// Received blocks which are waiting for their parent to be connected, by the hash of the parent
+    waiting: HashMap<BlockHash, (SocketAddr, Block)>,
+    /// The peer our last `getblocks` went to, and when
+    locator_request: Option<(SocketAddr, Instant)>,
+}
+
+impl BlockSync {
+    pub(crate) fn new() -> BlockSync {
+        BlockSync::default()
+    }
+
+    /// Queues the blocks `peer` announced, skipping those we already have or know about
+    pub(crate) fn blocks_announced<F>(&mut self, peer: SocketAddr, hashes: Vec<BlockHash>, have: F)
+    where
+        F: Fn(&BlockHash) -> bool,
+    {
+        if self.locator_request.map(|(asked, _)| asked) == Some(peer) {
+            self.locator_request = None;
+        }
+        for hash in hashes {
+            if self.queue.len() >= MAX_QUEUED_BLOCKS {
+                break;
+            }
+            if !have(&hash) && !self.knows(&hash) {
+                self.queue.push_back(hash);
+            }
+        }
+    }
+
+    fn knows(&self, hash: &BlockHash) -> bool {
+        self.in_flight.contains_key(hash)
+            || self.queue.contains(hash)
+            || self
+                .waiting
+                .values()
+                .any(|(_, block)| block.header().hash() == hash)
+    }
+
+    /// Takes queued blocks off the queue while there is room for them in flight, and groups them by the peer
+    /// `choose_peer` picks to request them from. Blocks no connected peer has are dropped; they will be announced
+    /// again.
+    pub(crate) fn next_requests<F>(
+        &mut self,
+        now: Instant,
+        mut choose_peer: F,
+    ) -> Vec<(SocketAddr, Vec<BlockHash>)>
+    where
+        F: FnMut(&BlockHash) -> Option<SocketAddr>,
+    {
+        let mut requests: Vec<(SocketAddr, Vec<BlockHash>)> = Vec::new();
+        while self.in_flight.len() + self.waiting.len() < MAX_BLOCKS_IN_FLIGHT {
+            let hash = match self.queue.pop_front() {
+                Some(hash) => hash,
+                None => break,
+            };
+            let peer = match choose_peer(&hash) {
+                Some(peer) => peer,
+                None => continue,
+            };
+            self.in_flight.insert(hash.clone(), (peer, now));
+            match requests.iter_mut().find(|(asked, _)| *asked == peer) {
+                Some((_, hashes)) => hashes.push(hash),
+                None => requests.push((peer, vec![hash])),
+            }
+        }
+        requests
+    }
+
+    /// Accepts a block we asked for, returning `false` if we didn't ask for it
+    pub(crate) fn block_received(&mut self, peer: SocketAddr, block: Block) -> bool {
+        if self.in_flight.remove(block.header().hash()).is_none() {
+            return false;
+        }
+        self.waiting
+            .insert(block.header().prev_hash().clone(), (peer, block));
+        true
+    }
+
+    /// Forgets blocks `peer` told us it doesn't have. They were most likely never announced to us.
+    pub(crate) fn not_found(&mut self, peer: SocketAddr, hashes: &[BlockHash]) {
+        for hash in hashes {
+            if self.in_flight.get(hash).map(|(asked, _)| *asked) == Some(peer) {
+                self.in_flight.remove(hash);
+            }
+        }
+    }
+
+    /// A received block which builds on one of `tips`, and the peer which sent it
+    pub(crate) fn take_child(&mut self, tips: &[&BlockHash]) -> Option<(SocketAddr, Block)> {
+        tips.iter().find_map(|tip| self.waiting.remove(*tip))
+    }
+
+    /// Drops received blocks which can never be connected to a chain ending at one of `tips`, because their parent
+    /// is neither a tip nor a block we are still downloading. Returns the number dropped.
+    pub(crate) fn discard_unconnectable(&mut self, tips: &[&BlockHash]) -> usize {
+        let mut connectable: Vec<BlockHash> = tips.iter().map(|tip| (*tip).clone()).collect();
+        connectable.extend(self.in_flight.keys().cloned());
+        connectable.extend(self.queue.iter().cloned());
+        // Blocks building on a connectable block are connectable themselves
+        let mut checked = 0;
+        while checked < connectable.len() {
+            if let Some((_, block)) = self.waiting.get(&connectable[checked]) {
+                connectable.push(block.header().hash().clone());
+            }
+            checked += 1;
+        }
+        let before = self.waiting.len();
+        self.waiting.retain(|prev, _| connectable.contains(prev));
+        before - self.waiting.len()
+    }
+
+    /// Returns the requests which timed out to the queue, and the peers which were asked for them
+    pub(crate) fn stalled_peers(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let stalled: Vec<(BlockHash, SocketAddr)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested))| now.saturating_duration_since(*requested) > BLOCK_TIMEOUT)
+            .map(|(hash, (peer, _))| (hash.clone(), *peer))
+            .collect();
+        let mut peers = Vec::new();
+        for (hash, peer) in stalled {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    /// Returns the blocks requested from a disconnected peer to the queue
+    pub(crate) fn peer_disconnected(&mut self, peer: &SocketAddr) {
+        let requested: Vec<BlockHash> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (asked, _))| asked == peer)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in requested {
+            self.in_flight.remove(&hash);
+            self.queue.push_front(hash);
+        }
+        if self.locator_request.map(|(asked, _)| asked) == Some(*peer) {
+            self.locator_request = None;
+        }
+    }
+
+    /// Whether we have run out of blocks to download, and should ask a peer for more with `getblocks`
+    pub(crate) fn wants_blocks(&self, now: Instant) -> bool {
+        let waiting_for_locator = match self.locator_request {
+            Some((_, sent)) => now.saturating_duration_since(sent) < LOCATOR_TIMEOUT,
+            None => false,
+        };
+        self.queue.is_empty()
+            && self.in_flight.is_empty()
+            && self.waiting.is_empty()
+            && !waiting_for_locator
+    }
+
+    pub(crate) fn locator_sent(&mut self, peer: SocketAddr, now: Instant) {
+        self.locator_request = Some((peer, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::{u256, BlockHeader, MerkleRoot, Nbits};
+
+    fn block(prev: &BlockHash, nonce: u32) -> Block {
+        let mut header = BlockHeader::new(
+            1,
+            prev.clone(),
+            MerkleRoot::from_u64(0),
+            0,
+            Nbits::new(u256::from(1)),
+            nonce,
+        );
+        header.set_hash();
+        Block::new(header, Vec::new())
+    }
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 8333))
+    }
+
+    #[test]
+    fn requests_announced_blocks_and_connects_them_in_order() {
+        let genesis = BlockHash::from([0; 32]);
+        let first = block(&genesis, 1);
+        let second = block(first.header().hash(), 2);
+        let hashes = vec![
+            first.header().hash().clone(),
+            second.header().hash().clone(),
+        ];
+        let mut sync = BlockSync::new();
+        assert!(sync.wants_blocks(Instant::now()));
+        sync.blocks_announced(peer(1), hashes.clone(), |hash| hash == &genesis);
+        // Announcing the same blocks again doesn't queue them twice
+        sync.blocks_announced(peer(2), hashes.clone(), |_| false);
+        assert!(!sync.wants_blocks(Instant::now()));
+
+        let requests = sync.next_requests(Instant::now(), |_| Some(peer(1)));
+        assert_eq!(requests, vec![(peer(1), hashes)]);
+        assert!(sync
+            .next_requests(Instant::now(), |_| Some(peer(1)))
+            .is_empty());
+
+        assert!(sync.block_received(peer(1), second));
+        assert!(sync.take_child(&[&genesis]).is_none());
+        assert!(sync.block_received(peer(1), first.clone()));
+        assert!(!sync.block_received(peer(1), first.clone()));
+        let (sender, connected) = sync.take_child(&[&genesis]).unwrap();
+        assert_eq!(sender, peer(1));
+        assert_eq!(connected.header().hash(), first.header().hash());
+        assert!(sync.take_child(&[first.header().hash()]).is_some());
+        assert!(sync.wants_blocks(Instant::now()));
+    }
+
+    #[test]
+    fn limits_blocks_in_flight() {
+        let hashes: Vec<BlockHash> = (0..MAX_BLOCKS_IN_FLIGHT as u64 + 4)
+            .map(BlockHash::from_u64)
+            .collect();
+        let mut sync = BlockSync::new();
+        sync.blocks_announced(peer(1), hashes, |_| false);
+        let requests = sync.next_requests(Instant::now(), |_| Some(peer(1)));
+        assert_eq!(requests[0].1.len(), MAX_BLOCKS_IN_FLIGHT);
+        // Nobody has the blocks which didn't fit any more
+        sync.in_flight.clear();
+        assert!(sync.next_requests(Instant::now(), |_| None).is_empty());
+        assert!(sync.wants_blocks(Instant::now()));
+    }
+
+    #[test]
+    fn stalled_requests_go_to_another_peer() {
+        let hash = BlockHash::from_u64(1);
+        let mut sync = BlockSync::new();
+        let start = Instant::now();
+        sync.blocks_announced(peer(1), vec![hash.clone()], |_| false);
+        sync.next_requests(start, |_| Some(peer(1)));
+        assert!(sync.stalled_peers(start + BLOCK_TIMEOUT).is_empty());
+        let later = start + BLOCK_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(sync.stalled_peers(later), vec![peer(1)]);
+        let requests = sync.next_requests(later, |_| Some(peer(2)));
+        assert_eq!(requests, vec![(peer(2), vec![hash.clone()])]);
+
+        sync.peer_disconnected(&peer(2));
+        let requests = sync.next_requests(later, |_| Some(peer(3)));
+        assert_eq!(requests, vec![(peer(3), vec![hash.clone()])]);
+        sync.not_found(peer(3), &[hash]);
+        assert!(sync.wants_blocks(later));
+    }
+
+    #[test]
+    fn discards_blocks_which_cannot_connect() {
+        let tip = BlockHash::from_u64(1);
+        let missing = BlockHash::from_u64(2);
+        let child = block(&tip, 1);
+        let grandchild = block(child.header().hash(), 2);
+        let orphan = block(&missing, 3);
+        let mut sync = BlockSync::new();
+        let hashes = [&child, &grandchild, &orphan]
+            .iter()
+            .map(|block| block.header().hash().clone())
+            .collect();
+        sync.blocks_announced(peer(1), hashes, |_| false);
+        sync.next_requests(Instant::now(), |_| Some(peer(1)));
+        assert!(sync.block_received(peer(1), grandchild));
+        assert!(sync.block_received(peer(1), orphan));
+        // The grandchild's parent is still in flight
+        assert_eq!(sync.discard_unconnectable(&[&tip]), 1);
+        assert!(sync.block_received(peer(1), child));
+        assert_eq!(sync.discard_unconnectable(&[&tip]), 0);
+        assert!(sync.take_child(&[&missing]).is_none());
+    }
+
+    #[test]
+    fn locator_requests_wait_for_an_answer() {
+        let mut sync = BlockSync::new();
+        let start = Instant::now();
+        sync.locator_sent(peer(1), start);
+        assert!(!sync.wants_blocks(start));
+        assert!(sync.wants_blocks(start + LOCATOR_TIMEOUT));
+        // An answer from anyone else doesn't count
+        sync.blocks_announced(peer(2), Vec::new(), |_| false);
+        assert!(!sync.wants_blocks(start));
+        sync.blocks_announced(peer(1), Vec::new(), |_| false);
+        assert!(sync.wants_blocks(start));
+    }
+}
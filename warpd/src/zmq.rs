@@ -0,0 +1,767 @@
+//! ZMQ notifications of new blocks and transactions, published like Core's `-zmqpub<topic>` options so that
+//! subscribers written for Core work unchanged.
+//!
+//! Every message has three frames: the topic, a body, and the number of messages published on that topic and
+//! socket before it, as a 4 byte little endian integer, so that subscribers can tell when they've missed some.
+//! The bodies are:
+//!
+//! - `hashblock`: the hash of each block connected to the tip
+//! - `hashtx`: the txid of each transaction added to the mempool, or in a connected or disconnected block
+//! - `rawblock`, `rawtx`: the serialized blocks and transactions of `hashblock` and `hashtx`
+//! - `sequence`: a block hash or txid, then a label: `C` when a block is connected, `D` when it's disconnected,
+//!   and `A` or `R` when a transaction is added to or removed from the mempool. `A` and `R` are followed by the
+//!   mempool sequence number as 8 little endian bytes, which continues from `getrawmempool`'s
+//!   `mempool_sequence`. Transactions which leave the mempool because they were confirmed get no `R`.
+//!
+//! Hashes are in the byte order they're displayed in. Messages are sent over ZMTP 3.0 with NULL security,
+//! speaking just enough of it to be a PUB socket. As with a ZMQ PUB socket, a subscriber which falls more than
+//! [`HIGH_WATER_MARK`] messages behind misses messages rather than holding the node up.
+use crate::daemon::{Node, TaskResult};
+use crate::Notification;
+use config::{ZmqEndpoint, ZmqTopic};
+use shared::Serializable;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// How many messages a subscriber may fall behind by before it misses some (Core's `DEFAULT_ZMQ_SNDHWM`)
+const HIGH_WATER_MARK: usize = 1000;
+/// How long a subscriber may take to complete the ZMTP handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Subscribers only send us subscriptions, so anything bigger than this is a misbehaving peer
+const MAX_FRAME_SIZE: u64 = 4096;
+/// How long to wait after failing to accept a connection, so that running out of file descriptors doesn't spin
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// The most distinct topic prefixes one subscriber may be subscribed to. Further subscriptions are ignored.
+const MAX_SUBSCRIPTIONS: usize = 64;
+
+// ZMTP frame flags
+const MORE: u8 = 0x01;
+const LONG: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+
+/// A message's frames: the topic, the body and the sequence number
+type Message = Arc<[Vec<u8>; 3]>;
+
+/// A topic published on a socket, with the sequence number of its next message
+struct Publisher {
+    topic: ZmqTopic,
+    socket: usize,
+    sequence: u32,
+}
+
+impl Publisher {
+    /// The messages a notification produces on this publisher's topic, numbered in the order they're sent
+    fn messages(&mut self, notification: &Notification) -> Vec<Message> {
+        bodies(self.topic, notification)
+            .into_iter()
+            .map(|body| {
+                let message = Arc::new([
+                    self.topic.name().as_bytes().to_vec(),
+                    body,
+                    self.sequence.to_le_bytes().to_vec(),
+                ]);
+                self.sequence = self.sequence.wrapping_add(1);
+                message
+            })
+            .collect()
+    }
+}
+
+/// The tasks accepting subscribers, which stop along with the `zmq` task
+struct Listeners {
+    tasks: Vec<JoinHandle<()>>,
+    /// IPC sockets, which are removed once nothing is listening on them
+    paths: Vec<std::path::PathBuf>,
+}
+
+impl Drop for Listeners {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+        for path in self.paths.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Publishes the notifications sent by [`crate::Warpd::notify`] on the sockets set up with `-zmqpub<topic>`
+pub(crate) async fn publish(
+    node: Arc<Node>,
+    notifications: Arc<Mutex<mpsc::UnboundedReceiver<Notification>>>,
+) -> TaskResult {
+    let mut notifications = notifications.lock().await;
+    let mut listeners = Listeners {
+        tasks: Vec::new(),
+        paths: Vec::new(),
+    };
+    // Topics published on the same endpoint share a socket
+    let mut sockets: Vec<(&ZmqEndpoint, broadcast::Sender<Message>)> = Vec::new();
+    let mut publishers = Vec::new();
+    for (topic, endpoint) in node.config.zmq_notifications() {
+        let socket = match sockets.iter().position(|(known, _)| *known == endpoint) {
+            Some(socket) => socket,
+            None => {
+                let (messages, _) = broadcast::channel(HIGH_WATER_MARK);
+                listen(endpoint, messages.clone(), &mut listeners).await?;
+                sockets.push((endpoint, messages));
+                sockets.len() - 1
+            }
+        };
+        info!(
+            "Publishing ZMQ {} notifications on {}",
+            topic.name(),
+            endpoint
+        );
+        publishers.push(Publisher {
+            topic: *topic,
+            socket,
+            sequence: 0,
+        });
+    }
+
+    loop {
+        let notification = tokio::select! {
+            notification = notifications.recv() => notification.ok_or("the node stopped sending notifications")?,
+            _ = node.stopped() => return Ok(()),
+        };
+        for publisher in publishers.iter_mut() {
+            for message in publisher.messages(&notification) {
+                // This only fails if nobody is subscribed, in which case the message is dropped as ZMQ would
+                let _ = sockets[publisher.socket].1.send(message);
+            }
+        }
+    }
+}
+
+/// The bodies of the messages a notification produces on `topic`, in the order they're sent
+fn bodies(topic: ZmqTopic, notification: &Notification) -> Vec<Vec<u8>> {
+    match (topic, notification) {
+        (ZmqTopic::HashBlock, Notification::BlockConnected(block)) => {
+            vec![display_order(block.header().hash().inner())]
+        }
+        (ZmqTopic::RawBlock, Notification::BlockConnected(block)) => vec![serialize(block)],
+        (ZmqTopic::HashTx, Notification::BlockConnected(block))
+        | (ZmqTopic::HashTx, Notification::BlockDisconnected(block)) => block
+            .transactions()
+            .iter()
+            .map(|tx| display_order(tx.txid().inner()))
+            .collect(),
+        (ZmqTopic::RawTx, Notification::BlockConnected(block))
+        | (ZmqTopic::RawTx, Notification::BlockDisconnected(block)) => {
+            block.transactions().iter().map(serialize).collect()
+        }
+        (ZmqTopic::HashTx, Notification::TransactionAdded { tx, .. }) => {
+            vec![display_order(tx.txid().inner())]
+        }
+        (ZmqTopic::RawTx, Notification::TransactionAdded { tx, .. }) => vec![serialize(tx)],
+        (ZmqTopic::Sequence, Notification::BlockConnected(block)) => {
+            vec![sequence_body(block.header().hash().inner(), b'C', None)]
+        }
+        (ZmqTopic::Sequence, Notification::BlockDisconnected(block)) => {
+            vec![sequence_body(block.header().hash().inner(), b'D', None)]
+        }
+        (
+            ZmqTopic::Sequence,
+            Notification::TransactionAdded {
+                tx,
+                mempool_sequence,
+            },
+        ) => vec![sequence_body(
+            tx.txid().inner(),
+            b'A',
+            Some(*mempool_sequence),
+        )],
+        (
+            ZmqTopic::Sequence,
+            Notification::TransactionRemoved {
+                txid,
+                mempool_sequence,
+            },
+        ) => vec![sequence_body(txid.inner(), b'R', Some(*mempool_sequence))],
+        _ => Vec::new(),
+    }
+}
+
+fn sequence_body(hash: &[u8; 32], label: u8, mempool_sequence: Option<u64>) -> Vec<u8> {
+    let mut body = display_order(hash);
+    body.push(label);
+    if let Some(sequence) = mempool_sequence {
+        body.extend_from_slice(&sequence.to_le_bytes());
+    }
+    body
+}
+
+fn display_order(hash: &[u8; 32]) -> Vec<u8> {
+    hash.iter().rev().copied().collect()
+}
+
+fn serialize<T: Serializable>(value: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    value
+        .serialize(&mut bytes)
+        .expect("Serialization to vec shouldn't fail");
+    bytes
+}
+
+/// Binds `endpoint` and starts accepting subscribers to the messages sent on `messages`
+async fn listen(
+    endpoint: &ZmqEndpoint,
+    messages: broadcast::Sender<Message>,
+    listeners: &mut Listeners,
+) -> TaskResult {
+    let bind_error = |e: std::io::Error| format!("could not listen on {}: {}", endpoint, e);
+    match endpoint {
+        ZmqEndpoint::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await.map_err(bind_error)?;
+            listeners.tasks.push(tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, remote)) => {
+                            let _ = stream.set_nodelay(true);
+                            tokio::spawn(serve_subscriber(
+                                stream,
+                                remote.to_string(),
+                                messages.subscribe(),
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Could not accept a ZMQ subscriber: {}", e);
+                            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        }
+                    }
+                }
+            }));
+        }
+        #[cfg(unix)]
+        ZmqEndpoint::Ipc(path) => {
+            // Like ZMQ, take over a socket left behind by a process which didn't clean up
+            let _ = std::fs::remove_file(path);
+            let listener = tokio::net::UnixListener::bind(path).map_err(bind_error)?;
+            listeners.paths.push(path.clone());
+            let name = path.display().to_string();
+            listeners.tasks.push(tokio::spawn(async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            tokio::spawn(serve_subscriber(
+                                stream,
+                                name.clone(),
+                                messages.subscribe(),
+                            ));
+                        }
+                        Err(e) => {
+                            warn!("Could not accept a ZMQ subscriber: {}", e);
+                            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                        }
+                    }
+                }
+            }));
+        }
+        #[cfg(not(unix))]
+        ZmqEndpoint::Ipc(_) => {
+            return Err(format!(
+                "could not listen on {}: IPC sockets need Unix",
+                endpoint
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// What a subscriber's connection asks of the task sending it messages
+enum Request {
+    Subscribe(Vec<u8>),
+    Unsubscribe(Vec<u8>),
+    /// A ZMTP 3.1 heartbeat, to be answered with `PONG` and this context
+    Ping(Vec<u8>),
+}
+
+/// The topic prefixes a subscriber wants messages for. As in ZMQ, subscribing to a prefix twice takes two
+/// cancellations to undo.
+#[derive(Default)]
+struct Subscriptions(Vec<(Vec<u8>, usize)>);
+
+impl Subscriptions {
+    /// Adds a subscription, returning `false` if the subscriber already has too many to add a new prefix
+    fn subscribe(&mut self, topic: Vec<u8>) -> bool {
+        if let Some((_, count)) = self.0.iter_mut().find(|(known, _)| *known == topic) {
+            *count = count.saturating_add(1);
+            return true;
+        }
+        if self.0.len() >= MAX_SUBSCRIPTIONS {
+            return false;
+        }
+        self.0.push((topic, 1));
+        true
+    }
+
+    fn unsubscribe(&mut self, topic: &[u8]) {
+        if let Some(i) = self.0.iter().position(|(known, _)| *known == topic) {
+            self.0[i].1 -= 1;
+            if self.0[i].1 == 0 {
+                self.0.remove(i);
+            }
+        }
+    }
+
+    fn matches(&self, topic: &[u8]) -> bool {
+        self.0.iter().any(|(prefix, _)| topic.starts_with(prefix))
+    }
+}
+
+/// Sends a subscriber the messages matching its subscriptions until it disconnects or the publisher stops
+async fn serve_subscriber<S>(stream: S, peer: String, mut messages: broadcast::Receiver<Message>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut writer = BufWriter::new(writer);
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut reader, &mut writer)).await {
+        Ok(Ok(())) => debug!("ZMQ subscriber {} connected", peer),
+        Ok(Err(e)) => return debug!("ZMQ handshake with {} failed: {}", peer, e),
+        Err(_) => return debug!("ZMQ handshake with {} timed out", peer),
+    }
+
+    // Frames are read by a task of their own, since a read can't be abandoned halfway through for a message
+    let (requests, mut incoming) = mpsc::unbounded_channel();
+    let reading = tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            let request = match frame {
+                Frame::Command { name, data } if name == b"SUBSCRIBE" => Request::Subscribe(data),
+                Frame::Command { name, data } if name == b"CANCEL" => Request::Unsubscribe(data),
+                Frame::Command { name, data } if name == b"PING" && data.len() >= 2 => {
+                    Request::Ping(data[2..].to_vec())
+                }
+                // In ZMTP 3.0, subscriptions are messages starting with 1, and cancellations with 0
+                Frame::Message(body) => match body.split_first() {
+                    Some((1, topic)) => Request::Subscribe(topic.to_vec()),
+                    Some((0, topic)) => Request::Unsubscribe(topic.to_vec()),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            if requests.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut subscriptions = Subscriptions::default();
+    let result: std::io::Result<()> = async {
+        loop {
+            tokio::select! {
+                request = incoming.recv() => match request {
+                    Some(Request::Subscribe(topic)) => {
+                        if !subscriptions.subscribe(topic) {
+                            debug!("ZMQ subscriber {} has too many subscriptions", peer);
+                        }
+                    }
+                    Some(Request::Unsubscribe(topic)) => subscriptions.unsubscribe(&topic),
+                    Some(Request::Ping(context)) => {
+                        write_frame(&mut writer, COMMAND, &command(b"PONG", &context)).await?;
+                        writer.flush().await?;
+                    }
+                    None => return Ok(()),
+                },
+                message = messages.recv() => match message {
+                    Ok(message) => {
+                        if !subscriptions.matches(&message[0]) {
+                            continue;
+                        }
+                        for (i, frame) in message.iter().enumerate() {
+                            let flags = if i + 1 < message.len() { MORE } else { 0 };
+                            write_frame(&mut writer, flags, frame).await?;
+                        }
+                        writer.flush().await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("ZMQ subscriber {} missed {} messages", peer, missed)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+    reading.abort();
+    match result {
+        Ok(()) => debug!("ZMQ subscriber {} disconnected", peer),
+        Err(e) => debug!("ZMQ subscriber {} disconnected: {}", peer, e),
+    }
+}
+
+/// Exchanges greetings and `READY` commands with a new subscriber
+async fn handshake<R, W>(reader: &mut R, writer: &mut W) -> Result<(), String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let io_error = |e: std::io::Error| e.to_string();
+    writer.write_all(&greeting()).await.map_err(io_error)?;
+    writer.flush().await.map_err(io_error)?;
+    let mut greeting = [0u8; 64];
+    reader.read_exact(&mut greeting).await.map_err(io_error)?;
+    if greeting[0] != 0xff || greeting[9] != 0x7f {
+        return Err(String::from("not a ZMTP peer"));
+    }
+    if greeting[10] < 3 {
+        return Err(format!("unsupported ZMTP version {}", greeting[10]));
+    }
+    let mechanism = &greeting[12..32];
+    if mechanism.iter().take_while(|&&c| c != 0).ne(b"NULL".iter()) {
+        return Err(String::from("unsupported security mechanism"));
+    }
+
+    let mut ready = Vec::new();
+    ready.push(b"Socket-Type".len() as u8);
+    ready.extend_from_slice(b"Socket-Type");
+    ready.extend_from_slice(&(b"PUB".len() as u32).to_be_bytes());
+    ready.extend_from_slice(b"PUB");
+    write_frame(writer, COMMAND, &command(b"READY", &ready))
+        .await
+        .map_err(io_error)?;
+    writer.flush().await.map_err(io_error)?;
+
+    match read_frame(reader).await.map_err(io_error)? {
+        Frame::Command { name, data } if name == b"READY" => {
+            match property(&data, b"Socket-Type") {
+                Some(b"SUB") | Some(b"XSUB") => Ok(()),
+                Some(other) => Err(format!(
+                    "{} sockets can't subscribe",
+                    String::from_utf8_lossy(other)
+                )),
+                None => Err(String::from("no socket type")),
+            }
+        }
+        Frame::Command { name, data } if name == b"ERROR" => Err(format!(
+            "peer reported {}",
+            String::from_utf8_lossy(data.get(1..).unwrap_or_default())
+        )),
+        _ => Err(String::from("expected READY")),
+    }
+}
+
+/// Our greeting: ZMTP 3.0 with the NULL mechanism, as a server
+fn greeting() -> [u8; 64] {
+    let mut greeting = [0u8; 64];
+    greeting[0] = 0xff;
+    greeting[9] = 0x7f;
+    greeting[10] = 3;
+    greeting[12..16].copy_from_slice(b"NULL");
+    greeting
+}
+
+/// The body of a command frame
+fn command(name: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1 + name.len() + data.len());
+    body.push(name.len() as u8);
+    body.extend_from_slice(name);
+    body.extend_from_slice(data);
+    body
+}
+
+/// Looks up a property in the metadata of a `READY` command
+fn property<'a>(mut metadata: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    while let Some((&name_len, rest)) = metadata.split_first() {
+        let name_len = name_len as usize;
+        let key = rest.get(..name_len)?;
+        let len = rest
+            .get(name_len..name_len + 4)?
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | byte as usize);
+        let value = rest.get(name_len + 4..name_len + 4 + len)?;
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        metadata = &rest[name_len + 4 + len..];
+    }
+    None
+}
+
+enum Frame {
+    Command { name: Vec<u8>, data: Vec<u8> },
+    Message(Vec<u8>),
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Frame> {
+    let invalid = |reason: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, reason);
+    let flags = reader.read_u8().await?;
+    let size = if flags & LONG != 0 {
+        reader.read_u64().await?
+    } else {
+        reader.read_u8().await? as u64
+    };
+    if size > MAX_FRAME_SIZE {
+        return Err(invalid("frame too large"));
+    }
+    let mut body = vec![0; size as usize];
+    reader.read_exact(&mut body).await?;
+    if flags & COMMAND == 0 {
+        return Ok(Frame::Message(body));
+    }
+    let (&name_len, rest) = body.split_first().ok_or_else(|| invalid("empty command"))?;
+    if rest.len() < name_len as usize {
+        return Err(invalid("truncated command"));
+    }
+    let (name, data) = rest.split_at(name_len as usize);
+    Ok(Frame::Command {
+        name: name.to_vec(),
+        data: data.to_vec(),
+    })
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    flags: u8,
+    body: &[u8],
+) -> std::io::Result<()> {
+    if body.len() > u8::MAX as usize {
+        writer.write_u8(flags | LONG).await?;
+        writer.write_u64(body.len() as u64).await?;
+    } else {
+        writer.write_u8(flags).await?;
+        writer.write_u8(body.len() as u8).await?;
+    }
+    writer.write_all(body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::Block;
+
+    /// A subscriber's greeting, advertising `version`
+    fn client_greeting(version: u8) -> [u8; 64] {
+        let mut greeting = greeting();
+        greeting[10] = version;
+        greeting
+    }
+
+    fn ready(socket_type: &[u8]) -> Vec<u8> {
+        let mut metadata = vec![b"Socket-Type".len() as u8];
+        metadata.extend_from_slice(b"Socket-Type");
+        metadata.extend_from_slice(&(socket_type.len() as u32).to_be_bytes());
+        metadata.extend_from_slice(socket_type);
+        command(b"READY", &metadata)
+    }
+
+    /// Runs our side of the handshake against a subscriber which sends `sent`
+    async fn handshake_with(sent: &[u8]) -> Result<(), String> {
+        let mut reader = sent;
+        let mut written = Vec::new();
+        let result = handshake(&mut reader, &mut written).await;
+        assert_eq!(written[..64], greeting()[..]);
+        result
+    }
+
+    #[tokio::test]
+    async fn greets_subscribers() {
+        let greeting = greeting();
+        assert_eq!((greeting[0], greeting[9], greeting[10]), (0xff, 0x7f, 3));
+        assert_eq!(&greeting[12..17], b"NULL\0");
+
+        let mut sent = client_greeting(3).to_vec();
+        write_frame(&mut sent, COMMAND, &ready(b"SUB"))
+            .await
+            .unwrap();
+        assert_eq!(handshake_with(&sent).await, Ok(()));
+
+        let mut sent = client_greeting(3).to_vec();
+        write_frame(&mut sent, COMMAND, &ready(b"PUB"))
+            .await
+            .unwrap();
+        assert!(handshake_with(&sent).await.is_err());
+
+        let mut sent = client_greeting(2).to_vec();
+        write_frame(&mut sent, COMMAND, &ready(b"SUB"))
+            .await
+            .unwrap();
+        assert!(handshake_with(&sent).await.is_err());
+
+        let mut sent = client_greeting(3).to_vec();
+        sent[12..16].copy_from_slice(b"CURV");
+        assert!(handshake_with(&sent).await.is_err());
+
+        let mut sent = client_greeting(3).to_vec();
+        sent[0] = 0;
+        assert!(handshake_with(&sent).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let mut written = Vec::new();
+        write_frame(&mut written, MORE, b"hashtx").await.unwrap();
+        assert_eq!(written[..2], [MORE, 6]);
+        let long = vec![7u8; 300];
+        write_frame(&mut written, 0, &long).await.unwrap();
+        write_frame(&mut written, COMMAND, &command(b"SUBSCRIBE", b"rawtx"))
+            .await
+            .unwrap();
+
+        let mut reader = &written[..];
+        assert!(
+            matches!(read_frame(&mut reader).await.unwrap(), Frame::Message(body) if body == b"hashtx")
+        );
+        assert!(
+            matches!(read_frame(&mut reader).await.unwrap(), Frame::Message(body) if body == long)
+        );
+        match read_frame(&mut reader).await.unwrap() {
+            Frame::Command { name, data } => {
+                assert_eq!(name, b"SUBSCRIBE");
+                assert_eq!(data, b"rawtx");
+            }
+            Frame::Message(_) => panic!("expected a command"),
+        }
+        assert!(reader.is_empty());
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_frames() {
+        let mut too_large = vec![LONG];
+        too_large.extend_from_slice(&(MAX_FRAME_SIZE + 1).to_be_bytes());
+        let error = read_frame(&mut &too_large[..]).await.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        // The command name claims to be longer than the frame
+        let truncated = [COMMAND, 3, 5, b'P', b'I'];
+        let error = read_frame(&mut &truncated[..]).await.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        let empty = [COMMAND, 0];
+        assert!(read_frame(&mut &empty[..]).await.is_err());
+    }
+
+    #[test]
+    fn parses_properties() {
+        let mut metadata = ready(b"SUB")[6..].to_vec();
+        metadata.push(b"Identity".len() as u8);
+        metadata.extend_from_slice(b"Identity");
+        metadata.extend_from_slice(&2u32.to_be_bytes());
+        metadata.extend_from_slice(b"me");
+        assert_eq!(property(&metadata, b"Socket-Type"), Some(&b"SUB"[..]));
+        assert_eq!(property(&metadata, b"socket-type"), Some(&b"SUB"[..]));
+        assert_eq!(property(&metadata, b"Identity"), Some(&b"me"[..]));
+        assert_eq!(property(&metadata, b"Resource"), None);
+        // A value running past the end of the metadata
+        assert_eq!(property(&metadata[..metadata.len() - 1], b"Identity"), None);
+        assert_eq!(property(&[], b"Socket-Type"), None);
+    }
+
+    #[test]
+    fn numbers_messages_per_topic() {
+        let block = Block::_test_block();
+        let mut publisher = Publisher {
+            topic: ZmqTopic::HashTx,
+            socket: 0,
+            sequence: u32::MAX - 1,
+        };
+        let messages = publisher.messages(&Notification::BlockConnected(block.clone()));
+        assert_eq!(messages.len(), 2);
+        for (message, tx) in messages.iter().zip(block.transactions()) {
+            assert_eq!(message[0], b"hashtx");
+            assert_eq!(message[1], display_order(tx.txid().inner()));
+        }
+        assert_eq!(messages[0][2], (u32::MAX - 1).to_le_bytes());
+        // The sequence number wraps around
+        assert_eq!(messages[1][2], u32::MAX.to_le_bytes());
+        let tx = block.transactions()[1].clone();
+        let messages = publisher.messages(&Notification::TransactionAdded {
+            tx,
+            mempool_sequence: 5,
+        });
+        assert_eq!(messages[0][2], 0u32.to_le_bytes());
+
+        // Notifications which don't concern a topic don't use up its sequence numbers
+        let mut publisher = Publisher {
+            topic: ZmqTopic::HashBlock,
+            socket: 0,
+            sequence: 0,
+        };
+        let txid = block.transactions()[0].txid().clone();
+        let removed = Notification::TransactionRemoved {
+            txid,
+            mempool_sequence: 6,
+        };
+        assert!(publisher.messages(&removed).is_empty());
+        let messages = publisher.messages(&Notification::BlockDisconnected(block));
+        assert!(messages.is_empty());
+        assert_eq!(publisher.sequence, 0);
+    }
+
+    #[test]
+    fn builds_sequence_bodies() {
+        let block = Block::_test_block();
+        let hash = display_order(block.header().hash().inner());
+        let connected = bodies(
+            ZmqTopic::Sequence,
+            &Notification::BlockConnected(block.clone()),
+        );
+        assert_eq!(connected, vec![[&hash[..], b"C"].concat()]);
+        let disconnected = bodies(
+            ZmqTopic::Sequence,
+            &Notification::BlockDisconnected(block.clone()),
+        );
+        assert_eq!(disconnected, vec![[&hash[..], b"D"].concat()]);
+
+        let tx = block.transactions()[1].clone();
+        let txid = display_order(tx.txid().inner());
+        let added = bodies(
+            ZmqTopic::Sequence,
+            &Notification::TransactionAdded {
+                tx: tx.clone(),
+                mempool_sequence: 0x0102,
+            },
+        );
+        assert_eq!(
+            added,
+            vec![[&txid[..], b"A", &[2, 1, 0, 0, 0, 0, 0, 0]].concat()]
+        );
+        let removed = bodies(
+            ZmqTopic::Sequence,
+            &Notification::TransactionRemoved {
+                txid: tx.txid().clone(),
+                mempool_sequence: 3,
+            },
+        );
+        assert_eq!(
+            removed,
+            vec![[&txid[..], b"R", &3u64.to_le_bytes()].concat()]
+        );
+    }
+
+    #[test]
+    fn tracks_subscriptions() {
+        let mut subscriptions = Subscriptions::default();
+        assert!(!subscriptions.matches(b"hashtx"));
+        assert!(subscriptions.subscribe(b"hash".to_vec()));
+        assert!(subscriptions.subscribe(b"hash".to_vec()));
+        assert!(subscriptions.matches(b"hashtx"));
+        assert!(subscriptions.matches(b"hashblock"));
+        assert!(!subscriptions.matches(b"rawtx"));
+        // Subscribing twice takes two cancellations to undo
+        subscriptions.unsubscribe(b"hash");
+        assert!(subscriptions.matches(b"hashtx"));
+        subscriptions.unsubscribe(b"hash");
+        assert!(!subscriptions.matches(b"hashtx"));
+
+        // An empty prefix matches everything
+        assert!(subscriptions.subscribe(Vec::new()));
+        assert!(subscriptions.matches(b"rawblock"));
+        subscriptions.unsubscribe(b"");
+
+        for i in 0..MAX_SUBSCRIPTIONS {
+            assert!(subscriptions.subscribe(format!("topic{}", i).into_bytes()));
+        }
+        assert!(!subscriptions.subscribe(b"rawtx".to_vec()));
+        assert!(!subscriptions.matches(b"rawtx"));
+        // Repeating a subscription doesn't take up another place
+        assert!(subscriptions.subscribe(b"topic0".to_vec()));
+        assert_eq!(subscriptions.0.len(), MAX_SUBSCRIPTIONS);
+    }
+}